@@ -7,7 +7,7 @@ use uuid::Uuid;
 
 fn storage_read_same_key(storage: &Storage, key: &str) {
     for _ in 0..3_000 {
-        storage.read(key).unwrap();
+        storage.read(key).unwrap().unwrap();
     }
 }
 
@@ -17,7 +17,7 @@ fn setup(size: usize) -> lsm_storage::Storage {
     path.push(".");
     path.push(&uuid);
 
-    let mut storage = Storage::builder().segments_path(path).build().unwrap();
+    let storage = Storage::builder().segments_path(path).build().unwrap();
 
     let mut writer = storage.open_as_writer().unwrap();
 
@@ -50,7 +50,7 @@ fn read_same_key(c: &mut Criterion) {
 
 fn storage_scan(engine: &Storage) {
     for i in 0..3_000 {
-        engine.read(&format!("key-{}", i));
+        engine.read(&format!("key-{}", i)).unwrap();
     }
 }
 
@@ -0,0 +1,597 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use url::Url;
+
+use crate::env::{Env, OsEnv};
+use crate::format;
+use crate::manifest::{self, Edit, Manifest, MANIFEST_NAME};
+use crate::storage::Storage;
+
+/// The name of the description of a backup, inside its directory. It is uploaded last, so that
+/// backups without one are incomplete, and ignored.
+const BACKUP_NAME: &str = "BACKUP";
+
+/// How many bytes the nonce that starts every encrypted object takes.
+const NONCE_LEN: usize = 12;
+
+/// A key backups are encrypted with, for AES-256-GCM.
+pub type BackupKey = [u8; 32];
+
+/// Hands out the keys backups are encrypted with, e.g. from a key management service, apart from
+/// anything the storage itself uses, so that off-site copies are managed on their own.
+///
+/// Keys are known by an id, which backups record, so that the current key can be rotated while
+/// the backups encrypted with the older ones can still be restored.
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new backups are encrypted with, along with the key.
+    fn current(&self) -> Result<(String, BackupKey)>;
+
+    /// The key with the given id.
+    fn get(&self, id: &str) -> Result<BackupKey>;
+}
+
+/// Provides a single key, known by the given id.
+pub struct StaticKey {
+    id: String,
+    key: BackupKey,
+}
+
+impl StaticKey {
+    pub fn new(id: impl Into<String>, key: BackupKey) -> Self {
+        StaticKey { id: id.into(), key }
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn current(&self) -> Result<(String, BackupKey)> {
+        Ok((self.id.clone(), self.key))
+    }
+
+    fn get(&self, id: &str) -> Result<BackupKey> {
+        if id != self.id {
+            bail!("unknown backup key {}", id);
+        }
+
+        Ok(self.key)
+    }
+}
+
+/// How backups are written and read.
+#[derive(Clone, Default)]
+pub struct BackupOptions {
+    /// Encrypts new backups with the current key, and decrypts the backups encrypted with any
+    /// of them. Defaults to none: backups are stored as they are, and encrypted ones can't be
+    /// restored nor verified.
+    pub keys: Option<Arc<dyn KeyProvider>>,
+}
+
+/// Describes a backup in object storage.
+///
+/// The sstables of every backup are kept together under `sstables/`, named after their file and
+/// the checksum of their contents, so that a backup only uploads those that no earlier backup
+/// did. Everything else a backup holds is under `backups/<id>/`, named the same way.
+///
+/// The objects of an encrypted backup hold a random nonce followed by their contents, encrypted
+/// with AES-256-GCM and authenticated along with their name. Their sstables are kept under
+/// `sstables/<key id>/`, as only the backups encrypted with the same key can reuse them. The
+/// description of the backup itself isn't encrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Orders the backups at the same location: a backup has the highest id so far.
+    pub id: u64,
+    /// The sstables of each level, as the name of their file and the object holding them.
+    pub levels: Vec<Vec<(String, String)>>,
+    /// The WALs of the memtables, as the name of their file and the object holding them.
+    pub wals: Vec<(String, String)>,
+    /// How many of the sstables were uploaded by this backup, rather than by an earlier one.
+    pub uploaded: usize,
+    /// The id of the key the objects of the backup are encrypted with, if they are.
+    pub key_id: Option<String>,
+}
+
+/// Uploads a checkpoint of the storage to the location the URL names, e.g. `s3://bucket/path`,
+/// `gs://bucket/path` or `file:///path`. See
+/// [`Storage::backup_to_remote`](crate::storage::Storage::backup_to_remote).
+pub(crate) async fn backup(storage: &Storage, url: &str, options: &BackupOptions) -> Result<BackupManifest> {
+    let (store, root) = open(url)?;
+    let env = storage.config.env.as_ref();
+
+    let (key_id, cipher) = match &options.keys {
+        Some(keys) => {
+            let (id, key) = keys.current()?;
+            (Some(id), Cipher(Some(Aes256Gcm::new(&key.into()))))
+        }
+        None => (None, Cipher(None)),
+    };
+    let sstables = sstables_path(&root, key_id.as_deref());
+
+    // The sstables of the checkpoint are hard links, which can't cross file systems.
+    let checkpoint = tempfile::Builder::new()
+        .prefix(".backup-")
+        .tempdir_in(&storage.config.segments_path)?;
+    storage.checkpoint(checkpoint.path())?;
+
+    let (_, edits) = Manifest::open(&storage.config.env, checkpoint.path(), &[])?;
+    let stored: Vec<ObjectPath> = store
+        .list(Some(&sstables))
+        .map(|meta| meta.map(|meta| meta.location))
+        .collect::<Result<_, _>>()
+        .await?;
+
+    let mut backup = BackupManifest {
+        id: latest(store.as_ref(), &root).await?.map_or(0, |backup| backup.id + 1),
+        levels: Vec::new(),
+        wals: Vec::new(),
+        uploaded: 0,
+        key_id,
+    };
+
+    for names in manifest::replay(edits) {
+        let mut level = Vec::new();
+
+        for name in names {
+            let contents = read(env, &checkpoint.path().join(&name))?;
+            let object = object_name(&name, &contents);
+            let location = sstables.child(object.as_str());
+
+            if !stored.contains(&location) {
+                store.put(&location, cipher.seal(&object, &contents)?).await?;
+                backup.uploaded += 1;
+            }
+            level.push((name, object));
+        }
+        backup.levels.push(level);
+    }
+
+    let directory = root.child("backups").child(format!("{:020}", backup.id));
+    for path in env.read_dir(checkpoint.path())? {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+        if name.starts_with(crate::WAL_NAME) {
+            let contents = read(env, &path)?;
+            let object = object_name(&name, &contents);
+
+            store.put(&directory.child(object.as_str()), cipher.seal(&object, &contents)?).await?;
+            backup.wals.push((name, object));
+        }
+    }
+
+    let mut description = Vec::new();
+    format::write_record(&mut description, &backup)?;
+    store.put(&directory.child(BACKUP_NAME), description.into()).await?;
+
+    Ok(backup)
+}
+
+/// Downloads the latest backup at the location the URL names into the given directory, which
+/// must be empty or missing. Fails if an object of the backup is corrupt. See
+/// [`Storage::restore_from_remote`](crate::storage::Storage::restore_from_remote).
+pub(crate) async fn restore(url: &str, dir: &Path, options: &BackupOptions) -> Result<BackupManifest> {
+    let (store, root) = open(url)?;
+    let env = OsEnv;
+
+    env.create_dir_all(dir)?;
+    if !env.read_dir(dir)?.is_empty() {
+        bail!("{} is not empty", dir.display());
+    }
+
+    let backup = latest(store.as_ref(), &root)
+        .await?
+        .ok_or_else(|| anyhow!("no backup at {}", url))?;
+    let cipher = Cipher::of(&backup, options)?;
+    let sstables = sstables_path(&root, backup.key_id.as_deref());
+
+    let mut edits = Vec::new();
+    for (level, tables) in backup.levels.iter().enumerate() {
+        for (name, object) in tables {
+            let location = sstables.child(object.as_str());
+            download(store.as_ref(), &location, object, &cipher, &env, &dir.join(name)).await?;
+
+            edits.push(Edit::AddTable { name: name.clone(), level });
+        }
+    }
+
+    let directory = root.child("backups").child(format!("{:020}", backup.id));
+    for (name, object) in &backup.wals {
+        let location = directory.child(object.as_str());
+        download(store.as_ref(), &location, object, &cipher, &env, &dir.join(name)).await?;
+    }
+
+    Manifest::create(&env, &dir.join(MANIFEST_NAME), &edits)?;
+    env.sync_dir(dir)?;
+
+    Ok(backup)
+}
+
+/// What verifying a backup found. See
+/// [`Storage::verify_backup`](crate::storage::Storage::verify_backup).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The id of the backup verified, the latest complete one.
+    pub id: u64,
+    /// How many objects the backup is made of.
+    pub objects: usize,
+    /// The objects of the backup that are gone.
+    pub missing: Vec<String>,
+    /// The objects of the backup whose contents don't match their checksum, or fail to decrypt.
+    pub corrupt: Vec<String>,
+    /// The backups newer than the one verified that were never completed, by their directory.
+    pub incomplete: Vec<String>,
+}
+
+impl BackupReport {
+    /// Whether restoring the backup would fail. Incomplete backups are ignored when restoring, so
+    /// they only tell that a later backup didn't finish.
+    pub fn is_hard_failure(&self) -> bool {
+        !self.missing.is_empty() || !self.corrupt.is_empty()
+    }
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "backup {}: {} objects, {} missing and {} corrupt, after {} incomplete backups",
+            self.id,
+            self.objects,
+            self.missing.len(),
+            self.corrupt.len(),
+            self.incomplete.len()
+        )
+    }
+}
+
+/// Checks that every object of the latest complete backup at the location the URL names is
+/// there and matches its checksum, without restoring it. See
+/// [`Storage::verify_backup`](crate::storage::Storage::verify_backup).
+pub(crate) async fn verify(url: &str, options: &BackupOptions) -> Result<BackupReport> {
+    let (store, root) = open(url)?;
+
+    let (backup, incomplete) = latest_with_incomplete(store.as_ref(), &root).await?;
+    let backup = backup.ok_or_else(|| anyhow!("no backup at {}", url))?;
+    let cipher = Cipher::of(&backup, options)?;
+
+    let directory = root.child("backups").child(format!("{:020}", backup.id));
+    let sstables_path = sstables_path(&root, backup.key_id.as_deref());
+    let sstables = backup.levels.iter().flatten().map(|(_, object)| (sstables_path.clone(), object));
+    let wals = backup.wals.iter().map(|(_, object)| (directory.clone(), object));
+
+    let mut report = BackupReport {
+        id: backup.id,
+        incomplete: incomplete.iter().map(|directory| directory.to_string()).collect(),
+        ..BackupReport::default()
+    };
+
+    for (parent, object) in sstables.chain(wals) {
+        let location = parent.child(object.as_str());
+        report.objects += 1;
+
+        match store.get(&location).await {
+            Ok(result) => {
+                let intact = cipher
+                    .open(object, &result.bytes().await?)
+                    .is_ok_and(|contents| matches_checksum(object, &contents));
+
+                if !intact {
+                    report.corrupt.push(location.to_string());
+                }
+            }
+            Err(object_store::Error::NotFound { .. }) => report.missing.push(location.to_string()),
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok(report)
+}
+
+/// The object store the URL names, along with the path of the backups inside it. The credentials
+/// are taken from the environment, e.g. `AWS_ACCESS_KEY_ID` or `GOOGLE_SERVICE_ACCOUNT`.
+fn open(url: &str) -> Result<(Box<dyn ObjectStore>, ObjectPath)> {
+    let url = Url::parse(url).with_context(|| format!("invalid backup location {}", url))?;
+    let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+
+    Ok(object_store::parse_url_opts(&url, options)?)
+}
+
+/// The complete backup with the highest id, if any.
+async fn latest(store: &dyn ObjectStore, root: &ObjectPath) -> Result<Option<BackupManifest>> {
+    Ok(latest_with_incomplete(store, root).await?.0)
+}
+
+/// Like [`latest`], but also returns the directories of the incomplete backups newer than it.
+async fn latest_with_incomplete(
+    store: &dyn ObjectStore,
+    root: &ObjectPath,
+) -> Result<(Option<BackupManifest>, Vec<ObjectPath>)> {
+    let listing = store.list_with_delimiter(Some(&root.child("backups"))).await?;
+
+    let mut directories = listing.common_prefixes;
+    directories.sort();
+
+    let mut incomplete = Vec::new();
+    for directory in directories.into_iter().rev() {
+        match store.get(&directory.child(BACKUP_NAME)).await {
+            Ok(result) => {
+                let description = result.bytes().await?;
+                let backup = format::read_record(description.as_ref())?
+                    .with_context(|| format!("{}: empty backup description", directory))?;
+
+                return Ok((Some(backup), incomplete));
+            }
+            Err(object_store::Error::NotFound { .. }) => incomplete.push(directory),
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok((None, incomplete))
+}
+
+/// Where the sstables of the backups encrypted with the given key, if any, are kept.
+fn sstables_path(root: &ObjectPath, key_id: Option<&str>) -> ObjectPath {
+    match key_id {
+        Some(id) => root.child("sstables").child(id),
+        None => root.child("sstables"),
+    }
+}
+
+/// Encrypts and decrypts the objects of a backup, or leaves them as they are if it isn't
+/// encrypted.
+struct Cipher(Option<Aes256Gcm>);
+
+impl Cipher {
+    /// The cipher of the objects of the given backup. Fails if it is encrypted with a key that
+    /// the options can't provide.
+    fn of(backup: &BackupManifest, options: &BackupOptions) -> Result<Self> {
+        let Some(id) = &backup.key_id else {
+            return Ok(Cipher(None));
+        };
+
+        let keys = options
+            .keys
+            .as_ref()
+            .ok_or_else(|| anyhow!("backup {} is encrypted with key {}", backup.id, id))?;
+
+        Ok(Cipher(Some(Aes256Gcm::new(&keys.get(id)?.into()))))
+    }
+
+    fn seal(&self, object: &str, contents: &Bytes) -> Result<Bytes> {
+        let Some(cipher) = &self.0 else {
+            return Ok(contents.clone());
+        };
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: contents, aad: object.as_bytes() };
+        let sealed = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("{}: failed to encrypt", object))?;
+
+        Ok([nonce.as_slice(), &sealed].concat().into())
+    }
+
+    fn open(&self, object: &str, contents: &Bytes) -> Result<Bytes> {
+        let Some(cipher) = &self.0 else {
+            return Ok(contents.clone());
+        };
+
+        if contents.len() < NONCE_LEN {
+            bail!("{}: truncated", object);
+        }
+
+        let (nonce, sealed) = contents.split_at(NONCE_LEN);
+        let payload = Payload { msg: sealed, aad: object.as_bytes() };
+        let opened = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("{}: failed to decrypt", object))?;
+
+        Ok(opened.into())
+    }
+}
+
+/// The name of the object holding the file with the given name and contents: the name of the
+/// file followed by the checksum of its contents.
+fn object_name(name: &str, contents: &[u8]) -> String {
+    format!("{}-{:08x}", name, crc32fast::hash(contents))
+}
+
+/// Whether the contents match the checksum that ends the name of their object.
+fn matches_checksum(object: &str, contents: &[u8]) -> bool {
+    object
+        .rsplit_once('-')
+        .and_then(|(_, checksum)| u32::from_str_radix(checksum, 16).ok())
+        .is_some_and(|checksum| checksum == crc32fast::hash(contents))
+}
+
+fn read(env: &dyn Env, path: &Path) -> Result<Bytes> {
+    let mut contents = Vec::new();
+    env.open(path)?.read_to_end(&mut contents)?;
+
+    Ok(contents.into())
+}
+
+async fn download(
+    store: &dyn ObjectStore,
+    location: &ObjectPath,
+    object: &str,
+    cipher: &Cipher,
+    env: &dyn Env,
+    path: &Path,
+) -> Result<()> {
+    let contents = cipher.open(object, &store.get(location).await?.bytes().await?)?;
+    if !matches_checksum(object, &contents) {
+        bail!("{}: checksum mismatch", location);
+    }
+
+    let mut file = env.create(path)?;
+    file.write_all(&contents)?;
+    file.sync()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use anyhow::Result;
+
+    use super::{BackupOptions, StaticKey};
+    use crate::scheduler::Scheduling;
+    use crate::storage::Storage;
+    use crate::test_utils::Test;
+
+    #[tokio::test]
+    async fn backups_upload_new_sstables_only_and_restore_into_a_storage() -> Result<()> {
+        let test = Test::new()?;
+        let remote = tempfile::tempdir()?;
+        let url = format!("file://{}", remote.path().display());
+        let options = BackupOptions::default();
+
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.insert("unflushed".to_owned(), b"in a WAL".to_vec())?;
+
+        let first = storage.backup_to_remote(&url, &options).await?;
+        let sstables = first.levels.iter().flatten().count();
+        assert!(sstables > 0);
+        assert_eq!(first.uploaded, sstables);
+        assert!(!first.wals.is_empty());
+
+        let second = storage.backup_to_remote(&url, &options).await?;
+        assert_eq!(second.id, first.id + 1);
+        assert_eq!(second.uploaded, 0);
+
+        let restored = test.path("restored");
+        assert_eq!(Storage::restore_from_remote(&url, &restored, &options).await?, second);
+        assert!(Storage::restore_from_remote(&url, &restored, &options).await.is_err());
+
+        let copy = Storage::builder()
+            .segments_path(restored.clone())
+            .wal_path(restored)
+            .scheduling(Scheduling::Manual)
+            .build()?;
+        assert_eq!(copy.read("unflushed")?, Some(b"in a WAL".to_vec()));
+        assert_eq!(copy.read("key-0")?, storage.read("key-0")?);
+        assert!(copy.read("key-0")?.is_some());
+
+        let empty = tempfile::tempdir()?;
+        let url = format!("file://{}", empty.path().display());
+        assert!(Storage::restore_from_remote(&url, &test.path("none"), &options).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifying_finds_the_missing_and_corrupt_objects_of_the_latest_backup() -> Result<()> {
+        let test = Test::new()?;
+        let remote = tempfile::tempdir()?;
+        let url = format!("file://{}", remote.path().display());
+        let options = BackupOptions::default();
+        assert!(Storage::verify_backup(&url, &options).await.is_err());
+
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.insert("unflushed".to_owned(), b"in a WAL".to_vec())?;
+
+        let backup = storage.backup_to_remote(&url, &options).await?;
+        let report = Storage::verify_backup(&url, &options).await?;
+        assert!(!report.is_hard_failure());
+        assert_eq!(report.objects, backup.levels.iter().flatten().count() + backup.wals.len());
+
+        // A later backup that never finished.
+        let unfinished = remote.path().join("backups").join(format!("{:020}", backup.id + 1));
+        fs::create_dir_all(&unfinished)?;
+        fs::write(unfinished.join("write-ahead-log-0-00000000"), b"")?;
+
+        let (_, sstable) = &backup.levels.iter().flatten().next().unwrap();
+        fs::write(remote.path().join("sstables").join(sstable), b"garbage")?;
+        let directory = remote.path().join("backups").join(format!("{:020}", backup.id));
+        fs::remove_file(directory.join(&backup.wals[0].1))?;
+
+        let report = Storage::verify_backup(&url, &options).await?;
+        assert!(report.is_hard_failure());
+        assert_eq!(report.id, backup.id);
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(report.corrupt[0].ends_with(sstable.as_str()));
+        assert_eq!(report.missing.len(), 1);
+        assert!(report.missing[0].ends_with(backup.wals[0].1.as_str()));
+        assert_eq!(report.incomplete.len(), 1);
+
+        assert!(Storage::restore_from_remote(&url, &test.path("restored"), &options).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_backups_need_their_key_to_be_restored_or_verified() -> Result<()> {
+        let test = Test::new()?;
+        let remote = tempfile::tempdir()?;
+        let url = format!("file://{}", remote.path().display());
+
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.insert("unflushed".to_owned(), b"in a WAL".to_vec())?;
+
+        let plain = storage.backup_to_remote(&url, &BackupOptions::default()).await?;
+        let options = BackupOptions {
+            keys: Some(Arc::new(StaticKey::new("first", [7; 32]))),
+        };
+        let encrypted = storage.backup_to_remote(&url, &options).await?;
+        assert_eq!(encrypted.key_id.as_deref(), Some("first"));
+        // The sstables of the plain backup can't be reused.
+        assert_eq!(encrypted.uploaded, plain.uploaded);
+
+        let (_, sstable) = encrypted.levels.iter().flatten().next().unwrap();
+        let stored = fs::read(remote.path().join("sstables").join("first").join(sstable))?;
+        let original = fs::read(test.path(sstable.rsplit_once('-').unwrap().0))?;
+        // A nonce, then the contents encrypted, then the tag authenticating them.
+        assert_eq!(stored.len(), 12 + original.len() + 16);
+        assert!(!stored.starts_with(&original[..16]) && !stored[12..].starts_with(&original[..16]));
+
+        assert!(Storage::verify_backup(&url, &BackupOptions::default()).await.is_err());
+        let other = BackupOptions {
+            keys: Some(Arc::new(StaticKey::new("second", [7; 32]))),
+        };
+        assert!(Storage::verify_backup(&url, &other).await.is_err());
+        let wrong = BackupOptions {
+            keys: Some(Arc::new(StaticKey::new("first", [8; 32]))),
+        };
+        let report = Storage::verify_backup(&url, &wrong).await?;
+        assert_eq!(report.corrupt.len(), report.objects);
+        assert!(!Storage::verify_backup(&url, &options).await?.is_hard_failure());
+
+        let restored = test.path("restored");
+        assert!(Storage::restore_from_remote(&url, &restored, &BackupOptions::default()).await.is_err());
+        assert_eq!(Storage::restore_from_remote(&url, &restored, &options).await?, encrypted);
+
+        let copy = Storage::builder()
+            .segments_path(restored.clone())
+            .wal_path(restored)
+            .scheduling(Scheduling::Manual)
+            .build()?;
+        assert_eq!(copy.read("unflushed")?, Some(b"in a WAL".to_vec()));
+        assert_eq!(copy.read("key-0")?, storage.read("key-0")?);
+
+        Ok(())
+    }
+}
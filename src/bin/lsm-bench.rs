@@ -0,0 +1,310 @@
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use lsm_storage::storage::Storage;
+
+const USAGE: &str = "\
+Usage: lsm-bench [options]
+
+Options:
+    --workload <name>     fill-sequential, fill-random, read-random, read-while-writing,
+                          ycsb-a (50% reads), ycsb-b (95% reads) or ycsb-c (100% reads)
+                          [default: fill-sequential]
+    --num <n>             number of keys in the key space, and of operations [default: 100000]
+    --key-size <bytes>    size of the generated keys [default: 16]
+    --value-size <bytes>  size of the generated values [default: 100]
+    --threads <n>         number of threads issuing operations [default: 1]
+    --wal-buffer <bytes>  how many bytes of entries are buffered before reaching the WAL
+                          [default: 0]
+    --data-dir <path>     where the storage is kept [default: a temporary directory]";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Workload {
+    FillSequential,
+    FillRandom,
+    ReadRandom,
+    ReadWhileWriting,
+    /// A YCSB-like mix of uniformly distributed reads and updates over a pre-filled key space.
+    Mixed { read_percentage: u64 },
+}
+
+struct Options {
+    workload: Workload,
+    num: usize,
+    key_size: usize,
+    value_size: usize,
+    threads: usize,
+    wal_buffer: usize,
+    data_dir: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options {
+            workload: Workload::FillSequential,
+            num: 100_000,
+            key_size: 16,
+            value_size: 100,
+            threads: 1,
+            wal_buffer: 0,
+            data_dir: None,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("missing value for {}", arg));
+
+            match arg.as_str() {
+                "--workload" => options.workload = parse_workload(&value()?)?,
+                "--num" => options.num = value()?.parse()?,
+                "--key-size" => options.key_size = value()?.parse()?,
+                "--value-size" => options.value_size = value()?.parse()?,
+                "--threads" => options.threads = value()?.parse()?,
+                "--wal-buffer" => options.wal_buffer = value()?.parse()?,
+                "--data-dir" => options.data_dir = Some(PathBuf::from(value()?)),
+                _ => bail!("unknown option {}", arg),
+            }
+        }
+
+        if options.threads == 0 {
+            bail!("--threads must be at least 1");
+        }
+
+        Ok(options)
+    }
+
+    fn key(&self, n: usize) -> String {
+        format!("{:0width$}", n, width = self.key_size)
+    }
+
+    fn value(&self) -> Vec<u8> {
+        vec![b'v'; self.value_size]
+    }
+}
+
+fn parse_workload(name: &str) -> Result<Workload> {
+    let workload = match name {
+        "fill-sequential" => Workload::FillSequential,
+        "fill-random" => Workload::FillRandom,
+        "read-random" => Workload::ReadRandom,
+        "read-while-writing" => Workload::ReadWhileWriting,
+        "ycsb-a" => Workload::Mixed { read_percentage: 50 },
+        "ycsb-b" => Workload::Mixed { read_percentage: 95 },
+        "ycsb-c" => Workload::Mixed { read_percentage: 100 },
+        _ => bail!("unknown workload {}", name),
+    };
+
+    Ok(workload)
+}
+
+/// A xorshift generator. Good enough to spread keys around, and avoids depending on a rand crate.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Rng((nanos ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        (self.0 % bound.max(1) as u64) as usize
+    }
+}
+
+/// The latencies recorded for one kind of operation.
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    fn record<T>(&mut self, operation: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = operation();
+        self.0.push(start.elapsed());
+
+        result
+    }
+
+    fn merge(&mut self, other: Latencies) {
+        self.0.extend(other.0);
+    }
+
+    fn report(mut self, name: &str, elapsed: Duration) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        self.0.sort();
+
+        let percentile = |p: f64| {
+            let index = ((self.0.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            self.0[index].as_secs_f64() * 1_000_000.0
+        };
+
+        println!(
+            "{:<6} {:>10} ops {:>12.0} ops/s | p50 {:>9.1}us p95 {:>9.1}us p99 {:>9.1}us p99.9 {:>9.1}us max {:>9.1}us",
+            name,
+            self.0.len(),
+            self.0.len() as f64 / elapsed.as_secs_f64(),
+            percentile(0.50),
+            percentile(0.95),
+            percentile(0.99),
+            percentile(0.999),
+            percentile(1.0),
+        );
+    }
+}
+
+/// The latencies recorded by a thread.
+#[derive(Default)]
+struct Measurements {
+    reads: Latencies,
+    writes: Latencies,
+}
+
+impl Measurements {
+    fn merge(&mut self, other: Measurements) {
+        self.reads.merge(other.reads);
+        self.writes.merge(other.writes);
+    }
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(error) = run(options) {
+        eprintln!("lsm-bench: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run(options: Options) -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let data_dir = options
+        .data_dir
+        .clone()
+        .unwrap_or_else(|| tempdir.path().to_path_buf());
+
+    let storage = Storage::builder()
+        .segments_path(data_dir.clone())
+        .wal_path(data_dir)
+        .wal_buffer_size(options.wal_buffer)
+        .build()?;
+
+    if !matches!(options.workload, Workload::FillSequential | Workload::FillRandom) {
+        let mut writer = storage.open_as_writer()?;
+
+        for n in 0..options.num {
+            writer.insert(options.key(n), options.value())?;
+        }
+    }
+
+    let options = Arc::new(options);
+
+    let start = Instant::now();
+    let mut measurements = Measurements::default();
+
+    let background_writer = (options.workload == Workload::ReadWhileWriting).then(|| {
+        let options = options.clone();
+        let writer = storage.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = done.clone();
+
+        let handle = thread::spawn(move || -> Result<Measurements> {
+            let mut rng = Rng::new(u64::MAX);
+            let mut measurements = Measurements::default();
+
+            while !stop.load(Ordering::Relaxed) {
+                let key = options.key(rng.below(options.num));
+                measurements
+                    .writes
+                    .record(|| writer.insert(key, options.value()))?;
+            }
+
+            Ok(measurements)
+        });
+
+        (done, handle)
+    });
+
+    let handles: Vec<_> = (0..options.threads)
+        .map(|thread| {
+            let options = options.clone();
+            let storage = storage.clone();
+
+            thread::spawn(move || -> Result<Measurements> {
+                let mut rng = Rng::new(thread as u64);
+                let mut measurements = Measurements::default();
+                let operations = (thread..options.num).step_by(options.threads);
+
+                for n in operations {
+                    let write = match options.workload {
+                        Workload::FillSequential | Workload::FillRandom => true,
+                        Workload::ReadRandom | Workload::ReadWhileWriting => false,
+                        Workload::Mixed { read_percentage } => {
+                            rng.below(100) as u64 >= read_percentage
+                        }
+                    };
+
+                    let key = match options.workload {
+                        Workload::FillSequential => options.key(n),
+                        _ => options.key(rng.below(options.num)),
+                    };
+
+                    if write {
+                        measurements
+                            .writes
+                            .record(|| storage.insert(key, options.value()))?;
+                    } else {
+                        measurements.reads.record(|| storage.read(&key))?;
+                    }
+                }
+
+                Ok(measurements)
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        let thread_measurements = handle.join().map_err(|_| anyhow!("a thread panicked"))??;
+        measurements.merge(thread_measurements);
+    }
+
+    let elapsed = start.elapsed();
+
+    if let Some((done, handle)) = background_writer {
+        done.store(true, Ordering::Relaxed);
+        let writer_measurements = handle.join().map_err(|_| anyhow!("a thread panicked"))??;
+        measurements.merge(writer_measurements);
+    }
+
+    println!(
+        "keys: {} | key size: {} bytes | value size: {} bytes | threads: {} | elapsed: {:.2}s",
+        options.num,
+        options.key_size,
+        options.value_size,
+        options.threads,
+        elapsed.as_secs_f64()
+    );
+    measurements.reads.report("read", elapsed);
+    measurements.writes.report("write", elapsed);
+
+    Ok(())
+}
@@ -0,0 +1,772 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+#[cfg(feature = "remote-backup")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+#[cfg(feature = "remote-backup")]
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "remote-backup")]
+use lsm_storage::backup::{BackupOptions, KeyProvider, StaticKey};
+use lsm_storage::scheduler::Scheduling;
+use lsm_storage::sstable::SSTableReader;
+use lsm_storage::storage::{Storage, PROPERTIES};
+use serde::{Deserialize, Serialize};
+
+/// How many entries are imported at once, through a single writer or request.
+const IMPORT_BATCH: usize = 1000;
+/// How many entries are listed at once when exporting from a server.
+const EXPORT_PAGE: usize = 1000;
+
+/// Reads and writes a storage, either through a running server or directly in its data directory.
+///
+/// Without a command, reads commands from the standard input, one per line, e.g. `put key "some
+/// value"`.
+#[derive(Parser)]
+#[command(name = "lsm-cli", version)]
+struct Cli {
+    /// Opens the storage in this directory instead of talking to a server. The storage must not be
+    /// open elsewhere, e.g. by a server. Only reads and imports are allowed: bulk loads are best
+    /// done offline.
+    #[arg(long, value_name = "PATH", conflicts_with = "server")]
+    data_dir: Option<PathBuf>,
+
+    /// Where the WALs of the storage opened with --data-dir are kept [default: the data directory]
+    #[arg(long, value_name = "PATH", requires = "data_dir")]
+    wal_dir: Option<PathBuf>,
+
+    /// The URL of the server [default: http://127.0.0.1:3000]
+    #[arg(long, value_name = "URL")]
+    server: Option<String>,
+
+    /// The API key sent to the server.
+    #[arg(long, env = "LSM_API_KEY", value_name = "KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the value of a key.
+    Get { key: String },
+    /// Sets the value of a key.
+    Put { key: String, value: String },
+    /// Removes a key.
+    Del { key: String },
+    /// Prints the keys and their values in key order, one per line, separated by a tab.
+    Scan {
+        /// The key to start from.
+        #[arg(long, default_value = "")]
+        from: String,
+        /// How many keys to print at most.
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Prints the properties of the storage.
+    Stats,
+    /// Inserts the entries read from a file, or from the standard input.
+    Import {
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// Whether the first row of a CSV file names the columns, rather than holding an entry.
+        #[arg(long)]
+        header: bool,
+        file: Option<PathBuf>,
+    },
+    /// Writes every entry, in key order, to a file, or to the standard output. Values that aren't
+    /// UTF-8 are written lossily.
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// Whether to write a first row naming the columns of a CSV file.
+        #[arg(long)]
+        header: bool,
+        file: Option<PathBuf>,
+    },
+    /// Checks the latest backup at the given location, e.g. `s3://bucket/path`, without restoring
+    /// it, and fails if an object it is made of is missing or corrupt. The credentials are taken
+    /// from the environment. Neither the server nor the data directory are used.
+    #[cfg(feature = "remote-backup")]
+    VerifyBackup {
+        url: String,
+        /// The key an encrypted backup was made with, as 64 hexadecimal digits. Its id is the
+        /// name of the file, without the extension.
+        #[arg(long, value_name = "PATH")]
+        key_file: Option<PathBuf>,
+    },
+    /// Describes the sstable at the given path, as recorded in its footer, without the storage it
+    /// belongs to, which may be open.
+    SstDump {
+        path: PathBuf,
+        /// Also prints its entries, one per line: the key, the sequence number and the value,
+        /// separated by tabs. Removed keys have no value.
+        #[arg(long)]
+        entries: bool,
+    },
+}
+
+/// How entries are written down by imports and exports.
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// One JSON object per line, e.g. `{"key":"a","value":"1"}`.
+    Jsonl,
+    /// One row per entry, with the key in the first column and the value in the second.
+    Csv,
+}
+
+/// An entry, as imported and exported, and as sent to and listed by the server.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+/// A page of a scan of the server, along with the token to pass to get the next one, if any.
+#[derive(Deserialize)]
+struct ScanPage {
+    entries: Vec<Entry>,
+    continuation: Option<String>,
+}
+
+/// A command read in interactive mode.
+#[derive(Parser)]
+#[command(no_binary_name = true, disable_version_flag = true, override_usage = "<COMMAND>")]
+struct Line {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Where the commands are sent.
+enum Target {
+    Direct { storage: Box<Storage>, writable: bool },
+    Server(Server),
+}
+
+impl Target {
+    fn open(cli: &Cli) -> Result<Self> {
+        if let Some(data_dir) = &cli.data_dir {
+            // Unless importing, nothing is ever written, so there is no background work to run
+            // either.
+            let writable = matches!(cli.command, Some(Command::Import { .. }));
+            let scheduling = if writable { Scheduling::Background } else { Scheduling::Manual };
+
+            let storage = Storage::builder()
+                .segments_path(data_dir.clone())
+                .wal_path(cli.wal_dir.clone().unwrap_or_else(|| data_dir.clone()))
+                .scheduling(scheduling)
+                .build()?;
+
+            return Ok(Target::Direct { storage: Box::new(storage), writable });
+        }
+
+        let url = cli.server.as_deref().unwrap_or("http://127.0.0.1:3000");
+
+        Ok(Target::Server(Server {
+            url: url.trim_end_matches('/').to_owned(),
+            api_key: cli.api_key.clone(),
+            agent: ureq::agent(),
+        }))
+    }
+
+    fn run(&self, command: &Command, out: &mut impl Write) -> Result<()> {
+        match command {
+            Command::Get { key } => match self.get(key)? {
+                Some(value) => {
+                    out.write_all(&value)?;
+                    writeln!(out)?;
+                }
+                None => bail!("{} not found", key),
+            },
+            Command::Put { key, value } => self.put(key, value.as_bytes())?,
+            Command::Del { key } => self.del(key)?,
+            Command::Scan { from, limit } => {
+                for (key, value) in self.scan(from, *limit)? {
+                    writeln!(out, "{}\t{}", key, value)?;
+                }
+            }
+            Command::Stats => {
+                for (name, value) in self.stats()? {
+                    writeln!(out, "{}: {}", name, value)?;
+                }
+            }
+            Command::Import { format, header, file } => {
+                let input: Box<dyn Read> = match file {
+                    Some(path) => Box::new(File::open(path).with_context(|| format!("opening {}", path.display()))?),
+                    None => Box::new(io::stdin()),
+                };
+
+                let imported = self.import(read_entries(input, *format, *header))?;
+                writeln!(out, "imported {} entries", imported)?;
+            }
+            Command::Export { format, header, file } => {
+                let output: Box<dyn Write> = match file {
+                    Some(path) => Box::new(File::create(path).with_context(|| format!("creating {}", path.display()))?),
+                    None => Box::new(out),
+                };
+
+                let mut writer = EntryWriter::new(BufWriter::new(output), *format, *header);
+                self.export(|entry| writer.write(&entry))?;
+                writer.finish()?;
+            }
+            #[cfg(feature = "remote-backup")]
+            Command::VerifyBackup { url, key_file } => {
+                let options = BackupOptions {
+                    keys: key_file.as_deref().map(read_key).transpose()?,
+                };
+
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+                let report = runtime.block_on(Storage::verify_backup(url, &options))?;
+
+                writeln!(out, "{}", report)?;
+                for object in &report.missing {
+                    writeln!(out, "missing: {}", object)?;
+                }
+                for object in &report.corrupt {
+                    writeln!(out, "corrupt: {}", object)?;
+                }
+                for directory in &report.incomplete {
+                    writeln!(out, "incomplete: {}", directory)?;
+                }
+
+                if report.is_hard_failure() {
+                    bail!("backup {} can't be restored", report.id);
+                }
+            }
+            Command::SstDump { path, entries } => {
+                let reader = SSTableReader::open(path).with_context(|| format!("opening {}", path.display()))?;
+
+                writeln!(out, "entries: {}", reader.len())?;
+                writeln!(out, "tombstones: {}", reader.tombstones())?;
+                writeln!(out, "first key: {}", reader.first_key().unwrap_or_default())?;
+                writeln!(out, "last key: {}", reader.last_key().unwrap_or_default())?;
+                match reader.metadata() {
+                    Some(metadata) => {
+                        writeln!(out, "created at: {}", metadata.created_at)?;
+                        writeln!(out, "source: {:?}", metadata.source)?;
+                        writeln!(out, "level: {}", metadata.level)?;
+                        writeln!(out, "min seqno: {}", metadata.min_seqno)?;
+                        writeln!(out, "max seqno: {}", metadata.max_seqno)?;
+                    }
+                    None => writeln!(out, "footer: none")?,
+                }
+
+                if *entries {
+                    for entry in reader.iter() {
+                        let (key, value, seqno) = entry?;
+                        let value = value.map(|value| String::from_utf8_lossy(&value).into_owned());
+                        writeln!(out, "{}\t{}\t{}", key, seqno, value.unwrap_or_default())?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Target::Direct { storage, .. } => storage.read(key),
+            Target::Server(server) => server.get(key),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        match self {
+            Target::Direct { .. } => bail!("{}", READ_ONLY),
+            Target::Server(server) => server.send(server.request("POST", &key_path(key)), value),
+        }
+    }
+
+    fn del(&self, key: &str) -> Result<()> {
+        match self {
+            Target::Direct { .. } => bail!("{}", READ_ONLY),
+            Target::Server(server) => server.send(server.request("DELETE", &key_path(key)), &[]),
+        }
+    }
+
+    /// Inserts the entries in batches of [`IMPORT_BATCH`], each written at once, so that a failed
+    /// import leaves whole batches behind, and returns how many entries were inserted. Stops at
+    /// the first entry that can't be read.
+    fn import(&self, entries: impl Iterator<Item = Result<Entry>>) -> Result<usize> {
+        let mut imported = 0;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        let mut entries = entries.peekable();
+
+        while entries.peek().is_some() {
+            batch.clear();
+            for entry in entries.by_ref().take(IMPORT_BATCH) {
+                batch.push(entry.with_context(|| format!("reading entry {}", imported + batch.len() + 1))?);
+            }
+
+            let count = batch.len();
+            match self {
+                Target::Direct { writable: false, .. } => bail!("{}", READ_ONLY),
+                Target::Direct { storage, .. } => {
+                    storage.insert_batch(batch.drain(..).map(|entry| (entry.key, entry.value.into_bytes())))?
+                }
+                Target::Server(server) => server.batch(&batch)?,
+            }
+
+            imported += count;
+        }
+
+        Ok(imported)
+    }
+
+    /// Calls `write` with every entry, in key order. Values that aren't UTF-8 are passed lossily.
+    fn export(&self, mut write: impl FnMut(Entry) -> Result<()>) -> Result<()> {
+        match self {
+            Target::Direct { storage, .. } => {
+                for entry in storage.iter()? {
+                    let (key, value) = entry?;
+                    write(Entry { key, value: String::from_utf8_lossy(&value).into_owned() })?;
+                }
+            }
+            Target::Server(server) => {
+                let mut continuation = None;
+
+                loop {
+                    let page = server.scan("", EXPORT_PAGE, continuation.as_deref())?;
+
+                    for entry in page.entries {
+                        write(entry)?;
+                    }
+
+                    continuation = match page.continuation {
+                        Some(continuation) => Some(continuation),
+                        None => return Ok(()),
+                    };
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Values that aren't UTF-8 are printed lossily.
+    fn scan(&self, from: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        match self {
+            Target::Direct { storage, .. } => storage
+                .iter_from(from)?
+                .take(limit)
+                .map(|entry| entry.map(|(key, value)| (key, String::from_utf8_lossy(&value).into_owned())))
+                .collect(),
+            Target::Server(server) => Ok(server
+                .scan(from, limit, None)?
+                .entries
+                .into_iter()
+                .map(|entry| (entry.key, entry.value))
+                .collect()),
+        }
+    }
+
+    fn stats(&self) -> Result<BTreeMap<String, String>> {
+        match self {
+            Target::Direct { storage, .. } => {
+                let mut properties = BTreeMap::new();
+                for name in PROPERTIES {
+                    properties.extend(storage.property(name)?.map(|value| (name.to_string(), value)));
+                }
+
+                Ok(properties)
+            }
+            Target::Server(server) => Ok(server.request("GET", "/stats").call().map_err(describe)?.into_json()?),
+        }
+    }
+}
+
+const READ_ONLY: &str = "the data directory is opened read-only but for imports, write through a server instead";
+
+/// A server reached over HTTP.
+struct Server {
+    url: String,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl Server {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{}", self.url, path));
+
+        match &self.api_key {
+            Some(key) => request.set("X-API-Key", key),
+            None => request,
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.request("GET", &key_path(key)).call() {
+            Ok(response) => {
+                let mut value = Vec::new();
+                response.into_reader().read_to_end(&mut value)?;
+
+                Ok(Some(value))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(error) => Err(describe(error)),
+        }
+    }
+
+    fn send(&self, request: ureq::Request, body: &[u8]) -> Result<()> {
+        request.send_bytes(body).map_err(describe)?;
+
+        Ok(())
+    }
+
+    fn scan(&self, from: &str, limit: usize, continuation: Option<&str>) -> Result<ScanPage> {
+        let mut request = self
+            .request("GET", "/scan")
+            .query("from", from)
+            .query("limit", &limit.to_string());
+        if let Some(continuation) = continuation {
+            request = request.query("continuation", continuation);
+        }
+
+        Ok(request.call().map_err(describe)?.into_json()?)
+    }
+
+    fn batch(&self, entries: &[Entry]) -> Result<()> {
+        self.request("POST", "/batch").send_json(entries).map_err(describe)?;
+
+        Ok(())
+    }
+}
+
+/// Reads entries written down in the given format.
+fn read_entries(input: impl Read + 'static, format: Format, header: bool) -> Box<dyn Iterator<Item = Result<Entry>>> {
+    match format {
+        Format::Jsonl => Box::new(
+            BufReader::new(input)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        Format::Csv => Box::new(
+            csv::ReaderBuilder::new()
+                .has_headers(header)
+                .from_reader(input)
+                .into_deserialize()
+                .map(|entry| Ok(entry?)),
+        ),
+    }
+}
+
+/// Writes entries down in a given format.
+enum EntryWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> EntryWriter<W> {
+    fn new(output: W, format: Format, header: bool) -> Self {
+        match format {
+            Format::Jsonl => EntryWriter::Jsonl(output),
+            Format::Csv => EntryWriter::Csv(Box::new(csv::WriterBuilder::new().has_headers(header).from_writer(output))),
+        }
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<()> {
+        match self {
+            EntryWriter::Jsonl(output) => {
+                serde_json::to_writer(&mut *output, entry)?;
+                writeln!(output)?;
+            }
+            EntryWriter::Csv(output) => output.serialize(entry)?,
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            EntryWriter::Jsonl(mut output) => output.flush()?,
+            EntryWriter::Csv(mut output) => output.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+fn key_path(key: &str) -> String {
+    let mut path = String::from("/key/");
+
+    // Percent-encodes everything but the unreserved characters, so that any key fits in a path.
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => path.push(byte as char),
+            _ => path.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    path
+}
+
+/// Includes the body of error responses, which explains them.
+fn describe(error: ureq::Error) -> anyhow::Error {
+    match error {
+        ureq::Error::Status(status, response) => {
+            let body = response.into_string().unwrap_or_default();
+            anyhow!("the server responded with {}: {}", status, body.trim())
+        }
+        error => error.into(),
+    }
+}
+
+/// Splits a line into words at whitespace, except inside double quotes. A backslash makes the
+/// next character part of the word, whatever it is.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or_else(|| anyhow!("the line ends with a backslash"))?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quoted {
+        bail!("unterminated quote");
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// Runs the commands read from the standard input until it ends or `exit` is read, reporting
+/// failures without stopping.
+fn interactive(target: &Target) -> Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let prompt = stdin.is_terminal();
+
+    loop {
+        if prompt {
+            write!(stdout, "lsm> ")?;
+            stdout.flush()?;
+        }
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit" | "quit") => return Ok(()),
+            Some(_) => {}
+        }
+
+        match Line::try_parse_from(words) {
+            Ok(line) => {
+                if let Err(error) = target.run(&line.command, &mut stdout) {
+                    eprintln!("{:#}", error);
+                }
+            }
+            Err(error) => eprint!("{}", error),
+        }
+    }
+}
+
+/// Reads the key of encrypted backups from a file, as 64 hexadecimal digits, known by the name of
+/// the file without the extension.
+#[cfg(feature = "remote-backup")]
+fn read_key(path: &Path) -> Result<Arc<dyn KeyProvider>> {
+    let invalid = || anyhow!("{}: expected a key of 64 hexadecimal digits", path.display());
+
+    let hex = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+
+    let id = path.file_stem().ok_or_else(invalid)?.to_string_lossy();
+    Ok(Arc::new(StaticKey::new(id, key)))
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let result = Target::open(&cli).and_then(|target| match &cli.command {
+        Some(command) => target.run(command, &mut io::stdout()),
+        None => interactive(&target),
+    });
+
+    if let Err(error) = result {
+        eprintln!("lsm-cli: {:#}", error);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use lsm_storage::storage::Storage;
+    use tempfile::TempDir;
+
+    use super::{key_path, split_words, Cli, Target, READ_ONLY};
+
+    /// Fills a storage kept in the given directory, then closes it.
+    fn fill(dir: &TempDir, entries: &[(&str, &str)]) {
+        let storage = Storage::builder()
+            .segments_path(dir.path().to_path_buf())
+            .wal_path(dir.path().to_path_buf())
+            .build()
+            .unwrap();
+
+        for (key, value) in entries {
+            storage.insert(key.to_string(), value.as_bytes().to_vec()).unwrap();
+        }
+    }
+
+    /// Runs the command given by the arguments against the storage in the given directory,
+    /// returning what it printed.
+    fn run(dir: &TempDir, args: &[&str]) -> anyhow::Result<String> {
+        let data_dir = dir.path().to_str().unwrap();
+        let cli = Cli::try_parse_from(["lsm-cli", "--data-dir", data_dir].iter().chain(args))?;
+        let target = Target::open(&cli)?;
+
+        let mut out = Vec::new();
+        target.run(cli.command.as_ref().unwrap(), &mut out)?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn the_data_directory_can_be_read() {
+        let dir = TempDir::new().unwrap();
+        fill(&dir, &[("a", "1"), ("b", "2"), ("c", "3")]);
+
+        assert_eq!(run(&dir, &["get", "b"]).unwrap(), "2\n");
+        assert_eq!(run(&dir, &["get", "d"]).unwrap_err().to_string(), "d not found");
+
+        assert_eq!(run(&dir, &["scan"]).unwrap(), "a\t1\nb\t2\nc\t3\n");
+        assert_eq!(run(&dir, &["scan", "--from", "b", "--limit", "1"]).unwrap(), "b\t2\n");
+
+        let stats = run(&dir, &["stats"]).unwrap();
+        assert!(stats.contains("num-entries-active-memtable: 3\n"), "{}", stats);
+    }
+
+    #[test]
+    fn the_data_directory_is_only_written_by_imports() {
+        let dir = TempDir::new().unwrap();
+        fill(&dir, &[("a", "1")]);
+
+        assert_eq!(run(&dir, &["put", "a", "2"]).unwrap_err().to_string(), READ_ONLY);
+        assert_eq!(run(&dir, &["del", "a"]).unwrap_err().to_string(), READ_ONLY);
+
+        assert_eq!(run(&dir, &["get", "a"]).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn the_data_directory_can_be_exported() {
+        let dir = TempDir::new().unwrap();
+        fill(&dir, &[("b", "2"), ("a", "1,\"one\"")]);
+
+        assert_eq!(
+            run(&dir, &["export"]).unwrap(),
+            "{\"key\":\"a\",\"value\":\"1,\\\"one\\\"\"}\n{\"key\":\"b\",\"value\":\"2\"}\n"
+        );
+        assert_eq!(run(&dir, &["export", "--format", "csv"]).unwrap(), "a,\"1,\"\"one\"\"\"\nb,2\n");
+        assert_eq!(
+            run(&dir, &["export", "--format", "csv", "--header"]).unwrap(),
+            "key,value\na,\"1,\"\"one\"\"\"\nb,2\n"
+        );
+    }
+
+    #[test]
+    fn exports_can_be_imported_back() {
+        let source = TempDir::new().unwrap();
+        fill(&source, &[("a", "1,\"one\""), ("b", "two\nlines"), ("c", "")]);
+        let exported = run(&source, &["scan"]).unwrap();
+
+        for args in [&["--format", "jsonl"][..], &["--format", "csv"], &["--format", "csv", "--header"]] {
+            let files = TempDir::new().unwrap();
+            let file = files.path().join("entries").to_str().unwrap().to_owned();
+            run(&source, &[&["export"], args, &[&file]].concat()).unwrap();
+
+            let target = TempDir::new().unwrap();
+            let imported = run(&target, &[&["import"], args, &[&file]].concat()).unwrap();
+            assert_eq!(imported, "imported 3 entries\n", "{:?}", args);
+            assert_eq!(run(&target, &["scan"]).unwrap(), exported, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn csv_headers_are_only_skipped_when_asked_to() {
+        let dir = TempDir::new().unwrap();
+        let files = TempDir::new().unwrap();
+        let file = files.path().join("entries.csv");
+        std::fs::write(&file, "key,value\na,1\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        run(&dir, &["import", "--format", "csv", file]).unwrap();
+        assert_eq!(run(&dir, &["scan"]).unwrap(), "a\t1\nkey\tvalue\n");
+
+        let dir = TempDir::new().unwrap();
+        run(&dir, &["import", "--format", "csv", "--header", file]).unwrap();
+        assert_eq!(run(&dir, &["scan"]).unwrap(), "a\t1\n");
+    }
+
+    #[test]
+    fn imports_stop_at_the_first_malformed_entry_without_writing_its_batch() {
+        let dir = TempDir::new().unwrap();
+        let files = TempDir::new().unwrap();
+        let file = files.path().join("entries.jsonl");
+        std::fs::write(&file, "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\"}\n").unwrap();
+
+        let error = run(&dir, &["import", file.to_str().unwrap()]).unwrap_err();
+        assert_eq!(error.to_string(), "reading entry 2");
+        assert_eq!(run(&dir, &["scan"]).unwrap(), "");
+    }
+
+    #[test]
+    fn lines_are_split_into_words_at_unquoted_whitespace() {
+        assert_eq!(split_words("  put key  value \n").unwrap(), ["put", "key", "value"]);
+        assert_eq!(split_words("put key \"some value\"").unwrap(), ["put", "key", "some value"]);
+        assert_eq!(split_words("put key \"\"").unwrap(), ["put", "key", ""]);
+        assert_eq!(split_words("put a\\ key \\\"value\\\\").unwrap(), ["put", "a key", "\"value\\"]);
+        assert_eq!(split_words("put some\" \"key").unwrap(), ["put", "some key"]);
+        assert!(split_words("").unwrap().is_empty());
+
+        assert_eq!(split_words("put key \"value").unwrap_err().to_string(), "unterminated quote");
+        assert_eq!(split_words("put key \\").unwrap_err().to_string(), "the line ends with a backslash");
+    }
+
+    #[test]
+    fn keys_are_percent_encoded_in_paths() {
+        assert_eq!(key_path("Az09-_.~"), "/key/Az09-_.~");
+        assert_eq!(key_path("a/b c?"), "/key/a%2Fb%20c%3F");
+        assert_eq!(key_path("é"), "/key/%C3%A9");
+    }
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+use crate::filter;
+
+/// How many bits filters take per key unless told otherwise, which makes about 1% of the absent
+/// keys look present.
+pub(crate) const BITS_PER_KEY: usize = 10;
+
+/// A bloom filter over a set of keys: tells that a key is definitely not in the set, or that it
+/// may be.
+///
+/// Each key sets `hashes` bits, derived from the two halves of a single hash of it. The hash is
+/// stable, so that filters can be persisted along with the sstables.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for the given number of keys, taking the given number of bits per
+    /// key, which must not be 0.
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        let words = Self::words(keys, bits_per_key);
+
+        // The number of bits each key sets that minimizes false positives is `ln 2` times the bits
+        // per key.
+        let hashes = (bits_per_key as f64 * std::f64::consts::LN_2).round().clamp(1.0, 30.0) as u64;
+
+        BloomFilter {
+            bits: vec![0; words],
+            hashes,
+        }
+    }
+
+    fn words(keys: usize, bits_per_key: usize) -> usize {
+        (keys * bits_per_key).div_ceil(64).max(1)
+    }
+
+    /// The number of bytes a filter for the given number of keys and bits per key takes once
+    /// serialized.
+    pub fn size_of(keys: usize, bits_per_key: usize) -> u64 {
+        Self::words(keys, bits_per_key) as u64 * 8 + 16
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(filter::hash(key));
+    }
+
+    /// Inserts the key of the given [`filter::hash`].
+    pub fn insert_hash(&mut self, hash: u64) {
+        for bit in self.bits_of(hash) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether the key may have been inserted. Never false for a key that was.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(filter::hash(key))
+    }
+
+    /// Whether the key of the given [`filter::hash`] may have been inserted.
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
+        self.bits_of(hash).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bits_of(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomFilter, BITS_PER_KEY};
+
+    #[test]
+    fn inserted_keys_are_always_found_and_most_others_are_not() {
+        let mut filter = BloomFilter::new(1000, BITS_PER_KEY);
+        for i in 0..1000 {
+            filter.insert(format!("key-{}", i).as_bytes());
+        }
+
+        assert!((0..1000).all(|i| filter.may_contain(format!("key-{}", i).as_bytes())));
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("other-{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        assert!(!BloomFilter::new(0, BITS_PER_KEY).may_contain(b"key"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::Result;
+
+/// Remembers keys recently confirmed to be absent from the storage, so that repeated misses don't
+/// pay for a lookup in every memtable and sstable.
+///
+/// When full, the oldest key is forgotten. A key must be invalidated as soon as it is written.
+pub(crate) struct NegativeCache {
+    capacity: usize,
+    keys: HashSet<String>,
+    /// The cached keys, from the oldest to the newest.
+    order: VecDeque<String>,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize) -> Self {
+        NegativeCache {
+            capacity,
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Remembers that the key is absent.
+    pub fn insert(&mut self, key: &str) {
+        if self.capacity == 0 || !self.keys.insert(key.to_owned()) {
+            return;
+        }
+
+        self.order.push_back(key.to_owned());
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forgets the key, as it may no longer be absent.
+    pub fn invalidate(&mut self, key: &str) {
+        if self.keys.remove(key) {
+            self.order.retain(|cached| cached != key);
+        }
+    }
+}
+
+/// Keeps the values of recently read keys, so that hot keys are not looked up and deserialized
+/// from the tables over and over.
+///
+/// The cache holds up to `capacity` bytes of keys and values, evicting the least recently used
+/// entries first. A key must be invalidated as soon as it is written.
+pub(crate) struct RowCache {
+    capacity: usize,
+    size: usize,
+    /// The cached values, along with when they were last used.
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    /// The cached keys, by when they were last used.
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> Self {
+        RowCache {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let (value, last_use) = self.entries.get_mut(key)?;
+
+        self.uses += 1;
+        let key = self.recency.remove(last_use)?;
+        self.recency.insert(self.uses, key);
+        *last_use = self.uses;
+
+        Some(value.clone())
+    }
+
+    /// Caches the value of the key, evicting the least recently used entries if needed. Entries
+    /// larger than the whole cache are not cached.
+    pub fn insert(&mut self, key: &str, value: &[u8]) {
+        self.invalidate(key);
+
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+
+        self.uses += 1;
+        self.size += size;
+        self.entries.insert(key.to_owned(), (value.to_vec(), self.uses));
+        self.recency.insert(self.uses, key.to_owned());
+
+        while self.size > self.capacity {
+            match self.recency.keys().next().copied() {
+                Some(oldest) => {
+                    let key = self.recency[&oldest].clone();
+                    self.invalidate(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Forgets the key, as its value may have changed.
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some((value, last_use)) = self.entries.remove(key) {
+            self.recency.remove(&last_use);
+            self.size -= key.len() + value.len();
+        }
+    }
+}
+
+/// Keeps the blocks recently read from the sstables, e.g. the partitions of their index, shared by
+/// every reader of the storage. Blocks are keyed by the reader they belong to and their offset in
+/// its file.
+///
+/// The cache holds up to `capacity` bytes of blocks, as they take on disk, evicting the least
+/// recently used ones first. Blocks are never invalidated: sstables don't change once written, and
+/// the blocks of those that are replaced are eventually evicted.
+pub(crate) struct BlockCache<T> {
+    capacity: usize,
+    state: Mutex<BlockCacheState<T>>,
+}
+
+struct BlockCacheState<T> {
+    size: usize,
+    /// The cached blocks, along with their size and when they were last used.
+    blocks: HashMap<(u64, u64), (Arc<T>, usize, u64)>,
+    /// The cached blocks, by when they were last used.
+    recency: BTreeMap<u64, (u64, u64)>,
+    uses: u64,
+}
+
+impl<T> BlockCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            state: Mutex::new(BlockCacheState {
+                size: 0,
+                blocks: HashMap::new(),
+                recency: BTreeMap::new(),
+                uses: 0,
+            }),
+        }
+    }
+
+    /// Returns the block of the reader at the offset, loading it if it isn't cached. `load` returns
+    /// the block along with its size. The cache isn't locked while it runs, so two readers of the
+    /// same block may both load it. Blocks larger than the whole cache are not cached.
+    pub fn get_or_load(&self, reader: u64, offset: u64, load: impl FnOnce() -> Result<(T, usize)>) -> Result<Arc<T>> {
+        let key = (reader, offset);
+        if let Some(block) = self.lock().get(key) {
+            return Ok(block);
+        }
+
+        let (block, size) = load()?;
+        let block = Arc::new(block);
+        if size <= self.capacity {
+            self.lock().insert(key, block.clone(), size, self.capacity);
+        }
+
+        Ok(block)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BlockCacheState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> BlockCacheState<T> {
+    fn get(&mut self, key: (u64, u64)) -> Option<Arc<T>> {
+        let (block, _, last_use) = self.blocks.get_mut(&key)?;
+
+        self.uses += 1;
+        self.recency.remove(last_use);
+        self.recency.insert(self.uses, key);
+        *last_use = self.uses;
+
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: (u64, u64), block: Arc<T>, size: usize, capacity: usize) {
+        if let Some((_, size, last_use)) = self.blocks.remove(&key) {
+            self.recency.remove(&last_use);
+            self.size -= size;
+        }
+
+        self.uses += 1;
+        self.size += size;
+        self.blocks.insert(key, (block, size, self.uses));
+        self.recency.insert(self.uses, key);
+
+        while self.size > capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some((_, size, _)) = self.blocks.remove(&oldest) {
+                        self.size -= size;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{BlockCache, NegativeCache, RowCache};
+
+    #[test]
+    fn invalidated_keys_are_no_longer_cached() {
+        let mut cache = NegativeCache::new(2);
+
+        cache.insert("key-1");
+        assert!(cache.contains("key-1"));
+
+        cache.invalidate("key-1");
+        assert!(!cache.contains("key-1"));
+
+        // Cached again, it is as new as any key cached after it.
+        cache.insert("key-1");
+        cache.insert("key-2");
+        assert!(cache.contains("key-1"));
+        assert!(cache.contains("key-2"));
+
+        cache.insert("key-3");
+        assert!(!cache.contains("key-1"));
+        assert!(cache.contains("key-2"));
+        assert!(cache.contains("key-3"));
+    }
+
+    #[test]
+    fn oldest_keys_are_forgotten_when_full() {
+        let mut cache = NegativeCache::new(2);
+
+        cache.insert("key-1");
+        cache.insert("key-2");
+        cache.insert("key-3");
+
+        assert!(!cache.contains("key-1"));
+        assert!(cache.contains("key-2"));
+        assert!(cache.contains("key-3"));
+    }
+
+    #[test]
+    fn least_recently_used_rows_are_evicted_when_over_capacity() {
+        let mut cache = RowCache::new(24);
+
+        cache.insert("key-1", b"value-1");
+        cache.insert("key-2", b"value-2");
+        assert_eq!(cache.get("key-1"), Some(b"value-1".to_vec()));
+
+        cache.insert("key-3", b"value-3");
+
+        assert_eq!(cache.get("key-1"), Some(b"value-1".to_vec()));
+        assert_eq!(cache.get("key-2"), None);
+        assert_eq!(cache.get("key-3"), Some(b"value-3".to_vec()));
+    }
+
+    #[test]
+    fn blocks_are_loaded_once_until_evicted() -> Result<()> {
+        let cache = BlockCache::new(10);
+        let mut loads = 0;
+        let mut load = |value: &'static str| {
+            loads += 1;
+            Ok((value, value.len()))
+        };
+
+        assert_eq!(*cache.get_or_load(1, 0, || load("block"))?, "block");
+        assert_eq!(*cache.get_or_load(1, 0, || load("other"))?, "block");
+        assert_eq!(*cache.get_or_load(1, 5, || load("next"))?, "next");
+
+        // The first block was used last, so the second one goes.
+        assert_eq!(*cache.get_or_load(1, 0, || load("other"))?, "block");
+        assert_eq!(*cache.get_or_load(2, 0, || load("third"))?, "third");
+        assert_eq!(*cache.get_or_load(1, 5, || load("again"))?, "again");
+
+        // Blocks larger than the cache are loaded every time.
+        assert_eq!(*cache.get_or_load(3, 0, || load("a large block"))?, "a large block");
+        assert_eq!(*cache.get_or_load(3, 0, || load("reloaded block"))?, "reloaded block");
+        assert_eq!(loads, 6);
+
+        Ok(())
+    }
+}
@@ -1,86 +1,863 @@
-use std::path::PathBuf;
-use tokio::sync::mpsc::UnboundedReceiver;
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use uuid::Uuid;
 
-use crate::engine::Engine;
-use crate::sstable::SSTable;
+use crate::SEGMENTS_NAME;
+use crate::cache::NegativeCache;
+use crate::engine::{Engine, Version};
+use crate::manifest::Edit;
+use crate::expiry::Expirer;
+use crate::scrubber::Scrubber;
+use crate::sstable::{SSTable, SSTableReader, TableOptions, TableSource};
+use crate::stats;
+use crate::storage::Config;
+use crate::supervisor::{self, Supervisor};
 
-pub fn start_compaction(engine: Arc<Mutex<Engine>>, mut receiver: UnboundedReceiver<String>) -> Result<()> {
-    // Current behavior: Picks all L0 and L1 SSTables and merges them into a single SSTable
-    //     Caveats:
-    //       - The final table should be split to multiple tables of a specific size
-    // Next steps:
-    // - Solve the previous caveat
-    //
-    while receiver.blocking_recv().is_some() {
-        persist_memtable(&*engine)?;
-        // trigger_l0_compaction(engine.clone());
-        // thread::sleep(Duration::new(120, 0));
+/// The background work the storage may schedule.
+pub(crate) enum Job {
+    /// Persists the oldest frozen memtable.
+    Flush,
+    /// Deletes files the storage no longer needs, skipping those that are already gone.
+    Delete(Vec<PathBuf>),
+}
+
+impl Job {
+    /// Jobs with a higher priority run first. Flushes come before everything else, as writes stall
+    /// once too many memtables are frozen, and compactions come before deletions, which only free
+    /// space. Compactions aren't scheduled, but checked for once no flush is waiting. There is only
+    /// one kind of compaction, from L0 into L1, the last level, so none has to give way to another.
+    fn priority(&self) -> u8 {
+        match self {
+            Job::Flush => 1,
+            Job::Delete(_) => 0,
+        }
+    }
+}
+
+/// The jobs scheduled but not started yet, shared by the threads running them.
+struct JobQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: Vec<Job>,
+    /// Whether a flush is running. Flushes persist the oldest frozen memtable, so they run one at
+    /// a time.
+    flushing: bool,
+    /// Whether the storage was dropped, after which the threads stop once the queue is empty.
+    closed: bool,
+}
+
+impl JobQueue {
+    fn push(&self, job: Job) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).pending.push(job);
+        self.ready.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Waits for a job the calling thread may run, taking the one with the highest priority, and
+    /// the oldest among those. Returns `None` once the queue is closed and empty.
+    fn take(&self, flushes_only: bool) -> Option<Job> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        loop {
+            let flushing = state.flushing;
+            let index = state
+                .pending
+                .iter()
+                .enumerate()
+                .filter(|(_, job)| match job {
+                    Job::Flush => !flushing,
+                    Job::Delete(_) => !flushes_only,
+                })
+                .max_by_key(|(index, job)| (job.priority(), std::cmp::Reverse(*index)))
+                .map(|(index, _)| index);
+
+            if let Some(index) = index {
+                let job = state.pending.remove(index);
+                state.flushing |= matches!(job, Job::Flush);
+                return Some(job);
+            }
+
+            if state.closed && state.pending.is_empty() {
+                return None;
+            }
+
+            state = self.ready.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Marks the job taken by the calling thread as done.
+    fn done(&self, job: &Job) {
+        if matches!(job, Job::Flush) {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner).flushing = false;
+            self.ready.notify_all();
+        }
+    }
+
+    fn has_pending_flush(&self) -> bool {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).pending.iter().any(|job| matches!(job, Job::Flush))
+    }
+}
+
+/// Runs the background work of the storage: persists memtables as they are frozen and, if a
+/// compaction interval is configured, compacts L0 once the interval elapses. Sstables with many
+/// tombstones make the compaction due earlier, if a tombstone ratio is configured, and so does L0
+/// holding too many sstables, if a trigger is.
+///
+/// Jobs run by priority, see [`Job::priority`]. When scheduled manually, it also runs the
+/// scrubber and purges the expired keys. Otherwise, each runs in its own thread.
+pub(crate) struct Compactor {
+    engine: Arc<Mutex<Engine>>,
+    config: Config,
+    receiver: Receiver<Job>,
+    last_compaction: Arc<Mutex<Duration>>,
+    scrubber: Scrubber,
+    expirer: Expirer,
+}
+
+impl Compactor {
+    pub fn new(engine: Arc<Mutex<Engine>>, config: Config, receiver: Receiver<Job>) -> Self {
+        let last_compaction = config.clock.now();
+        let scrubber = Scrubber::new(&engine, config.clone());
+        let expirer = Expirer::new(&engine, config.clone());
+
+        Compactor {
+            engine,
+            config,
+            receiver,
+            last_compaction: Arc::new(Mutex::new(last_compaction)),
+            scrubber,
+            expirer,
+        }
+    }
+
+    /// Runs jobs as they are scheduled, until the storage is dropped, in
+    /// [`Config::max_background_jobs`] threads, plus one only running flushes if
+    /// [`Config::dedicated_flush_thread`] is set, all owned by the supervisor. Once a job fails or
+    /// panics, the error is recorded in the engine and the jobs scheduled later are dropped.
+    pub fn spawn(self, supervisor: &Supervisor) -> Result<()> {
+        let Compactor {
+            engine,
+            config,
+            receiver,
+            last_compaction,
+            ..
+        } = self;
+        let queue = Arc::new(JobQueue {
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        });
+
+        let threads = config.max_background_jobs.max(1);
+        let flush_thread = config.dedicated_flush_thread.then_some(true);
+        for (i, flushes_only) in std::iter::repeat_n(false, threads).chain(flush_thread).enumerate() {
+            let worker = Worker {
+                engine: engine.clone(),
+                config: config.clone(),
+                queue: queue.clone(),
+                last_compaction: last_compaction.clone(),
+                flushes_only,
+            };
+
+            let name = if flushes_only { "lsm-flush".to_owned() } else { format!("lsm-worker-{}", i) };
+            supervisor.spawn(&name, Some(WORKER_RESTART_DELAY), move || {
+                worker.run();
+                Ok(())
+            })?;
+        }
+
+        supervisor.spawn("lsm-dispatcher", None, move || {
+            while let Ok(job) = receiver.recv() {
+                queue.push(job);
+            }
+            queue.close();
+            Ok(())
+        })
+    }
+
+    /// Runs the jobs scheduled so far, without waiting for new ones, followed by a compaction, a
+    /// scrub and a purge of the expired keys if they are due. Returns how many jobs ran, including
+    /// those.
+    ///
+    /// Fails like [`Compactor::run`] records errors: once a job failed, with its error.
+    pub fn run_pending(&mut self) -> Result<usize> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).check_background_error()?;
+
+        self.run_jobs()
+            .map_err(|error| self.engine.lock().unwrap_or_else(PoisonError::into_inner).record_background_error(error))
+    }
+
+    fn run_jobs(&mut self) -> Result<usize> {
+        let mut pending: Vec<Job> = self.receiver.try_iter().collect();
+        pending.sort_by_key(|job| std::cmp::Reverse(job.priority()));
+
+        let mut jobs = 0;
+        for job in pending {
+            execute(&self.engine, &self.config, &job)?;
+            jobs += 1;
+        }
+
+        if compact_if_due(&self.engine, &self.config, &mut self.last_compaction.lock().unwrap_or_else(PoisonError::into_inner))? {
+            jobs += 1;
+        }
+
+        if self.scrubber.scrub_if_due()? {
+            jobs += 1;
+        }
+
+        if self.expirer.purge_if_due()? {
+            jobs += 1;
+        }
+
+        Ok(jobs)
+    }
+}
+
+/// How long a worker thread that panicked outside of a job waits before running again.
+const WORKER_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// A thread running background jobs. See [`Compactor::spawn`].
+struct Worker {
+    engine: Arc<Mutex<Engine>>,
+    config: Config,
+    queue: Arc<JobQueue>,
+    last_compaction: Arc<Mutex<Duration>>,
+    /// Whether the thread is reserved for flushes, so that they never wait for a compaction.
+    flushes_only: bool,
+}
+
+impl Worker {
+    fn run(&self) {
+        while let Some(job) = self.queue.take(self.flushes_only) {
+            if self.engine.lock().unwrap_or_else(PoisonError::into_inner).background_error.is_some() {
+                self.queue.done(&job);
+                continue;
+            }
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| execute(&self.engine, &self.config, &job)))
+                .unwrap_or_else(|panic| Err(anyhow!("panicked: {}", supervisor::panic_message(&panic))));
+            self.queue.done(&job);
+
+            // Compactions only start once no flush is waiting for a thread, so that they never
+            // delay one.
+            let result = result.and_then(|()| {
+                if self.flushes_only || self.queue.has_pending_flush() {
+                    return Ok(());
+                }
+
+                // A compaction already running in another thread takes the L0 sstables there are.
+                match self.last_compaction.try_lock() {
+                    Ok(mut last_compaction) => compact_if_due(&self.engine, &self.config, &mut last_compaction).map(drop),
+                    Err(_) => Ok(()),
+                }
+            });
+
+            if let Err(error) = result {
+                self.engine.lock().unwrap_or_else(PoisonError::into_inner).record_background_error(error);
+            }
+        }
+    }
+}
+
+fn execute(engine: &Mutex<Engine>, config: &Config, job: &Job) -> Result<()> {
+    match job {
+        Job::Flush => persist_memtable(engine, config),
+        Job::Delete(paths) => delete_files(paths, config),
+    }
+}
+
+/// Compacts L0 if the compaction interval elapsed since the last one, or if L0 calls for it
+/// earlier. Returns whether it did.
+fn compact_if_due(engine: &Arc<Mutex<Engine>>, config: &Config, last_compaction: &mut Duration) -> Result<bool> {
+    let now = config.clock.now();
+    let interval_elapsed = config
+        .compaction_interval
+        .is_some_and(|interval| now.saturating_sub(*last_compaction) >= interval);
+
+    if !interval_elapsed && !has_dense_tombstones(engine, config) && !l0_is_crowded(engine) {
+        return Ok(false);
     }
 
+    // Current behavior: Picks all L0 and L1 SSTables and merges them into L1 SSTables capped
+    // at the target file size.
+    trigger_l0_compaction(engine.clone(), config)?;
+    *last_compaction = now;
+
+    Ok(true)
+}
+
+/// Whether L0 holds as many sstables as the trigger, as configured or tuned.
+fn l0_is_crowded(engine: &Mutex<Engine>) -> bool {
+    let engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+    engine
+        .tuning
+        .l0_compaction_trigger
+        .is_some_and(|files| engine.version.sstables0.len() >= files.max(1))
+}
+
+/// Whether any L0 sstable reached the configured tombstone ratio. L1 is left out, as it only
+/// holds tombstones until the next compaction drops them.
+fn has_dense_tombstones(engine: &Mutex<Engine>, config: &Config) -> bool {
+    let ratio = match config.compaction_tombstone_ratio {
+        Some(ratio) => ratio,
+        None => return false,
+    };
+
+    let version = engine.lock().unwrap_or_else(PoisonError::into_inner).version.clone();
+    version
+        .sstable_readers0
+        .iter()
+        .any(|reader| reader.tombstones() > 0 && reader.tombstone_ratio() >= ratio)
+}
+
+/// Persists the oldest frozen memtable as a L0 SSTable named after the memtable id, which keeps
+/// the SSTables ordered by age when they are loaded again.
+///
+/// The WAL of the memtable is only removed once the SSTable is synced and recorded in the
+/// manifest. A crash at any point before leaves the WAL behind, and the memtable is recovered
+/// from it when the storage is opened again.
+///
+/// Clearing the storage drops the frozen memtables: their flushes find nothing to persist, and
+/// the SSTable of one cleared while being persisted is deleted instead of recorded.
+fn persist_memtable(engine: &Mutex<Engine>, config: &Config) -> Result<()> {
+    let start = Instant::now();
+    let started = config.clock.now();
+    let locked_engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+    let memtable = match locked_engine.version.memtables.first() {
+        Some(memtable) => memtable.clone(),
+        None => return Ok(()),
+    };
+    drop(locked_engine);
+
+    let name = format!("{}-{}", SEGMENTS_NAME, memtable.id);
+    let path = config.segments_path.join(&name);
+
+    let sstable = memtable.persist(&path, &config.table_options(0))?;
+    config.env.sync_dir(&config.segments_path)?;
+    let sstable_reader = sstable.cached_reader(&config.block_cache)?;
+    let written = sstable.size()?;
+
+    let mut locked_engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+    if !locked_engine.version.memtables.first().is_some_and(|first| Arc::ptr_eq(first, &memtable)) {
+        drop(locked_engine);
+        return delete_files(&[path], config);
+    }
+
+    locked_engine.manifest.record(&Edit::AddTable { name, level: 0 })?;
+    locked_engine.bytes_written.flush += written;
+
+    let version = locked_engine.version_mut();
+    version.memtables.remove(0);
+    version.sstables0.push(sstable);
+    version.sstable_readers0.push(Arc::new(sstable_reader));
+    stats::record(&mut locked_engine.latencies.flush, start.elapsed());
+    locked_engine.flushed.notify_all();
+    locked_engine.measure_prefixes(&config.quotas)?;
+
+    let Engine { tuner, tuning, .. } = &mut *locked_engine;
+    let now = config.clock.now();
+    let tuned = tuner
+        .as_mut()
+        .and_then(|tuner| tuner.observe(tuning, now, now.saturating_sub(started)));
+    drop(locked_engine);
+
+    if let Some(info) = tuned {
+        for listener in &config.event_listeners {
+            listener.on_tuning(&info);
+        }
+    }
+
+    config.retire_wal(&memtable)?;
+
     Ok(())
 }
 
-fn persist_memtable(engine: &Mutex<Engine>) -> Result<()> {
-        let engine2 = engine.lock().unwrap();
-        let memtable = engine2.memtables.first().unwrap().clone();
-        drop(engine2);
+fn delete_files(paths: &[PathBuf], config: &Config) -> Result<()> {
+    for path in paths {
+        match config.remove_obsolete(path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+    }
 
-        let uuid = uuid::Uuid::new_v4();
-        let mut path = PathBuf::new();
-        path.push(".");
-        path.push("sstables");
-        path.push(format!("sstables-{}", uuid));
+    Ok(())
+}
 
-        let sstable = memtable.persist(&path)?;
-        let sstable_reader = sstable.reader()?;
+/// What a compaction did. See [`trigger_l0_compaction`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Compacted {
+    /// How many sstables were replaced.
+    pub inputs: usize,
+    /// The size of the sstables replaced, in bytes.
+    pub input_bytes: u64,
+    /// The size of the sstables that replaced them, in bytes.
+    pub output_bytes: u64,
+}
 
-        let mut engine2 = engine.lock().unwrap();
-        engine2.memtables.remove(0);
-        engine2.sstables0.push(sstable);
-        engine2.sstable_readers0.push(sstable_reader);
-        drop(engine2);
+/// Describes what a compaction would do, computed without executing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPlan {
+    /// The SSTables that would be merged, from the oldest to the newest.
+    pub inputs: Vec<PathBuf>,
+    /// The total size of the input SSTables, in bytes.
+    pub input_bytes: u64,
+    /// The expected size of the merged SSTables, in bytes.
+    pub estimated_output_bytes: u64,
+    /// The bytes written by the compaction per byte of L0 data moved into L1.
+    pub estimated_write_amplification: f64,
+    /// The fraction of the input entries that are tombstones, which the compaction drops.
+    pub tombstone_ratio: f64,
+}
 
-        Ok(())
+/// Picks the tables for a L0 compaction: the given L1 tables, those overlapping L0, followed by
+/// all L0 tables.
+///
+/// The size of the output is estimated by assuming that entries have the same size on average and
+/// that only the most recent entry for each key survives the merge.
+pub(crate) fn plan_l0_compaction(
+    sstables0: &[SSTable],
+    sstables1: &[SSTable],
+) -> Result<Option<CompactionPlan>> {
+    if sstables0.is_empty() {
+        return Ok(None);
+    }
+
+    let mut input_bytes = 0;
+    let mut entries = 0;
+    let mut tombstones = 0;
+    let mut keys = HashSet::new();
+
+    for sstable in sstables1.iter().chain(sstables0) {
+        let reader = sstable.reader()?;
+
+        input_bytes += sstable.size()?;
+        entries += reader.len();
+        tombstones += reader.tombstones();
+        keys.extend(reader.keys()?);
+    }
+
+    let l0_bytes = sstables0
+        .iter()
+        .map(SSTable::size)
+        .sum::<Result<u64>>()?;
+
+    let estimated_output_bytes = if entries == 0 {
+        0
+    } else {
+        input_bytes * keys.len() as u64 / entries as u64
+    };
+
+    let estimated_write_amplification = if l0_bytes == 0 {
+        0.0
+    } else {
+        estimated_output_bytes as f64 / l0_bytes as f64
+    };
+
+    let tombstone_ratio = if entries == 0 {
+        0.0
+    } else {
+        tombstones as f64 / entries as f64
+    };
+
+    Ok(Some(CompactionPlan {
+        inputs: sstables1
+            .iter()
+            .chain(sstables0)
+            .map(|sstable| sstable.path().to_path_buf())
+            .collect(),
+        input_bytes,
+        estimated_output_bytes,
+        estimated_write_amplification,
+        tombstone_ratio,
+    }))
+}
+
+/// The name of the file of a sstable, as recorded in the manifest.
+fn table_name(sstable: &SSTable) -> String {
+    sstable.path().file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// Merges every L0 sstable into L1, along with the L1 sstables they overlap, dropping
+/// tombstones. Returns what it merged.
+pub(crate) fn trigger_l0_compaction(engine: Arc<Mutex<Engine>>, config: &Config) -> Result<Compacted> {
+    compact_l0(engine, config, |_| true)
+}
+
+/// Merges the L0 sstables the predicate selects into L1 as [`trigger_l0_compaction`] does. The
+/// older L0 sstables whose key range overlaps a selected one are merged too, as they could hold
+/// older values of its keys, which would otherwise shadow the merged ones from L0. The newer ones
+/// are left in L0, where they still shadow L1.
+pub(crate) fn compact_l0(
+    engine: Arc<Mutex<Engine>>,
+    config: &Config,
+    select: impl Fn(&SSTableReader) -> bool,
+) -> Result<Compacted> {
+    let start = Instant::now();
+
+    // The merge runs on a copy of the current version, without holding the engine, so that reads,
+    // writes and flushes go on meanwhile. It is planned again if the inputs changed by the time
+    // the outputs are installed.
+    loop {
+        let version = engine.lock().unwrap_or_else(PoisonError::into_inner).version.clone();
+        let l0_inputs = select_l0_inputs(&version.sstable_readers0, &select);
+        let sstables0: Vec<SSTable> = l0_inputs.iter().map(|&index| version.sstables0[index].clone()).collect();
+        let readers0: Vec<&Arc<SSTableReader>> = l0_inputs.iter().map(|&index| &version.sstable_readers0[index]).collect();
+        let range = key_range(readers0.iter().copied());
+        let l1_inputs = match range {
+            Some((first, last)) => version.overlapping_l1(first, last),
+            None => 0..0,
+        };
+        let sstables1 = &version.sstables1[l1_inputs.clone()];
+        // From the oldest to the newest, as the merge expects. Without L0 inputs, there is nothing
+        // to merge.
+        let tables_to_merge: Vec<SSTable> = match sstables0.is_empty() {
+            true => Vec::new(),
+            false => sstables1.iter().chain(&sstables0).cloned().collect(),
+        };
+        let input_bytes = tables_to_merge.iter().map(SSTable::size).sum::<Result<u64>>()?;
+
+        // Unlike L0 sstables, named after the memtable they were persisted from, the outputs are
+        // only ordered by their key range, so they just need unique names.
+        let output_path = || {
+            config
+                .segments_path
+                .join(format!("{}-{}", SEGMENTS_NAME, Uuid::new_v4().to_simple()))
+        };
+
+        // L1 is the last level, and every table the merge leaves out of L1 doesn't overlap L0, so
+        // the merged tables hold everything a tombstone could shadow.
+        let options = TableOptions {
+            drop_tombstones: true,
+            retention: config.retention(),
+            target_file_size: Some(config.target_file_size),
+            ..config.table_options(1)
+        };
+
+        // Even a single table is rewritten, rather than moved to L1 as is, so that its tombstones
+        // and the versions the retention drops go, and it is written as L1 is.
+        let (merged_tables, entries, dropped_versions) = match tables_to_merge.as_slice() {
+            [] => (Vec::new(), 0, 0),
+            tables => {
+                let inputs: Vec<&SSTable> = tables.iter().collect();
+                let merged = SSTable::merge(&config.env, output_path, &inputs, &options)?;
+
+                (merged.tables, merged.entries, merged.dropped_versions)
+            }
+        };
+        let output_bytes = merged_tables.iter().map(SSTable::size).sum::<Result<u64>>()?;
+        let compacted = Compacted {
+            inputs: tables_to_merge.len(),
+            input_bytes,
+            output_bytes,
+        };
+
+        if merged_tables.is_empty() {
+            return Ok(compacted);
+        }
+
+        let outputs: Vec<PathBuf> = merged_tables.iter().map(|table| table.path().to_path_buf()).collect();
+
+        if config.verify_compaction_outputs {
+            if let Err(error) = verify_outputs(&merged_tables, entries) {
+                delete_files(&outputs, config)?;
+
+                return Err(error.context("compaction output failed verification"));
+            }
+        }
+
+        let reader = |table: &SSTable| Ok(Arc::new(table.cached_reader(&config.block_cache)?));
+        let merged_table_readers: Vec<Arc<SSTableReader>> = merged_tables.iter().map(reader).collect::<Result<_>>()?;
+        config.env.sync_dir(&config.segments_path)?;
+
+        let mut locked_engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+        let installed = installed_inputs(&locked_engine.version, &sstables0, range, sstables1);
+        let (current_l0, current_l1) = match installed {
+            Some(inputs) => inputs,
+            None => {
+                drop(locked_engine);
+                delete_files(&outputs, config)?;
+                continue;
+            }
+        };
+
+        // The outputs replace the inputs in a single edit, so that a crash never leaves the
+        // manifest with both or neither of them.
+        let edit = Edit::ReplaceTables {
+            removed: tables_to_merge.iter().map(table_name).collect(),
+            added: merged_tables.iter().map(table_name).collect(),
+            level: 1,
+        };
+        locked_engine.manifest.record(&edit)?;
+        locked_engine.bytes_written.compaction += output_bytes;
+        locked_engine.dropped_versions += dropped_versions;
+
+        for reader in readers0.iter().copied().chain(&version.sstable_readers1[l1_inputs]) {
+            reader.mark_obsolete(&config.env, config.secure_delete);
+        }
+
+        let version = locked_engine.version_mut();
+        for &index in current_l0.iter().rev() {
+            version.sstables0.remove(index);
+            version.sstable_readers0.remove(index);
+        }
+
+        // The outputs cover the range of the inputs, which no L1 table left out overlaps, so they
+        // take the place of the L1 inputs. Only an empty output, which holds no range, may have to
+        // move to keep L1 sorted.
+        version.sstables1.splice(current_l1.clone(), merged_tables);
+        version.sstable_readers1.splice(current_l1, merged_table_readers);
+        version.sort_l1();
+        locked_engine.measure_prefixes(&config.quotas)?;
+        stats::record(&mut locked_engine.latencies.compaction, start.elapsed());
+
+        return Ok(compacted);
+    }
 }
 
-fn trigger_l0_compaction(engine: Arc<Mutex<Engine>>) {
-    let mut locked_engine = engine.lock().unwrap();
+/// Where the current version holds the inputs a compaction planned on an older one: the indexes of
+/// its L0 inputs, and the range of the L1 sstables overlapping their key range. `None` if any L0
+/// input is gone, or if the L1 sstables overlapping them aren't the L1 inputs anymore, as after a
+/// concurrent compaction, ingestion or clear, whose outputs would conflict.
+fn installed_inputs(
+    version: &Version,
+    sstables0: &[SSTable],
+    range: Option<(&str, &str)>,
+    sstables1: &[SSTable],
+) -> Option<(Vec<usize>, Range<usize>)> {
+    let l0 = sstables0
+        .iter()
+        .map(|sstable| version.sstables0.iter().position(|current| current == sstable))
+        .collect::<Option<Vec<usize>>>()?;
 
-    let tables_to_merge = locked_engine
-        .sstables0
-        .clone()
-        .into_iter()
-        .chain(locked_engine.sstables1.clone().into_iter());
+    let l1 = match range {
+        Some((first, last)) => version.overlapping_l1(first, last),
+        None => 0..0,
+    };
 
-    // TODO: merge all tables in 1 pass
-    let merged_table = tables_to_merge.reduce(|acc, table| {
-        let mut acc_reader = acc.reader().unwrap();
-        let mut table_reader = table.reader().unwrap();
+    (version.sstables1[l1.clone()] == *sstables1).then_some((l0, l1))
+}
 
-        let tempfile = tempfile::NamedTempFile::new().unwrap().into_temp_path().to_path_buf();
-        SSTable::merge(tempfile, &mut acc_reader, &mut table_reader).unwrap()
-    });
+/// The indexes, from the oldest to the newest, of the L0 sstables a compaction of those the
+/// predicate selects has to merge. See [`compact_l0`].
+fn select_l0_inputs(readers: &[Arc<SSTableReader>], select: impl Fn(&SSTableReader) -> bool) -> Vec<usize> {
+    let mut selected: Vec<bool> = readers.iter().map(|reader| select(reader)).collect();
 
-    merged_table.map(|merged_table| {
-        let merged_table_reader = merged_table.reader().unwrap();
+    // From the newest to the oldest, so that each table is checked against every newer one that
+    // ends up selected.
+    for older in (0..readers.len()).rev() {
+        if selected[older] {
+            continue;
+        }
 
-        locked_engine.sstable_readers0.clear();
-        locked_engine.sstables0.clear();
-        locked_engine.sstable_readers1.clear();
-        locked_engine.sstables1.clear();
+        selected[older] = (older + 1..readers.len())
+            .filter(|&newer| selected[newer])
+            .any(|newer| overlap(&readers[older], &readers[newer]));
+    }
 
-        locked_engine.sstables1.push(merged_table);
-        locked_engine.sstable_readers1.push(merged_table_reader);
-    });
+    (0..readers.len()).filter(|&index| selected[index]).collect()
+}
+
+/// Whether the key ranges of both sstables overlap. Empty sstables overlap none.
+fn overlap(a: &SSTableReader, b: &SSTableReader) -> bool {
+    match (a.first_key().zip(a.last_key()), b.first_key().zip(b.last_key())) {
+        (Some((a_first, a_last)), Some((b_first, b_last))) => a_first <= b_last && b_first <= a_last,
+        _ => false,
+    }
+}
+
+/// The smallest and largest keys of the given sstables, unless they are all empty.
+fn key_range<'a>(readers: impl Iterator<Item = &'a Arc<SSTableReader>> + Clone) -> Option<(&'a str, &'a str)> {
+    let first = readers.clone().filter_map(|reader| reader.first_key()).min()?;
+    let last = readers.filter_map(|reader| reader.last_key()).max()?;
+
+    Some((first, last))
+}
+
+/// Ingests the sstable at the given path, written by another storage, into L1, behind everything
+/// the storage holds: its entries are only read when no newer one has the same key. Only the L1
+/// tables whose range overlaps that of the ingested one are rewritten, merged with it, so that
+/// backfilling a range doesn't compact the whole tree.
+pub(crate) fn ingest_behind(engine: &Mutex<Engine>, config: &Config, path: &Path) -> Result<()> {
+    let external = SSTable::new(&config.env, path);
+    external.verify()?;
+
+    let reader = external.reader()?;
+    let (first, last) = match reader.first_key().zip(reader.last_key()) {
+        Some(range) => range,
+        None => return Ok(()),
+    };
+
+    // As for compactions, the merge runs on a copy of the current version, without holding the
+    // engine, and again if the L1 sstables it rewrites changed by the time it is done.
+    loop {
+        let version = engine.lock().unwrap_or_else(PoisonError::into_inner).version.clone();
+        let l1_inputs = version.overlapping_l1(first, last);
+        let overlapping = &version.sstables1[l1_inputs.clone()];
+
+        let output_path = || {
+            config
+                .segments_path
+                .join(format!("{}-{}", SEGMENTS_NAME, Uuid::new_v4().to_simple()))
+        };
+
+        // Nothing is older than L1, so there is nothing left for tombstones to shadow.
+        let options = TableOptions {
+            drop_tombstones: true,
+            retention: config.retention(),
+            source: TableSource::Ingestion,
+            target_file_size: Some(config.target_file_size),
+            ..config.table_options(1)
+        };
+
+        // The L1 tables are newer than the ingested one, so that they win the ties with the
+        // ingested entries, whose sequence number is 0.
+        let inputs: Vec<&SSTable> = overlapping.iter().collect();
+        let merged = SSTable::ingest(&config.env, output_path, &external, &inputs, &options)?;
+        let dropped_versions = merged.dropped_versions;
+        let outputs: Vec<PathBuf> = merged.tables.iter().map(|table| table.path().to_path_buf()).collect();
+
+        let reader = |table: &SSTable| Ok(Arc::new(table.cached_reader(&config.block_cache)?));
+        let readers: Vec<Arc<SSTableReader>> = merged.tables.iter().map(reader).collect::<Result<_>>()?;
+        config.env.sync_dir(&config.segments_path)?;
+
+        let mut locked_engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+        let current = locked_engine.version.overlapping_l1(first, last);
+        if locked_engine.version.sstables1[current.clone()] != *overlapping {
+            drop(locked_engine);
+            delete_files(&outputs, config)?;
+            continue;
+        }
+
+        let edit = Edit::ReplaceTables {
+            removed: overlapping.iter().map(table_name).collect(),
+            added: merged.tables.iter().map(table_name).collect(),
+            level: 1,
+        };
+        locked_engine.manifest.record(&edit)?;
+
+        for reader in &version.sstable_readers1[l1_inputs] {
+            reader.mark_obsolete(&config.env, config.secure_delete);
+        }
+
+        let version = locked_engine.version_mut();
+        version.sstables1.splice(current.clone(), merged.tables);
+        version.sstable_readers1.splice(current, readers);
+        version.sort_l1();
+        locked_engine.dropped_versions += dropped_versions;
+        locked_engine.measure_prefixes(&config.quotas)?;
+
+        // Keys known to be absent may have been ingested.
+        locked_engine.absent_keys = NegativeCache::new(config.negative_cache_capacity);
+
+        return Ok(());
+    }
+}
+
+/// Checks that the outputs of a compaction can be read back whole, and hold as many entries as
+/// were written to them.
+fn verify_outputs(tables: &[SSTable], written: usize) -> Result<()> {
+    let read = tables.iter().map(SSTable::verify).sum::<Result<usize>>()?;
+    if read != written {
+        bail!("{} entries were written, but {} were read back", written, read);
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeSet;
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
     use anyhow::Result;
-    use crate::{test_utils::Test, compactor::trigger_l0_compaction};
+    use super::{Job, JobQueue, QueueState};
+    use crate::env::{Env, EnvFile, OsEnv};
+    use crate::scheduler::{ManualClock, Scheduling};
+    use crate::storage::Storage;
+    use crate::{test_utils::Test, compactor::trigger_l0_compaction, Stored, SEGMENTS_NAME, WAL_NAME};
+
+    /// The sstable files in the segments path of the storage.
+    fn sstable_files(storage: &Storage) -> Result<BTreeSet<PathBuf>> {
+        let paths = storage.config.env.read_dir(&storage.config.segments_path)?;
+
+        Ok(paths
+            .into_iter()
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(SEGMENTS_NAME))
+            .collect())
+    }
+
+    #[test]
+    fn jobs_are_taken_by_priority_with_one_flush_at_a_time() {
+        let queue = JobQueue {
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        };
+        queue.push(Job::Delete(vec![PathBuf::from("old")]));
+        queue.push(Job::Flush);
+        queue.push(Job::Flush);
+
+        let flush = queue.take(false).unwrap();
+        assert!(matches!(flush, Job::Flush));
+        assert!(queue.has_pending_flush());
+
+        // The second flush waits for the first one to be done.
+        assert!(matches!(queue.take(false), Some(Job::Delete(_))));
+        queue.done(&flush);
+        assert!(matches!(queue.take(true), Some(Job::Flush)));
+
+        queue.push(Job::Delete(vec![PathBuf::from("older")]));
+        queue.close();
+        assert!(matches!(queue.take(false), Some(Job::Delete(_))));
+        assert!(queue.take(false).is_none());
+    }
+
+    #[test]
+    fn background_threads_flush_and_compact_on_their_own() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test
+            .storage_builder()
+            .scheduling(Scheduling::Background)
+            .max_background_jobs(2)
+            .dedicated_flush_thread(true)
+            .l0_compaction_trigger(2)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 4)?;
+
+        let start = Instant::now();
+        while !storage.engine.lock().unwrap().version.memtables.is_empty() || storage.engine.lock().unwrap().version.sstables1.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10), "the background work never ran");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for i in [0, threshold * 4 - 1] {
+            assert_eq!(storage.read(&format!("key-{}", i))?, Some(b"value".to_vec()));
+        }
+
+        Ok(())
+    }
 
     #[test]
     fn compaction_in_l0_changes_all_files_in_l1() -> Result<()> {
@@ -91,33 +868,35 @@ mod tests {
         let expected_sstables = 5;
 
         Test::inject_data(&mut storage, threshold * expected_sstables)?;
-        
+        storage.tick()?;
+
         {
             let engine = storage.engine.lock().unwrap();
-            assert_eq!(engine.sstables0.len(), expected_sstables);
+            assert_eq!(engine.version.sstables0.len(), expected_sstables);
         }
 
-        trigger_l0_compaction(storage.engine.clone());
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
 
         let sstables;
 
         {
             let engine = storage.engine.lock().unwrap();
-            assert_eq!(engine.sstables0.len(), 0);
-            assert_eq!(engine.sstables1.len(), 1);
-            sstables = Some(engine.sstables1.clone());
+            assert_eq!(engine.version.sstables0.len(), 0);
+            assert_eq!(engine.version.sstables1.len(), 1);
+            sstables = Some(engine.version.sstables1.clone());
         }
 
         Test::inject_data(&mut storage, threshold * expected_sstables)?;
-        trigger_l0_compaction(storage.engine.clone());
+        storage.tick()?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
 
         {
             let engine = storage.engine.lock().unwrap();
-            assert_eq!(engine.sstables0.len(), 0);
-            assert_eq!(engine.sstables1.len(), 1);
+            assert_eq!(engine.version.sstables0.len(), 0);
+            assert_eq!(engine.version.sstables1.len(), 1);
 
             for original_sstable1 in sstables.unwrap() {
-                assert!(!engine.sstables1.contains(&original_sstable1));
+                assert!(!engine.version.sstables1.contains(&original_sstable1));
             }
         }
 
@@ -125,15 +904,709 @@ mod tests {
     }
 
     #[test]
-    fn compacted_data_after_l0_is_broken_into_ordered_files_with_capped_size() {
+    fn compaction_runs_once_the_interval_elapses() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let mut storage = test
+            .storage_builder()
+            .clock(clock.clone())
+            .compaction_interval(Duration::from_secs(60))
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        assert_eq!(storage.tick()?, 2);
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(storage.tick()?, 0);
 
+        {
+            let engine = storage.engine.lock().unwrap();
+            assert_eq!(engine.version.sstables0.len(), 2);
+            assert_eq!(engine.version.sstables1.len(), 0);
+        }
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(storage.tick()?, 1);
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 0);
+        assert_eq!(engine.version.sstables1.len(), 1);
+
+        Ok(())
     }
 
-    fn compaction_after_L1_only_touches_specific_files() {}
+    #[test]
+    fn sstables_with_many_tombstones_are_compacted_early() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test
+            .storage_builder()
+            .compaction_interval(Duration::from_secs(3600))
+            .compaction_tombstone_ratio(0.5)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold)?;
+        assert_eq!(storage.tick()?, 1);
+
+        for i in 0..threshold {
+            storage.remove(format!("key-{i}"))?;
+        }
+
+        assert_eq!(storage.tick()?, 2);
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 0);
+        assert_eq!(engine.version.sstable_readers1.len(), 1);
+        assert_eq!(engine.version.sstable_readers1[0].len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn l0_is_compacted_once_it_holds_as_many_sstables_as_the_trigger() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test
+            .storage_builder()
+            .compaction_interval(Duration::from_secs(3600))
+            .l0_compaction_trigger(3)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        assert_eq!(storage.tick()?, 2);
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 2);
+
+        Test::inject_data(&mut storage, threshold)?;
+        assert_eq!(storage.tick()?, 2);
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 0);
+        assert_eq!(engine.version.sstables1.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn planning_picks_l1_and_l0_tables_and_estimates_the_output() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        let value = || Stored::Value(b"value".to_vec());
+        let sstable1 = test.generate_sstable("1", &[("a".to_owned(), value()), ("b".to_owned(), value())])?;
+        let sstable0 = test.generate_sstable("0", &[("b".to_owned(), value()), ("c".to_owned(), value())])?;
+
+        {
+            let mut engine = storage.engine.lock().unwrap();
+            let version = engine.version_mut();
+            version.sstables0.push(sstable0.clone());
+            version.sstable_readers0.push(Arc::new(sstable0.reader()?));
+            version.sstables1.push(sstable1.clone());
+            version.sstable_readers1.push(Arc::new(sstable1.reader()?));
+        }
+
+        let plan = storage.plan_compaction()?.unwrap();
 
-    fn compaction_in_last_layer_removes_tombstones() {}
+        assert_eq!(plan.inputs, vec![sstable1.path().to_path_buf(), sstable0.path().to_path_buf()]);
+        assert_eq!(plan.input_bytes, sstable0.size()? + sstable1.size()?);
+        assert_eq!(plan.estimated_output_bytes, plan.input_bytes * 3 / 4);
+        assert_eq!(plan.estimated_write_amplification, 1.5);
+        assert_eq!(plan.tombstone_ratio, 0.0);
 
-    fn merged_sttables_are_removed_from_view_and_deleted() {}
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 1);
+        assert_eq!(engine.version.sstables1.len(), 1);
 
-    fn result_of_compaction_is_available_at_the_correct_level() {}
+        Ok(())
+    }
+
+    #[test]
+    fn planning_without_l0_tables_yields_no_plan() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        assert_eq!(storage.plan_compaction()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_data_after_l0_is_broken_into_ordered_files_with_capped_size() -> Result<()> {
+        let test = Test::new()?;
+        let target_file_size = 4096;
+        let mut storage = test.storage_builder().target_file_size(target_file_size).build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        let names: Vec<_> = {
+            let engine = storage.engine.lock().unwrap();
+            assert!(engine.version.sstables1.len() > 1);
+
+            let mut last_key: Option<String> = None;
+            let mut entries = 0;
+
+            for (sstable, reader) in engine.version.sstables1.iter().zip(&engine.version.sstable_readers1) {
+                // Every sstable ends with the entry that reaches the target size, followed by the
+                // filter and the footer.
+                assert!(sstable.size()? < target_file_size + 128);
+
+                let mut keys = reader.keys()?;
+                keys.sort();
+
+                assert!(last_key < keys.first().cloned());
+                last_key = keys.last().cloned();
+                entries += keys.len();
+            }
+
+            assert_eq!(entries, threshold * 2);
+            engine.version.sstables1.iter().map(|sstable| sstable.path().to_path_buf()).collect()
+        };
+
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+        drop(storage);
+
+        let storage = test.create_storage()?;
+        let engine = storage.engine.lock().unwrap();
+        let reopened: Vec<_> = engine.version.sstables1.iter().map(|sstable| sstable.path().to_path_buf()).collect();
+        assert_eq!(reopened, names);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_in_l0_only_touches_the_overlapping_files_in_l1() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().target_file_size(4096).build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        let before: Vec<_> = storage.engine.lock().unwrap().version.sstables1.clone();
+        assert!(before.len() > 2);
+
+        let sstable = test.generate_sequenced_sstable(
+            "0",
+            &[("key-0".to_owned(), Stored::Value(b"new-value".to_vec()), u64::MAX)],
+        )?;
+
+        {
+            let mut engine = storage.engine.lock().unwrap();
+            let version = engine.version_mut();
+            version.sstable_readers0.push(Arc::new(sstable.reader()?));
+            version.sstables0.push(sstable);
+        }
+
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        {
+            let engine = storage.engine.lock().unwrap();
+            let replaced: Vec<_> = before
+                .iter()
+                .filter(|sstable| !engine.version.sstables1.contains(sstable))
+                .map(|sstable| sstable.path())
+                .collect();
+
+            assert_eq!(engine.version.sstables0.len(), 0);
+            assert_eq!(engine.version.sstables1.len(), before.len());
+            assert_eq!(replaced, vec![before[0].path()]);
+        }
+
+        assert_eq!(storage.read("key-0")?, Some(b"new-value".to_vec()));
+        for i in 1..threshold * 2 {
+            assert_eq!(storage.read(&format!("key-{i}"))?, Some(b"value".to_vec()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_in_last_layer_removes_tombstones() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold)?;
+        storage.remove("key-0".to_owned())?;
+        for i in 1..threshold {
+            storage.insert(format!("key-{i}"), b"value".to_vec())?;
+        }
+        storage.tick()?;
+
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstable_readers1[0].tombstones(), 0);
+        assert!(!engine.version.sstable_readers1[0].keys()?.iter().any(|key| key == "key-0"));
+        assert_eq!(engine.version.sstable_readers1[0].len(), threshold - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn a_single_l0_sstable_is_rewritten_into_l1() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        storage.insert("a".to_owned(), b"value".to_vec())?;
+        storage.remove("b".to_owned())?;
+        storage.flush()?;
+        storage.tick()?;
+        let persisted = sstable_files(&storage)?;
+
+        let compacted = trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert_eq!(compacted.inputs, 1);
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 0);
+        let reader = &engine.version.sstable_readers1[0];
+        assert!(!persisted.contains(reader.path()));
+        assert_eq!(reader.tombstones(), 0);
+        assert_eq!(reader.keys()?, ["a"]);
+        assert_eq!(reader.metadata().map(|metadata| metadata.level), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merged_sstables_are_removed_from_view_and_deleted() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        let l1 = sstable_files(&storage)?;
+
+        // Both the L0 and the L1 inputs are deleted as soon as they are replaced.
+        Test::inject_data(&mut storage, threshold)?;
+        storage.tick()?;
+        let inputs = sstable_files(&storage)?;
+        assert!(l1.is_subset(&inputs));
+
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        let outputs = sstable_files(&storage)?;
+        assert!(outputs.is_disjoint(&inputs));
+        {
+            let engine = storage.engine.lock().unwrap();
+            assert!(engine.version.sstables0.is_empty());
+            let current: BTreeSet<PathBuf> =
+                engine.version.sstables1.iter().map(|sstable| sstable.path().to_path_buf()).collect();
+            assert_eq!(outputs, current);
+        }
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_of_many_tables_leave_only_the_live_sstables_behind() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        for _ in 0..6 {
+            Test::inject_data(&mut storage, threshold)?;
+            storage.tick()?;
+        }
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 6);
+        let files = || -> Result<BTreeSet<PathBuf>> {
+            std::fs::read_dir(test.test_path())?.map(|entry| Ok(entry?.path())).collect()
+        };
+        let before = files()?;
+
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        // The tables are merged at once, so that no intermediate table is left anywhere.
+        let live: BTreeSet<PathBuf> = {
+            let engine = storage.engine.lock().unwrap();
+            engine.version.sstables1.iter().map(|sstable| sstable.path().to_path_buf()).collect()
+        };
+        assert_eq!(sstable_files(&storage)?, live);
+        let after = files()?;
+        let added: BTreeSet<&PathBuf> = after.difference(&before).collect();
+        assert_eq!(added, live.iter().collect());
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn result_of_compaction_is_available_at_the_correct_level() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().compaction_interval(Duration::ZERO).build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        drop(storage);
+
+        let storage = test.create_storage()?;
+
+        {
+            let engine = storage.engine.lock().unwrap();
+            assert_eq!(engine.version.sstables0.len(), 0);
+            assert_eq!(engine.version.sstables1.len(), 1);
+            assert_eq!(engine.version.sstable_readers1[0].len(), threshold * 2);
+        }
+
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_keep_the_newest_values_over_those_in_l1() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+        let persist = |writes: &[(&str, Option<&str>)]| -> Result<()> {
+            for (key, value) in writes {
+                match value {
+                    Some(value) => storage.insert(key.to_string(), value.as_bytes().to_vec())?,
+                    None => storage.remove(key.to_string())?,
+                }
+            }
+            storage.flush()?;
+            storage.tick()?;
+            Ok(())
+        };
+
+        persist(&[("key-0", Some("old")), ("key-1", Some("old"))])?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        // Both L0 sstables are newer than L1, and the second one newer than the first.
+        persist(&[("key-0", Some("new"))])?;
+        persist(&[("key-0", Some("newest")), ("key-1", None)])?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 0);
+
+        assert_eq!(storage.read("key-0")?, Some(b"newest".to_vec()));
+        assert_eq!(storage.read("key-1")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_inputs_are_deleted_once_no_iterator_reads_them() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        let inputs = sstable_files(&storage)?;
+        assert_eq!(inputs.len(), 2);
+
+        // The iterator holds the version listing the inputs, so their files outlive the
+        // compaction until it is dropped.
+        let iter = storage.iter()?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert!(inputs.is_subset(&sstable_files(&storage)?));
+        assert_eq!(iter.count(), threshold * 2);
+
+        // Once it is, they are deleted.
+        assert!(sstable_files(&storage)?.is_disjoint(&inputs));
+
+        Ok(())
+    }
+
+    #[test]
+    fn secure_delete_overwrites_obsolete_files_unless_a_checkpoint_links_them() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().secure_delete(true).build()?;
+        let threshold = storage.config.threshold;
+
+        // Removed files are still read through the handles opened on them beforehand.
+        let open = |paths: &BTreeSet<PathBuf>| -> Result<Vec<File>> {
+            Ok(paths.iter().map(File::open).collect::<std::io::Result<_>>()?)
+        };
+        let contents = |mut file: &File| -> Result<Vec<u8>> {
+            let mut contents = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut contents)?;
+            Ok(contents)
+        };
+        let shredded = |file: &File| -> Result<bool> {
+            let contents = contents(file)?;
+            Ok(!contents.is_empty() && contents.iter().all(|byte| *byte == 0))
+        };
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        let wals: BTreeSet<PathBuf> = storage.config.env.read_dir(&test.test_path())?
+            .into_iter()
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(WAL_NAME))
+            .collect();
+        let wals = open(&wals)?;
+        storage.tick()?;
+        assert_eq!(wals.iter().filter(|wal| shredded(wal).unwrap()).count(), 2);
+
+        let inputs = sstable_files(&storage)?;
+        let handles = open(&inputs)?;
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert!(sstable_files(&storage)?.is_disjoint(&inputs));
+        for handle in &handles {
+            assert!(shredded(handle)?);
+        }
+
+        // The inputs of the next compaction are linked into a checkpoint, which keeps reading
+        // them.
+        Test::inject_data(&mut storage, threshold)?;
+        storage.tick()?;
+        let inputs = sstable_files(&storage)?;
+        let handles = open(&inputs)?;
+        let checkpoint = test.path("checkpoint");
+        storage.checkpoint(&checkpoint)?;
+
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert!(sstable_files(&storage)?.is_disjoint(&inputs));
+        for (path, handle) in inputs.iter().zip(&handles) {
+            let linked = std::fs::read(checkpoint.join(path.file_name().unwrap()))?;
+            assert!(!shredded(handle)?);
+            assert_eq!(contents(handle)?, linked);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn scans_running_alongside_compactions_neither_miss_nor_repeat_keys() -> Result<()> {
+        /// Stops the scanners once dropped, even when the writes fail or panic.
+        struct Done<'a>(&'a AtomicBool);
+
+        impl Drop for Done<'_> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        let mut expected: Vec<String> = (0..threshold * 2).map(|i| format!("key-{i}")).collect();
+        expected.sort();
+
+        let storage = &storage;
+        let expected = &expected;
+        let done = &AtomicBool::new(false);
+
+        thread::scope(|scope| -> Result<()> {
+            let mut scanners = Vec::new();
+            for _ in 0..3 {
+                scanners.push(scope.spawn(move || -> Result<()> {
+                    while !done.load(Ordering::SeqCst) {
+                        let keys: Vec<String> = storage.iter()?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+                        assert_eq!(&keys, expected);
+                    }
+
+                    Ok(())
+                }));
+            }
+
+            // The pages of a scan span several compactions.
+            scanners.push(scope.spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    let mut keys = Vec::new();
+                    let mut continuation = None;
+
+                    loop {
+                        let page = storage.scan("", 100, continuation.as_deref())?;
+                        keys.extend(page.entries.into_iter().map(|(key, _)| key));
+                        continuation = match page.continuation {
+                            Some(token) => Some(token),
+                            None => break,
+                        };
+                    }
+                    assert_eq!(&keys, expected);
+                }
+
+                Ok(())
+            }));
+
+            // Every round overwrites half of the keys, leaving their versions in both L0 and L1,
+            // and compacts them.
+            let writing = Done(done);
+            for round in 0..10 {
+                for i in (round % 2..threshold * 2).step_by(2) {
+                    storage.insert(format!("key-{i}"), format!("value-{round}").into_bytes())?;
+                }
+                storage.flush()?;
+                storage.tick()?;
+                trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+            }
+            drop(writing);
+
+            for scanner in scanners {
+                scanner.join().unwrap()?;
+            }
+
+            Ok(())
+        })?;
+
+        assert_eq!(storage.read("key-0")?, Some(b"value-8".to_vec()));
+        assert_eq!(storage.read("key-1")?, Some(b"value-9".to_vec()));
+
+        Ok(())
+    }
+
+    /// Runs a hook, once, when the next sstable is created through it.
+    #[derive(Default)]
+    struct HookEnv {
+        hook: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    }
+
+    impl HookEnv {
+        fn on_next_sstable(&self, hook: impl FnOnce() + Send + 'static) {
+            *self.hook.lock().unwrap() = Some(Box::new(hook));
+        }
+    }
+
+    impl Env for HookEnv {
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            OsEnv.create_dir_all(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+            OsEnv.read_dir(path)
+        }
+
+        fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+            if path.file_name().unwrap().to_string_lossy().starts_with(SEGMENTS_NAME) {
+                let hook = self.hook.lock().unwrap().take();
+                if let Some(hook) = hook {
+                    hook();
+                }
+            }
+
+            OsEnv.create(path)
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+            OsEnv.open(path)
+        }
+
+        fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+            OsEnv.open_writable(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            OsEnv.remove_file(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            OsEnv.rename(from, to)
+        }
+
+        fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+            OsEnv.link(from, to)
+        }
+
+        fn file_size(&self, path: &Path) -> io::Result<u64> {
+            OsEnv.file_size(path)
+        }
+
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            OsEnv.sync_dir(path)
+        }
+
+        fn lock(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+            OsEnv.lock(path)
+        }
+    }
+
+    #[test]
+    fn compactions_merge_without_holding_the_engine() -> Result<()> {
+        let env = Arc::new(HookEnv::default());
+        let test = Test::with_env(env.clone())?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+
+        let merging = storage.clone();
+        env.on_next_sstable(move || {
+            merging.insert("key-new".to_owned(), b"value".to_vec()).unwrap();
+            assert_eq!(merging.read("key-0").unwrap(), Some(b"value".to_vec()));
+        });
+        trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 0);
+        assert_eq!(engine.version.sstables1.len(), 1);
+        assert_eq!(engine.active_memtable.get("key-new"), Some(&b"value"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_are_planned_again_if_their_inputs_change_while_merging() -> Result<()> {
+        let env = Arc::new(HookEnv::default());
+        let test = Test::with_env(env.clone())?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+
+        // Another compaction replaces the inputs first, so the outputs of the first merge are
+        // discarded, and there is nothing left to compact once planned again.
+        let compacting = storage.clone();
+        env.on_next_sstable(move || {
+            trigger_l0_compaction(compacting.engine.clone(), &compacting.config).unwrap();
+        });
+        let compacted = trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert_eq!(compacted.inputs, 0);
+
+        let installed: BTreeSet<PathBuf> = {
+            let engine = storage.engine.lock().unwrap();
+            assert_eq!(engine.version.sstables0.len(), 0);
+            engine.version.sstables1.iter().map(|sstable| sstable.path().to_path_buf()).collect()
+        };
+        assert_eq!(installed.len(), 1);
+        storage.tick()?;
+        assert_eq!(sstable_files(&storage)?, installed);
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn memtables_are_persisted_while_a_compaction_merges() -> Result<()> {
+        let env = Arc::new(HookEnv::default());
+        let test = Test::with_env(env.clone())?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+        storage.insert("key-new".to_owned(), b"value".to_vec())?;
+        storage.flush()?;
+
+        // As a dedicated flush thread would, while the compaction merges the older sstables.
+        let flushing = storage.clone();
+        env.on_next_sstable(move || {
+            super::persist_memtable(&flushing.engine, &flushing.config).unwrap();
+        });
+        let compacted = trigger_l0_compaction(storage.engine.clone(), &storage.config)?;
+        assert_eq!(compacted.inputs, 2);
+
+        {
+            let engine = storage.engine.lock().unwrap();
+            assert!(engine.version.memtables.is_empty());
+            assert_eq!(engine.version.sstables0.len(), 1);
+            assert_eq!(engine.version.sstables1.len(), 1);
+        }
+        assert_eq!(storage.read("key-new")?, Some(b"value".to_vec()));
+        assert_eq!(storage.read("key-0")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// The codecs that can compress the values stored in sstables.
+///
+/// Each level of the tree can use a different codec: hot levels favour speed, while cold levels
+/// favour the compression ratio.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    /// Zstandard, at the given compression level.
+    Zstd(i32),
+}
+
+impl Compression {
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(data, level)?),
+        }
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(data)?),
+            Compression::Zstd(_) => Ok(zstd::stream::decode_all(data)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+    use anyhow::Result;
+
+    #[test]
+    fn decompression_yields_the_original_data() -> Result<()> {
+        let data = b"value-1 value-1 value-1 value-1 value-1 value-1".repeat(10);
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd(19)] {
+            let compressed = compression.compress(&data)?;
+            assert_eq!(compression.decompress(&compressed)?, data, "{:?}", compression);
+        }
+
+        Ok(())
+    }
+}
@@ -1,14 +1,184 @@
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Condvar};
 
+use anyhow::Result;
+
+use crate::cache::{NegativeCache, RowCache};
+use crate::hot_keys::HotKeys;
+use crate::manifest::Manifest;
 use crate::memtable::MemTable;
 use crate::sstable::{SSTable, SSTableReader};
+use crate::stats::{BytesWritten, Latencies, TenantStats};
+use crate::tuning::{Tuner, Tuning};
+use crate::Error;
 
-/// The storage engine. It holds the current memtable and the set of sstables
-pub struct Engine {
-    pub active_memtable: MemTable,
+/// The frozen memtables and the sstables of each level, as they were at some point.
+///
+/// A version never changes once installed in the engine: flushes and compactions edit a copy of
+/// it, see [`Engine::version_mut`], so that whoever holds the current one keeps seeing the same
+/// memtables and sstables, and can read them without holding the engine.
+///
+/// The sstables of L0 may overlap, and are ordered by age. Those of L1 never do, and are ordered
+/// by key range, so that the ones holding a key can be found by binary search.
+#[derive(Clone, Default)]
+pub struct Version {
+    /// The memtables waiting to be flushed, from the oldest to the newest.
     pub memtables: Vec<Arc<MemTable>>,
     pub sstables0: Vec<SSTable>,
     pub sstables1: Vec<SSTable>,
-    pub sstable_readers0: Vec<SSTableReader>,
-    pub sstable_readers1: Vec<SSTableReader>,
+    pub sstable_readers0: Vec<Arc<SSTableReader>>,
+    pub sstable_readers1: Vec<Arc<SSTableReader>>,
+}
+
+/// The storage engine. It holds the current memtable, which every write changes, and the current
+/// [`Version`].
+pub struct Engine {
+    pub active_memtable: MemTable,
+    pub version: Arc<Version>,
+    pub absent_keys: NegativeCache,
+    pub rows: RowCache,
+    pub bytes_written: BytesWritten,
+    pub latencies: Latencies,
+    pub manifest: Manifest,
+    /// The first error of a flush or compaction, if any failed.
+    pub background_error: Option<Error>,
+    /// The bytes the keys starting with each prefix with a quota take in the sstables.
+    pub prefix_bytes: HashMap<String, u64>,
+    pub tenants: HashMap<String, TenantStats>,
+    /// The versions dropped by compactions since the storage was opened.
+    pub dropped_versions: u64,
+    /// Notified whenever frozen memtables are persisted or dropped, or background work fails,
+    /// waking the writes waiting for them.
+    pub flushed: Arc<Condvar>,
+    pub hot_keys: HotKeys,
+    /// The threshold of the memtables and the L0 compaction trigger, as configured unless they
+    /// are tuned.
+    pub tuning: Tuning,
+    /// Adjusts the tuning after every flush, if the storage tunes itself.
+    pub tuner: Option<Tuner>,
+    /// The keys inserted with a TTL, by when they expire, so that the expired ones can be found
+    /// without reading everything. Keys written again since stay until they are purged.
+    pub expiries: BTreeSet<(u64, String)>,
+}
+
+impl Engine {
+    /// The current version, copied first if anyone still holds it, to be edited in place.
+    pub fn version_mut(&mut self) -> &mut Version {
+        Arc::make_mut(&mut self.version)
+    }
+
+    /// Fails with the error of the flush or compaction that failed, if any did.
+    pub fn check_background_error(&self) -> Result<()> {
+        match &self.background_error {
+            Some(error) => Err(error.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the failure of a flush or compaction, unless one was recorded already, making
+    /// every later check fail. Returns the recorded error.
+    pub fn record_background_error(&mut self, error: anyhow::Error) -> anyhow::Error {
+        let reason = format!("{:#}", error);
+        self.flushed.notify_all();
+
+        self.background_error
+            .get_or_insert(Error::BackgroundError { reason })
+            .clone()
+            .into()
+    }
+
+    /// The stats of the given tenant, which starts with none.
+    pub fn tenant_stats(&mut self, tenant: &str) -> &mut TenantStats {
+        if !self.tenants.contains_key(tenant) {
+            self.tenants.insert(tenant.to_owned(), TenantStats::default());
+        }
+
+        self.tenants.get_mut(tenant).unwrap()
+    }
+
+    /// Measures again the bytes the keys starting with each of the given prefixes take in the
+    /// sstables, once these changed.
+    pub fn measure_prefixes(&mut self, quotas: &[(String, u64)]) -> Result<()> {
+        self.prefix_bytes = quotas
+            .iter()
+            .map(|(prefix, _)| {
+                let readers = self.version.sstable_readers0.iter().chain(&self.version.sstable_readers1);
+                Ok((prefix.clone(), readers.map(|reader| reader.prefix_bytes(prefix)).sum::<Result<_>>()?))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    /// Fails with [`Error::QuotaExceeded`] if the keys starting with the prefix of any quota that
+    /// the given key falls under already take as many bytes as it allows.
+    pub fn check_quotas(&self, key: &str, quotas: &[(String, u64)]) -> Result<()> {
+        for (prefix, quota) in quotas.iter().filter(|(prefix, _)| key.starts_with(prefix.as_str())) {
+            if self.prefix_bytes.get(prefix).copied().unwrap_or(0) >= *quota {
+                let (prefix, quota) = (prefix.clone(), *quota);
+                return Err(Error::QuotaExceeded { prefix, quota }.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Version {
+    /// The readers of every sstable, from the newest to the oldest, so that the first one holding
+    /// a key has its latest entry. L1 doesn't overlap, so at most one of its sstables holds each
+    /// key.
+    pub fn readers_newest_first(&self) -> Vec<&SSTableReader> {
+        self.sstable_readers0.iter().rev().chain(&self.sstable_readers1).map(Arc::as_ref).collect()
+    }
+
+    /// The reader of the L1 sstable whose key range holds the given key, if any.
+    pub fn l1_reader_for(&self, key: &str) -> Option<&SSTableReader> {
+        let index = self
+            .sstable_readers1
+            .partition_point(|reader| reader.last_key() < Some(key));
+
+        self.sstable_readers1
+            .get(index)
+            .map(Arc::as_ref)
+            .filter(|reader| reader.first_key() <= Some(key))
+    }
+
+    /// The smallest and largest keys of all the L0 sstables, unless they are all empty.
+    pub fn l0_key_range(&self) -> Option<(&str, &str)> {
+        let first = self.sstable_readers0.iter().filter_map(|reader| reader.first_key()).min()?;
+        let last = self.sstable_readers0.iter().filter_map(|reader| reader.last_key()).max()?;
+
+        Some((first, last))
+    }
+
+    /// The indexes of the L1 sstables whose key range overlaps the given one.
+    pub fn overlapping_l1(&self, first: &str, last: &str) -> Range<usize> {
+        let start = self
+            .sstable_readers1
+            .partition_point(|reader| reader.last_key() < Some(first));
+        let end = self
+            .sstable_readers1
+            .partition_point(|reader| reader.first_key() <= Some(last));
+
+        start..end.max(start)
+    }
+
+    /// The indexes of the L1 sstables an L0 compaction has to merge along with L0: those
+    /// overlapping L0, as the others cannot hold any of its keys.
+    pub fn l1_compaction_inputs(&self) -> Range<usize> {
+        match self.l0_key_range() {
+            Some((first, last)) => self.overlapping_l1(first, last),
+            None => 0..0,
+        }
+    }
+
+    /// Sorts the sstables of L1 by key range. Empty ones, which hold no range, go first.
+    pub fn sort_l1(&mut self) {
+        let mut tables: Vec<_> = self.sstables1.drain(..).zip(self.sstable_readers1.drain(..)).collect();
+        tables.sort_by(|(_, a), (_, b)| a.first_key().cmp(&b.first_key()));
+
+        (self.sstables1, self.sstable_readers1) = tables.into_iter().unzip();
+    }
 }
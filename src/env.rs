@@ -0,0 +1,273 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+mod direct;
+
+/// Abstracts the interactions of the storage with the file system.
+///
+/// All the I/O performed by the storage goes through an `Env`. This allows running it on top of
+/// alternative implementations, e.g. one that injects failures to test crash recovery.
+pub trait Env: Send + Sync {
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the paths of the entries of a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Creates a file for reading and writing, truncating it if it already exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+
+    /// Opens an existing file for reading and writing.
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+
+    /// Creates a file like [`Env::create`], but bypassing the page cache if supported. The file
+    /// only needs to support being appended to.
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.create(path)
+    }
+
+    /// Opens a file like [`Env::open`], but bypassing the page cache if supported.
+    fn open_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.open(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Overwrites a file with zeros, makes that durable, and then removes it, so that its contents
+    /// can't be recovered from the device once the file is gone. Implementations that can tell
+    /// a file has other hard links, e.g. into a checkpoint, should only remove it, as its contents
+    /// are still in use through them.
+    ///
+    /// This only erases what the file system overwrites in place, which excludes copy-on-write
+    /// file systems, snapshots and the spare blocks of SSDs.
+    fn shred_file(&self, path: &Path) -> io::Result<()> {
+        let len = self.file_size(path)?;
+        overwrite(self.open_writable(path)?.as_mut(), len)?;
+
+        self.remove_file(path)
+    }
+
+    /// Atomically replaces `to` with `from`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Creates a hard link at `to` to the file at `from`.
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// The size of a file, in bytes.
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Makes the files created, removed and renamed inside a directory durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates the file if it doesn't exist, and locks it until the returned file is dropped.
+    /// Fails with [`io::ErrorKind::WouldBlock`] if it is already locked, by this process or any
+    /// other.
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+}
+
+/// How a file is going to be accessed, so that the operating system can manage its cache
+/// accordingly. See `posix_fadvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The file will be read from start to end, so reading ahead pays off.
+    Sequential,
+    /// The file won't be accessed in the near future, so it shouldn't take space in the cache.
+    DontNeed,
+}
+
+/// A file opened through an [`Env`].
+pub trait EnvFile: Read + Write + Seek + Send + Sync {
+    /// Ensures the contents of the file reached the storage device.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Reads from the given offset, without moving the position of the file, so that many
+    /// threads can read the same file at once. See `pread`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Truncates or extends the file to the given length.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Hints how the file is going to be accessed. Ignored by default.
+    fn advise(&mut self, _advice: Advice) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The descriptor of the file, for reads submitted through io_uring rather than
+    /// [`EnvFile::read_at`]. `None` by default, which keeps every read of the file going through
+    /// the latter.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+}
+
+/// The default `Env`, backed by the file system of the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsEnv;
+
+impl Env for OsEnv {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(Box::new(file))
+    }
+
+    /// Falls back to [`Env::create`] if the file system doesn't support direct I/O.
+    #[cfg(target_os = "linux")]
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        match direct::DirectWriter::create(path) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(error) if error.raw_os_error() == Some(libc::EINVAL) => self.create(path),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Falls back to [`Env::open`] if the file system doesn't support direct I/O.
+    #[cfg(target_os = "linux")]
+    fn open_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        match direct::DirectReader::open(path) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(error) if error.raw_os_error() == Some(libc::EINVAL) => self.open(path),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    /// Only removes files with other hard links.
+    #[cfg(unix)]
+    fn shred_file(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata(path)?;
+        if metadata.nlink() == 1 {
+            overwrite(self.open_writable(path)?.as_mut(), metadata.len())?;
+        }
+
+        self.remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::hard_link(from, to)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(file)),
+            Err(std::fs::TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(std::fs::TryLockError::Error(error)) => Err(error),
+        }
+    }
+}
+
+/// Overwrites the first `len` bytes of a file with zeros, and syncs it.
+fn overwrite(file: &mut dyn EnvFile, len: u64) -> io::Result<()> {
+    let zeros = [0; 64 * 1024];
+
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut left = len;
+    while left > 0 {
+        let chunk = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        left -= chunk as u64;
+    }
+    file.flush()?;
+
+    file.sync()
+}
+
+impl EnvFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn advise(&mut self, advice: Advice) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+
+        // Covers the whole file, regardless of its size.
+        // SAFETY: `posix_fadvise` only reads its arguments, and the descriptor is owned by this
+        // file, so it stays open for the whole call.
+        match unsafe { libc::posix_fadvise(self.as_raw_fd(), 0, 0, advice) } {
+            0 => Ok(()),
+            error => Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        Some(self.as_raw_fd())
+    }
+}
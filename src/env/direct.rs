@@ -0,0 +1,303 @@
+//! Files opened with `O_DIRECT`, whose I/O bypasses the page cache.
+//!
+//! Direct I/O requires the offsets, lengths and memory addresses of every read and write to be
+//! aligned to the block size of the device. These files hide that behind aligned buffers, so they
+//! can be used as any other `EnvFile` as long as they are only read, or only appended to.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use super::EnvFile;
+
+/// The alignment required by direct I/O. Large enough for any common device.
+const ALIGNMENT: usize = 4096;
+/// How many bytes are read or written at once.
+const BUFFER_SIZE: usize = 64 * ALIGNMENT;
+
+fn unsupported(operation: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported by files opened for direct I/O", operation),
+    )
+}
+
+/// A buffer whose contents start at a memory address aligned for direct I/O.
+struct AlignedBuffer {
+    memory: Vec<u8>,
+    start: usize,
+}
+
+impl AlignedBuffer {
+    fn new() -> Self {
+        let memory = vec![0; BUFFER_SIZE + ALIGNMENT];
+        let start = memory.as_ptr().align_offset(ALIGNMENT);
+
+        AlignedBuffer { memory, start }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.memory[self.start..self.start + BUFFER_SIZE]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.memory[self.start..self.start + BUFFER_SIZE]
+    }
+}
+
+/// A file created for direct I/O, which can only be appended to.
+///
+/// Writes are buffered until a whole buffer can be written. Flushing writes the buffered tail
+/// padded to the alignment, and truncates the padding away: the tail is rewritten once more data
+/// is appended.
+pub(super) struct DirectWriter {
+    file: File,
+    buffer: AlignedBuffer,
+    /// The offset of the buffer in the file. Always aligned.
+    offset: u64,
+    /// How many bytes are buffered.
+    len: usize,
+}
+
+impl DirectWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        Ok(DirectWriter {
+            file,
+            buffer: AlignedBuffer::new(),
+            offset: 0,
+            len: 0,
+        })
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len().min(BUFFER_SIZE - self.len);
+        self.buffer.as_mut_slice()[self.len..self.len + written].copy_from_slice(&buf[..written]);
+        self.len += written;
+
+        if self.len == BUFFER_SIZE {
+            self.file.write_all_at(self.buffer.as_slice(), self.offset)?;
+            self.offset += BUFFER_SIZE as u64;
+            self.len = 0;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+
+        let padded_len = self.len.div_ceil(ALIGNMENT) * ALIGNMENT;
+        self.file.write_all_at(&self.buffer.as_slice()[..padded_len], self.offset)?;
+        self.file.set_len(self.offset + self.len as u64)
+    }
+}
+
+impl Read for DirectWriter {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(unsupported("reading"))
+    }
+}
+
+impl Seek for DirectWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.offset + self.len as u64),
+            _ => Err(unsupported("seeking")),
+        }
+    }
+}
+
+impl EnvFile for DirectWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync_all()
+    }
+
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> io::Result<usize> {
+        Err(unsupported("reading"))
+    }
+
+    fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        Err(unsupported("resizing"))
+    }
+}
+
+impl Drop for DirectWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// A file opened for direct I/O, which can only be read.
+///
+/// Reads are served from a buffer holding the aligned region around the current position.
+pub(super) struct DirectReader {
+    file: File,
+    buffer: AlignedBuffer,
+    /// The offset of the buffer in the file. Always aligned.
+    offset: u64,
+    /// How many bytes of the buffer hold data of the file.
+    len: usize,
+    /// The position of the next read.
+    position: u64,
+}
+
+impl DirectReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        Ok(DirectReader {
+            file,
+            buffer: AlignedBuffer::new(),
+            offset: 0,
+            len: 0,
+            position: 0,
+        })
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.offset = self.position / ALIGNMENT as u64 * ALIGNMENT as u64;
+        self.len = 0;
+
+        while self.len < BUFFER_SIZE {
+            let offset = self.offset + self.len as u64;
+            match FileExt::read_at(&self.file, &mut self.buffer.as_mut_slice()[self.len..], offset)? {
+                0 => break,
+                read => self.len += read,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let buffered = self.offset..self.offset + self.len as u64;
+        if !buffered.contains(&self.position) {
+            self.fill_buffer()?;
+        }
+
+        let start = (self.position - self.offset) as usize;
+        if start >= self.len {
+            return Ok(0);
+        }
+
+        let read = buf.len().min(self.len - start);
+        buf[..read].copy_from_slice(&self.buffer.as_slice()[start..start + read]);
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for DirectReader {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(unsupported("writing"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DirectReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.file.metadata()?.len().checked_add_signed(delta),
+        };
+
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+impl EnvFile for DirectReader {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reads the aligned region around the offset into a buffer of its own, as the buffer of the
+    /// reader can't be shared. Reads at most up to the end of that region.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut buffer = AlignedBuffer::new();
+        let start = offset / ALIGNMENT as u64 * ALIGNMENT as u64;
+        let skip = (offset - start) as usize;
+
+        let len = FileExt::read_at(&self.file, buffer.as_mut_slice(), start)?;
+        if len <= skip {
+            return Ok(0);
+        }
+
+        let read = buf.len().min(len - skip);
+        buf[..read].copy_from_slice(&buffer.as_slice()[skip..skip + read]);
+
+        Ok(read)
+    }
+
+    fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        Err(unsupported("resizing"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{DirectReader, DirectWriter, BUFFER_SIZE};
+    use crate::env::EnvFile;
+    use crate::test_utils::Test;
+    use anyhow::Result;
+
+    #[test]
+    fn direct_files_read_what_was_written_in_unaligned_chunks() -> Result<()> {
+        let test = Test::new()?;
+        let path = test.path("direct");
+        let data: Vec<u8> = (0..BUFFER_SIZE * 2 + 1234).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = DirectWriter::create(&path)?;
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk)?;
+            writer.flush()?;
+        }
+        writer.sync()?;
+
+        assert_eq!(std::fs::read(&path)?, data);
+
+        let mut reader = DirectReader::open(&path)?;
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read)?;
+        assert_eq!(read, data);
+
+        let mut tail = Vec::new();
+        reader.seek(SeekFrom::Start(BUFFER_SIZE as u64 + 10))?;
+        reader.read_to_end(&mut tail)?;
+        assert_eq!(tail, &data[BUFFER_SIZE + 10..]);
+
+        let mut positioned = [0; 100];
+        assert_eq!(reader.read_at(&mut positioned, 5000)?, 100);
+        assert_eq!(positioned, &data[5000..5100]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::verify::VerifyReport;
+
+/// The errors of the storage that callers may want to handle, rather than only report.
+///
+/// Fallible functions return `anyhow::Result`, from which these can be told apart with
+/// `downcast_ref::<Error>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Data read from a file is not what was written to it.
+    Corruption {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+    /// The storage is already open for writing.
+    WriterBusy,
+    /// The storage is already open, by this process or another.
+    Locked,
+    /// A flush or compaction failed, e.g. because the disk is full. The storage refuses to go on
+    /// until it is opened again, which recovers what wasn't persisted from the WALs. A Raft member
+    /// fails the same way once persisting its log fails, until it is built again.
+    BackgroundError { reason: String },
+    /// The keys starting with a prefix already take as many bytes as its quota allows. See
+    /// [`StorageBuilder::quota`](crate::storage::StorageBuilder::quota).
+    QuotaExceeded { prefix: String, quota: u64 },
+    /// A continuation token given to a scan wasn't returned by one.
+    InvalidContinuation,
+    /// Checking the storage on open found sstables that can't be read. See
+    /// [`StorageBuilder::verify_on_open`](crate::storage::StorageBuilder::verify_on_open).
+    VerificationFailed { report: VerifyReport },
+    /// The memtables no longer hold the changes after the sequence number a subscription or a WAL
+    /// tail was to resume from. See [`Storage::subscribe`](crate::storage::Storage::subscribe) and
+    /// [`WalTail`](crate::wal::WalTail).
+    ChangesUnavailable { after: u64 },
+    /// A subscriber or a WAL tail fell too far behind, and missed the given number of changes.
+    ChangesLost { missed: u64 },
+    /// As many memtables as allowed are frozen, waiting to be persisted, and background work is
+    /// scheduled manually, so writes can't wait for them. See
+    /// [`StorageBuilder::max_frozen_memtables`](crate::storage::StorageBuilder::max_frozen_memtables).
+    WriteStalled { frozen: usize },
+    /// The segments path and the WAL path hold the identities of different storages. See
+    /// [`Identity`](crate::identity::Identity).
+    IdentityMismatch { reason: String },
+    /// The storage with the given id was created with a newer format, or orders or encodes keys
+    /// differently than this build does, so merging its sstables would misorder them.
+    IncompatibleFormat { id: String, reason: String },
+    /// This member of a Raft cluster isn't its leader, which takes the writes, or stopped being it
+    /// before a write committed. The leader it knows of, if any, is given. See
+    /// [`Raft`](crate::raft::Raft).
+    NotLeader { leader: Option<u64> },
+    /// A write, e.g. a key along with its value or the whole of a batch, would take more bytes
+    /// on disk than the limit of 256 MiB a single entry of a WAL or a SSTable may take. Nothing
+    /// was written.
+    TooLarge { size: u64, limit: u64 },
+    /// The key can't be written to this storage, e.g. one holding `\0` while the storage has a
+    /// retention policy, whose compactions would take it for a version. See
+    /// [`StorageBuilder::retention`](crate::storage::StorageBuilder::retention).
+    InvalidKey { key: String, reason: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Corruption {
+                path,
+                offset,
+                reason,
+            } => write!(f, "corruption in {} at offset {}: {}", path.display(), offset, reason),
+            Error::WriterBusy => write!(f, "the storage is already open for writing"),
+            Error::Locked => write!(f, "the storage is already open"),
+            Error::BackgroundError { reason } => write!(f, "background work failed: {}", reason),
+            Error::QuotaExceeded { prefix, quota } => {
+                write!(f, "the keys starting with {:?} exceed their quota of {} bytes", prefix, quota)
+            }
+            Error::InvalidContinuation => write!(f, "invalid continuation token"),
+            Error::VerificationFailed { report } => write!(f, "the storage failed verification: {}", report),
+            Error::ChangesUnavailable { after } => {
+                write!(f, "the changes after sequence number {} are no longer available", after)
+            }
+            Error::ChangesLost { missed } => write!(f, "the subscriber missed {} changes", missed),
+            Error::WriteStalled { frozen } => {
+                write!(f, "writes are stalled until some of the {} frozen memtables are persisted", frozen)
+            }
+            Error::IdentityMismatch { reason } => write!(f, "the identity of the storage doesn't match: {}", reason),
+            Error::IncompatibleFormat { id, reason } => write!(f, "storage {} is incompatible: {}", id, reason),
+            Error::NotLeader { leader: Some(leader) } => write!(f, "not the leader, which is member {}", leader),
+            Error::NotLeader { leader: None } => write!(f, "not the leader, which is unknown"),
+            Error::TooLarge { size, limit } => write!(f, "the write takes {} bytes, over the limit of {}", size, limit),
+            Error::InvalidKey { key, reason } => write!(f, "invalid key {:?}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use crate::tuning::Tuning;
+
+/// Describes a corrupt sstable found by the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptionInfo {
+    /// The path of the sstable, before it was quarantined.
+    pub path: PathBuf,
+    /// What is wrong with the sstable.
+    pub error: String,
+    /// Whether the sstable was moved away from the storage.
+    pub quarantined: bool,
+}
+
+/// Describes an adjustment of the storage to its workload. See
+/// [`StorageBuilder::auto_tune`](crate::storage::StorageBuilder::auto_tune).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningInfo {
+    pub previous: Tuning,
+    pub tuning: Tuning,
+    /// The share of the time since the previous flush that the last one took, which prompted the
+    /// adjustment.
+    pub flush_load: f64,
+}
+
+/// Receives notifications about what happens inside the storage.
+///
+/// Listeners are called from the thread that triggered the event, often a background one, so they
+/// should return quickly. Every method does nothing by default.
+pub trait EventListener: Send + Sync {
+    /// Called when a corrupt sstable is found.
+    fn on_corruption(&self, _info: &CorruptionInfo) {}
+
+    /// Called when the storage tunes itself, after a flush.
+    fn on_tuning(&self, _info: &TuningInfo) {}
+}
@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::engine::Engine;
+use crate::storage::Config;
+use crate::Stored;
+
+/// Periodically removes the keys whose TTL is past, instead of leaving them to compactions. See
+/// [`purge_expired`].
+pub(crate) struct Expirer {
+    engine: Weak<Mutex<Engine>>,
+    config: Config,
+    last_purge: Duration,
+}
+
+impl Expirer {
+    pub fn new(engine: &Arc<Mutex<Engine>>, config: Config) -> Self {
+        let last_purge = config.clock.now();
+
+        Expirer {
+            engine: Arc::downgrade(engine),
+            config,
+            last_purge,
+        }
+    }
+
+    /// Purges once per purge interval, until the storage is dropped.
+    pub fn run(&mut self) -> Result<()> {
+        let interval = match self.config.expiry_purge_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+
+        loop {
+            thread::sleep(interval);
+
+            match self.engine.upgrade() {
+                Some(engine) => purge_expired(&engine, &self.config)?,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Purges if the purge interval elapsed since the last time. Returns whether it did.
+    pub fn purge_if_due(&mut self) -> Result<bool> {
+        let interval = match self.config.expiry_purge_interval {
+            Some(interval) => interval,
+            None => return Ok(false),
+        };
+
+        let now = self.config.clock.now();
+        if now.saturating_sub(self.last_purge) < interval {
+            return Ok(false);
+        }
+
+        if let Some(engine) = self.engine.upgrade() {
+            purge_expired(&engine, &self.config)?;
+        }
+        self.last_purge = now;
+
+        Ok(true)
+    }
+}
+
+/// Removes the keys whose TTL is past, as found through the expiry index of the engine, by
+/// putting tombstones in the active memtable, as a single batch. The keys written again since
+/// they were indexed are left alone. Returns how many keys were removed.
+///
+/// The keys already read as absent, so their watchers aren't notified. The writer isn't taken
+/// either: the active memtable may go past the threshold, until the next write converts it.
+pub(crate) fn purge_expired(engine: &Mutex<Engine>, config: &Config) -> Result<usize> {
+    let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+    engine.check_background_error()?;
+    let now = config.now();
+
+    let pending = engine.expiries.split_off(&(now.saturating_add(1), String::new()));
+    let expired = std::mem::replace(&mut engine.expiries, pending);
+
+    let mut keys: Vec<String> = Vec::new();
+    for (_, key) in expired {
+        if newest(&engine, &key)?.is_some_and(|stored| stored.expired(now)) {
+            keys.push(key);
+        }
+    }
+    keys.sort();
+    keys.dedup();
+
+    engine.active_memtable.remove_all(&keys)?;
+
+    Ok(keys.len())
+}
+
+/// What the newest memtable or sstable holding the key stores for it, bypassing the caches.
+fn newest(engine: &Engine, key: &str) -> Result<Option<Stored>> {
+    let memtables = std::iter::once(&engine.active_memtable)
+        .chain(engine.version.memtables.iter().rev().map(|memtable| memtable.as_ref()));
+    if let Some(stored) = memtables.filter_map(|memtable| memtable.lookup(key)).next() {
+        return Ok(Some(stored));
+    }
+
+    for table in engine.version.sstable_readers0.iter().rev() {
+        if let Some(stored) = table.lookup(key, false)? {
+            return Ok(Some(stored));
+        }
+    }
+
+    match engine.version.l1_reader_for(key) {
+        Some(table) => table.lookup(key, false),
+        None => Ok(None),
+    }
+}
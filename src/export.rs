@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::env::{Env, EnvFile};
+use crate::{format, Stored};
+
+/// The version of the layout of exported sstables, bumped whenever it changes.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Ends every exported sstable, after the offset of its metadata.
+const MAGIC: &[u8; 8] = b"LSMEXPRT";
+
+/// How many bytes the offset of the metadata and the magic take at the end of the file.
+const TRAILER_LEN: u64 = 16;
+
+/// Describes an exported sstable. It is stored in the footer of the file, so that readers need
+/// nothing but the file itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportMetadata {
+    /// The [`EXPORT_VERSION`] of the layout the file was written with.
+    pub version: u32,
+    /// The codec the values are compressed with.
+    pub compression: Compression,
+    /// How many entries the file holds.
+    pub entries: u64,
+    pub first_key: String,
+    pub last_key: String,
+    /// The sequence number of the last write the export saw. Every file of an export has the
+    /// same one.
+    pub snapshot: u64,
+}
+
+/// Writes the given entries, in key order, into exported sstables inside the directory, starting
+/// a new one once the current one reaches the target size. Returns their paths, in key order.
+///
+/// An exported sstable holds the entries as a sstable of the storage does, followed by a footer:
+/// the [`ExportMetadata`] as a checksummed record, its offset as a little-endian `u64`, and an
+/// 8-byte magic.
+pub(crate) fn write(
+    env: &dyn Env,
+    dir: &Path,
+    entries: impl Iterator<Item = Result<(String, Vec<u8>)>>,
+    snapshot: u64,
+    compression: Compression,
+    target_file_size: u64,
+) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut current: Option<ExportWriter> = None;
+    let mut entry = Vec::new();
+
+    for result in entries {
+        let (key, value) = result?;
+
+        let writer = match current.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let path = dir.join(format!("export-{:06}.sst", paths.len()));
+                paths.push(path.clone());
+                current.insert(ExportWriter::new(env, path, &key, snapshot, compression)?)
+            }
+        };
+
+        entry.clear();
+        format::write_table_entry(&mut entry, &key, &Stored::Value(value), 0, compression)?;
+        writer.output.write_all(&entry)?;
+        writer.size += entry.len() as u64;
+        writer.metadata.entries += 1;
+        writer.metadata.last_key = key;
+
+        if writer.size >= target_file_size {
+            current.take().unwrap().finish()?;
+        }
+    }
+
+    if let Some(writer) = current {
+        writer.finish()?;
+    }
+    env.sync_dir(dir)?;
+
+    Ok(paths)
+}
+
+/// The exported sstable being written.
+struct ExportWriter {
+    output: BufWriter<Box<dyn EnvFile>>,
+    /// How many bytes of entries were written.
+    size: u64,
+    metadata: ExportMetadata,
+}
+
+impl ExportWriter {
+    fn new(env: &dyn Env, path: PathBuf, first_key: &str, snapshot: u64, compression: Compression) -> Result<Self> {
+        Ok(ExportWriter {
+            output: BufWriter::new(env.create(&path)?),
+            size: 0,
+            metadata: ExportMetadata {
+                version: EXPORT_VERSION,
+                compression,
+                entries: 0,
+                first_key: first_key.to_owned(),
+                last_key: first_key.to_owned(),
+                snapshot,
+            },
+        })
+    }
+
+    fn finish(mut self) -> Result<()> {
+        format::write_record(&mut self.output, &self.metadata)?;
+        self.output.write_all(&self.size.to_le_bytes())?;
+        self.output.write_all(MAGIC)?;
+
+        let mut file = self.output.into_inner().map_err(|error| error.into_error())?;
+        file.sync()?;
+
+        Ok(())
+    }
+}
+
+/// Reads an sstable written by [`Storage::export_sst`](crate::storage::Storage::export_sst),
+/// without the storage it was exported from. Yields its entries in key order, checking their
+/// checksums.
+pub struct ExportReader {
+    metadata: ExportMetadata,
+    input: BufReader<Take<File>>,
+}
+
+impl ExportReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let context = || format!("{}: not an exported sstable", path.display());
+        let mut file = File::open(path)?;
+
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < TRAILER_LEN {
+            bail!("{}", context());
+        }
+
+        let mut trailer = [0; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+
+        let (offset, magic) = trailer.split_at(8);
+        let offset = u64::from_le_bytes(offset.try_into().unwrap());
+        if magic != MAGIC || offset > len - TRAILER_LEN {
+            bail!("{}", context());
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let metadata: ExportMetadata = format::read_record(&mut file)
+            .with_context(context)?
+            .with_context(context)?;
+        if metadata.version > EXPORT_VERSION {
+            bail!("{}: unsupported export version {}", path.display(), metadata.version);
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(ExportReader {
+            metadata,
+            input: BufReader::new(file.take(offset)),
+        })
+    }
+
+    pub fn metadata(&self) -> &ExportMetadata {
+        &self.metadata
+    }
+}
+
+impl Iterator for ExportReader {
+    type Item = Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match format::read_checked_entry(&mut self.input, true) {
+            Ok(Some((key, Stored::Value(value) | Stored::Expiring(value, _)))) => Some(Ok((key, value))),
+            Ok(Some((key, Stored::Tombstone))) => Some(Err(anyhow!("unexpected tombstone for {}", key))),
+            Ok(None) => None,
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+
+    use super::ExportReader;
+    use crate::test_utils::Test;
+
+    #[test]
+    fn exported_sstables_hold_the_range_and_describe_themselves() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().target_file_size(200).build()?;
+
+        for i in 0..20 {
+            storage.insert(format!("key-{:02}", i), format!("value-{}", i).into_bytes())?;
+        }
+        storage.flush()?;
+        storage.tick()?;
+        storage.insert("key-05".to_owned(), b"updated".to_vec())?;
+        storage.remove("key-06".to_owned())?;
+
+        let dir = test.path("export");
+        let paths = storage.export_sst(&dir, "key-04".."key-15")?;
+        assert!(paths.len() > 1);
+        assert!(storage.export_sst(&dir, ..).is_err());
+
+        let mut entries = Vec::new();
+        let mut last_key = String::new();
+        for path in &paths {
+            let reader = ExportReader::open(path)?;
+            let metadata = reader.metadata().clone();
+            assert!(metadata.first_key > last_key);
+
+            let read: Vec<_> = reader.collect::<Result<_>>()?;
+            assert_eq!(metadata.entries, read.len() as u64);
+            assert_eq!(read.first().map(|(key, _)| key), Some(&metadata.first_key));
+            assert_eq!(read.last().map(|(key, _)| key), Some(&metadata.last_key));
+
+            last_key = metadata.last_key;
+            entries.extend(read);
+        }
+
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        let expected: Vec<String> = (4..15).filter(|i| *i != 6).map(|i| format!("key-{:02}", i)).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(entries[1].1, b"updated");
+
+        fs::write(test.path("not-exported"), b"garbage")?;
+        assert!(ExportReader::open(&test.path("not-exported")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,712 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::env::{Advice, Env, EnvFile, OsEnv};
+
+/// The faults injected by a [`FaultInjectionEnv`].
+#[derive(Default)]
+struct Faults {
+    /// How many more writes succeed before the environment crashes.
+    writes_until_crash: Option<usize>,
+    /// Whether a crash point was reached. A crashed environment fails every operation, as if the
+    /// process performing them had died.
+    crashed: bool,
+    /// Whether writes only persist the first half of the buffer they are given.
+    short_writes: bool,
+    /// Whether writes fail after persisting the first half of the buffer they are given, as they
+    /// would on a full disk, without crashing the environment.
+    fail_writes: bool,
+    /// Whether writes flip a bit of the buffer they are given, as a faulty disk would, while
+    /// reporting success.
+    corrupt_writes: bool,
+    /// Whether syncing a file fails.
+    fail_syncs: bool,
+    /// How many more bytes can be read before reads fail, as they would past a bad sector.
+    readable_bytes: Option<usize>,
+    /// Whether renaming a file crashes the environment before the rename happens.
+    crash_on_rename: bool,
+    /// Whether removing a file crashes the environment before the removal happens.
+    crash_on_remove: bool,
+    /// Whether positioned reads wait until they are released, as they would behind a slow disk.
+    hold_reads: bool,
+    /// How many positioned reads are waiting to be released.
+    held_reads: usize,
+}
+
+/// An environment that injects failures into the I/O performed through it.
+///
+/// Clones share the same faults, so a test can keep a handle to the environment used by a storage
+/// and change its behaviour at any point.
+#[derive(Clone, Default)]
+pub(crate) struct FaultInjectionEnv {
+    faults: Arc<Mutex<Faults>>,
+    /// Notified whenever reads are held or released.
+    reads: Arc<Condvar>,
+}
+
+impl FaultInjectionEnv {
+    /// Crashes the environment once the given number of writes succeeded. The write reaching the
+    /// crash point is torn: only the first half of its buffer is persisted.
+    pub fn crash_after_writes(&self, writes: usize) {
+        self.faults().writes_until_crash = Some(writes);
+    }
+
+    /// Crashes the environment right away.
+    pub fn crash(&self) {
+        self.faults().crashed = true;
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.faults().crashed
+    }
+
+    pub fn short_writes(&self, enabled: bool) {
+        self.faults().short_writes = enabled;
+    }
+
+    pub fn fail_writes(&self, enabled: bool) {
+        self.faults().fail_writes = enabled;
+    }
+
+    pub fn corrupt_writes(&self, enabled: bool) {
+        self.faults().corrupt_writes = enabled;
+    }
+
+    pub fn fail_syncs(&self, enabled: bool) {
+        self.faults().fail_syncs = enabled;
+    }
+
+    /// Fails every read once the given number of bytes were read, through any file.
+    pub fn fail_reads_after(&self, bytes: usize) {
+        self.faults().readable_bytes = Some(bytes);
+    }
+
+    pub fn crash_on_rename(&self, enabled: bool) {
+        self.faults().crash_on_rename = enabled;
+    }
+
+    pub fn crash_on_remove(&self, enabled: bool) {
+        self.faults().crash_on_remove = enabled;
+    }
+
+    /// Makes positioned reads, through any file, wait until they are released by disabling it.
+    pub fn hold_reads(&self, enabled: bool) {
+        self.faults().hold_reads = enabled;
+        self.reads.notify_all();
+    }
+
+    /// Waits until the given number of positioned reads are held at once, or the timeout elapses.
+    /// Returns whether they were.
+    pub fn wait_for_held_reads(&self, reads: usize, timeout: Duration) -> bool {
+        let faults = self.faults();
+        let (_faults, waited) = self
+            .reads
+            .wait_timeout_while(faults, timeout, |faults| faults.held_reads < reads)
+            .unwrap();
+
+        !waited.timed_out()
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap()
+    }
+
+    fn check(&self) -> io::Result<()> {
+        check(&self.faults())
+    }
+
+    fn wrap(&self, file: Box<dyn EnvFile>) -> Box<dyn EnvFile> {
+        Box::new(FaultInjectionFile {
+            inner: file,
+            faults: self.faults.clone(),
+            reads: self.reads.clone(),
+        })
+    }
+}
+
+fn check(faults: &Faults) -> io::Result<()> {
+    if faults.crashed {
+        Err(crashed())
+    } else {
+        Ok(())
+    }
+}
+
+/// How many of the given bytes can be read before reads fail, taking them from those left.
+fn readable(faults: &mut Faults, len: usize) -> io::Result<usize> {
+    match faults.readable_bytes.as_mut() {
+        Some(0) if len > 0 => Err(io::Error::other("injected read failure")),
+        Some(readable) => {
+            let len = len.min(*readable);
+            *readable -= len;
+            Ok(len)
+        }
+        None => Ok(len),
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("injected crash")
+}
+
+impl Env for FaultInjectionEnv {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        OsEnv.create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.check()?;
+        OsEnv.read_dir(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        Ok(self.wrap(OsEnv.create(path)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        Ok(self.wrap(OsEnv.open(path)?))
+    }
+
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        Ok(self.wrap(OsEnv.open_writable(path)?))
+    }
+
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        Ok(self.wrap(OsEnv.create_direct(path)?))
+    }
+
+    fn open_direct(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        Ok(self.wrap(OsEnv.open_direct(path)?))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut faults = self.faults();
+        check(&faults)?;
+
+        if faults.crash_on_remove {
+            faults.crashed = true;
+            return Err(crashed());
+        }
+
+        OsEnv.remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut faults = self.faults();
+        check(&faults)?;
+
+        if faults.crash_on_rename {
+            faults.crashed = true;
+            return Err(crashed());
+        }
+
+        OsEnv.rename(from, to)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check()?;
+        OsEnv.link(from, to)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.check()?;
+        OsEnv.file_size(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        OsEnv.sync_dir(path)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check()?;
+        OsEnv.lock(path)
+    }
+}
+
+struct FaultInjectionFile {
+    inner: Box<dyn EnvFile>,
+    faults: Arc<Mutex<Faults>>,
+    reads: Arc<Condvar>,
+}
+
+impl Write for FaultInjectionFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock().unwrap();
+        check(&faults)?;
+
+        match faults.writes_until_crash.as_mut() {
+            Some(0) => {
+                faults.crashed = true;
+                self.inner.write_all(&buf[..buf.len() / 2])?;
+                return Err(crashed());
+            }
+            Some(writes) => *writes -= 1,
+            None => {}
+        }
+
+        if faults.fail_writes {
+            self.inner.write_all(&buf[..buf.len() / 2])?;
+            return Err(io::Error::other("injected write failure"));
+        }
+
+        if faults.corrupt_writes && !buf.is_empty() {
+            let mut corrupt = buf.to_vec();
+            corrupt[buf.len() / 2] ^= 1;
+            self.inner.write_all(&corrupt)?;
+            return Ok(buf.len());
+        }
+
+        if faults.short_writes && buf.len() > 1 {
+            self.inner.write(&buf[..buf.len() / 2])
+        } else {
+            self.inner.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check(&self.faults.lock().unwrap())?;
+        self.inner.flush()
+    }
+}
+
+impl Read for FaultInjectionFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock().unwrap();
+        check(&faults)?;
+
+        let len = readable(&mut faults, buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Seek for FaultInjectionFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        check(&self.faults.lock().unwrap())?;
+        self.inner.seek(pos)
+    }
+}
+
+impl EnvFile for FaultInjectionFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let faults = self.faults.lock().unwrap();
+        check(&faults)?;
+
+        if faults.fail_syncs {
+            return Err(io::Error::other("injected sync failure"));
+        }
+
+        self.inner.sync()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.hold_reads {
+            faults.held_reads += 1;
+            self.reads.notify_all();
+            faults = self.reads.wait_while(faults, |faults| faults.hold_reads).unwrap();
+            faults.held_reads -= 1;
+        }
+        check(&faults)?;
+
+        let len = readable(&mut faults, buf.len())?;
+        self.inner.read_at(&mut buf[..len], offset)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        check(&self.faults.lock().unwrap())?;
+        self.inner.set_len(len)
+    }
+
+    fn advise(&mut self, advice: Advice) -> io::Result<()> {
+        check(&self.faults.lock().unwrap())?;
+        self.inner.advise(advice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use anyhow::Result;
+
+    use super::FaultInjectionEnv;
+    use crate::env::{Advice, Env, OsEnv};
+    use crate::storage::{ReadOptions, Storage};
+    use crate::test_utils::Test;
+    use crate::Error;
+
+    const THRESHOLD: usize = 8;
+
+    fn key(i: usize) -> String {
+        format!("key-{}", i)
+    }
+
+    fn value(i: usize) -> Vec<u8> {
+        format!("value-{}", i).into_bytes()
+    }
+
+    fn open_storage(test: &Test) -> Result<Storage> {
+        Storage::builder()
+            .segments_path(test.test_path())
+            .wal_path(test.test_path())
+            .threshold(THRESHOLD)
+            .env(test.env())
+            .build()
+    }
+
+    /// Reopens the storage of a test on top of a healthy environment, as a restarted process
+    /// would.
+    fn reopen_storage(test: &Test) -> Result<Storage> {
+        Storage::builder()
+            .segments_path(test.test_path())
+            .wal_path(test.test_path())
+            .threshold(THRESHOLD)
+            .build()
+    }
+
+    /// Inserts entries until the storage fails, returning how many of them were acknowledged.
+    fn insert_until_failure(storage: &mut Storage, entries: usize) -> usize {
+        (0..entries)
+            .take_while(|&i| storage.insert(key(i), value(i)).is_ok())
+            .count()
+    }
+
+    #[test]
+    fn advice_leaves_files_as_they_are() -> Result<()> {
+        let test = Test::new()?;
+        let envs: [Arc<dyn Env>; 2] = [Arc::new(OsEnv), Arc::new(FaultInjectionEnv::default())];
+
+        for (i, env) in envs.iter().enumerate() {
+            let path = test.path(&format!("advised-{i}"));
+            let mut file = env.create(&path)?;
+            file.write_all(b"contents")?;
+            file.sync()?;
+            drop(file);
+
+            let mut file = env.open(&path)?;
+            file.advise(Advice::Sequential)?;
+            file.advise(Advice::DontNeed)?;
+
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+            assert_eq!(contents, b"contents");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn acknowledged_writes_survive_a_crash_at_any_write() -> Result<()> {
+        for crash_point in 0..300 {
+            let env = FaultInjectionEnv::default();
+            let test = Test::with_env(Arc::new(env.clone()))?;
+            let mut storage = open_storage(&test)?;
+
+            env.crash_after_writes(crash_point);
+            let acknowledged = insert_until_failure(&mut storage, 5 * THRESHOLD);
+            env.crash();
+            drop(storage);
+
+            let storage = reopen_storage(&test)?;
+            for i in 0..acknowledged {
+                assert_eq!(
+                    storage.read(&key(i))?,
+                    Some(value(i)),
+                    "lost {} after crashing at write {}",
+                    key(i),
+                    crash_point
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn acknowledged_writes_survive_repeated_crashes() -> Result<()> {
+        let test = Test::new()?;
+        let mut acknowledged = 0;
+
+        for crash_point in [5, 40, 13, 70] {
+            let env = FaultInjectionEnv::default();
+            let storage = Storage::builder()
+                .segments_path(test.test_path())
+                .wal_path(test.test_path())
+                .threshold(THRESHOLD)
+                .env(Arc::new(env.clone()))
+                .build()?;
+
+            env.crash_after_writes(crash_point);
+            acknowledged += (acknowledged..acknowledged + 2 * THRESHOLD)
+                .take_while(|&i| storage.insert(key(i), value(i)).is_ok())
+                .count();
+            env.crash();
+        }
+
+        let storage = reopen_storage(&test)?;
+        for i in 0..acknowledged {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)), "lost {}", key(i));
+        }
+
+        Ok(())
+    }
+
+    /// Freezes a memtable and crashes while flushing it, at the point chosen by `inject`. Checks
+    /// that the reopened storage holds every entry of the memtable, and only complete sstables.
+    /// Returns whether the flush completed before the crash.
+    fn crash_during_flush(inject: impl Fn(&FaultInjectionEnv)) -> Result<bool> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = test.storage_builder().threshold(THRESHOLD).build()?;
+
+        for i in 0..THRESHOLD {
+            storage.insert(key(i), value(i))?;
+        }
+
+        inject(&env);
+        let flushed = storage.tick().is_ok();
+        env.crash();
+        drop(storage);
+
+        let storage = test.storage_builder().threshold(THRESHOLD).env(Arc::new(OsEnv)).build()?;
+        storage.tick()?;
+
+        for i in 0..THRESHOLD {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)), "lost {}", key(i));
+        }
+
+        let engine = storage.engine.lock().unwrap();
+        assert_eq!(engine.version.sstables0.len(), 1);
+        engine.version.sstables0[0].verify()?;
+
+        Ok(flushed)
+    }
+
+    #[test]
+    fn flushes_survive_a_crash_at_every_step() -> Result<()> {
+        // While writing the sstable, or the manifest edit recording it.
+        let mut crash_point = 0;
+        while !crash_during_flush(|env| env.crash_after_writes(crash_point))? {
+            crash_point += 1;
+        }
+
+        // Before the sstable and the manifest edit are synced.
+        assert!(!crash_during_flush(|env| env.fail_syncs(true))?);
+
+        // Before the WAL of the flushed memtable is removed.
+        assert!(!crash_during_flush(|env| env.crash_on_remove(true))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_writes_survive_a_crash_once_closed() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = Storage::builder()
+            .segments_path(test.test_path())
+            .wal_path(test.test_path())
+            .threshold(THRESHOLD)
+            .wal_buffer_size(1024 * 1024)
+            .env(test.env())
+            .build()?;
+
+        for i in 0..3 * THRESHOLD / 2 {
+            storage.insert(key(i), value(i))?;
+        }
+
+        env.fail_syncs(true);
+        assert!(storage.clone().close().is_err());
+
+        env.fail_syncs(false);
+        storage.clone().close()?;
+        env.crash();
+        drop(storage);
+
+        let storage = reopen_storage(&test)?;
+        for i in 0..3 * THRESHOLD / 2 {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)), "lost {}", key(i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_flushes_fail_the_storage_until_reopened() -> Result<()> {
+        for read_only in [false, true] {
+            let env = FaultInjectionEnv::default();
+            let test = Test::with_env(Arc::new(env.clone()))?;
+            let storage = test
+                .storage_builder()
+                .threshold(THRESHOLD)
+                .read_only_on_background_error(read_only)
+                .build()?;
+
+            for i in 0..THRESHOLD {
+                storage.insert(key(i), value(i))?;
+            }
+
+            env.fail_syncs(true);
+            assert!(storage.tick().is_err());
+            env.fail_syncs(false);
+
+            // The error is sticky, even though syncing works again.
+            let failed = |result: Result<()>| {
+                matches!(
+                    result.unwrap_err().downcast_ref::<Error>(),
+                    Some(Error::BackgroundError { .. })
+                )
+            };
+            assert!(failed(storage.tick().map(drop)));
+            assert!(failed(storage.insert(key(THRESHOLD), value(THRESHOLD))));
+            assert!(failed(storage.remove(key(0))));
+
+            let read = storage.read(&key(0));
+            if read_only {
+                assert_eq!(read?, Some(value(0)));
+            } else {
+                assert!(failed(read.map(drop)));
+                assert!(failed(storage.read_with(&key(0), &ReadOptions::default()).map(drop)));
+            }
+
+            drop(storage);
+            let storage = reopen_storage(&test)?;
+            for i in 0..THRESHOLD {
+                assert_eq!(storage.read(&key(i))?, Some(value(i)), "lost {}", key(i));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_compaction_outputs_are_never_installed() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = test
+            .storage_builder()
+            .threshold(THRESHOLD)
+            .verify_compaction_outputs(true)
+            .read_only_on_background_error(true)
+            .build()?;
+
+        for i in 0..2 * THRESHOLD {
+            storage.insert(key(i), value(i))?;
+        }
+        storage.tick()?;
+
+        env.corrupt_writes(true);
+        assert!(storage.compact().is_err());
+        env.corrupt_writes(false);
+
+        for i in 0..2 * THRESHOLD {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)));
+        }
+
+        drop(storage);
+        let storage = reopen_storage(&test)?;
+        let levels = storage.levels()?;
+        assert_eq!((levels[0].len(), levels[1].len()), (2, 0));
+        for i in 0..2 * THRESHOLD {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_writes_do_not_corrupt_the_storage() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let mut storage = open_storage(&test)?;
+
+        env.short_writes(true);
+        assert_eq!(insert_until_failure(&mut storage, 5 * THRESHOLD), 5 * THRESHOLD);
+        env.crash();
+        drop(storage);
+
+        let storage = reopen_storage(&test)?;
+        for i in 0..5 * THRESHOLD {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_writes_never_reach_the_wal() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = open_storage(&test)?;
+
+        storage.insert(key(0), value(0))?;
+        env.fail_writes(true);
+        assert!(storage.insert(key(1), value(1)).is_err());
+        assert!(storage.insert_batch([(key(2), value(2)), (key(3), value(3))]).is_err());
+        env.fail_writes(false);
+        storage.insert(key(4), value(4))?;
+
+        drop(storage);
+        let storage = reopen_storage(&test)?;
+        for i in [0, 4] {
+            assert_eq!(storage.read(&key(i))?, Some(value(i)), "lost {}", key(i));
+        }
+        for i in 1..4 {
+            assert_eq!(storage.read(&key(i))?, None, "revived {}", key(i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_writes_are_not_counted_as_written() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = open_storage(&test)?;
+
+        storage.insert(key(0), value(0))?;
+        env.fail_writes(true);
+        assert!(storage.insert(key(1), value(1)).is_err());
+        assert!(storage.remove(key(0)).is_err());
+        assert!(storage.insert_batch([(key(2), value(2))]).is_err());
+        assert!(storage.remove_batch([key(0)]).is_err());
+        env.fail_writes(false);
+
+        assert_eq!(storage.stats()?.user_bytes_written, (key(0).len() + value(0).len()) as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn injected_faults_are_reported_to_the_caller() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::new()?;
+
+        let mut file = env.create(&test.path("file"))?;
+        file.write_all(b"contents")?;
+
+        env.fail_syncs(true);
+        assert!(file.sync().is_err());
+        assert!(!env.has_crashed());
+
+        env.crash_on_rename(true);
+        assert!(env.rename(&test.path("file"), &test.path("renamed")).is_err());
+        assert!(env.has_crashed());
+        assert!(test.path("file").exists());
+        assert!(!test.path("renamed").exists());
+
+        assert!(file.write_all(b"more contents").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use std::ops::BitXor;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bloom::BloomFilter;
+
+/// The filters sstables can be written with, which tell that a key is definitely not in a sstable
+/// without looking it up, or that it may be.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterKind {
+    /// A bloom filter, which makes about 1% of the absent keys look present at 10 bits per key,
+    /// and about ten times fewer every 5 more bits.
+    #[default]
+    Bloom,
+    /// A xor filter, made of 8-bit fingerprints, or 16-bit ones from 16 bits per key on. It takes
+    /// 1.23 times the bits of its fingerprints per key, whatever the bits per key asked for, and
+    /// makes about 0.4% of the absent keys look present with 8-bit fingerprints, and 0.002% with
+    /// 16-bit ones. It can only be built once every key is known, which those of sstables are, and
+    /// fits best the large tables of the bottom level.
+    Xor,
+}
+
+/// Describes the filter of a sstable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterInfo {
+    pub kind: FilterKind,
+    /// The bits per key the filter was built with.
+    pub bits_per_key: usize,
+}
+
+/// The filter of a sstable, persisted along with its kind and bits per key, so that tables written
+/// with other settings are still read.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Filter {
+    bits_per_key: u32,
+    inner: Inner,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Inner {
+    Bloom(BloomFilter),
+    Xor8(XorFilter<u8>),
+    Xor16(XorFilter<u16>),
+}
+
+impl Filter {
+    /// Builds a filter of the given kind and bits per key, which must not be 0, over the keys of
+    /// the given hashes, which may repeat.
+    pub fn build(kind: FilterKind, bits_per_key: usize, mut hashes: Vec<u64>) -> Self {
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        let inner = match kind {
+            FilterKind::Bloom => {
+                let mut filter = BloomFilter::new(hashes.len(), bits_per_key);
+                for hash in hashes {
+                    filter.insert_hash(hash);
+                }
+
+                Inner::Bloom(filter)
+            }
+            FilterKind::Xor if bits_per_key < 16 => Inner::Xor8(XorFilter::new(&hashes)),
+            FilterKind::Xor => Inner::Xor16(XorFilter::new(&hashes)),
+        };
+
+        Filter {
+            bits_per_key: bits_per_key as u32,
+            inner,
+        }
+    }
+
+    /// About how many bytes a filter of the given kind and bits per key takes on disk for the
+    /// given number of keys.
+    pub fn size_of(kind: FilterKind, bits_per_key: usize, keys: usize) -> u64 {
+        // The entry holding the filter, and its kind and bits per key.
+        const OVERHEAD: u64 = 28;
+
+        let size = match kind {
+            FilterKind::Bloom => BloomFilter::size_of(keys, bits_per_key),
+            FilterKind::Xor if bits_per_key < 16 => XorFilter::<u8>::size_of(keys),
+            FilterKind::Xor => XorFilter::<u16>::size_of(keys),
+        };
+
+        OVERHEAD + size
+    }
+
+    pub fn info(&self) -> FilterInfo {
+        let kind = match self.inner {
+            Inner::Bloom(_) => FilterKind::Bloom,
+            Inner::Xor8(_) | Inner::Xor16(_) => FilterKind::Xor,
+        };
+
+        FilterInfo {
+            kind,
+            bits_per_key: self.bits_per_key as usize,
+        }
+    }
+
+    /// Whether the key may be in the filter. Never false for one that is.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(hash(key))
+    }
+
+    /// Whether the key of the given [`hash`] may be in the filter.
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
+        match &self.inner {
+            Inner::Bloom(filter) => filter.may_contain_hash(hash),
+            Inner::Xor8(filter) => filter.may_contain_hash(hash),
+            Inner::Xor16(filter) => filter.may_contain_hash(hash),
+        }
+    }
+}
+
+/// Hashes a key for the filters. Unlike the hashers of the standard library, the hash never
+/// changes, so that persisted filters stay valid.
+///
+/// FNV-1a, whose bits are then mixed by the finalizer of SplitMix64.
+pub(crate) fn hash(key: &[u8]) -> u64 {
+    let hash = key
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3));
+
+    mix(hash)
+}
+
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// A xor filter: each key maps to three slots, one in each third of the filter, whose
+/// fingerprints xor to the fingerprint of the key. See "Xor Filters: Faster and Smaller Than
+/// Bloom and Cuckoo Filters", by Graf and Lemire.
+#[derive(Serialize, Deserialize)]
+struct XorFilter<F> {
+    /// Mixed with the hashes of the keys, and changed until the filter can be built.
+    seed: u64,
+    /// The number of slots in each third of the filter.
+    segment: u64,
+    fingerprints: Vec<F>,
+}
+
+/// The fingerprints of the keys in a [`XorFilter`]: the more bits they have, the fewer absent keys
+/// look present.
+trait Fingerprint: Copy + Default + Eq + BitXor<Output = Self> + Serialize + DeserializeOwned {
+    fn of(hash: u64) -> Self;
+}
+
+impl Fingerprint for u8 {
+    fn of(hash: u64) -> Self {
+        (hash ^ (hash >> 32)) as u8
+    }
+}
+
+impl Fingerprint for u16 {
+    fn of(hash: u64) -> Self {
+        (hash ^ (hash >> 32)) as u16
+    }
+}
+
+impl<F: Fingerprint> XorFilter<F> {
+    /// Builds the filter over the keys of the given hashes, which must be distinct.
+    fn new(hashes: &[u64]) -> Self {
+        let slots = Self::slots(hashes.len());
+        let segment = slots as u64 / 3;
+
+        // Keys are peeled off the slots only one of them maps to, until none is left. A seed for
+        // which some can't be is rare, and only means retrying with another.
+        let mut seed = 0u64;
+        let peeled = loop {
+            seed = mix(seed.wrapping_add(0x9e37_79b9_7f4a_7c15));
+            if let Some(peeled) = Self::peel(hashes, seed, segment, slots) {
+                break peeled;
+            }
+        };
+
+        // Each slot is given a fingerprint once the keys peeled after it are, which makes its
+        // key the last to need it.
+        let mut filter = XorFilter {
+            seed,
+            segment,
+            fingerprints: vec![F::default(); slots],
+        };
+        for (slot, hash) in peeled.into_iter().rev() {
+            let [a, b, c] = slots_of(hash, segment);
+            filter.fingerprints[slot] =
+                F::of(hash) ^ filter.fingerprints[a] ^ filter.fingerprints[b] ^ filter.fingerprints[c];
+        }
+
+        filter
+    }
+
+    /// Peels the keys off the slots, returning each along with the slot it was peeled off, in
+    /// order, unless some can't be.
+    fn peel(hashes: &[u64], seed: u64, segment: u64, slots: usize) -> Option<Vec<(usize, u64)>> {
+        let mut counts = vec![0u32; slots];
+        let mut xors = vec![0u64; slots];
+
+        for &hash in hashes {
+            let hash = mix(hash ^ seed);
+            for slot in slots_of(hash, segment) {
+                counts[slot] += 1;
+                xors[slot] ^= hash;
+            }
+        }
+
+        let mut single: Vec<usize> = (0..slots).filter(|&slot| counts[slot] == 1).collect();
+        let mut peeled = Vec::with_capacity(hashes.len());
+
+        while let Some(slot) = single.pop() {
+            if counts[slot] != 1 {
+                continue;
+            }
+
+            let hash = xors[slot];
+            peeled.push((slot, hash));
+            for other in slots_of(hash, segment) {
+                counts[other] -= 1;
+                xors[other] ^= hash;
+                if counts[other] == 1 {
+                    single.push(other);
+                }
+            }
+        }
+
+        (peeled.len() == hashes.len()).then_some(peeled)
+    }
+
+    /// The number of slots of a filter over the given number of keys: about 1.23 per key, which
+    /// makes peeling them all off likely to succeed.
+    fn slots(keys: usize) -> usize {
+        3 * (32 + keys * 123 / 100).div_ceil(3)
+    }
+
+    /// The number of bytes a filter over the given number of keys takes once serialized.
+    fn size_of(keys: usize) -> u64 {
+        (Self::slots(keys) * std::mem::size_of::<F>()) as u64 + 24
+    }
+
+    fn may_contain_hash(&self, hash: u64) -> bool {
+        let hash = mix(hash ^ self.seed);
+        let [a, b, c] = slots_of(hash, self.segment);
+
+        F::of(hash) == self.fingerprints[a] ^ self.fingerprints[b] ^ self.fingerprints[c]
+    }
+}
+
+/// The slots of the key of the given mixed hash, one in each third of the filter.
+fn slots_of(hash: u64, segment: u64) -> [usize; 3] {
+    let reduce = |hash: u64| ((hash as u32 as u64 * segment) >> 32) as usize;
+    let segment = segment as usize;
+
+    [
+        reduce(hash),
+        segment + reduce(hash.rotate_left(21)),
+        2 * segment + reduce(hash.rotate_left(42)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, Filter, FilterInfo, FilterKind};
+
+    /// How many of 10000 absent keys the filter lets through.
+    fn false_positives(filter: &Filter) -> usize {
+        (0..10000).filter(|i| filter.may_contain(format!("other-{}", i).as_bytes())).count()
+    }
+
+    #[test]
+    fn inserted_keys_are_always_found_and_most_others_are_not() {
+        for (kind, max_false_positives) in [(FilterKind::Bloom, 300), (FilterKind::Xor, 100)] {
+            for keys in [0, 1, 10, 1000, 10_000] {
+                let hashes = (0..keys).map(|i| hash(format!("key-{}", i).as_bytes())).collect();
+                let filter = Filter::build(kind, 10, hashes);
+                assert_eq!(filter.info(), FilterInfo { kind, bits_per_key: 10 });
+
+                assert!((0..keys).all(|i| filter.may_contain(format!("key-{}", i).as_bytes())), "{:?}", kind);
+
+                let false_positives = false_positives(&filter);
+                assert!(false_positives < max_false_positives, "{:?}: {} false positives", kind, false_positives);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_are_read_back_as_they_were_written() {
+        let hashes: Vec<_> = (0..100).map(|i| hash(format!("key-{}", i).as_bytes())).collect();
+
+        for kind in [FilterKind::Bloom, FilterKind::Xor] {
+            // Repeated keys are only inserted once.
+            let filter = Filter::build(kind, 20, [hashes.clone(), hashes.clone()].concat());
+            let filter: Filter = bincode::deserialize(&bincode::serialize(&filter).unwrap()).unwrap();
+
+            assert_eq!(filter.info(), FilterInfo { kind, bits_per_key: 20 });
+            assert!((0..100).all(|i| filter.may_contain(format!("key-{}", i).as_bytes())));
+        }
+    }
+
+    #[test]
+    fn more_bits_per_key_make_fewer_false_positives_for_more_space() {
+        let hashes: Vec<_> = (0..10_000).map(|i| hash(format!("key-{}", i).as_bytes())).collect();
+
+        for kind in [FilterKind::Bloom, FilterKind::Xor] {
+            let small = Filter::build(kind, 4, hashes.clone());
+            let large = Filter::build(kind, 20, hashes.clone());
+
+            assert!(false_positives(&small) > 10 * false_positives(&large).max(1), "{:?}", kind);
+            assert!(
+                Filter::size_of(kind, 4, hashes.len()) < Filter::size_of(kind, 20, hashes.len()),
+                "{:?}",
+                kind
+            );
+            for (filter, bits_per_key) in [(small, 4), (large, 20)] {
+                let size = bincode::serialize(&filter).unwrap().len() as u64;
+                assert!(size <= Filter::size_of(kind, bits_per_key, hashes.len()) + 16, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        // Persisted filters were built with these.
+        assert_eq!(hash(b""), 0xf52a_15e9_a9b5_e89b);
+        assert_eq!(hash(b"key"), 0x487e_b6f7_e0ea_7e7c);
+    }
+}
@@ -1,56 +1,414 @@
-use crate::Stored;
+use crate::compression::Compression;
+use crate::filter::Filter;
+use crate::index::{IndexPartition, TableIndex};
+use crate::sstable::TableMetadata;
+use crate::{Error, Stored};
 use anyhow::bail;
 use anyhow::Result;
-use bincode::ErrorKind;
+use bincode::{ErrorKind, Options};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
+/// The most bytes anything written to a WAL or a SSTable may take. Writes past it fail with
+/// [`Error::TooLarge`], so a length read past it can only be corrupt, and fails the read before
+/// anything is allocated for it.
+pub(crate) const MAX_ITEM_SIZE: u64 = 1 << 28;
+
+/// The encoding of everything written to a WAL or a SSTable: bincode's default one.
+fn encoding() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes()
+}
+
+/// The encoding of everything read from a WAL or a SSTable, bounded by [`MAX_ITEM_SIZE`].
+fn options() -> impl Options {
+    encoding().with_limit(MAX_ITEM_SIZE)
+}
+
+/// Writes an item, failing with [`Error::TooLarge`] before anything is written if it takes more
+/// than [`MAX_ITEM_SIZE`] bytes, as it couldn't be read back.
+fn write_item<W, T>(writer: &mut W, item: &T) -> Result<()>
+where
+    W: std::io::Write,
+    T: Serialize + ?Sized,
+{
+    let size = encoding().serialized_size(item)?;
+    if size > MAX_ITEM_SIZE {
+        bail!(Error::TooLarge { size, limit: MAX_ITEM_SIZE });
+    }
+
+    encoding().serialize_into(writer, item)?;
+    Ok(())
+}
+
+/// How a value is laid out on disk. The first variants mirror `Stored`, so that WAL entries can be
+/// read as any other.
+#[derive(Serialize, Deserialize)]
+enum Persisted {
+    Tombstone,
+    Value(Vec<u8>),
+    Compressed(Compression, Vec<u8>),
+    /// A value along with the CRC32 of the entry holding it.
+    Checksummed(u32, Box<Persisted>),
+    /// A value along with the sequence number of the write that stored it.
+    Sequenced(u64, Box<Persisted>),
+    /// Follows the last entry of a SSTable, under an empty key, to describe it.
+    Footer(TableMetadata),
+    /// The writes of a batch, under an empty key, along with the sequence number of the first
+    /// one. The others follow it.
+    Batch(u64, Vec<(String, Persisted)>),
+    /// A value along with when it expires, in milliseconds since the UNIX epoch.
+    Expiring(u64, Box<Persisted>),
+    /// Follows the last entry of a SSTable written with a filter, under an empty key, before the
+    /// footer.
+    Filter(Filter),
+    /// A partition of the index of a SSTable, under an empty key, after its last entry.
+    IndexPartition(IndexPartition),
+    /// The index of a SSTable whose index is partitioned, under an empty key, after its partitions
+    /// and before the footer.
+    Index(TableIndex),
+}
+
+/// What is read from a SSTable: its entries, then its filter, if it was written with one, and then
+/// its footer, unless it was written before footers were introduced. SSTables whose index is
+/// partitioned hold the partitions, along with their filters, and the index instead of the filter.
+pub(crate) enum TableItem {
+    Entry(String, Stored, u64),
+    Filter(Filter),
+    IndexPartition(IndexPartition),
+    Index(TableIndex),
+    Footer(TableMetadata),
+}
+
+/// An entry read whole from a WAL.
+pub(crate) enum WalEntry {
+    /// The writes of the entry, along with their sequence numbers: the one of a single write, or
+    /// all those of a batch.
+    Writes(Vec<(String, Stored, u64)>),
+    /// The entry doesn't match its checksum, for the given reason.
+    Corrupt(String),
+}
+
+impl From<&Stored> for Persisted {
+    fn from(value: &Stored) -> Self {
+        match value {
+            Stored::Tombstone => Persisted::Tombstone,
+            Stored::Value(value) => Persisted::Value(value.clone()),
+            Stored::Expiring(value, expires_at) => {
+                Persisted::Expiring(*expires_at, Box::new(Persisted::Value(value.clone())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
 pub(crate) fn read_entry<R>(reader: R) -> Result<Option<(String, Stored)>>
 where
     R: std::io::Read,
 {
-    match bincode::deserialize_from::<_, (String, Stored)>(reader) {
-        Ok(entry) => Ok(Some(entry)),
-        Err(error) if reached_eof(&error) => Ok(None),
+    read_checked_entry(reader, false)
+}
+
+/// Reads an entry, validating its checksum if `verify` is set. Entries written without a checksum
+/// are never validated.
+pub(crate) fn read_checked_entry<R>(reader: R, verify: bool) -> Result<Option<(String, Stored)>>
+where
+    R: std::io::Read,
+{
+    Ok(read_sequenced_entry(reader, verify)?.map(|(key, value, _)| (key, value)))
+}
+
+/// Reads an entry along with its sequence number, like [`read_checked_entry`]. Entries written
+/// without a sequence number, before they were introduced, have sequence number 0. The footer of
+/// a SSTable ends its entries, as the end of the input does, and so does anything else following
+/// them.
+pub(crate) fn read_sequenced_entry<R>(reader: R, verify: bool) -> Result<Option<(String, Stored, u64)>>
+where
+    R: std::io::Read,
+{
+    match read_table_item(reader, verify)? {
+        Some(TableItem::Entry(key, value, seqno)) => Ok(Some((key, value, seqno))),
+        Some(_) | None => Ok(None),
+    }
+}
+
+/// Reads an entry of a SSTable, like [`read_sequenced_entry`], or its footer.
+pub(crate) fn read_table_item<R>(reader: R, verify: bool) -> Result<Option<TableItem>>
+where
+    R: std::io::Read,
+{
+    let (key, mut value) = match options().deserialize_from::<_, (String, Persisted)>(reader) {
+        Ok(entry) => entry,
+        Err(error) if reached_eof(&error) => return Ok(None),
+        Err(error) => bail!(error),
+    };
+
+    if let Persisted::Checksummed(expected, inner) = value {
+        if verify {
+            if let Some(mismatch) = mismatch(&key, expected, &inner)? {
+                bail!(mismatch);
+            }
+        }
+
+        value = *inner;
+    }
+
+    decode(key, value).map(Some)
+}
+
+/// Reads an entry of a WAL, validating its checksum. Returns `None` at the end of the input, even
+/// if it tears the entry, and fails if the entry can't be decoded, as when its lengths are corrupt
+/// and claim more than [`MAX_ITEM_SIZE`] bytes. Entries written before WAL entries had a
+/// checksum are always valid. A batch is read whole, or not at all.
+pub(crate) fn read_wal_entry<R>(reader: R) -> Result<Option<WalEntry>>
+where
+    R: std::io::Read,
+{
+    let (key, mut value) = match options().deserialize_from::<_, (String, Persisted)>(reader) {
+        Ok(entry) => entry,
+        Err(error) if reached_eof(&error) => return Ok(None),
         Err(error) => bail!(error),
+    };
+
+    if let Persisted::Checksummed(expected, inner) = value {
+        if let Some(mismatch) = mismatch(&key, expected, &inner)? {
+            return Ok(Some(WalEntry::Corrupt(mismatch)));
+        }
+
+        value = *inner;
     }
+
+    if let Persisted::Batch(first_seqno, entries) = value {
+        let writes = (first_seqno..)
+            .zip(entries)
+            .map(|(seqno, (key, value))| match value {
+                Persisted::Tombstone => Ok((key, Stored::Tombstone, seqno)),
+                Persisted::Value(value) => Ok((key, Stored::Value(value), seqno)),
+                Persisted::Expiring(expires_at, value) => match *value {
+                    Persisted::Value(value) => Ok((key, Stored::Expiring(value, expires_at), seqno)),
+                    _ => bail!("unexpected expiring entry inside a batch"),
+                },
+                _ => bail!("unexpected entry inside a batch"),
+            })
+            .collect::<Result<_>>()?;
+
+        return Ok(Some(WalEntry::Writes(writes)));
+    }
+
+    match decode(key, value)? {
+        TableItem::Entry(key, value, seqno) => Ok(Some(WalEntry::Writes(vec![(key, value, seqno)]))),
+        TableItem::Filter(_) => bail!("filter inside a WAL"),
+        TableItem::IndexPartition(_) | TableItem::Index(_) => bail!("index inside a WAL"),
+        TableItem::Footer(_) => bail!("footer inside a WAL"),
+    }
+}
+
+/// Describes how the entry differs from its checksum, if it does.
+fn mismatch(key: &str, expected: u32, value: &Persisted) -> Result<Option<String>> {
+    let actual = checksum(key, value)?;
+
+    Ok((actual != expected).then(|| format!("checksum mismatch: expected {:08x}, found {:08x}", expected, actual)))
+}
+
+/// Decodes an entry or footer, once stripped of its checksum.
+fn decode(key: String, mut value: Persisted) -> Result<TableItem> {
+    match value {
+        Persisted::Footer(metadata) => return Ok(TableItem::Footer(metadata)),
+        Persisted::Filter(filter) => return Ok(TableItem::Filter(filter)),
+        Persisted::IndexPartition(partition) => return Ok(TableItem::IndexPartition(partition)),
+        Persisted::Index(index) => return Ok(TableItem::Index(index)),
+        _ => {}
+    }
+
+    let mut seqno = 0;
+    if let Persisted::Sequenced(sequenced, inner) = value {
+        seqno = sequenced;
+        value = *inner;
+    }
+
+    let mut expires_at = None;
+    if let Persisted::Expiring(expiry, inner) = value {
+        expires_at = Some(expiry);
+        value = *inner;
+    }
+
+    let value = match value {
+        Persisted::Tombstone => Stored::Tombstone,
+        Persisted::Value(value) => Stored::Value(value),
+        Persisted::Compressed(compression, data) => Stored::Value(compression.decompress(&data)?),
+        Persisted::Checksummed(..) => bail!("nested checksums"),
+        Persisted::Sequenced(..) => bail!("nested sequence numbers"),
+        Persisted::Footer(_) => bail!("footer inside an entry"),
+        Persisted::Batch(..) => bail!("batch outside a WAL"),
+        Persisted::Expiring(..) => bail!("nested expiries"),
+        Persisted::Filter(_) => bail!("filter inside an entry"),
+        Persisted::IndexPartition(_) | Persisted::Index(_) => bail!("index inside an entry"),
+    };
+
+    let value = match (value, expires_at) {
+        (value, None) => value,
+        (Stored::Value(value), Some(expires_at)) => Stored::Expiring(value, expires_at),
+        (_, Some(_)) => bail!("expiring tombstone"),
+    };
+
+    Ok(TableItem::Entry(key, value, seqno))
+}
+
+fn checksum(key: &str, value: &Persisted) -> Result<u32> {
+    Ok(crc32fast::hash(&bincode::serialize(&(key, value))?))
 }
 
-pub(crate) fn write_entry<W>(writer: &mut W, key: &str, value: &Stored) -> Result<()>
+/// Writes an entry of a WAL, along with the sequence number of the write and followed by a
+/// checksum, so that recovery can tell a corrupt entry from a valid one.
+pub(crate) fn write_entry<W>(writer: &mut W, key: &str, value: &Stored, seqno: u64) -> Result<()>
 where
     W: std::io::Write,
 {
-    bincode::serialize_into(writer, &(key, value))?;
-    Ok(())
+    let value = Persisted::Sequenced(seqno, Box::new(value.into()));
+    let persisted = Persisted::Checksummed(checksum(key, &value)?, Box::new(value));
+    write_item(writer, &(key, persisted))
+}
+
+/// Writes the writes of a batch as a single entry of a WAL, with consecutive sequence numbers from
+/// the given one and followed by a checksum, so that recovery replays either all of them or none.
+pub(crate) fn write_batch<W>(writer: &mut W, writes: &[(String, Stored)], first_seqno: u64) -> Result<()>
+where
+    W: std::io::Write,
+{
+    let writes = writes.iter().map(|(key, value)| (key.clone(), value.into())).collect();
+    let batch = Persisted::Batch(first_seqno, writes);
+    let persisted = Persisted::Checksummed(checksum("", &batch)?, Box::new(batch));
+    write_item(writer, &("", persisted))
+}
+
+/// Writes an entry of a SSTable, with its value compressed by the given codec, along with its
+/// sequence number and followed by a checksum.
+pub(crate) fn write_table_entry<W>(
+    writer: &mut W,
+    key: &str,
+    value: &Stored,
+    seqno: u64,
+    compression: Compression,
+) -> Result<()>
+where
+    W: std::io::Write,
+{
+    let persisted = match value {
+        Stored::Value(value) | Stored::Expiring(value, _) if compression != Compression::None => {
+            Persisted::Compressed(compression, compression.compress(value)?)
+        }
+        Stored::Value(value) | Stored::Expiring(value, _) => Persisted::Value(value.clone()),
+        Stored::Tombstone => Persisted::Tombstone,
+    };
+    let persisted = match value {
+        Stored::Expiring(_, expires_at) => Persisted::Expiring(*expires_at, Box::new(persisted)),
+        _ => persisted,
+    };
+    let value = Persisted::Sequenced(seqno, Box::new(persisted));
+
+    let persisted = Persisted::Checksummed(checksum(key, &value)?, Box::new(value));
+    write_item(writer, &(key, persisted))
+}
+
+/// Writes the filter of a SSTable, or of a partition of its index, after its last entry, followed
+/// by a checksum as entries are.
+pub(crate) fn write_table_filter<W>(writer: &mut W, filter: Filter) -> Result<()>
+where
+    W: std::io::Write,
+{
+    write_table_item(writer, Persisted::Filter(filter))
+}
+
+/// Writes a partition of the index of a SSTable, after its last entry, followed by a checksum as
+/// entries are.
+pub(crate) fn write_index_partition<W>(writer: &mut W, partition: IndexPartition) -> Result<()>
+where
+    W: std::io::Write,
+{
+    write_table_item(writer, Persisted::IndexPartition(partition))
+}
+
+/// Writes the index of a SSTable, after its partitions, followed by a checksum as entries are.
+pub(crate) fn write_table_index<W>(writer: &mut W, index: TableIndex) -> Result<()>
+where
+    W: std::io::Write,
+{
+    write_table_item(writer, Persisted::Index(index))
+}
+
+fn write_table_item<W>(writer: &mut W, item: Persisted) -> Result<()>
+where
+    W: std::io::Write,
+{
+    let persisted = Persisted::Checksummed(checksum("", &item)?, Box::new(item));
+    write_item(writer, &("", persisted))
+}
+
+/// Writes the footer of a SSTable, after its last entry, or its filter, followed by a checksum as
+/// entries are.
+pub(crate) fn write_table_footer<W>(writer: &mut W, metadata: &TableMetadata) -> Result<()>
+where
+    W: std::io::Write,
+{
+    let footer = Persisted::Footer(metadata.clone());
+    let persisted = Persisted::Checksummed(checksum("", &footer)?, Box::new(footer));
+    write_item(writer, &("", persisted))
 }
 
 pub(crate) fn write_memtable_header<W>(writer: &mut W, id: usize) -> Result<()>
 where
     W: std::io::Write,
 {
-    bincode::serialize_into(writer, &id)?;
-    Ok(())
+    write_item(writer, &id)
 }
 
 pub(crate) fn read_memtable_header<R>(reader: R) -> Result<Option<usize>>
 where
     R: std::io::Read,
 {
-    match bincode::deserialize_from::<_, usize>(reader) {
+    match options().deserialize_from::<_, usize>(reader) {
         Ok(entry) => Ok(Some(entry)),
         Err(error) if reached_eof(&error) => Ok(None),
         Err(error) => bail!(error),
     }
 }
 
-pub(crate) fn memtable_metadata_size(metadata: usize) -> Result<u64> {
-    Ok(bincode::serialized_size(&metadata)?)
+/// Writes a record followed by its CRC32, so that a torn record can be told apart from a valid one.
+pub(crate) fn write_record<W, T>(writer: &mut W, record: &T) -> Result<()>
+where
+    W: std::io::Write,
+    T: Serialize,
+{
+    let payload = bincode::serialize(record)?;
+    write_item(writer, &(payload.as_slice(), crc32fast::hash(&payload)))
 }
 
-pub(crate) fn entry_size(entry: &(String, Stored)) -> Result<u64> {
-    Ok(bincode::serialized_size(&entry)?)
+/// Reads a record written by [`write_record`]. Returns `None` at the end of the input, and fails
+/// if the record is torn or corrupt.
+pub(crate) fn read_record<R, T>(reader: R) -> Result<Option<T>>
+where
+    R: std::io::Read,
+    T: DeserializeOwned,
+{
+    let (payload, expected) = match options().deserialize_from::<_, (Vec<u8>, u32)>(reader) {
+        Ok(record) => record,
+        Err(error) if reached_eof(&error) => return Ok(None),
+        Err(error) => bail!(error),
+    };
+
+    let actual = crc32fast::hash(&payload);
+    if actual != expected {
+        bail!("checksum mismatch: expected {:08x}, found {:08x}", expected, actual);
+    }
+
+    Ok(Some(bincode::deserialize(&payload)?))
 }
 
-pub(crate) fn entry_size_kv(key: &str, value: &Stored) -> Result<usize> {
-    Ok(bincode::serialized_size(&(key, value))? as usize)
+/// Whether the given error comes from reading the underlying file, rather than from what was read.
+pub(crate) fn is_io_error(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<bincode::Error>() {
+        Some(error) => matches!(**error, bincode::ErrorKind::Io(_)),
+        None => error.is::<std::io::Error>(),
+    }
 }
 
 fn reached_eof(error: &ErrorKind) -> bool {
@@ -65,7 +423,8 @@ fn reached_eof(error: &ErrorKind) -> bool {
 mod test {
     use std::fs::File;
 
-    use crate::{test_utils::Test, Stored};
+    use super::{write_item, MAX_ITEM_SIZE};
+    use crate::{test_utils::Test, Error, Stored};
     use anyhow::Result;
 
     #[test]
@@ -74,7 +433,7 @@ mod test {
 
         test.generate_sstable(
             "name",
-            &vec![("key-1".to_owned(), Stored::Value(b"value-1".to_vec()))],
+            &[("key-1".to_owned(), Stored::Value(b"value-1".to_vec()))],
         )?;
 
         let fd = File::open(test.sstable_path("name"))?;
@@ -87,4 +446,36 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn read_entry_fails_when_a_length_is_corrupt() -> Result<()> {
+        let test = Test::new()?;
+
+        test.generate_sstable(
+            "name",
+            &[("key-1".to_owned(), Stored::Value(b"value-1".to_vec()))],
+        )?;
+
+        let mut contents = std::fs::read(test.sstable_path("name"))?;
+        contents[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let error = crate::format::read_entry(contents.as_slice()).err().unwrap();
+        assert!(!crate::format::is_io_error(&error));
+
+        Ok(())
+    }
+
+    #[test]
+    fn items_too_large_to_be_read_back_are_not_written() -> Result<()> {
+        // The zeroed allocation is only mapped, and a string is sized without being walked.
+        let key = String::from_utf8(vec![0; MAX_ITEM_SIZE as usize])?;
+        let mut output = Vec::new();
+
+        let error = write_item(&mut output, &key).err().unwrap();
+        let size = MAX_ITEM_SIZE + 8;
+        assert_eq!(error.downcast_ref(), Some(&Error::TooLarge { size, limit: MAX_ITEM_SIZE }));
+        assert!(output.is_empty());
+
+        Ok(())
+    }
 }
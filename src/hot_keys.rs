@@ -0,0 +1,123 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::stats::HotKey;
+
+/// How many rows of counters the sketch has, each indexed by its own hash of the keys.
+const DEPTH: usize = 4;
+
+/// How many counters each row of the sketch has.
+const WIDTH: usize = 2048;
+
+/// Estimates how often each key is read with a count-min sketch, and keeps the keys read the most.
+///
+/// Every read increments one counter per row, and the estimate of a key is the smallest of its
+/// counters: keys sharing counters only ever make it too high, never too low. Only the hottest
+/// `capacity` keys are remembered, so that tracking takes constant memory however many keys are
+/// read.
+pub(crate) struct HotKeys {
+    capacity: usize,
+    counters: Vec<u64>,
+    /// The hottest keys, along with their estimate when last read.
+    top: HashMap<String, u64>,
+}
+
+impl HotKeys {
+    pub fn new(capacity: usize) -> Self {
+        let counters = if capacity == 0 { Vec::new() } else { vec![0; DEPTH * WIDTH] };
+
+        HotKeys {
+            capacity,
+            counters,
+            top: HashMap::new(),
+        }
+    }
+
+    /// Counts a read of the key, unless no keys are tracked.
+    pub fn record(&mut self, key: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut estimate = u64::MAX;
+        for row in 0..DEPTH {
+            let counter = &mut self.counters[row * WIDTH + slot(row, key)];
+            *counter += 1;
+            estimate = estimate.min(*counter);
+        }
+
+        if let Some(reads) = self.top.get_mut(key) {
+            *reads = estimate;
+            return;
+        }
+
+        if self.top.len() >= self.capacity {
+            let (coldest, reads) = self
+                .top
+                .iter()
+                .min_by_key(|(_, reads)| **reads)
+                .map(|(key, reads)| (key.clone(), *reads))
+                .unwrap();
+
+            if reads >= estimate {
+                return;
+            }
+            self.top.remove(&coldest);
+        }
+
+        self.top.insert(key.to_owned(), estimate);
+    }
+
+    /// The `n` keys read the most, from the hottest on, along with an estimate of their reads.
+    pub fn top(&self, n: usize) -> Vec<HotKey> {
+        let mut keys: Vec<HotKey> = self
+            .top
+            .iter()
+            .map(|(key, reads)| HotKey {
+                key: key.clone(),
+                reads: *reads,
+            })
+            .collect();
+
+        keys.sort_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.key.cmp(&b.key)));
+        keys.truncate(n);
+        keys
+    }
+}
+
+/// The counter of the key in the given row of the sketch.
+fn slot(row: usize, key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    key.hash(&mut hasher);
+
+    (hasher.finish() % WIDTH as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HotKeys;
+
+    #[test]
+    fn the_keys_read_the_most_are_kept() {
+        let mut hot_keys = HotKeys::new(2);
+
+        for i in 0..100 {
+            hot_keys.record("hot");
+            if i % 2 == 0 {
+                hot_keys.record("warm");
+            }
+            hot_keys.record(&format!("cold-{}", i));
+        }
+
+        let top = hot_keys.top(10);
+        let keys: Vec<&str> = top.iter().map(|hot_key| hot_key.key.as_str()).collect();
+        assert_eq!(keys, ["hot", "warm"]);
+        assert!(top[0].reads >= 100);
+        assert!(top[1].reads >= 50);
+
+        assert_eq!(hot_keys.top(1).len(), 1);
+        assert!(HotKeys::new(0).top(10).is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::compression::Compression;
+use crate::env::Env;
+use crate::error::Error;
+use crate::format;
+
+/// The name of the identity file, inside the segments path and the WAL path.
+pub(crate) const IDENTITY_NAME: &str = "IDENTITY";
+
+/// The version of the on-disk format written by this build. Storages written by a newer one are
+/// refused.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The name of the order keys are kept in: the order of their bytes. Merges rely on every
+/// sstable being sorted by it, so a storage sorted by another one is refused.
+pub const COMPARATOR: &str = "bytewise";
+
+/// The version of the encoding of keys in the WALs and sstables, bumped whenever it changes.
+/// Unlike [`FORMAT_VERSION`], only storages with the same one are opened, as keys encoded
+/// otherwise may sort differently.
+pub const KEY_ENCODING_VERSION: u32 = 1;
+
+/// Identifies a storage, and records the parameters it was created with.
+///
+/// It is kept in both the segments path and the WAL path, and checked when the storage is opened,
+/// so that a directory swapped for the one of another storage is refused rather than mixed in.
+/// Replication and backup tooling can compare it to tell whether two copies are of the same
+/// storage. See [`Storage::identity`](crate::storage::Storage::identity).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// A random UUID, generated when the storage was created.
+    pub id: String,
+    /// The [`FORMAT_VERSION`] of the build that created the storage.
+    pub format_version: u32,
+    /// The [`COMPARATOR`] of the build that created the storage.
+    pub comparator: String,
+    /// The [`KEY_ENCODING_VERSION`] of the build that created the storage.
+    pub key_encoding: u32,
+    /// The compression of each level when the storage was created. Unlike the other parameters,
+    /// it may change since, as every entry records the codec it was compressed with.
+    pub compression: Vec<Compression>,
+}
+
+impl Identity {
+    fn new(compression: &[Compression]) -> Self {
+        Identity {
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            format_version: FORMAT_VERSION,
+            comparator: COMPARATOR.to_owned(),
+            key_encoding: KEY_ENCODING_VERSION,
+            compression: compression.to_vec(),
+        }
+    }
+
+    /// Loads the identity of the storage in the given paths, creating it if neither has one, e.g.
+    /// for a new storage or one created before identities existed. Fails with
+    /// [`Error::IdentityMismatch`] if the paths hold different identities, and with
+    /// [`Error::IncompatibleFormat`] if the storage was created with parameters this build can't
+    /// read it with.
+    ///
+    /// The identity is written into the segments path first, so a WAL path missing it after a
+    /// crash is given it, but a segments path missing it when the WAL path has one is refused.
+    pub(crate) fn load(env: &dyn Env, segments_path: &Path, wal_path: &Path, compression: &[Compression]) -> Result<Self> {
+        let wal_identity = match wal_path == segments_path {
+            true => None,
+            false => Identity::read(env, wal_path)?,
+        };
+
+        let identity = match (Identity::read(env, segments_path)?, wal_identity) {
+            (Some(identity), Some(wal_identity)) if identity.id != wal_identity.id => {
+                return Err(mismatch(format!(
+                    "the segments path belongs to {}, but the WAL path to {}",
+                    identity.id, wal_identity.id
+                )));
+            }
+            (Some(identity), Some(_)) => identity,
+            (None, Some(wal_identity)) => {
+                return Err(mismatch(format!(
+                    "the WAL path belongs to {}, but the segments path to no storage",
+                    wal_identity.id
+                )));
+            }
+            (identity, None) => {
+                let identity = match identity {
+                    Some(identity) => identity,
+                    None => {
+                        let identity = Identity::new(compression);
+                        identity.write(env, segments_path)?;
+                        identity
+                    }
+                };
+
+                if wal_path != segments_path {
+                    identity.write(env, wal_path)?;
+                }
+                identity
+            }
+        };
+
+        identity.check_compatible()?;
+
+        Ok(identity)
+    }
+
+    /// Fails with [`Error::IncompatibleFormat`] unless this build can read the storage: its
+    /// format version is not newer, and it sorts and encodes keys the same way.
+    fn check_compatible(&self) -> Result<()> {
+        let reason = if self.format_version > FORMAT_VERSION {
+            format!("format version {}, but at most {} is supported", self.format_version, FORMAT_VERSION)
+        } else if self.comparator != COMPARATOR {
+            format!("keys ordered by {}, but only {} is supported", self.comparator, COMPARATOR)
+        } else if self.key_encoding != KEY_ENCODING_VERSION {
+            format!("key encoding {}, but only {} is supported", self.key_encoding, KEY_ENCODING_VERSION)
+        } else {
+            return Ok(());
+        };
+
+        Err(Error::IncompatibleFormat { id: self.id.clone(), reason }.into())
+    }
+
+    fn read(env: &dyn Env, dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(IDENTITY_NAME);
+        if !env.read_dir(dir)?.contains(&path) {
+            return Ok(None);
+        }
+
+        match format::read_record(env.open(&path)?)? {
+            Some(identity) => Ok(Some(identity)),
+            None => Err(mismatch(format!("{} is empty", path.display()))),
+        }
+    }
+
+    /// Writes the identity into the given directory, replacing the one it may hold at once.
+    pub(crate) fn write(&self, env: &dyn Env, dir: &Path) -> Result<()> {
+        let path = dir.join(IDENTITY_NAME);
+        let mut temporary = path.clone();
+        temporary.set_extension("tmp");
+
+        let mut file = env.create(&temporary)?;
+        format::write_record(&mut file, self)?;
+        file.flush()?;
+        file.sync()?;
+
+        env.rename(&temporary, &path)?;
+        env.sync_dir(dir)?;
+
+        Ok(())
+    }
+}
+
+fn mismatch(reason: String) -> anyhow::Error {
+    Error::IdentityMismatch { reason }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::OsEnv;
+    use crate::test_utils::Test;
+
+    #[test]
+    fn identities_are_created_once_and_shared_by_both_paths() -> Result<()> {
+        let test = Test::new()?;
+        let segments_path = test.test_path().join("segments");
+        let wal_path = test.test_path().join("wal");
+        OsEnv.create_dir_all(&segments_path)?;
+        OsEnv.create_dir_all(&wal_path)?;
+
+        let identity = Identity::load(&OsEnv, &segments_path, &wal_path, &[Compression::Lz4])?;
+        assert_eq!(identity.format_version, FORMAT_VERSION);
+        assert_eq!(identity.comparator, COMPARATOR);
+        assert_eq!(identity.compression, vec![Compression::Lz4]);
+
+        let reloaded = Identity::load(&OsEnv, &segments_path, &wal_path, &[])?;
+        assert_eq!(reloaded, identity);
+
+        // A WAL path left without the identity by a crash is given it again.
+        OsEnv.remove_file(&wal_path.join(IDENTITY_NAME))?;
+        assert_eq!(Identity::load(&OsEnv, &segments_path, &wal_path, &[])?, identity);
+        assert_eq!(Identity::read(&OsEnv, &wal_path)?, Some(identity));
+
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_and_incompatible_identities_are_refused() -> Result<()> {
+        let test = Test::new()?;
+        let segments_path = test.test_path().join("segments");
+        let wal_path = test.test_path().join("wal");
+        OsEnv.create_dir_all(&segments_path)?;
+        OsEnv.create_dir_all(&wal_path)?;
+
+        let is_mismatch = |result: Result<Identity>| {
+            matches!(
+                result.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::IdentityMismatch { .. })
+            )
+        };
+        let is_incompatible = |result: Result<Identity>| {
+            matches!(
+                result.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::IncompatibleFormat { .. })
+            )
+        };
+
+        let identity = Identity::load(&OsEnv, &segments_path, &wal_path, &[])?;
+        Identity::new(&[]).write(&OsEnv, &wal_path)?;
+        assert!(is_mismatch(Identity::load(&OsEnv, &segments_path, &wal_path, &[])));
+
+        OsEnv.remove_file(&segments_path.join(IDENTITY_NAME))?;
+        assert!(is_mismatch(Identity::load(&OsEnv, &segments_path, &wal_path, &[])));
+
+        let newer = Identity {
+            format_version: FORMAT_VERSION + 1,
+            ..identity.clone()
+        };
+        newer.write(&OsEnv, &segments_path)?;
+        newer.write(&OsEnv, &wal_path)?;
+        assert!(is_incompatible(Identity::load(&OsEnv, &segments_path, &wal_path, &[])));
+
+        let reversed = Identity {
+            comparator: "reverse-bytewise".to_owned(),
+            ..identity.clone()
+        };
+        reversed.write(&OsEnv, &segments_path)?;
+        reversed.write(&OsEnv, &wal_path)?;
+        assert!(is_incompatible(Identity::load(&OsEnv, &segments_path, &wal_path, &[])));
+
+        let reencoded = Identity {
+            key_encoding: KEY_ENCODING_VERSION + 1,
+            ..identity.clone()
+        };
+        reencoded.write(&OsEnv, &segments_path)?;
+        reencoded.write(&OsEnv, &wal_path)?;
+        assert!(is_incompatible(Identity::load(&OsEnv, &segments_path, &wal_path, &[])));
+
+        // Older format versions are still read.
+        let older = Identity {
+            format_version: 0,
+            ..identity
+        };
+        older.write(&OsEnv, &segments_path)?;
+        older.write(&OsEnv, &wal_path)?;
+        assert_eq!(Identity::load(&OsEnv, &segments_path, &wal_path, &[])?, older);
+
+        Ok(())
+    }
+}
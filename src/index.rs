@@ -0,0 +1,243 @@
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{self, Filter, FilterInfo};
+use crate::format;
+use crate::sstable::{self, TableOptions};
+use crate::Stored;
+
+/// Ends every SSTable whose index is partitioned, after the offset of its index.
+pub(crate) const MAGIC: &[u8; 8] = b"LSMINDEX";
+
+/// How many bytes the offset of the index and the magic take at the end of the file.
+pub(crate) const TRAILER_LEN: u64 = 16;
+
+/// About how many bytes of keys each partition of an index holds.
+const PARTITION_SIZE: usize = 4096;
+
+/// The index of a SSTable whose keys are split in partitions, each along with a filter of its own
+/// if the SSTable has one. Only the index is held in memory while the SSTable is open: partitions
+/// are read on demand, through the block cache of the storage.
+///
+/// A partitioned SSTable holds its entries, then the partitions and their filters, its index and
+/// its footer, as checksummed entries, followed by the offset of the index as a little-endian
+/// `u64` and an 8-byte magic, so that it can be opened without reading the entries.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TableIndex {
+    pub partitions: Vec<PartitionHandle>,
+    pub entries: u64,
+    pub tombstones: u64,
+    /// The highest sequence number of the entries, or 0 if there are none.
+    pub max_seqno: u64,
+    /// The offset right after the last entry.
+    pub end: u64,
+    /// When each value inserted with a TTL expires, along with its key.
+    pub expiring: Vec<(u64, String)>,
+    pub filter: Option<FilterInfo>,
+}
+
+/// Where a partition of an index is, and which keys it holds.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PartitionHandle {
+    pub first_key: String,
+    pub last_key: String,
+    /// The position of the first key of the partition among all those of the SSTable.
+    pub first_index: u64,
+    /// The offset of the partition, and how many bytes it takes.
+    pub index: (u64, u64),
+    /// The offset of the filter of the keys of the partition, and how many bytes it takes, unless
+    /// the SSTable has no filter.
+    pub filter: Option<(u64, u64)>,
+}
+
+/// The keys of a partition of an index, along with the offset of their entry.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexPartition {
+    pub offsets: Vec<(String, u64)>,
+}
+
+/// What partitioned SSTables read on demand.
+pub(crate) enum Block {
+    Index(IndexPartition),
+    Filter(Filter),
+}
+
+/// Collects what a SSTable records about its entries as they are written, to write it once they
+/// all are: its filter, its index, if the SSTable is large enough to partition it, and its footer.
+pub(crate) struct IndexBuilder {
+    options: TableOptions,
+    seqnos: Option<(u64, u64)>,
+    tombstones: u64,
+    /// The [`filter::hash`] of each key, if the SSTable has a filter.
+    hashes: Vec<u64>,
+    /// The offset of the entry of each key, and when each value inserted with a TTL expires, if
+    /// the index may be partitioned.
+    offsets: Vec<(String, u64)>,
+    expiring: Vec<(u64, String)>,
+    /// How many bytes the keys of the offsets take.
+    keys_size: u64,
+}
+
+impl IndexBuilder {
+    pub fn new(options: &TableOptions) -> Self {
+        IndexBuilder {
+            options: *options,
+            seqnos: None,
+            tombstones: 0,
+            hashes: Vec::new(),
+            offsets: Vec::new(),
+            expiring: Vec::new(),
+            keys_size: 0,
+        }
+    }
+
+    /// Records the entry written at the given offset.
+    pub fn add(&mut self, key: &str, value: &Stored, seqno: u64, offset: u64) {
+        sstable::extend_seqnos(&mut self.seqnos, seqno);
+        if *value == Stored::Tombstone {
+            self.tombstones += 1;
+        }
+
+        if self.options.filter.is_some() {
+            self.hashes.push(filter::hash(key.as_bytes()));
+        }
+
+        if self.options.partition_index_above.is_some() {
+            if let Stored::Expiring(_, expires_at) = value {
+                self.expiring.push((*expires_at, key.to_owned()));
+            }
+            self.offsets.push((key.to_owned(), offset));
+            self.keys_size += key.len() as u64;
+        }
+    }
+
+    /// About how many bytes what follows the entries takes, once they take the given number.
+    pub fn size(&self, entries_size: u64) -> u64 {
+        let filter_size = self.options.filter.map_or(0, |kind| {
+            Filter::size_of(kind, self.options.filter_bits_per_key, self.hashes.len())
+        });
+
+        // Each key of a partition takes its length, along with the lengths and offsets.
+        let index_size = match self.partitioned(entries_size) {
+            true => self.keys_size + 16 * self.offsets.len() as u64,
+            false => 0,
+        };
+
+        filter_size + index_size
+    }
+
+    fn partitioned(&self, entries_size: u64) -> bool {
+        self.options.partition_index_above.is_some_and(|above| entries_size > above)
+    }
+
+    /// Writes what follows the entries, which end at the given offset: the filter, if any, and the
+    /// footer, or the partitions of the index along with their filters, the index, the footer and
+    /// the trailer if the entries take more bytes than the options allow for a resident index.
+    pub fn finish<W: Write>(self, writer: &mut W, end: u64) -> Result<()> {
+        let options = self.options;
+        let metadata = options.metadata(self.seqnos);
+
+        if !self.partitioned(end) {
+            if let Some(kind) = options.filter {
+                format::write_table_filter(writer, Filter::build(kind, options.filter_bits_per_key, self.hashes))?;
+            }
+
+            return format::write_table_footer(writer, &metadata);
+        }
+
+        let entries = self.offsets.len() as u64;
+        let mut partitions = Vec::new();
+        let mut offset = end;
+        let mut block = Vec::new();
+        let mut first_index = 0;
+        let mut offsets = self.offsets.into_iter();
+        let mut hashes = self.hashes.into_iter();
+
+        loop {
+            let mut partition = Vec::new();
+            let mut size = 0;
+            while size < PARTITION_SIZE {
+                match offsets.next() {
+                    Some((key, key_offset)) => {
+                        size += key.len();
+                        partition.push((key, key_offset));
+                    }
+                    None => break,
+                }
+            }
+
+            let (first_key, last_key) = match (partition.first(), partition.last()) {
+                (Some((first_key, _)), Some((last_key, _))) => (first_key.clone(), last_key.clone()),
+                _ => break,
+            };
+
+            let filter = match options.filter {
+                Some(kind) => {
+                    let hashes = hashes.by_ref().take(partition.len()).collect();
+                    block.clear();
+                    format::write_table_filter(&mut block, Filter::build(kind, options.filter_bits_per_key, hashes))?;
+                    writer.write_all(&block)?;
+                    let filter = (offset, block.len() as u64);
+                    offset += block.len() as u64;
+                    Some(filter)
+                }
+                None => None,
+            };
+
+            let keys = partition.len() as u64;
+            block.clear();
+            format::write_index_partition(&mut block, IndexPartition { offsets: partition })?;
+            writer.write_all(&block)?;
+            partitions.push(PartitionHandle {
+                first_key,
+                last_key,
+                first_index,
+                index: (offset, block.len() as u64),
+                filter,
+            });
+            offset += block.len() as u64;
+            first_index += keys;
+        }
+
+        let index = TableIndex {
+            partitions,
+            entries,
+            tombstones: self.tombstones,
+            max_seqno: metadata.max_seqno,
+            end,
+            expiring: self.expiring,
+            filter: options.filter.map(|kind| FilterInfo {
+                kind,
+                bits_per_key: options.filter_bits_per_key,
+            }),
+        };
+        format::write_table_index(writer, index)?;
+        format::write_table_footer(writer, &metadata)?;
+
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(MAGIC)?;
+
+        Ok(())
+    }
+}
+
+impl Block {
+    /// The keys of the partition along with the offset of their entry, or none if the block is a
+    /// filter.
+    pub fn offsets(&self) -> &[(String, u64)] {
+        match self {
+            Block::Index(partition) => &partition.offsets,
+            Block::Filter(_) => &[],
+        }
+    }
+
+    /// Whether the filter may contain the key, or true if the block is a partition.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match self {
+            Block::Filter(filter) => filter.may_contain(key),
+            Block::Index(_) => true,
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::engine::Version;
+use crate::format;
+use crate::memtable::{self, Entries};
+use crate::prefetch::BlockReader;
+use crate::{Error, Stored};
+
+/// An entry along with its sequence number.
+type Entry = (String, Stored, u64);
+
+/// Where an iterator reads entries from, in key order.
+pub(crate) enum Source {
+    /// The entries of a memtable, as they were when the iterator was created.
+    MemTable {
+        entries: Arc<Entries>,
+        /// Where the next entry is looked for.
+        position: Bound<String>,
+    },
+    /// SSTables opened when the iterator was created, read one after the other. Their ranges must
+    /// not overlap, and they must be sorted by them. The first block of each is only prefetched
+    /// once the last one of the sstable before it is reached, so that opening every sstable of a
+    /// level upfront doesn't read a block of each.
+    SSTables {
+        inputs: VecDeque<BlockReader>,
+        verify_checksums: bool,
+    },
+}
+
+impl Source {
+    /// Reads the entries of a memtable from the given key on.
+    pub fn memtable(entries: Arc<Entries>, start: &str) -> Self {
+        Source::MemTable {
+            entries,
+            position: Bound::Included(start.to_owned()),
+        }
+    }
+
+    pub fn sstables(inputs: Vec<BlockReader>, verify_checksums: bool) -> Self {
+        Source::SSTables {
+            inputs: inputs.into(),
+            verify_checksums,
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Entry>> {
+        match self {
+            Source::MemTable { entries, position } => {
+                let from = position.as_ref().map(|key| key.as_bytes());
+
+                let entry = match entries.range::<[u8], _>((from, Bound::Unbounded)).next() {
+                    Some((key, record)) => (String::from_utf8(key.to_vec())?, memtable::stored(record), record.seqno),
+                    None => return Ok(None),
+                };
+
+                *position = Bound::Excluded(entry.0.clone());
+                Ok(Some(entry))
+            }
+            Source::SSTables { inputs, verify_checksums } => {
+                while !inputs.is_empty() {
+                    if inputs[0].on_last_block() {
+                        if let Some(next) = inputs.get_mut(1) {
+                            next.prefetch();
+                        }
+                    }
+
+                    match format::read_sequenced_entry(&mut inputs[0], *verify_checksums)? {
+                        Some(entry) => return Ok(Some(entry)),
+                        None => {
+                            inputs.pop_front();
+                        }
+                    }
+                }
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Where a scan left off: the last key it returned, and the sequence number of the last write it
+/// could see. See [`Storage::scan`](crate::Storage::scan).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Continuation {
+    pub last_key: String,
+    pub snapshot: u64,
+}
+
+impl Continuation {
+    /// Encodes the continuation as an opaque token, made of the hexadecimal sequence number and
+    /// key, so that it can be passed around in URLs as is.
+    pub fn encode(&self) -> String {
+        let key: String = self.last_key.bytes().map(|byte| format!("{:02x}", byte)).collect();
+
+        format!("{:x}-{}", self.snapshot, key)
+    }
+
+    /// Decodes a token made by [`Continuation::encode`], failing with
+    /// [`Error::InvalidContinuation`] if it isn't one.
+    pub fn decode(token: &str) -> Result<Self> {
+        let decode = || -> Option<Continuation> {
+            let (snapshot, key) = token.split_once('-')?;
+            let snapshot = u64::from_str_radix(snapshot, 16).ok()?;
+
+            let key = (0..key.len())
+                .step_by(2)
+                .map(|start| u8::from_str_radix(key.get(start..start + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+
+            let last_key = String::from_utf8(key).ok()?;
+            Some(Continuation { last_key, snapshot })
+        };
+
+        decode().ok_or_else(|| Error::InvalidContinuation.into())
+    }
+}
+
+/// An iterator over the keys and values of the storage, in key order. See [`Storage::iter`].
+///
+/// It sees the storage as it was when created: the memtables it reads from are snapshots, and the
+/// sstables are opened upfront and kept until it is dropped, so writes, flushes and compactions
+/// that happen while it is alive don't change what it yields.
+///
+/// [`Storage::iter`]: crate::Storage::iter
+pub struct StorageIterator {
+    /// The sources, from the newest to the oldest, each along with its next entry.
+    sources: Vec<(Option<Entry>, Source)>,
+    /// The sequence number of the last write the iterator sees. Newer versions of a key are only
+    /// yielded if none of its versions the sources hold is as old.
+    snapshot: u64,
+    /// When the iterator was created, in milliseconds since the UNIX epoch. Values that expired by
+    /// then are skipped, as removed ones are.
+    now: u64,
+    /// The version the sstables were opened from, held so that their files aren't deleted while
+    /// they are read, even once a compaction replaces them.
+    _version: Arc<Version>,
+}
+
+impl StorageIterator {
+    /// Creates an iterator over the given sources, from the newest to the oldest, seeing the
+    /// writes up to the given sequence number and the values that didn't expire by the given
+    /// time. The sstables must belong to the given version.
+    pub(crate) fn new(sources: Vec<Source>, snapshot: u64, now: u64, version: Arc<Version>) -> Result<Self> {
+        let sources = sources
+            .into_iter()
+            .map(|mut source| Ok((source.next()?, source)))
+            .collect::<Result<_>>()?;
+
+        Ok(StorageIterator {
+            sources,
+            snapshot,
+            now,
+            _version: version,
+        })
+    }
+
+    /// The sequence number of the last write the iterator sees.
+    pub(crate) fn snapshot(&self) -> u64 {
+        self.snapshot
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        let now = self.now;
+
+        Ok(self.next_live()?.and_then(|(key, value)| Some((key, value.value_at(now)?))))
+    }
+
+    /// The next key, along with what it holds, skipping those that were removed or whose value
+    /// expired.
+    pub(crate) fn next_live(&mut self) -> Result<Option<(String, Stored)>> {
+        loop {
+            // The entry of the smallest key with the highest sequence number, preferring those
+            // the snapshot sees. Entries written before sequence numbers existed all have 0, so
+            // ties go to the newest source.
+            let mut newest: Option<&Entry> = None;
+            let rank = |entry: &Entry| (entry.2 <= self.snapshot, entry.2);
+
+            for (entry, _) in &self.sources {
+                if let Some(entry) = entry {
+                    let is_newer = newest.is_none_or(|newest| {
+                        entry.0 < newest.0 || (entry.0 == newest.0 && rank(entry) > rank(newest))
+                    });
+
+                    if is_newer {
+                        newest = Some(entry);
+                    }
+                }
+            }
+
+            let (key, value, _) = match newest {
+                Some(entry) => entry.clone(),
+                None => return Ok(None),
+            };
+
+            for (entry, source) in &mut self.sources {
+                if entry.as_ref().is_some_and(|(other, _, _)| *other == key) {
+                    *entry = source.next()?;
+                }
+            }
+
+            if value != Stored::Tombstone && !value.expired(self.now) {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+}
+
+impl Iterator for StorageIterator {
+    type Item = Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
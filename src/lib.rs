@@ -1,20 +1,80 @@
 #[cfg(test)]
 mod test_utils;
+#[cfg(test)]
+mod fault_injection;
 
+#[cfg(feature = "remote-backup")]
+pub mod backup;
+mod bloom;
+mod cache;
 mod engine;
+pub mod env;
+mod error;
+pub mod events;
+mod expiry;
+pub mod export;
+pub mod filter;
 mod format;
-mod memtable;
-mod sstable;
+mod hot_keys;
+mod index;
+pub mod identity;
+mod iterator;
+mod manifest;
+pub mod memtable;
+mod prefetch;
+#[cfg(feature = "raft")]
+pub mod raft;
+mod rate_limiter;
+pub mod scheduler;
+mod scrubber;
+pub mod sstable;
+pub mod stats;
+pub mod tuning;
 mod compactor;
+pub mod compression;
 pub mod storage;
+mod supervisor;
+pub mod typed;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+pub mod verify;
+pub mod versioned;
+pub mod wal;
+mod watch;
+
+pub use error::Error;
+pub use storage::Storage;
 
 use serde::{Deserialize, Serialize};
 
-const SEGMENTS_NAME: &'static str = "sstable";
-const WAL_NAME: &'static str = "write-ahead-log";
+const SEGMENTS_NAME: &str = "sstable";
+const WAL_NAME: &str = "write-ahead-log";
+/// Names the WALs of persisted memtables kept for replication followers to catch up from. See
+/// [`StorageBuilder::wal_archive_size`](storage::StorageBuilder::wal_archive_size).
+const WAL_ARCHIVE_NAME: &str = "archived-write-ahead-log";
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 enum Stored {
     Tombstone,
     Value(Vec<u8>),
+    /// A value inserted with a TTL, along with when it expires, in milliseconds since the UNIX
+    /// epoch, as told by the clock of the storage. Once expired, it reads as a tombstone would.
+    Expiring(Vec<u8>, u64),
+}
+
+impl Stored {
+    /// The value, unless the key was removed or its value expired by the given time, in
+    /// milliseconds since the UNIX epoch.
+    fn value_at(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Stored::Value(value) => Some(value),
+            Stored::Expiring(value, expires_at) if expires_at > now => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Whether the value expired by the given time, in milliseconds since the UNIX epoch.
+    fn expired(&self, now: u64) -> bool {
+        matches!(self, Stored::Expiring(_, expires_at) if *expires_at <= now)
+    }
 }
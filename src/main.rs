@@ -1,55 +1,2873 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Path as FsPath, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::http::StatusCode;
-use lsm_storage::storage::Storage;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Redirect, Response};
+use lsm_storage::stats::{Aggregate, HotKey, PurgeReport, TableInfo, TenantStats};
+use lsm_storage::storage::{CatchUp, Change, ReadOptions, ScanOptions, Storage, PROPERTIES};
+use lsm_storage::raft::{
+    AppendRequest, AppendResponse, NodeId, Raft, SnapshotRequest, SnapshotResponse, Transport, VoteRequest,
+    VoteResponse,
+};
+use lsm_storage::wal::WalTail;
+use lsm_storage::Error;
 
-use axum::extract::{Path, State};
-use axum::{routing::get, Router};
+use axum::body::StreamBody;
+use axum::extract::{BodyStream, Path, Query, State};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use axum_server::accept::Accept;
+use clap::Parser;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use axum_server::Handle;
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tempfile::TempDir;
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tower::Layer;
+
+/// The environment variable listing the API keys accepted by the server. See [`ApiKeys`].
+const API_KEYS_VAR: &str = "LSM_API_KEYS";
+/// How long the requests in flight may take to complete once the server is asked to stop. Watches
+/// never complete on their own, so they are cut off.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Serves a storage over HTTP.
+///
+/// Every option but `--config` may also be set in the TOML file named by `--config`, under the
+/// same name, e.g. `data-dir = "/var/lib/lsm"`. The command line takes precedence over the
+/// environment, which takes precedence over the file.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(version)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Options {
+    /// Where the sstables are kept.
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// Where the WALs are kept [default: the data directory]
+    #[arg(long, value_name = "PATH")]
+    wal_dir: Option<PathBuf>,
+
+    /// The address to listen on [default: 0.0.0.0:3000, unless --unix-socket is set]
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<SocketAddr>,
+
+    /// A Unix domain socket to listen on, along with --listen if set. Requests over the socket
+    /// skip the API key checks: whoever may open the socket file may do anything.
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
+
+    /// The number of entries at which a memtable is converted into a sstable [default: 1024]
+    #[arg(long, value_name = "ENTRIES")]
+    threshold: Option<usize>,
+
+    /// Compacts L0 once it holds this many sstables [default: 4]
+    #[arg(long, value_name = "FILES")]
+    l0_compaction_trigger: Option<usize>,
+
+    /// How many threads run flushes and compactions [default: 1]
+    #[arg(long, value_name = "THREADS")]
+    max_background_jobs: Option<usize>,
+
+    /// Runs flushes in one more thread, reserved for them, so that compactions never delay them.
+    #[arg(long)]
+    dedicated_flush_thread: bool,
+
+    /// Stalls writes while this many frozen memtables wait to be persisted, rather than letting
+    /// them pile up in memory.
+    #[arg(long, value_name = "MEMTABLES")]
+    max_frozen_memtables: Option<usize>,
+
+    /// Throttles writes to this many bytes of keys and values per second, so that bulk imports
+    /// can't outrun flushes and compactions.
+    #[arg(long, value_name = "BYTES")]
+    write_bytes_per_sec: Option<u64>,
+
+    /// Throttles writes to this many keys per second.
+    #[arg(long, value_name = "KEYS")]
+    write_ops_per_sec: Option<u64>,
+
+    /// Overwrites obsolete sstables and WALs with zeros before removing them, so that removed
+    /// values can't be recovered from the disk.
+    #[arg(long)]
+    secure_delete: bool,
+
+    /// Archives the WALs of persisted memtables, up to this many bytes, so that followers that
+    /// fall behind catch up from them rather than from a whole checkpoint.
+    #[arg(long, value_name = "BYTES")]
+    wal_archive_size: Option<u64>,
+
+    /// Tells the tenant of a key as the part before the first occurrence of this character, so
+    /// that /metrics reports the reads and writes of each tenant apart.
+    #[arg(long, value_name = "CHAR")]
+    tenant_separator: Option<char>,
+
+    /// Tracks how often keys are read, remembering this many of the keys read the most for
+    /// /admin/hot-keys [default: 0, which tracks nothing]
+    #[arg(long, value_name = "KEYS")]
+    hot_keys: Option<usize>,
+
+    /// The most entries a page of /scan holds, whatever limit the request asks for.
+    #[arg(long, value_name = "KEYS")]
+    max_scan_keys: Option<usize>,
+
+    /// The bytes of keys and values past which a page of /scan ends, whatever limit the request
+    /// asks for.
+    #[arg(long, value_name = "BYTES")]
+    max_scan_bytes: Option<usize>,
+
+    /// How long reading a page of /scan may take before it ends, in milliseconds.
+    #[arg(long, value_name = "MILLIS")]
+    scan_timeout: Option<u64>,
+
+    /// How long a request to the routes outside /admin may take, in milliseconds, before it is
+    /// answered with 503 and a Retry-After header. The request keeps running, so its write may
+    /// still happen.
+    #[arg(long, value_name = "MILLIS")]
+    request_timeout: Option<u64>,
+
+    /// How long a request to the /admin routes may take, in milliseconds, as --request-timeout.
+    #[arg(long, value_name = "MILLIS")]
+    admin_request_timeout: Option<u64>,
+
+    /// How many requests to the routes outside /admin may be in flight at once, those timed out
+    /// included. Any more are answered with 503 and a Retry-After header right away.
+    #[arg(long, value_name = "REQUESTS")]
+    max_concurrent_requests: Option<usize>,
+
+    /// The PEM file of the certificate chain of the server. It serves HTTPS if set, along with
+    /// --tls-key, and plain HTTP otherwise.
+    #[arg(long, env = "LSM_TLS_CERT", value_name = "PATH")]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM file of the private key of the server.
+    #[arg(long, env = "LSM_TLS_KEY", value_name = "PATH")]
+    tls_key: Option<PathBuf>,
+
+    /// A PEM file of CA certificates. If set, clients may present a certificate signed by one of
+    /// them, and the admin routes require one.
+    #[arg(long, env = "LSM_TLS_CLIENT_CA", value_name = "PATH")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// A file listing API keys as <key>:<scope>[,<scope>...], one per line. Lines starting with #
+    /// are ignored.
+    #[arg(long, env = "LSM_API_KEYS_FILE", value_name = "PATH")]
+    api_keys_file: Option<PathBuf>,
+
+    /// The URL of a leader to follow, e.g. http://leader:3000. This instance then applies the
+    /// writes of the leader as they are committed, and refuses those of its clients. A follower
+    /// that can't resume from its last write anymore stops, and replaces its storage with a
+    /// checkpoint of the leader once restarted. Reads given a `max_staleness` parameter are
+    /// answered with 503 if this instance lags further behind its leader, in writes.
+    #[arg(long, value_name = "URL")]
+    follow: Option<String>,
+
+    /// An API key granting the admin scope on the leader to follow.
+    #[arg(long, env = "LSM_LEADER_API_KEY", value_name = "KEY")]
+    leader_api_key: Option<String>,
+
+    /// The id of this instance as a member of a Raft cluster, among those of --raft-members. Writes
+    /// then only land once a majority of the members logged them, and every member applies them
+    /// in the same order. Only the leader of the cluster takes reads and writes: the others
+    /// redirect them to it with 307, or answer with 503 while there is none. The leader first
+    /// makes sure that it still leads before reading, so that reads see every write acknowledged
+    /// before them. Can't be set along with --follow.
+    #[arg(long, value_name = "ID")]
+    raft_id: Option<u64>,
+
+    /// The members of the Raft cluster, this instance included, as <id>=<url> pairs separated by
+    /// commas, e.g. 1=http://a:3000,2=http://b:3000,3=http://c:3000. Every member must be given
+    /// the same ones.
+    #[arg(long, value_name = "MEMBERS")]
+    raft_members: Option<String>,
+
+    /// An API key granting the admin scope on the other members of the Raft cluster, which take
+    /// the requests of this instance under /admin/raft.
+    #[arg(long, env = "LSM_RAFT_API_KEY", value_name = "KEY")]
+    raft_api_key: Option<String>,
+
+    /// A TOML file setting any of the other options.
+    #[arg(long, value_name = "PATH")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+}
+
+impl Options {
+    /// Parses the command line, filling what it leaves unset from the config file.
+    fn load() -> Result<Self> {
+        Options::parse().with_config_file()
+    }
+
+    /// Fills what these options leave unset from the config file they name, if any.
+    fn with_config_file(self) -> Result<Self> {
+        let file = match &self.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                toml::from_str(&contents).with_context(|| format!("parsing {}", path.display()))?
+            }
+            None => Options::default(),
+        };
+
+        // Destructured whole, so that no option can be left out of the merge.
+        let Options {
+            data_dir,
+            wal_dir,
+            listen,
+            unix_socket,
+            threshold,
+            l0_compaction_trigger,
+            max_background_jobs,
+            dedicated_flush_thread,
+            max_frozen_memtables,
+            write_bytes_per_sec,
+            write_ops_per_sec,
+            secure_delete,
+            wal_archive_size,
+            tenant_separator,
+            hot_keys,
+            max_scan_keys,
+            max_scan_bytes,
+            scan_timeout,
+            request_timeout,
+            admin_request_timeout,
+            max_concurrent_requests,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            api_keys_file,
+            follow,
+            leader_api_key,
+            raft_id,
+            raft_members,
+            raft_api_key,
+            config: _,
+        } = file;
+
+        Ok(Options {
+            data_dir: self.data_dir.or(data_dir),
+            wal_dir: self.wal_dir.or(wal_dir),
+            listen: self.listen.or(listen),
+            unix_socket: self.unix_socket.or(unix_socket),
+            threshold: self.threshold.or(threshold),
+            l0_compaction_trigger: self.l0_compaction_trigger.or(l0_compaction_trigger),
+            max_background_jobs: self.max_background_jobs.or(max_background_jobs),
+            dedicated_flush_thread: self.dedicated_flush_thread || dedicated_flush_thread,
+            max_frozen_memtables: self.max_frozen_memtables.or(max_frozen_memtables),
+            write_bytes_per_sec: self.write_bytes_per_sec.or(write_bytes_per_sec),
+            write_ops_per_sec: self.write_ops_per_sec.or(write_ops_per_sec),
+            secure_delete: self.secure_delete || secure_delete,
+            wal_archive_size: self.wal_archive_size.or(wal_archive_size),
+            tenant_separator: self.tenant_separator.or(tenant_separator),
+            hot_keys: self.hot_keys.or(hot_keys),
+            max_scan_keys: self.max_scan_keys.or(max_scan_keys),
+            max_scan_bytes: self.max_scan_bytes.or(max_scan_bytes),
+            scan_timeout: self.scan_timeout.or(scan_timeout),
+            request_timeout: self.request_timeout.or(request_timeout),
+            admin_request_timeout: self.admin_request_timeout.or(admin_request_timeout),
+            max_concurrent_requests: self.max_concurrent_requests.or(max_concurrent_requests),
+            tls_cert: self.tls_cert.or(tls_cert),
+            tls_key: self.tls_key.or(tls_key),
+            tls_client_ca: self.tls_client_ca.or(tls_client_ca),
+            api_keys_file: self.api_keys_file.or(api_keys_file),
+            follow: self.follow.or(follow),
+            leader_api_key: self.leader_api_key.or(leader_api_key),
+            raft_id: self.raft_id.or(raft_id),
+            raft_members: self.raft_members.or(raft_members),
+            raft_api_key: self.raft_api_key.or(raft_api_key),
+            config: self.config,
+        })
+    }
+
+    /// The directory of the sstables and that of the WALs.
+    fn dirs(&self) -> Result<(PathBuf, PathBuf)> {
+        let data_dir = self
+            .data_dir
+            .clone()
+            .context("--data-dir must be set, either on the command line or in the config file")?;
+        let wal_dir = self.wal_dir.clone().unwrap_or_else(|| data_dir.clone());
+
+        Ok((data_dir, wal_dir))
+    }
+
+    /// Opens the storage in the configured directories.
+    fn open(&self) -> Result<Storage> {
+        let (data_dir, wal_dir) = self.dirs()?;
+
+        let mut builder = Storage::builder()
+            .segments_path(data_dir)
+            .wal_path(wal_dir)
+            .l0_compaction_trigger(self.l0_compaction_trigger.unwrap_or(4))
+            .dedicated_flush_thread(self.dedicated_flush_thread)
+            .secure_delete(self.secure_delete);
+        if let Some(threads) = self.max_background_jobs {
+            builder = builder.max_background_jobs(threads);
+        }
+        if let Some(memtables) = self.max_frozen_memtables {
+            builder = builder.max_frozen_memtables(memtables);
+        }
+        if let Some(bytes) = self.write_bytes_per_sec {
+            builder = builder.write_bytes_per_sec(bytes);
+        }
+        if let Some(ops) = self.write_ops_per_sec {
+            builder = builder.write_ops_per_sec(ops);
+        }
+        if let Some(threshold) = self.threshold {
+            builder = builder.threshold(threshold);
+        }
+        if let Some(separator) = self.tenant_separator {
+            builder = builder.tenant_of(move |key| key.split_once(separator).map(|(tenant, _)| tenant));
+        }
+        if let Some(hot_keys) = self.hot_keys {
+            builder = builder.hot_keys(hot_keys);
+        }
+        if let Some(bytes) = self.wal_archive_size {
+            builder = builder.wal_archive_size(bytes);
+        }
+
+        builder.build()
+    }
+
+    /// How the server takes part in replication.
+    fn replication(&self, follower: Option<&Follower>, member: Option<Arc<Member>>) -> Replication {
+        Replication {
+            checkpoints: Arc::new(self.data_dir.clone().unwrap_or_default()),
+            following: follower.map(|follower| follower.lag.clone()),
+            raft: member,
+        }
+    }
+
+    /// Starts this instance as a member of the Raft cluster, if it is one, with the storage as its
+    /// state machine. It keeps its log in the `raft` directory inside the data directory.
+    fn member(&self, storage: &Storage) -> Result<Option<Arc<Member>>> {
+        let id = match self.raft_id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        if self.follow.is_some() {
+            bail!("--raft-id and --follow can't be set together");
+        }
+
+        let members = self
+            .raft_members
+            .as_deref()
+            .context("--raft-members must be set along with --raft-id")?;
+        let urls = parse_members(members).context("parsing --raft-members")?;
+        let transport = HttpTransport {
+            urls: urls.clone(),
+            api_key: self.raft_api_key.clone(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(MEMBER_CONNECT_TIMEOUT)
+                .timeout_read(LEADER_READ_TIMEOUT)
+                .build(),
+        };
+
+        let (data_dir, _) = self.dirs()?;
+        let raft = Raft::builder(id, urls.keys().copied().collect())
+            .dir(data_dir.join("raft"))
+            .build(storage.clone(), Arc::new(transport))?;
+
+        Ok(Some(Arc::new(Member { raft, urls })))
+    }
+
+    /// Follows the leader, if there is one to follow.
+    fn follower(&self) -> Option<Follower> {
+        self.follow.as_ref().map(|leader| Follower {
+            leader: leader.trim_end_matches('/').to_owned(),
+            api_key: self.leader_api_key.clone(),
+            agent: ureq::AgentBuilder::new().timeout_read(LEADER_READ_TIMEOUT).build(),
+            lag: Arc::new(Lag::new()),
+        })
+    }
+
+    /// The limits the requests to each group of routes are held to.
+    fn route_limits(&self) -> RouteLimits {
+        RouteLimits {
+            data: Arc::new(RequestLimits {
+                timeout: self.request_timeout.map(Duration::from_millis),
+                slots: self.max_concurrent_requests.map(|requests| Arc::new(Semaphore::new(requests))),
+            }),
+            admin: Arc::new(RequestLimits {
+                timeout: self.admin_request_timeout.map(Duration::from_millis),
+                slots: None,
+            }),
+        }
+    }
+
+    /// The limits every page of /scan is held to.
+    fn scan_budget(&self) -> ScanBudget {
+        ScanBudget {
+            max_keys: self.max_scan_keys,
+            max_bytes: self.max_scan_bytes,
+            timeout: self.scan_timeout.map(Duration::from_millis),
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let segments = PathBuf::from(std::env::args().nth(1).unwrap());
-    let storage = Storage::builder().segments_path(segments).build().unwrap();
+async fn main() -> Result<()> {
+    let options = Options::load()?;
+    let storage = options.open()?;
+    let member = options.member(&storage)?;
+    let follower = options.follower();
+    let (storage, after) = match &follower {
+        Some(follower) => follower.start(storage, &options)?,
+        None => (storage, 0),
+    };
+
+    let api_keys = Arc::new(ApiKeys::load(options.api_keys_file.as_deref())?);
+    let tls = Tls::load(&options)?;
+
+    let stopping = stop_on_signal();
+    let verifies_clients = tls.as_ref().is_some_and(|tls| tls.verifies_clients);
+    let limits = options.route_limits();
+    let replication = options.replication(follower.as_ref(), member);
+
+    let tcp = async {
+        let addr = match (options.listen, &options.unix_socket) {
+            (Some(addr), _) => addr,
+            (None, None) => SocketAddr::from(([0, 0, 0, 0], 3000)),
+            (None, Some(_)) => return Ok(()),
+        };
+
+        let app = routes(verifies_clients, options.scan_budget(), limits.clone(), replication.clone())
+            .layer(middleware::from_fn_with_state(api_keys, authenticate))
+            .with_state(storage.clone());
 
-    let app = Router::new()
+        serve_tcp(addr, tls, app, stopping.clone()).await
+    };
+
+    let unix = async {
+        match &options.unix_socket {
+            Some(path) => {
+                let app = routes(false, options.scan_budget(), limits.clone(), replication.clone())
+                    .with_state(storage.clone());
+                serve_unix(path, app, stopping.clone()).await
+            }
+            None => Ok(()),
+        }
+    };
+
+    // Following only stops once the leader can't resume, which fails the server.
+    let following = async {
+        let follower = match follower {
+            Some(follower) => follower,
+            None => return std::future::pending().await,
+        };
+        let storage = storage.clone();
+        let (done, failed) = oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = done.send(follower.follow(&storage, after));
+        });
+
+        failed.await?
+    };
+
+    tokio::select! {
+        served = async { tokio::try_join!(tcp, unix) } => {
+            served?;
+        }
+        followed = following => followed?,
+    }
+
+    // Stops the member of the Raft cluster, if any, before the storage it applies the writes to.
+    drop(replication);
+    storage.close()
+}
+
+fn routes(
+    verifies_clients: bool,
+    scan_budget: ScanBudget,
+    limits: RouteLimits,
+    replication: Replication,
+) -> Router<Storage> {
+    let mut admin = Router::new()
+        .route("/flush", post(admin_flush))
+        .route("/compact", post(admin_compact))
+        .route("/purge", post(admin_purge))
+        .route("/checkpoint", post(admin_checkpoint))
+        .route("/lsm", get(admin_lsm))
+        .route("/hot-keys", get(admin_hot_keys))
+        .route("/replicate", get(admin_replicate))
+        .route_layer(middleware::from_fn_with_state(limits.admin, limit_requests));
+
+    let mut data = Router::new()
         .route("/key/:key", get(kv_get).post(kv_insert).delete(kv_delete))
-        .with_state(storage);
+        .route("/scan", get(kv_scan))
+        .route("/keys", get(kv_keys))
+        .route("/count", get(kv_count))
+        .route("/aggregate", get(kv_aggregate))
+        .route("/batch", post(kv_batch))
+        .route("/mget", post(kv_mget))
+        .route("/watch/:key", get(kv_watch))
+        .route("/subscribe", get(kv_subscribe));
+
+    // The requests of the other members aren't held to the limits of the admin routes, as they
+    // wait on the storage of this one. The stats and metrics are those of each member.
+    if let Some(member) = &replication.raft {
+        let raft = Router::new()
+            .route("/vote", post(raft_vote))
+            .route("/append", post(raft_append))
+            .route("/snapshot", post(raft_snapshot))
+            .with_state(member.clone());
+
+        admin = admin.nest("/raft", raft);
+        data = data.route_layer(middleware::from_fn_with_state(member.clone(), lead));
+    }
+
+    if verifies_clients {
+        admin = admin.route_layer(middleware::from_fn(require_client_certificate));
+    }
+
+    let mut data = data
+        .route("/stats", get(stats))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(limits.data, limit_requests));
+
+    if let Some(lag) = &replication.following {
+        data = data
+            .route_layer(middleware::from_fn(refuse_writes))
+            .route_layer(middleware::from_fn_with_state(lag.clone(), bound_staleness));
+    }
+
+    data.nest("/admin", admin)
+        .layer(Extension(scan_budget))
+        .layer(Extension(replication))
+}
+
+async fn serve_tcp(addr: SocketAddr, tls: Option<Tls>, app: Router, mut stopping: Stopping) -> Result<()> {
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            let _ = stopping.changed().await;
+            handle.graceful_shutdown(Some(SHUTDOWN_GRACE_PERIOD));
+        }
+    });
+
+    let server = axum_server::bind(addr).handle(handle);
+    match tls {
+        Some(tls) => server
+            .acceptor(ClientCertificateAcceptor::new(tls.config))
+            .serve(app.into_make_service())
+            .await?,
+        None => server.serve(app.into_make_service()).await?,
+    }
+
+    Ok(())
+}
+
+/// Serves plain HTTP on a Unix domain socket, replacing the socket left behind by a previous run,
+/// if any, and removing it once stopped.
+#[cfg(unix)]
+async fn serve_unix(path: &FsPath, app: Router, stopping: Stopping) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+    let listener = UnixListener::bind(path).with_context(|| format!("binding {}", path.display()))?;
+    let incoming = hyper::server::accept::from_stream(UnixListenerStream::new(listener));
+
+    let mut stopped = stopping.clone();
+    let server = axum::Server::builder(incoming)
         .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .with_graceful_shutdown(async move {
+            let _ = stopped.changed().await;
+        });
+
+    // Like the TCP server, stops waiting for the requests in flight after the grace period.
+    let mut stopped = stopping;
+    let grace_period = async move {
+        let _ = stopped.changed().await;
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = grace_period => {}
+    }
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: &FsPath, _app: Router, _stopping: Stopping) -> Result<()> {
+    bail!("Unix domain sockets are not supported on this platform")
+}
+
+/// Changes once the server is asked to stop.
+type Stopping = watch::Receiver<()>;
+
+/// Signals the servers to stop accepting connections once the process is asked to stop, by SIGINT
+/// or SIGTERM. They wait up to [`SHUTDOWN_GRACE_PERIOD`] for the requests in flight to complete.
+fn stop_on_signal() -> Stopping {
+    let (stop, stopping) = watch::channel(());
+
+    tokio::spawn(async move {
+        let interrupt = async {
+            tokio::signal::ctrl_c().await.expect("failed to listen for SIGINT");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to listen for SIGTERM")
+                .recv()
+                .await;
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate => {}
+        }
+
+        stop.send_replace(());
+    });
+
+    stopping
+}
+
+/// How the server speaks HTTPS.
+struct Tls {
+    config: RustlsConfig,
+    /// Whether clients may present certificates, which are then verified.
+    verifies_clients: bool,
+}
+
+impl Tls {
+    /// Loads the configuration from the files named by the options, or returns `None` if the
+    /// server speaks plain HTTP.
+    fn load(options: &Options) -> Result<Option<Self>> {
+        let (cert, key) = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => bail!("--tls-cert and --tls-key must be set together"),
+        };
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let client_ca = &options.tls_client_ca;
+
+        let builder = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in read_certificates(path)? {
+                    roots.add(&certificate)?;
+                }
+
+                builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(read_certificates(cert)?, read_private_key(key)?)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Some(Tls {
+            config: RustlsConfig::from_config(Arc::new(config)),
+            verifies_clients: client_ca.is_some(),
+        }))
+    }
+}
+
+fn read_pem(path: &FsPath) -> Result<Vec<rustls_pemfile::Item>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("reading {}", path.display()))
+}
+
+fn read_certificates(path: &FsPath) -> Result<Vec<Certificate>> {
+    let certificates: Vec<_> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+
+    if certificates.is_empty() {
+        bail!("{} holds no certificates", path.display());
+    }
+
+    Ok(certificates)
+}
+
+fn read_private_key(path: &FsPath) -> Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .with_context(|| format!("{} holds no private key", path.display()))
+}
+
+/// Whether the client presented a certificate that was verified during the TLS handshake.
+#[derive(Debug, Clone, Copy)]
+struct ClientCertificate {
+    verified: bool,
+}
+
+/// Accepts TLS connections, telling the requests of each one whether its client presented a
+/// verified certificate, through the [`ClientCertificate`] extension.
+#[derive(Clone)]
+struct ClientCertificateAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertificateAcceptor {
+    fn new(config: RustlsConfig) -> Self {
+        ClientCertificateAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertificateAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = <Extension<ClientCertificate> as Layer<S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let verified = stream.get_ref().1.peer_certificates().is_some();
+
+            Ok((stream, Extension(ClientCertificate { verified }).layer(service)))
+        })
+    }
+}
+
+/// Rejects the requests of clients that didn't present a verified certificate.
+async fn require_client_certificate<B>(request: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    match request.extensions().get::<ClientCertificate>() {
+        Some(certificate) if certificate.verified => Ok(next.run(request).await),
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+/// How a value is carried in the body of a request or response, as told by its media type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueFormat {
+    /// The bytes of the value as they are.
+    Raw,
+    /// A `{"value": <base64>}` JSON object.
+    Json,
+    /// A MessagePack map from `value` to the bytes of the value.
+    MsgPack,
+}
+
+/// A value as carried in JSON.
+#[derive(Serialize, Deserialize)]
+struct JsonValue {
+    value: String,
+}
+
+impl ValueFormat {
+    /// The format of the given media type, if it is one of those supported.
+    fn of(media_type: &str) -> Option<Self> {
+        match media_type.split(';').next().unwrap_or_default().trim() {
+            "application/octet-stream" => Some(ValueFormat::Raw),
+            "application/json" => Some(ValueFormat::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(ValueFormat::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// The format asked for by the `Accept` header: the first supported media type it lists,
+    /// ignoring their weights. Values are sent raw otherwise.
+    fn accepted(headers: &HeaderMap) -> Self {
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(|accept| accept.split(',').find_map(ValueFormat::of))
+            .unwrap_or(ValueFormat::Raw)
+    }
+
+    /// The format told by the `Content-Type` header. Bodies of other types are taken as raw, as
+    /// they always were.
+    fn sent(headers: &HeaderMap) -> Self {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(ValueFormat::of)
+            .unwrap_or(ValueFormat::Raw)
+    }
+
+    fn media_type(self) -> &'static str {
+        match self {
+            ValueFormat::Raw => "application/octet-stream",
+            ValueFormat::Json => "application/json",
+            ValueFormat::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn encode(self, value: Vec<u8>) -> Vec<u8> {
+        match self {
+            ValueFormat::Raw => value,
+            ValueFormat::Json => {
+                let value = JsonValue { value: BASE64.encode(value) };
+                serde_json::to_vec(&value).expect("a string always serializes")
+            }
+            ValueFormat::MsgPack => {
+                let mut body = vec![0x81, 0xa5];
+                body.extend_from_slice(b"value");
+
+                // The smallest of bin 8, bin 16 and bin 32 that fits the value.
+                match u8::try_from(value.len()) {
+                    Ok(len) => body.extend_from_slice(&[0xc4, len]),
+                    Err(_) => match u16::try_from(value.len()) {
+                        Ok(len) => {
+                            body.push(0xc5);
+                            body.extend_from_slice(&len.to_be_bytes());
+                        }
+                        Err(_) => {
+                            body.push(0xc6);
+                            body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                        }
+                    },
+                }
+
+                body.extend_from_slice(&value);
+                body
+            }
+        }
+    }
+
+    fn decode(self, body: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ValueFormat::Raw => Ok(body.to_vec()),
+            ValueFormat::Json => {
+                let value: JsonValue = serde_json::from_slice(body)?;
+                Ok(BASE64.decode(value.value)?)
+            }
+            ValueFormat::MsgPack => {
+                let payload = body
+                    .strip_prefix(b"\x81\xa5value")
+                    .context("expected a map holding nothing but value")?;
+
+                // Values are taken as bin or str, whichever the client encodes bytes as.
+                let (len, rest) = match payload.split_first() {
+                    Some((0xa0..=0xbf, rest)) => ((payload[0] & 0x1f) as usize, rest),
+                    Some((0xc4 | 0xd9, rest)) => split_len::<1>(rest)?,
+                    Some((0xc5 | 0xda, rest)) => split_len::<2>(rest)?,
+                    Some((0xc6 | 0xdb, rest)) => split_len::<4>(rest)?,
+                    _ => bail!("expected the value as bin or str"),
+                };
+
+                if rest.len() != len {
+                    bail!("expected {} bytes of value, got {}", len, rest.len());
+                }
+                Ok(rest.to_vec())
+            }
+        }
+    }
 }
 
+/// Splits the big-endian length of a MessagePack bin or str, of `N` bytes, from what follows it.
+fn split_len<const N: usize>(bytes: &[u8]) -> Result<(usize, &[u8])> {
+    if bytes.len() < N {
+        bail!("truncated length");
+    }
+
+    let (len, rest) = bytes.split_at(N);
+    let len = len.iter().fold(0, |len, byte| len << 8 | *byte as usize);
+    Ok((len, rest))
+}
+
+/// The entity tag of a value sent in the given format: its checksum, which changes along with it,
+/// tagged with the format unless it is sent raw, as each format makes a different body of it.
+fn etag(format: ValueFormat, checksum: u32) -> String {
+    match format {
+        ValueFormat::Raw => format!("\"{:08x}\"", checksum),
+        ValueFormat::Json => format!("\"{:08x}-json\"", checksum),
+        ValueFormat::MsgPack => format!("\"{:08x}-msgpack\"", checksum),
+    }
+}
+
+/// Returns the value of the key in the format the `Accept` header asks for, along with its
+/// entity tag in that format, which clients can check the value against, and the seconds left
+/// before it expires in the `X-TTL` header, rounded up, if it was inserted with a TTL. Answers
+/// with 304 and no value if the `If-None-Match` header lists the tag, and with 500 if the value
+/// is corrupt.
 async fn kv_get(
     State(storage): State<Storage>,
     Path(key): Path<String>,
-) -> Result<String, StatusCode> {
-    let value = storage
-        .read(&key)
-        .and_then(|bytes| String::from_utf8(bytes).ok());
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let verified = blocking(move || storage.read_verified(&key))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let format = ValueFormat::accepted(&headers);
+    let etag = etag(format, verified.checksum);
 
-    match value {
-        Some(value) => Ok(value),
-        None => Err(StatusCode::NOT_FOUND),
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    if not_modified {
+        let headers = [(header::ETAG, etag), (header::VARY, header::ACCEPT.to_string())];
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
     }
+
+    let headers = [
+        (header::CONTENT_TYPE, format.media_type().to_owned()),
+        (header::ETAG, etag),
+        (header::VARY, header::ACCEPT.to_string()),
+    ];
+
+    let mut response = (headers, format.encode(verified.value)).into_response();
+    if let Some(ttl) = verified.ttl {
+        let seconds = u64::try_from(ttl.as_millis().div_ceil(1000)).unwrap_or(u64::MAX);
+        response.headers_mut().insert(TTL_HEADER, HeaderValue::from(seconds));
+    }
+
+    Ok(response)
 }
 
+/// The header carrying the TTL of a value, in seconds.
+const TTL_HEADER: &str = "x-ttl";
+
+#[derive(Deserialize)]
+struct InsertParams {
+    ttl: Option<String>,
+}
+
+/// Inserts the value in the body, in the format its `Content-Type` header tells. The value
+/// expires after the seconds given by the `ttl` parameter, or else by the `X-TTL` header, if any.
 async fn kv_insert(
-    State(mut storage): State<Storage>,
+    State(storage): State<Storage>,
+    Extension(replication): Extension<Replication>,
     Path(key): Path<String>,
-    body: String,
-) -> Result<(), StatusCode> {
-    storage.insert(key, body.into_bytes()).unwrap();
+    Query(params): Query<InsertParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(), (StatusCode, String)> {
+    let value = ValueFormat::sent(&headers)
+        .decode(&body)
+        .map_err(|error| (StatusCode::BAD_REQUEST, format!("{:#}", error)))?;
 
-    Ok(())
+    let ttl = match params.ttl {
+        Some(ttl) => Some(ttl),
+        None => headers
+            .get(TTL_HEADER)
+            .map(|ttl| ttl.to_str().map(str::to_owned))
+            .transpose()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "invalid TTL".to_owned()))?,
+    };
+
+    let ttl = match ttl {
+        Some(ttl) => {
+            let seconds = ttl
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|&seconds| seconds > 0)
+                .ok_or_else(|| {
+                    let message = format!("invalid TTL {:?}: expected a positive number of seconds", ttl);
+                    (StatusCode::BAD_REQUEST, message)
+                })?;
+
+            Some(Duration::from_secs(seconds))
+        }
+        None => None,
+    };
+
+    if let Some(member) = &replication.raft {
+        let inserted = member
+            .run(move |raft| match ttl {
+                Some(ttl) => raft.insert_with_ttl(key, value, ttl),
+                None => raft.insert(key, value),
+            })
+            .await;
+
+        return inserted.map_err(insert_error);
+    }
+
+    let inserted = blocking(move || match ttl {
+        Some(ttl) => storage.insert_with_ttl(key, value, ttl),
+        None => storage.insert(key, value),
+    });
+
+    inserted.await.map_err(insert_error)
 }
 
 async fn kv_delete(
-    State(mut storage): State<Storage>,
+    State(storage): State<Storage>,
+    Extension(replication): Extension<Replication>,
     Path(key): Path<String>
-) -> Result<(), StatusCode> {
-    storage.remove(key).unwrap();
+) -> Result<(), (StatusCode, String)> {
+    match &replication.raft {
+        Some(member) => member.run(move |raft| raft.remove(key)).await.map_err(insert_error),
+        None => blocking(move || storage.remove(key)).await.map_err(internal_error),
+    }
+}
 
-    Ok(())
-}
\ No newline at end of file
+#[derive(Deserialize)]
+struct ScanParams {
+    #[serde(default)]
+    from: String,
+    limit: Option<usize>,
+    max_bytes: Option<usize>,
+    continuation: Option<String>,
+}
+
+/// The limits the server holds every page of a scan to, on top of those of the request. See
+/// `--max-scan-keys`, `--max-scan-bytes` and `--scan-timeout`.
+#[derive(Debug, Clone, Copy)]
+struct ScanBudget {
+    max_keys: Option<usize>,
+    max_bytes: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+/// An entry, as listed by scans and inserted by batches. Values that aren't UTF-8 are listed
+/// lossily.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+/// A page of a scan, along with the token to pass to get the next one, if any.
+#[derive(Serialize)]
+struct ScanPage {
+    entries: Vec<Entry>,
+    continuation: Option<String>,
+}
+
+/// Lists the keys and values in key order, up to `limit` of them or `max_bytes` of keys and
+/// values, from the `from` parameter on or from where the page that returned the `continuation`
+/// parameter left off. The page ends early, with a continuation, once it reaches the budget of
+/// the server. See [`Storage::scan_with`].
+async fn kv_scan(
+    State(storage): State<Storage>,
+    Extension(budget): Extension<ScanBudget>,
+    Query(params): Query<ScanParams>,
+) -> Result<Json<ScanPage>, (StatusCode, String)> {
+    let smallest = |a: Option<usize>, b: Option<usize>| a.into_iter().chain(b).min();
+    let options = ScanOptions {
+        max_keys: smallest(params.limit, budget.max_keys),
+        max_bytes: smallest(params.max_bytes, budget.max_bytes),
+        deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+    };
+
+    let page = blocking(move || storage.scan_with(&params.from, params.continuation.as_deref(), &options))
+        .await
+        .map_err(|error| match error.downcast_ref::<Error>() {
+            Some(Error::InvalidContinuation) => (StatusCode::BAD_REQUEST, format!("{:#}", error)),
+            _ => internal_error(error),
+        })?;
+
+    let entries = page
+        .entries
+        .into_iter()
+        .map(|(key, value)| Entry {
+            key,
+            value: String::from_utf8_lossy(&value).into_owned(),
+        })
+        .collect();
+
+    Ok(Json(ScanPage { entries, continuation: page.continuation }))
+}
+
+#[derive(Deserialize)]
+struct KeysParams {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+    after: Option<String>,
+}
+
+/// Lists the keys starting with `prefix` in key order, without their values, up to `limit` of
+/// them, from the one after `after` on. The next page starts after the last key listed. Lists no
+/// more keys than the pages of /scan may hold. See [`Storage::keys`].
+async fn kv_keys(
+    State(storage): State<Storage>,
+    Extension(budget): Extension<ScanBudget>,
+    Query(params): Query<KeysParams>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let limit = params.limit.into_iter().chain(budget.max_keys).min().unwrap_or(usize::MAX);
+
+    let keys = blocking(move || {
+        storage
+            .keys(&params.prefix, params.after.as_deref())
+            .and_then(|keys| keys.take(limit).collect())
+    });
+
+    keys.await.map(Json).map_err(internal_error)
+}
+
+/// The keys from `from` on, up to `to` if given, excluding it.
+#[derive(Deserialize)]
+struct RangeParams {
+    #[serde(default)]
+    from: String,
+    to: Option<String>,
+}
+
+impl RangeParams {
+    fn bounds(&self) -> (Bound<&str>, Bound<&str>) {
+        let end = self.to.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+
+        (Bound::Included(self.from.as_str()), end)
+    }
+}
+
+/// Counts the keys in the range, without listing them. See [`Storage::count`].
+async fn kv_count(
+    State(storage): State<Storage>,
+    Query(params): Query<RangeParams>,
+) -> Result<Json<u64>, (StatusCode, String)> {
+    blocking(move || storage.count(params.bounds())).await.map(Json).map_err(internal_error)
+}
+
+/// Counts the keys in the range, and finds the smallest and largest of them and the bytes of
+/// their values. See [`Storage::aggregate`].
+async fn kv_aggregate(
+    State(storage): State<Storage>,
+    Query(params): Query<RangeParams>,
+) -> Result<Json<Aggregate>, (StatusCode, String)> {
+    blocking(move || storage.aggregate(params.bounds())).await.map(Json).map_err(internal_error)
+}
+
+/// Inserts the entries of a JSON array in order, through a single writer, so that no other write
+/// lands between them.
+async fn kv_batch(
+    State(storage): State<Storage>,
+    Extension(replication): Extension<Replication>,
+    Json(entries): Json<Vec<Entry>>,
+) -> Result<(), (StatusCode, String)> {
+    if let Some(member) = &replication.raft {
+        let entries = entries.into_iter().map(|entry| (entry.key, entry.value.into_bytes()));
+
+        return member.run(move |raft| raft.insert_batch(entries)).await.map_err(insert_error);
+    }
+
+    let inserted = blocking(move || {
+        let mut writer = storage.wait_for_writer();
+
+        for entry in entries {
+            writer.insert(entry.key, entry.value.into_bytes())?;
+        }
+
+        Ok(())
+    });
+
+    inserted.await.map_err(insert_error)
+}
+
+/// Reads the keys of a JSON array at once, returning a JSON array of their values in the same
+/// order, with null for the missing keys. Values that aren't UTF-8 are returned lossily. See
+/// [`Storage::multi_get`].
+async fn kv_mget(
+    State(storage): State<Storage>,
+    Json(keys): Json<Vec<String>>,
+) -> Result<Json<Vec<Option<String>>>, (StatusCode, String)> {
+    let values = blocking(move || {
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        storage.multi_get(&keys, &ReadOptions::default())
+    });
+    let values = values.await.map_err(internal_error)?;
+
+    let values = values
+        .into_iter()
+        .map(|value| value.map(|value| String::from_utf8_lossy(&value).into_owned()))
+        .collect();
+
+    Ok(Json(values))
+}
+
+/// Streams the updates of a key as server-sent events: an `update` event carrying the new value,
+/// or a `delete` event when the key is removed.
+async fn kv_watch(
+    State(storage): State<Storage>,
+    Path(key): Path<String>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = storage.watch(&key).map(|update| {
+        let event = match update {
+            Some(value) => Event::default()
+                .event("update")
+                .data(String::from_utf8_lossy(&value)),
+            None => Event::default().event("delete"),
+        };
+
+        Ok(event)
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize)]
+struct SubscribeParams {
+    #[serde(default)]
+    prefix: String,
+    after: Option<u64>,
+}
+
+/// The data of an event of /subscribe. Deletes carry no value.
+#[derive(Serialize)]
+struct ChangeData {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+/// Streams the committed changes of the keys starting with `prefix` as server-sent events: an
+/// `update` or `delete` event whose id is the sequence number of the change. Resumes after the
+/// sequence number given by `after`, or by the `Last-Event-ID` header a reconnecting client
+/// sends, answering 410 if those changes were flushed already. A client that falls too far behind
+/// gets a `lost` event with the number of changes it missed. See [`Storage::subscribe`].
+async fn kv_subscribe(
+    State(storage): State<Storage>,
+    headers: HeaderMap,
+    Query(params): Query<SubscribeParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .map(|id| id.to_str().ok().and_then(|id| id.parse().ok()))
+        .map(|id| id.ok_or((StatusCode::BAD_REQUEST, "invalid Last-Event-ID".to_owned())))
+        .transpose()?;
+
+    let changes = blocking(move || storage.subscribe(&params.prefix, last_event_id.or(params.after)))
+        .await
+        .map_err(|error| match error.downcast_ref::<Error>() {
+            Some(Error::ChangesUnavailable { .. }) => (StatusCode::GONE, format!("{:#}", error)),
+            _ => internal_error(error),
+        })?;
+
+    let events = changes.map(|change| {
+        let event = match change {
+            Ok(change) => {
+                let kind = if change.value.is_some() { "update" } else { "delete" };
+                let data = ChangeData {
+                    key: change.key,
+                    value: change.value.map(|value| String::from_utf8_lossy(&value).into_owned()),
+                };
+
+                Event::default()
+                    .id(change.seqno.to_string())
+                    .event(kind)
+                    .data(serde_json::to_string(&data).expect("a change always serializes"))
+            }
+            Err(error) => match error.downcast_ref::<Error>() {
+                Some(Error::ChangesLost { missed }) => Event::default().event("lost").data(missed.to_string()),
+                _ => Event::default().event("error").data(format!("{:#}", error)),
+            },
+        };
+
+        Ok(event)
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Reports every property of the storage, as a JSON object from their names to their values.
+async fn stats(State(storage): State<Storage>) -> Result<Json<BTreeMap<&'static str, String>>, StatusCode> {
+    let properties = blocking(move || {
+        let mut properties = BTreeMap::new();
+
+        for name in PROPERTIES {
+            let value = storage.property(name)?;
+            properties.extend(value.map(|value| (*name, value)));
+        }
+
+        anyhow::Ok(properties)
+    });
+
+    properties.await.map(Json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Reports the stats of the storage in the Prometheus text format, with the reads and writes of
+/// each tenant labelled by it.
+async fn metrics(State(storage): State<Storage>) -> Result<([(header::HeaderName, &'static str); 1], String), (StatusCode, String)> {
+    let stats = blocking(move || storage.stats()).await.map_err(internal_error)?;
+    let mut metrics = String::new();
+
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        metrics.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in samples {
+            metrics.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    family(
+        "lsm_bytes_written_total",
+        "counter",
+        "Bytes written since the storage was opened, by who wrote them.",
+        vec![
+            ("{source=\"user\"}".to_owned(), stats.user_bytes_written),
+            ("{source=\"flush\"}".to_owned(), stats.flush_bytes_written),
+            ("{source=\"compaction\"}".to_owned(), stats.compaction_bytes_written),
+        ],
+    );
+    family(
+        "lsm_dropped_versions_total",
+        "counter",
+        "Versions dropped by compactions past their retention.",
+        vec![(String::new(), stats.dropped_versions)],
+    );
+    family(
+        "lsm_sstable_bytes",
+        "gauge",
+        "The size of all the sstables.",
+        vec![(String::new(), stats.sstable_bytes)],
+    );
+    family(
+        "lsm_frozen_memtables",
+        "gauge",
+        "Frozen memtables waiting to be persisted.",
+        vec![(String::new(), stats.frozen_memtables as u64)],
+    );
+    family(
+        "lsm_write_throttle_milliseconds_total",
+        "counter",
+        "Time writes waited for the write rate limits.",
+        vec![(String::new(), stats.write_throttle.as_millis() as u64)],
+    );
+
+    let tenant = |tenant: &str| format!("{{tenant=\"{}\"}}", escape_label(tenant));
+    let per_tenant = |value: fn(&TenantStats) -> u64| {
+        stats.tenants.iter().map(|(name, stats)| (tenant(name), value(stats))).collect()
+    };
+    family("lsm_tenant_reads_total", "counter", "Keys read, by tenant.", per_tenant(|stats| stats.reads));
+    family("lsm_tenant_read_bytes_total", "counter", "Bytes of the values read, by tenant.", per_tenant(|stats| stats.read_bytes));
+    family("lsm_tenant_writes_total", "counter", "Inserts and removals, by tenant.", per_tenant(|stats| stats.writes));
+    family("lsm_tenant_write_bytes_total", "counter", "Bytes of the keys and values written, by tenant.", per_tenant(|stats| stats.write_bytes));
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics))
+}
+
+/// Escapes the value of a Prometheus label.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// What an API key allows its bearer to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    /// Reading keys, watching them and reading the stats.
+    Read,
+    /// Inserting and removing keys.
+    Write,
+    /// The maintenance operations under `/admin`.
+    Admin,
+}
+
+impl Scope {
+    /// The scope a request needs. Multi-gets are posted, as their keys are in the body, but only
+    /// read.
+    fn of<B>(request: &Request<B>) -> Self {
+        if request.uri().path().starts_with("/admin") {
+            Scope::Admin
+        } else if request.method() == Method::GET || request.uri().path() == "/mget" {
+            Scope::Read
+        } else {
+            Scope::Write
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(scope: &str) -> Result<Self> {
+        match scope {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => bail!("unknown scope {}", scope),
+        }
+    }
+}
+
+/// The API keys accepted by the server, along with the scopes each of them grants.
+///
+/// Keys are listed as `<key>:<scope>[,<scope>...]`, separated by whitespace, in the `LSM_API_KEYS`
+/// variable and in the file named by `--api-keys-file`, where lines starting with `#` are
+/// ignored. The scopes are `read`, `write` and `admin`.
+///
+/// Requests carry their key either as a bearer token or in the `X-API-Key` header. Without any
+/// key configured, reads and writes are open to anyone, and the admin routes to no one.
+#[derive(Debug, Default)]
+struct ApiKeys {
+    scopes: HashMap<String, Vec<Scope>>,
+}
+
+impl ApiKeys {
+    fn load(file: Option<&FsPath>) -> Result<Self> {
+        let mut keys = ApiKeys::default();
+
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            let lines = contents.lines().filter(|line| !line.trim_start().starts_with('#'));
+
+            for line in lines {
+                keys.parse(line).with_context(|| format!("parsing {}", path.display()))?;
+            }
+        }
+
+        if let Ok(list) = std::env::var(API_KEYS_VAR) {
+            keys.parse(&list).with_context(|| format!("parsing {}", API_KEYS_VAR))?;
+        }
+
+        Ok(keys)
+    }
+
+    fn parse(&mut self, list: &str) -> Result<()> {
+        for entry in list.split_whitespace() {
+            let (key, scopes) = entry
+                .split_once(':')
+                .with_context(|| format!("{} has no scopes", entry))?;
+            let scopes = scopes.split(',').map(str::parse).collect::<Result<_>>()?;
+
+            self.scopes.insert(key.to_owned(), scopes);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the request is allowed, or why not.
+    fn check<B>(&self, request: &Request<B>) -> Result<(), StatusCode> {
+        let scope = Scope::of(request);
+
+        if self.scopes.is_empty() {
+            return match scope {
+                Scope::Admin => Err(StatusCode::FORBIDDEN),
+                _ => Ok(()),
+            };
+        }
+
+        let headers = request.headers();
+        let key = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| headers.get("x-api-key").and_then(|value| value.to_str().ok()));
+
+        match key.and_then(|key| self.scopes.get(key)) {
+            Some(scopes) if scopes.contains(&scope) => Ok(()),
+            Some(_) => Err(StatusCode::FORBIDDEN),
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Rejects the requests whose API key doesn't grant the scope they need.
+async fn authenticate<B>(
+    State(keys): State<Arc<ApiKeys>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    keys.check(&request)?;
+
+    Ok(next.run(request).await)
+}
+
+/// The limits the requests to a group of routes are held to.
+struct RequestLimits {
+    timeout: Option<Duration>,
+    /// The permits of the requests in flight, if their number is limited.
+    slots: Option<Arc<Semaphore>>,
+}
+
+/// The limits of the routes outside /admin, and those of the /admin routes.
+#[derive(Clone)]
+struct RouteLimits {
+    data: Arc<RequestLimits>,
+    admin: Arc<RequestLimits>,
+}
+
+/// Answers with 503 the requests that would go past the number allowed in flight, and those
+/// that time out, e.g. while the engine is stalled, instead of letting them queue up on it.
+///
+/// Handlers call the storage from blocking tasks, see [`blocking`], so that the timeout still
+/// fires while they wait on a stalled engine. A request that may time out runs in a task of its
+/// own, which it keeps running in after timing out, holding on to its permit until it is done.
+async fn limit_requests<B: Send + 'static>(
+    State(limits): State<Arc<RequestLimits>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let permit = match &limits.slots {
+        Some(slots) => match slots.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return unavailable("too many requests in flight"),
+        },
+        None => None,
+    };
+
+    let timeout = match limits.timeout {
+        Some(timeout) => timeout,
+        None => return next.run(request).await,
+    };
+
+    let handler = tokio::spawn(async move {
+        let _permit = permit;
+        next.run(request).await
+    });
+
+    match tokio::time::timeout(timeout, handler).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(error)) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+        Err(_) => unavailable("the request timed out"),
+    }
+}
+
+/// Makes a call to the storage from a blocking task, as it may wait on the engine, e.g. while
+/// writes stall, which would otherwise hold up every request sharing the worker thread, along
+/// with their timeouts.
+async fn blocking<T: Send + 'static>(call: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(call).await {
+        Ok(result) => result,
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    }
+}
+
+/// A 503 response telling the client to retry after a second.
+fn unavailable(reason: &str) -> Response {
+    let headers = [(header::RETRY_AFTER, "1")];
+
+    (StatusCode::SERVICE_UNAVAILABLE, headers, reason.to_owned()).into_response()
+}
+
+fn internal_error(error: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", error))
+}
+
+/// Like [`internal_error`], but reports inserts over the quota of their prefix or too large to be
+/// stored as such, and the writes of a member of a Raft cluster that stopped leading it before
+/// they committed as unavailable.
+fn insert_error(error: anyhow::Error) -> (StatusCode, String) {
+    match error.downcast_ref::<Error>() {
+        Some(Error::QuotaExceeded { .. }) => (StatusCode::INSUFFICIENT_STORAGE, format!("{:#}", error)),
+        Some(Error::NotLeader { .. }) => (StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", error)),
+        Some(Error::TooLarge { .. }) => (StatusCode::PAYLOAD_TOO_LARGE, format!("{:#}", error)),
+        Some(Error::InvalidKey { .. }) => (StatusCode::BAD_REQUEST, format!("{:#}", error)),
+        _ => internal_error(error),
+    }
+}
+
+/// Freezes the active memtable and schedules its persistence.
+async fn admin_flush(State(storage): State<Storage>) -> Result<(), (StatusCode, String)> {
+    blocking(move || storage.flush()).await.map_err(internal_error)
+}
+
+/// Compacts L0 into L1, returning once the compaction is done.
+async fn admin_compact(State(storage): State<Storage>) -> Result<(), (StatusCode, String)> {
+    blocking(move || storage.compact()).await.map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct PurgeParams {
+    start: Option<String>,
+    end: Option<String>,
+}
+
+/// Erases the values removed from the keys between the `start` and `end` parameters, both
+/// included, from the files of the storage, reporting what it rewrote.
+async fn admin_purge(
+    State(storage): State<Storage>,
+    Query(params): Query<PurgeParams>,
+) -> Result<Json<PurgeReport>, (StatusCode, String)> {
+    let purged = blocking(move || {
+        let start = params.start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+        let end = params.end.as_deref().map_or(Bound::Unbounded, Bound::Included);
+
+        storage.purge_deleted((start, end))
+    });
+
+    purged.await.map(Json).map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct CheckpointParams {
+    dir: PathBuf,
+}
+
+/// Writes a copy of the storage into the directory given by the `dir` parameter.
+async fn admin_checkpoint(
+    State(storage): State<Storage>,
+    Query(params): Query<CheckpointParams>,
+) -> Result<(), (StatusCode, String)> {
+    blocking(move || storage.checkpoint(&params.dir)).await.map_err(internal_error)
+}
+
+/// Describes the sstables of each level.
+async fn admin_lsm(State(storage): State<Storage>) -> Result<Json<Vec<Vec<TableInfo>>>, (StatusCode, String)> {
+    blocking(move || storage.levels()).await.map(Json).map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct HotKeysParams {
+    n: Option<usize>,
+}
+
+/// Lists the keys read the most, from the hottest on, up to the `n` parameter or 10 of them.
+async fn admin_hot_keys(State(storage): State<Storage>, Query(params): Query<HotKeysParams>) -> Json<Vec<HotKey>> {
+    Json(blocking(move || storage.top_keys(params.n.unwrap_or(10))).await)
+}
+
+/// How the server takes part in replication.
+#[derive(Clone)]
+struct Replication {
+    /// Where the checkpoints sent to followers are written: inside the data directory, as their
+    /// sstables are hard-linked.
+    checkpoints: Arc<PathBuf>,
+    /// How far this instance lags behind its leader, if it follows one, which makes it refuse the
+    /// writes of its clients.
+    following: Option<Arc<Lag>>,
+    /// This instance as a member of a Raft cluster, if it is one, which the writes go through.
+    raft: Option<Arc<Member>>,
+}
+
+/// How far a follower lags behind its leader, in writes.
+struct Lag {
+    /// The sequence number of the last write of the leader, as of its last `head` event, or
+    /// [`Lag::UNKNOWN`] while the follower isn't connected to it.
+    leader: AtomicU64,
+    /// The sequence number of the last write of the leader the follower applied.
+    applied: AtomicU64,
+}
+
+impl Lag {
+    const UNKNOWN: u64 = u64::MAX;
+
+    fn new() -> Self {
+        Lag {
+            leader: AtomicU64::new(Lag::UNKNOWN),
+            applied: AtomicU64::new(0),
+        }
+    }
+
+    /// How many writes of the leader the follower hasn't applied yet, unless it isn't connected to
+    /// the leader, which leaves it unknown.
+    fn writes(&self) -> Option<u64> {
+        match self.leader.load(Ordering::Relaxed) {
+            Lag::UNKNOWN => None,
+            leader => Some(leader.saturating_sub(self.applied.load(Ordering::Relaxed))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StalenessParams {
+    max_staleness: Option<u64>,
+}
+
+/// Answers with 503 the reads of a follower that lags further behind its leader than the number
+/// of writes allowed by their `max_staleness` parameter, or that isn't connected to its leader,
+/// which leaves its lag unknown. Leaders, which never lag, ignore the parameter.
+async fn bound_staleness<B>(State(lag): State<Arc<Lag>>, request: Request<B>, next: Next<B>) -> Response {
+    let max_staleness = match Query::<StalenessParams>::try_from_uri(request.uri()) {
+        Ok(Query(params)) => params.max_staleness,
+        Err(rejection) => return (StatusCode::BAD_REQUEST, rejection.body_text()).into_response(),
+    };
+
+    match (max_staleness, lag.writes()) {
+        (Some(_), None) => unavailable("this instance lost its leader, so its lag is unknown"),
+        (Some(max_staleness), Some(writes)) if writes > max_staleness => {
+            unavailable(&format!("this instance lags {} writes behind its leader", writes))
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// Refuses the writes of clients on a follower, whose storage only applies those of its leader.
+async fn refuse_writes<B>(request: Request<B>, next: Next<B>) -> Result<Response, (StatusCode, String)> {
+    if Scope::of(&request) == Scope::Write {
+        return Err((StatusCode::FORBIDDEN, "this instance follows a leader, which takes the writes".to_owned()));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+struct ReplicateParams {
+    #[serde(default)]
+    after: u64,
+}
+
+/// The data of a `change` event of /admin/replicate. Values are base64-encoded, as they may be
+/// any bytes, and deletes carry none.
+#[derive(Serialize, Deserialize)]
+struct ReplicatedChange {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    /// When the value expires, in milliseconds since the UNIX epoch, if it was inserted with a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// The media type of the checkpoints /admin/replicate answers with.
+const CHECKPOINT_MEDIA_TYPE: &str = "application/x-lsm-checkpoint";
+/// The header carrying the sequence number of the last write a checkpoint holds.
+const SEQNO_HEADER: &str = "x-seqno";
+/// How many writes /admin/replicate reads ahead of a follower.
+const REPLICATION_BUFFER: usize = 1024;
+/// How long /admin/replicate waits before looking for new writes again once it sent them all.
+const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Catches up a follower whose last write is the one given by `after`: streams the writes after
+/// it as they are committed, as server-sent `change` events whose id is their sequence number,
+/// or else, if the WALs don't hold them anymore, answers with a checkpoint of the storage, along
+/// with the sequence number of its last write in the `X-Seqno` header. Whenever the follower got
+/// all the writes committed so far, a `head` event tells the sequence number of the last one, so
+/// that the follower knows how far it lags. A follower that falls so far behind that writes are
+/// lost gets a `lost` event with their number, and has to catch up again. See
+/// [`Storage::catch_up`].
+///
+/// A checkpoint is sent as each of its files in turn: a line holding the name of the file and its
+/// size in bytes, separated by a space, followed by its contents.
+async fn admin_replicate(
+    State(storage): State<Storage>,
+    Extension(replication): Extension<Replication>,
+    Query(params): Query<ReplicateParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let dir = tempfile::Builder::new()
+        .prefix(".replica-")
+        .tempdir_in(replication.checkpoints.as_path())
+        .map_err(|error| internal_error(error.into()))?;
+
+    let (path, catching_up) = (dir.path().to_path_buf(), storage.clone());
+    let caught_up = blocking(move || catching_up.catch_up(params.after, &path));
+
+    match caught_up.await.map_err(internal_error)? {
+        CatchUp::Resume(tail) => {
+            let events = Sse::new(replicated_changes(storage, tail)).keep_alive(KeepAlive::default());
+
+            Ok(events.into_response())
+        }
+        CatchUp::Checkpoint { seqno } => {
+            let mut response = StreamBody::new(checkpoint_files(dir)).into_response();
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(CHECKPOINT_MEDIA_TYPE));
+            headers.insert(SEQNO_HEADER, HeaderValue::from(seqno));
+
+            Ok(response)
+        }
+    }
+}
+
+/// Sends the writes the tail yields as `change` events, along with a `head` event whenever the
+/// last write of the storage changed once they were all sent, polling the tail from a blocking
+/// task until the follower disconnects or the tail fails.
+fn replicated_changes(storage: Storage, mut tail: Box<WalTail>) -> impl Stream<Item = Result<Event, Infallible>> {
+    let (sender, receiver) = mpsc::channel(REPLICATION_BUFFER);
+    let mut head = None;
+
+    tokio::task::spawn_blocking(move || loop {
+        let (event, failed) = match tail.poll() {
+            Ok(Some(change)) => {
+                let data = ReplicatedChange {
+                    key: change.key,
+                    value: change.value.map(|value| BASE64.encode(value)),
+                    expires_at: change.expires_at,
+                };
+                let event = Event::default()
+                    .id(change.seqno.to_string())
+                    .event("change")
+                    .data(serde_json::to_string(&data).expect("a change always serializes"));
+
+                (event, false)
+            }
+            Ok(None) if sender.is_closed() => return,
+            Ok(None) => {
+                let last = storage.last_seqno();
+                if head == Some(last) {
+                    std::thread::sleep(REPLICATION_POLL_INTERVAL);
+                    continue;
+                }
+
+                head = Some(last);
+                (Event::default().event("head").data(last.to_string()), false)
+            }
+            Err(error) => match error.downcast_ref::<Error>() {
+                Some(Error::ChangesLost { missed }) => (Event::default().event("lost").data(missed.to_string()), true),
+                _ => (Event::default().event("error").data(format!("{:#}", error)), true),
+            },
+        };
+
+        if sender.blocking_send(event).is_err() || failed {
+            return;
+        }
+    });
+
+    ReceiverStream::new(receiver).map(Ok)
+}
+
+/// Sends the files of the checkpoint written into the directory from a blocking task, removing
+/// the directory once they were sent. See [`admin_replicate`].
+fn checkpoint_files(dir: TempDir) -> impl Stream<Item = io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(error) = send_checkpoint(dir.path(), &sender) {
+            // Cuts the response short, so that the follower can tell it is incomplete.
+            let _ = sender.blocking_send(Err(error));
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+fn send_checkpoint(dir: &FsPath, sender: &mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let mut file = File::open(&path)?;
+        let header = format!("{} {}\n", name, file.metadata()?.len());
+        if sender.blocking_send(Ok(Bytes::from(header))).is_err() {
+            return Ok(());
+        }
+
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            if sender.blocking_send(Ok(Bytes::copy_from_slice(&chunk[..read]))).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How long a follower waits for its leader to send anything, keep-alives included, before it
+/// reconnects.
+const LEADER_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a follower waits before reconnecting to its leader.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Applies the writes of a leader to the storage of this instance. See `--follow`.
+struct Follower {
+    /// The URL of the leader, without a trailing slash.
+    leader: String,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+    lag: Arc<Lag>,
+}
+
+impl Follower {
+    /// Asks the leader how to catch up from the given write. See [`admin_replicate`].
+    fn catch_up(&self, after: u64) -> Result<ureq::Response> {
+        let mut request = self
+            .agent
+            .get(&format!("{}/admin/replicate", self.leader))
+            .query("after", &after.to_string());
+        if let Some(key) = &self.api_key {
+            request = request.set("X-API-Key", key);
+        }
+
+        request.call().map_err(|error| match error {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                anyhow!("{} responded with {}: {}", self.leader, status, body.trim())
+            }
+            error => error.into(),
+        })
+    }
+
+    /// Catches the storage up with the leader before the server starts, replacing it with a
+    /// checkpoint of the leader if the leader can't resume from its last write. Returns the
+    /// storage, along with the sequence number of the last write of the leader it holds.
+    fn start(&self, storage: Storage, options: &Options) -> Result<(Storage, u64)> {
+        let after = storage.last_seqno();
+        let response = self.catch_up(after)?;
+        if response.content_type() != CHECKPOINT_MEDIA_TYPE {
+            return Ok((storage, after));
+        }
+
+        let seqno = response
+            .header(SEQNO_HEADER)
+            .and_then(|seqno| seqno.parse().ok())
+            .context("the checkpoint of the leader has no sequence number")?;
+        storage.close()?;
+
+        let (data_dir, wal_dir) = options.dirs()?;
+        Storage::builder().segments_path(data_dir.clone()).wal_path(wal_dir.clone()).destroy()?;
+        install_checkpoint(BufReader::new(response.into_reader()), &data_dir, &wal_dir)?;
+
+        Ok((options.open()?, seqno))
+    }
+
+    /// Applies the writes of the leader after the given one as they are committed, reconnecting
+    /// whenever the stream breaks. Only returns once the leader can't resume from the last write
+    /// applied anymore, as replacing the storage with a checkpoint takes a restart.
+    fn follow(&self, storage: &Storage, mut after: u64) -> Result<()> {
+        loop {
+            after = after.max(storage.last_seqno());
+            self.lag.applied.store(after, Ordering::Relaxed);
+
+            match self.catch_up(after) {
+                Ok(response) if response.content_type() == CHECKPOINT_MEDIA_TYPE => {
+                    bail!("{} can't resume after write {}: restart to install a checkpoint of it", self.leader, after)
+                }
+                Ok(response) => {
+                    let reader = BufReader::new(response.into_reader());
+                    if let Err(error) = apply_changes(reader, storage, &self.lag) {
+                        eprintln!("following {}: {:#}", self.leader, error);
+                    }
+                }
+                Err(error) => eprintln!("following {}: {:#}", self.leader, error),
+            }
+            self.lag.leader.store(Lag::UNKNOWN, Ordering::Relaxed);
+
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    }
+}
+
+/// Writes the files of a checkpoint sent by /admin/replicate into the data directory, or into the
+/// WAL directory for the WALs.
+fn install_checkpoint(mut reader: impl BufRead, data_dir: &FsPath, wal_dir: &FsPath) -> Result<()> {
+    let mut line = String::new();
+
+    while reader.read_line(&mut line)? > 0 {
+        let (name, len) = line
+            .trim_end()
+            .rsplit_once(' ')
+            .and_then(|(name, len)| Some((name, len.parse::<u64>().ok()?)))
+            .with_context(|| format!("malformed checkpoint file header {:?}", line))?;
+        let name = FsPath::new(name).file_name().context("checkpoint files must be named")?;
+
+        let dir = if lsm_storage::wal::is_wal(FsPath::new(name)) { wal_dir } else { data_dir };
+        let mut file = File::create(dir.join(name))?;
+        let copied = io::copy(&mut (&mut reader).take(len), &mut file)?;
+        if copied < len {
+            bail!("the checkpoint ends within {:?}", name);
+        }
+        file.sync_all()?;
+        line.clear();
+    }
+
+    File::open(data_dir)?.sync_all()?;
+    File::open(wal_dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Applies the `change` events of /admin/replicate read from the stream, until it ends, and tracks
+/// how far the storage lags behind the leader through its `head` events. See [`admin_replicate`].
+fn apply_changes(reader: impl BufRead, storage: &Storage, lag: &Lag) -> Result<()> {
+    let (mut event, mut id, mut data) = (String::new(), String::new(), String::new());
+
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            // Keep-alives are comments, which start with a colon.
+            if let Some((field, value)) = line.split_once(':') {
+                let value = value.strip_prefix(' ').unwrap_or(value).to_owned();
+                match field {
+                    "event" => event = value,
+                    "id" => id = value,
+                    "data" => data.push_str(&value),
+                    _ => {}
+                }
+            }
+            continue;
+        }
+
+        match event.as_str() {
+            "change" => {
+                let change: ReplicatedChange = serde_json::from_str(&data)?;
+                let seqno = id.parse().with_context(|| format!("invalid sequence number {:?}", id))?;
+                storage.apply(Change {
+                    key: change.key,
+                    value: change.value.map(|value| BASE64.decode(value)).transpose()?,
+                    seqno,
+                    expires_at: change.expires_at,
+                })?;
+                lag.applied.store(seqno, Ordering::Relaxed);
+            }
+            "head" => {
+                let head = data.parse().with_context(|| format!("invalid sequence number {:?}", data))?;
+                lag.leader.store(head, Ordering::Relaxed);
+            }
+            "lost" => bail!("{} writes were lost", data),
+            "error" => bail!("the leader failed: {}", data),
+            _ => {}
+        }
+        (event, id, data) = (String::new(), String::new(), String::new());
+    }
+
+    Ok(())
+}
+
+/// How long a member of a Raft cluster waits to connect to another before giving up on the request.
+const MEMBER_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// This instance as a member of a Raft cluster. See `--raft-id`.
+struct Member {
+    raft: Raft,
+    /// The URLs of the members, this one included, without a trailing slash.
+    urls: BTreeMap<NodeId, String>,
+}
+
+impl Member {
+    /// Makes the call from a blocking task, as it waits on the other members.
+    async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        call: impl FnOnce(&Raft) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let member = self.clone();
+
+        tokio::task::spawn_blocking(move || call(&member.raft)).await?
+    }
+}
+
+/// Parses the members of a Raft cluster and their URLs. See `--raft-members`.
+fn parse_members(list: &str) -> Result<BTreeMap<NodeId, String>> {
+    list.split(',')
+        .map(|member| {
+            let (id, url) = member
+                .trim()
+                .split_once('=')
+                .with_context(|| format!("{:?} isn't an <id>=<url> pair", member))?;
+            let id = id.parse().with_context(|| format!("invalid member id {:?}", id))?;
+
+            Ok((id, url.trim_end_matches('/').to_owned()))
+        })
+        .collect()
+}
+
+/// Sends the requests to the members of a Raft cluster that don't lead it to the leader, which
+/// alone takes writes and knows which reads are up to date: redirects them to it with 307, or
+/// answers with 503 while there is none. The leader makes sure that it still leads before
+/// reading. See [`Raft::read_barrier`].
+async fn lead<B>(State(member): State<Arc<Member>>, request: Request<B>, next: Next<B>) -> Response {
+    let leads = match Scope::of(&request) {
+        Scope::Read => member.run(Raft::read_barrier).await,
+        _ => match member.raft.leader() {
+            Some(leader) if leader == member.raft.id() => Ok(()),
+            leader => Err(Error::NotLeader { leader }.into()),
+        },
+    };
+
+    let error = match leads {
+        Ok(()) => return next.run(request).await,
+        Err(error) => error,
+    };
+    let url = match error.downcast_ref::<Error>() {
+        Some(Error::NotLeader { leader: Some(leader) }) => member.urls.get(leader),
+        Some(Error::NotLeader { leader: None }) => return unavailable("the cluster has no leader for now"),
+        _ => return unavailable(&format!("{:#}", error)),
+    };
+
+    match url {
+        Some(url) => {
+            let path = request.uri().path_and_query().map_or("", |path| path.as_str());
+            Redirect::temporary(&format!("{}{}", url, path)).into_response()
+        }
+        None => unavailable(&format!("{:#}", error)),
+    }
+}
+
+/// Answers a vote request of another member of the Raft cluster. See [`Raft::handle_vote`].
+async fn raft_vote(
+    State(member): State<Arc<Member>>,
+    Json(request): Json<VoteRequest>,
+) -> Result<Json<VoteResponse>, (StatusCode, String)> {
+    member.run(move |raft| raft.handle_vote(&request)).await.map(Json).map_err(internal_error)
+}
+
+/// Answers an append request of the leader of the Raft cluster. See [`Raft::handle_append`].
+async fn raft_append(
+    State(member): State<Arc<Member>>,
+    Json(request): Json<AppendRequest>,
+) -> Result<Json<AppendResponse>, (StatusCode, String)> {
+    member.run(move |raft| raft.handle_append(&request)).await.map(Json).map_err(internal_error)
+}
+
+/// Installs the snapshot the leader of the Raft cluster sent in the body, as a checkpoint in the
+/// format of /admin/replicate, with the fields of its request as parameters. See
+/// [`Raft::install_snapshot`].
+async fn raft_snapshot(
+    State(member): State<Arc<Member>>,
+    Query(request): Query<SnapshotRequest>,
+    mut body: BodyStream,
+) -> Result<Json<SnapshotResponse>, (StatusCode, String)> {
+    let dir = member.raft.snapshot_dir().map_err(internal_error)?;
+
+    // The body is spooled to an unnamed file, as installing the checkpoint blocks.
+    let mut spool = tempfile::tempfile_in(dir.path()).map_err(|error| internal_error(error.into()))?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+        spool.write_all(&chunk).map_err(|error| internal_error(error.into()))?;
+    }
+
+    let installed = member
+        .run(move |raft| {
+            spool.rewind()?;
+            install_checkpoint(BufReader::new(spool), dir.path(), dir.path())?;
+            raft.install_snapshot(&request, dir)
+        })
+        .await;
+
+    installed.map(Json).map_err(internal_error)
+}
+
+/// Carries the requests of this member of a Raft cluster to the /admin/raft routes of the others.
+struct HttpTransport {
+    urls: BTreeMap<NodeId, String>,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl HttpTransport {
+    fn post(&self, to: NodeId, route: &str) -> Result<ureq::Request> {
+        let url = self.urls.get(&to).with_context(|| format!("member {} has no URL", to))?;
+        let mut request = self.agent.post(&format!("{}/admin/raft/{}", url, route));
+        if let Some(key) = &self.api_key {
+            request = request.set("X-API-Key", key);
+        }
+
+        Ok(request)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn request_vote(&self, to: NodeId, request: &VoteRequest) -> Result<VoteResponse> {
+        Ok(self.post(to, "vote")?.send_json(request)?.into_json()?)
+    }
+
+    fn append_entries(&self, to: NodeId, request: &AppendRequest) -> Result<AppendResponse> {
+        Ok(self.post(to, "append")?.send_json(request)?.into_json()?)
+    }
+
+    fn install_snapshot(&self, to: NodeId, request: &SnapshotRequest, dir: &FsPath) -> Result<SnapshotResponse> {
+        let response = self
+            .post(to, "snapshot")?
+            .query("term", &request.term.to_string())
+            .query("leader", &request.leader.to_string())
+            .query("last_index", &request.last_index.to_string())
+            .query("last_term", &request.last_term.to_string())
+            .send(checkpoint_reader(dir)?)?;
+
+        Ok(response.into_json()?)
+    }
+}
+
+/// Reads the files of the checkpoint written into the directory in the format of
+/// /admin/replicate. See [`admin_replicate`].
+fn checkpoint_reader(dir: &FsPath) -> io::Result<impl Read> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let file = File::open(&path)?;
+        let header = format!("{} {}\n", name, file.metadata()?.len());
+
+        reader = Box::new(reader.chain(io::Cursor::new(header)).chain(file));
+    }
+
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::{header, Method, Request, StatusCode};
+    use axum::Router;
+    use clap::Parser;
+    use lsm_storage::raft::AppendRequest;
+    use lsm_storage::storage::Storage;
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    use super::{
+        apply_changes, install_checkpoint, limit_requests, parse_members, routes, serve_unix, ApiKeys,
+        ClientCertificate, Lag, Options, RequestLimits, Scope, Tls, ValueFormat, CHECKPOINT_MEDIA_TYPE,
+    };
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+    }
+
+    /// A storage kept in the given directory.
+    fn storage(dir: &TempDir) -> Storage {
+        Storage::builder()
+            .segments_path(dir.path().to_path_buf())
+            .wal_path(dir.path().to_path_buf())
+            .build()
+            .unwrap()
+    }
+
+    /// The routes of the server, as configured by the options, over the given storage.
+    fn app(options: &Options, verifies_clients: bool, storage: &Storage) -> Router {
+        let replication = options.replication(options.follower().as_ref(), options.member(storage).unwrap());
+        routes(verifies_clients, options.scan_budget(), options.route_limits(), replication).with_state(storage.clone())
+    }
+
+    /// Sends the request to the routes, returning the status and the body of the response.
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn requests_need_the_scope_of_what_they_do() {
+        assert_eq!(Scope::of(&request(Method::GET, "/key/a")), Scope::Read);
+        assert_eq!(Scope::of(&request(Method::GET, "/scan")), Scope::Read);
+        assert_eq!(Scope::of(&request(Method::POST, "/key/a")), Scope::Write);
+        assert_eq!(Scope::of(&request(Method::DELETE, "/key/a")), Scope::Write);
+        assert_eq!(Scope::of(&request(Method::POST, "/batch")), Scope::Write);
+
+        // Multi-gets are posted, but only read.
+        assert_eq!(Scope::of(&request(Method::POST, "/mget")), Scope::Read);
+
+        assert_eq!(Scope::of(&request(Method::GET, "/admin/lsm")), Scope::Admin);
+        assert_eq!(Scope::of(&request(Method::POST, "/admin/flush")), Scope::Admin);
+    }
+
+    #[test]
+    fn api_keys_grant_their_scopes_whichever_header_carries_them() {
+        let mut keys = ApiKeys::default();
+        keys.parse("reader:read\nwriter:read,write  admin:admin").unwrap();
+
+        let with_key = |method, uri, header: &str, value: &str| {
+            let mut request = request(method, uri);
+            request.headers_mut().insert(header.parse::<header::HeaderName>().unwrap(), value.parse().unwrap());
+            keys.check(&request)
+        };
+
+        assert_eq!(with_key(Method::GET, "/key/a", "authorization", "Bearer reader"), Ok(()));
+        assert_eq!(with_key(Method::GET, "/key/a", "x-api-key", "reader"), Ok(()));
+        assert_eq!(with_key(Method::POST, "/key/a", "x-api-key", "writer"), Ok(()));
+        assert_eq!(with_key(Method::POST, "/mget", "authorization", "Bearer reader"), Ok(()));
+        assert_eq!(with_key(Method::POST, "/admin/flush", "x-api-key", "admin"), Ok(()));
+
+        // A known key without the scope is forbidden, an unknown or missing one unauthorized.
+        assert_eq!(with_key(Method::POST, "/key/a", "authorization", "Bearer reader"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(with_key(Method::GET, "/admin/lsm", "x-api-key", "writer"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(with_key(Method::GET, "/key/a", "x-api-key", "stranger"), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(with_key(Method::GET, "/key/a", "authorization", "reader"), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(keys.check(&request(Method::GET, "/key/a")), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn without_api_keys_everything_but_the_admin_routes_is_open() {
+        let keys = ApiKeys::default();
+
+        assert_eq!(keys.check(&request(Method::GET, "/key/a")), Ok(()));
+        assert_eq!(keys.check(&request(Method::POST, "/key/a")), Ok(()));
+        assert_eq!(keys.check(&request(Method::POST, "/admin/compact")), Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn api_keys_must_list_known_scopes() {
+        assert!(ApiKeys::default().parse("key").is_err());
+        assert!(ApiKeys::default().parse("key:read,delete").is_err());
+    }
+
+    #[test]
+    fn tls_needs_both_a_certificate_and_a_key() {
+        assert!(Tls::load(&Options::default()).unwrap().is_none());
+
+        let cert = Options { tls_cert: Some(PathBuf::from("cert.pem")), ..Options::default() };
+        let error = Tls::load(&cert).err().unwrap();
+        assert_eq!(error.to_string(), "--tls-cert and --tls-key must be set together");
+
+        let key = Options { tls_key: Some(PathBuf::from("key.pem")), ..Options::default() };
+        assert!(Tls::load(&key).is_err());
+
+        let missing = Options {
+            tls_cert: Some(PathBuf::from("/nonexistent/cert.pem")),
+            tls_key: Some(PathBuf::from("/nonexistent/key.pem")),
+            ..Options::default()
+        };
+        assert!(Tls::load(&missing).err().unwrap().to_string().starts_with("opening /nonexistent/cert.pem"));
+    }
+
+    #[tokio::test]
+    async fn admin_routes_require_a_verified_client_certificate_when_clients_are_verified() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        let options = Options::default();
+        let flush = |verified: Option<bool>| {
+            let mut request = request(Method::POST, "/admin/flush");
+            if let Some(verified) = verified {
+                request.extensions_mut().insert(ClientCertificate { verified });
+            }
+            request
+        };
+
+        let verifying = app(&options, true, &storage);
+        assert_eq!(send(&verifying, flush(None)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&verifying, flush(Some(false))).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&verifying, flush(Some(true))).await.0, StatusCode::OK);
+
+        // The other routes don't.
+        assert_eq!(send(&verifying, request(Method::GET, "/key/a")).await.0, StatusCode::NOT_FOUND);
+
+        let open = app(&options, false, &storage);
+        assert_eq!(send(&open, flush(None)).await.0, StatusCode::OK);
+    }
+
+    #[test]
+    fn the_command_line_takes_precedence_over_the_config_file() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.path().join("lsm.toml");
+        std::fs::write(
+            &config,
+            r#"
+                data-dir = "/from/file"
+                listen = "127.0.0.1:4000"
+                threshold = 5
+                secure-delete = true
+                tenant-separator = "/"
+                request-timeout = 100
+            "#,
+        )
+        .unwrap();
+
+        let options = Options::try_parse_from([
+            "lsm-storage",
+            "--config",
+            config.to_str().unwrap(),
+            "--data-dir",
+            "/from/arguments",
+            "--threshold",
+            "7",
+            "--dedicated-flush-thread",
+        ])
+        .unwrap()
+        .with_config_file()
+        .unwrap();
+
+        assert_eq!(options.data_dir, Some(PathBuf::from("/from/arguments")));
+        assert_eq!(options.threshold, Some(7));
+        assert!(options.dedicated_flush_thread);
+
+        assert_eq!(options.listen, Some("127.0.0.1:4000".parse().unwrap()));
+        assert!(options.secure_delete);
+        assert_eq!(options.tenant_separator, Some('/'));
+        assert_eq!(options.request_timeout, Some(100));
+
+        assert_eq!(options.wal_dir, None);
+        assert_eq!(options.config, Some(config));
+    }
+
+    #[test]
+    fn config_files_with_unknown_options_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let config = dir.path().join("lsm.toml");
+        std::fs::write(&config, "data-dir = \"/data\"\nthreshhold = 5\n").unwrap();
+
+        let options = Options { config: Some(config), ..Options::default() };
+        let error = options.with_config_file().err().unwrap();
+        assert!(format!("{:#}", error).contains("threshhold"), "{:#}", error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_replace_those_left_behind_but_no_other_file() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+        use tokio::sync::watch;
+
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        storage.insert("key".to_owned(), b"value".to_vec()).unwrap();
+        let app = app(&Options::default(), false, &storage);
+        let path = dir.path().join("lsm.sock");
+
+        std::fs::write(&path, b"precious").unwrap();
+        let (_stop, stopping) = watch::channel(());
+        let error = serve_unix(&path, app.clone(), stopping).await.err().unwrap();
+        assert_eq!(error.to_string(), format!("{} exists and is not a socket", path.display()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"precious");
+        std::fs::remove_file(&path).unwrap();
+
+        // A previous run left its socket behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let (stop, stopping) = watch::channel(());
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { serve_unix(&path, app, stopping).await }
+        });
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /key/key HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("value"), "{}", response);
+
+        // Once stopped, the socket is removed.
+        stop.send_replace(());
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn requests_past_the_limits_are_answered_with_503_and_retry_after() {
+        use axum::middleware;
+        use axum::routing::get;
+        use tokio::sync::Semaphore;
+
+        let limited = |limits: RequestLimits| {
+            Router::new()
+                .route("/fast", get(|| async {}))
+                .route("/slow", get(|| tokio::time::sleep(Duration::from_secs(60))))
+                .route_layer(middleware::from_fn_with_state(Arc::new(limits), limit_requests))
+        };
+        let unavailable = |response: &axum::response::Response| {
+            response.status() == StatusCode::SERVICE_UNAVAILABLE && response.headers()[header::RETRY_AFTER] == "1"
+        };
+
+        // Every slot is taken, so requests are shed right away.
+        let slots = Arc::new(Semaphore::new(1));
+        let app = limited(RequestLimits { timeout: None, slots: Some(slots.clone()) });
+        let taken = slots.clone().try_acquire_owned().unwrap();
+        let response = app.clone().oneshot(request(Method::GET, "/fast")).await.unwrap();
+        assert!(unavailable(&response), "{:?}", response);
+
+        drop(taken);
+        let response = app.oneshot(request(Method::GET, "/fast")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A request timing out keeps its slot until it completes.
+        let app = limited(RequestLimits { timeout: Some(Duration::from_millis(10)), slots: Some(slots.clone()) });
+        let response = app.clone().oneshot(request(Method::GET, "/slow")).await.unwrap();
+        assert!(unavailable(&response), "{:?}", response);
+        assert_eq!(slots.available_permits(), 0);
+
+        let response = app.oneshot(request(Method::GET, "/fast")).await.unwrap();
+        assert!(unavailable(&response), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn requests_time_out_while_the_storage_holds_them_up() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        let options = Options { request_timeout: Some(50), ..Options::default() };
+        let app = app(&options, false, &storage);
+
+        // The open writer holds up the insert as a stalled engine would, on the only worker
+        // thread of the runtime.
+        let writer = storage.open_as_writer().unwrap();
+        let insert = Request::builder().method(Method::POST).uri("/key/a").body(Body::from("value")).unwrap();
+        let (status, _) = send(&app, insert).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(send(&app, request(Method::GET, "/key/a")).await.0, StatusCode::NOT_FOUND);
+
+        // The insert goes on once the writer is dropped.
+        drop(writer);
+        for _ in 0..100 {
+            if storage.read("a").unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(storage.read("a").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn values_round_trip_through_every_format() {
+        for len in [0, 1, 31, 32, 255, 256, 65_535, 65_536] {
+            let value: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+            for format in [ValueFormat::Raw, ValueFormat::Json, ValueFormat::MsgPack] {
+                let encoded = format.encode(value.clone());
+                assert_eq!(format.decode(&encoded).unwrap(), value, "{:?} of {} bytes", format, len);
+            }
+        }
+    }
+
+    #[test]
+    fn msgpack_values_are_encoded_as_the_smallest_bin_that_fits() {
+        let header = |len: usize| ValueFormat::MsgPack.encode(vec![0; len])[7..].to_vec();
+
+        assert_eq!(ValueFormat::MsgPack.encode(b"ab".to_vec()), b"\x81\xa5value\xc4\x02ab");
+        assert_eq!(header(255)[..2], [0xc4, 0xff]);
+        assert_eq!(header(256)[..3], [0xc5, 0x01, 0x00]);
+        assert_eq!(header(65_535)[..3], [0xc5, 0xff, 0xff]);
+        assert_eq!(header(65_536)[..5], [0xc6, 0x00, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn msgpack_values_may_be_sent_as_str() {
+        let decode = |value: &[u8]| ValueFormat::MsgPack.decode(&[b"\x81\xa5value", value].concat());
+
+        assert_eq!(decode(b"\xa3abc").unwrap(), b"abc");
+        assert_eq!(decode(b"\xa0").unwrap(), b"");
+        assert_eq!(decode(b"\xd9\x03abc").unwrap(), b"abc");
+        assert_eq!(decode(b"\xda\x00\x03abc").unwrap(), b"abc");
+        assert_eq!(decode(b"\xdb\x00\x00\x00\x03abc").unwrap(), b"abc");
+    }
+
+    #[test]
+    fn malformed_values_are_refused() {
+        let msgpack = |value: &[u8]| ValueFormat::MsgPack.decode(&[b"\x81\xa5value", value].concat());
+
+        // Anything but a map holding nothing but value.
+        assert!(ValueFormat::MsgPack.decode(b"").is_err());
+        assert!(ValueFormat::MsgPack.decode(b"\x81\xa5other\xc4\x00").is_err());
+        assert!(ValueFormat::MsgPack.decode(b"\x82\xa5value\xc4\x00").is_err());
+
+        // Neither bin nor str.
+        assert!(msgpack(b"").is_err());
+        assert!(msgpack(b"\xc0").is_err());
+        assert!(msgpack(b"\x01").is_err());
+
+        // Truncated lengths.
+        assert_eq!(msgpack(b"\xc4").unwrap_err().to_string(), "truncated length");
+        assert_eq!(msgpack(b"\xc5\x00").unwrap_err().to_string(), "truncated length");
+        assert_eq!(msgpack(b"\xdb\x00\x00\x00").unwrap_err().to_string(), "truncated length");
+
+        // Lengths that don't match the bytes that follow.
+        assert_eq!(msgpack(b"\xc4\x05abc").unwrap_err().to_string(), "expected 5 bytes of value, got 3");
+        assert_eq!(msgpack(b"\xa2abc").unwrap_err().to_string(), "expected 2 bytes of value, got 3");
+
+        assert!(ValueFormat::Json.decode(b"{\"value\": \"not base64!\"}").is_err());
+        assert!(ValueFormat::Json.decode(b"{\"other\": \"\"}").is_err());
+    }
+
+    #[tokio::test]
+    async fn multi_gets_return_the_values_in_order_with_null_for_missing_keys() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        storage.insert("a".to_owned(), b"value-a".to_vec()).unwrap();
+        storage.insert("c".to_owned(), b"value-c".to_vec()).unwrap();
+        storage.flush().unwrap();
+        storage.insert("b".to_owned(), b"value-b".to_vec()).unwrap();
+        storage.remove("b".to_owned()).unwrap();
+
+        let mget = Request::builder()
+            .method(Method::POST)
+            .uri("/mget")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"["c", "missing", "a", "b", "c"]"#))
+            .unwrap();
+        let (status, body) = send(&app(&Options::default(), false, &storage), mget).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"["value-c",null,"value-a",null,"value-c"]"#);
+    }
+
+    #[tokio::test]
+    async fn each_format_of_a_value_has_its_own_entity_tag() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        storage.insert("key".to_owned(), b"value".to_vec()).unwrap();
+        let app = app(&Options::default(), false, &storage);
+
+        let get = |accept: &str, if_none_match: Option<&str>| {
+            let mut request = Request::builder().uri("/key/key").header(header::ACCEPT, accept);
+            if let Some(tag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, tag);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let etag = response.headers()[header::ETAG].to_str().unwrap().to_owned();
+                (response.status(), etag)
+            }
+        };
+
+        let (_, raw) = get("application/octet-stream", None).await;
+        let (_, json) = get("application/json", None).await;
+        let (_, msgpack) = get("application/msgpack", None).await;
+        assert!(raw != json && json != msgpack && msgpack != raw);
+
+        assert_eq!(get("application/json", Some(&json)).await.0, StatusCode::NOT_MODIFIED);
+        assert_eq!(get("application/json", Some(&raw)).await.0, StatusCode::OK);
+        assert_eq!(get("application/octet-stream", Some(&json)).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn values_can_be_inserted_with_a_ttl_which_reads_report() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        let app = app(&Options::default(), false, &storage);
+
+        let insert = |uri: &str, ttl: Option<&str>| {
+            let mut request = Request::builder().method(Method::POST).uri(uri);
+            if let Some(ttl) = ttl {
+                request = request.header("x-ttl", ttl);
+            }
+            request.body(Body::from("value")).unwrap()
+        };
+
+        assert_eq!(send(&app, insert("/key/query?ttl=90", None)).await.0, StatusCode::OK);
+        assert_eq!(send(&app, insert("/key/header", Some("30"))).await.0, StatusCode::OK);
+        assert_eq!(send(&app, insert("/key/both?ttl=90", Some("30"))).await.0, StatusCode::OK);
+        assert_eq!(send(&app, insert("/key/none", None)).await.0, StatusCode::OK);
+
+        for (uri, ttl) in [("/key/zero?ttl=0", None), ("/key/bad?ttl=soon", None), ("/key/bad", Some("-1"))] {
+            assert_eq!(send(&app, insert(uri, ttl)).await.0, StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        assert_eq!(storage.read("zero").unwrap(), None);
+        assert_eq!(storage.read("bad").unwrap(), None);
+
+        let ttl_of = |key: &str| {
+            let app = app.clone();
+            let uri = format!("/key/{}", key);
+            async move {
+                let response = app.oneshot(request(Method::GET, &uri)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers().get("x-ttl").map(|ttl| ttl.to_str().unwrap().parse::<u64>().unwrap())
+            }
+        };
+
+        // The seconds left are rounded up, so they only drop once a whole second has gone by.
+        assert!(matches!(ttl_of("query").await, Some(89..=90)));
+        assert!(matches!(ttl_of("header").await, Some(29..=30)));
+        assert!(matches!(ttl_of("both").await, Some(89..=90)));
+        assert_eq!(ttl_of("none").await, None);
+    }
+
+    #[tokio::test]
+    async fn followers_resume_from_the_writes_of_the_leader_or_else_install_its_checkpoint() {
+        use hyper::body::HttpBody;
+
+        let dir = TempDir::new().unwrap();
+        let leader = storage(&dir);
+        let options = Options { data_dir: Some(dir.path().to_path_buf()), ..Options::default() };
+        let app = app(&options, false, &leader);
+
+        leader.insert("a".to_owned(), b"value-a".to_vec()).unwrap();
+        leader.insert_with_ttl("b".to_owned(), vec![0, 255], Duration::from_secs(60)).unwrap();
+        leader.remove("a".to_owned()).unwrap();
+
+        // The writes are streamed as they are committed, so the stream is read until it holds them.
+        let response = app.clone().oneshot(request(Method::GET, "/admin/replicate?after=0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut events = String::new();
+        while events.matches("event:change").count() < 3 || !events.contains("event:head") {
+            events.push_str(std::str::from_utf8(&body.data().await.unwrap().unwrap()).unwrap());
+        }
+
+        let follower_dir = TempDir::new().unwrap();
+        let follower = storage(&follower_dir);
+        let lag = Lag::new();
+        apply_changes(events.as_bytes(), &follower, &lag).unwrap();
+        assert_eq!(follower.last_seqno(), 3);
+        assert_eq!(lag.writes(), Some(0));
+        assert_eq!(follower.read("a").unwrap(), None);
+        let b = follower.read_verified("b").unwrap().unwrap();
+        assert_eq!(b.value, [0, 255]);
+        assert!(b.ttl.is_some());
+
+        // A follower ahead of its leader gets a checkpoint instead, which it can install apart.
+        let response = app.oneshot(request(Method::GET, "/admin/replicate?after=10")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CHECKPOINT_MEDIA_TYPE);
+        assert_eq!(response.headers()["x-seqno"], "3");
+        let checkpoint = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let installed = TempDir::new().unwrap();
+        let (data_dir, wal_dir) = (installed.path().join("data"), installed.path().join("wal"));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::create_dir_all(&wal_dir).unwrap();
+        install_checkpoint(&checkpoint[..], &data_dir, &wal_dir).unwrap();
+        assert!(std::fs::read_dir(&wal_dir).unwrap().count() > 0);
+
+        let copy = Storage::builder().segments_path(data_dir).wal_path(wal_dir).build().unwrap();
+        assert_eq!(copy.read("b").unwrap(), Some(vec![0, 255]));
+        assert_eq!((copy.read("a").unwrap(), copy.last_seqno()), (None, 3));
+    }
+
+    #[tokio::test]
+    async fn followers_refuse_the_writes_of_their_clients() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        storage.insert("key".to_owned(), b"value".to_vec()).unwrap();
+        let options = Options { follow: Some("http://leader:3000".to_owned()), ..Options::default() };
+        let app = app(&options, false, &storage);
+
+        let insert = Request::builder().method(Method::POST).uri("/key/key").body(Body::from("other")).unwrap();
+        assert_eq!(send(&app, insert).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&app, request(Method::DELETE, "/key/key")).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&app, request(Method::GET, "/key/key")).await, (StatusCode::OK, "value".to_owned()));
+        assert_eq!(send(&app, request(Method::POST, "/admin/flush")).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn followers_refuse_the_reads_that_allow_less_staleness_than_they_lag() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        storage.insert("key".to_owned(), b"value".to_vec()).unwrap();
+        let options = Options { follow: Some("http://leader:3000".to_owned()), ..Options::default() };
+        let follower = options.follower().unwrap();
+        let replication = options.replication(Some(&follower), None);
+        let app = routes(false, options.scan_budget(), options.route_limits(), replication).with_state(storage);
+
+        // Until the follower hears from its leader, its lag is unknown, which only bounded reads mind.
+        assert_eq!(send(&app, request(Method::GET, "/key/key")).await.0, StatusCode::OK);
+        let bounded = send(&app, request(Method::GET, "/key/key?max_staleness=100")).await;
+        assert_eq!(bounded.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        follower.lag.leader.store(5, Ordering::Relaxed);
+        follower.lag.applied.store(3, Ordering::Relaxed);
+        assert_eq!(send(&app, request(Method::GET, "/key/key?max_staleness=2")).await.0, StatusCode::OK);
+        assert_eq!(send(&app, request(Method::GET, "/scan?max_staleness=2")).await.0, StatusCode::OK);
+        let lagging = send(&app, request(Method::GET, "/key/key?max_staleness=1")).await;
+        let expected = "this instance lags 2 writes behind its leader";
+        assert_eq!(lagging, (StatusCode::SERVICE_UNAVAILABLE, expected.to_owned()));
+        let count = send(&app, request(Method::GET, "/count?max_staleness=1")).await;
+        assert_eq!(count.0, StatusCode::SERVICE_UNAVAILABLE);
+        let invalid = send(&app, request(Method::GET, "/key/key?max_staleness=soon")).await;
+        assert_eq!(invalid.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn raft_members_are_listed_as_ids_and_urls() {
+        let members = parse_members("1=http://a:3000/, 2=http://b:3000").unwrap();
+        let expected = [(1, "http://a:3000".to_owned()), (2, "http://b:3000".to_owned())];
+        assert_eq!(members, expected.into_iter().collect());
+
+        assert!(parse_members("http://a:3000").is_err());
+        assert!(parse_members("a=http://a:3000").is_err());
+
+        let dir = TempDir::new().unwrap();
+        let options = Options {
+            data_dir: Some(dir.path().to_path_buf()),
+            raft_id: Some(1),
+            raft_members: Some("1=http://a:3000".to_owned()),
+            follow: Some("http://leader:3000".to_owned()),
+            ..Options::default()
+        };
+        assert!(options.member(&storage(&dir)).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_member_of_a_raft_cluster_takes_reads_and_writes_once_it_leads() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        let options = Options {
+            data_dir: Some(dir.path().to_path_buf()),
+            raft_id: Some(1),
+            raft_members: Some("1=http://127.0.0.1:1".to_owned()),
+            ..Options::default()
+        };
+        let app = app(&options, false, &storage);
+
+        let insert = || {
+            let mut request = request(Method::POST, "/key/a?ttl=60");
+            *request.body_mut() = Body::from("1");
+            request
+        };
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while send(&app, insert()).await.0 != StatusCode::OK {
+            assert!(std::time::Instant::now() < deadline, "the member never led");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(send(&app, request(Method::GET, "/key/a")).await, (StatusCode::OK, "1".to_owned()));
+
+        let mut batch = request(Method::POST, "/batch");
+        batch.headers_mut().insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        *batch.body_mut() = Body::from(r#"[{"key": "b", "value": "2"}]"#);
+        assert_eq!(send(&app, batch).await.0, StatusCode::OK);
+        assert_eq!(send(&app, request(Method::DELETE, "/key/a")).await.0, StatusCode::OK);
+
+        assert_eq!(send(&app, request(Method::GET, "/key/a")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(storage.read("b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn members_of_a_raft_cluster_send_the_requests_to_their_leader() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+        let options = Options {
+            data_dir: Some(dir.path().to_path_buf()),
+            raft_id: Some(1),
+            raft_members: Some("1=http://127.0.0.1:1,2=http://b:3000,3=http://127.0.0.1:3".to_owned()),
+            ..Options::default()
+        };
+        let member = options.member(&storage).unwrap();
+        let replication = options.replication(None, member.clone());
+        let app = routes(false, options.scan_budget(), options.route_limits(), replication).with_state(storage);
+
+        // Until a leader is elected, there is none to send them to.
+        let (status, _) = send(&app, request(Method::POST, "/key/a")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(send(&app, request(Method::GET, "/stats")).await.0, StatusCode::OK);
+
+        let heartbeat = AppendRequest {
+            term: 1000,
+            leader: 2,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries: Vec::new(),
+            leader_commit: 0,
+        };
+        assert!(member.unwrap().raft.handle_append(&heartbeat).unwrap().success);
+
+        for request in [request(Method::GET, "/key/a?max_bytes=1"), request(Method::POST, "/key/a?max_bytes=1")] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(response.headers()[header::LOCATION], "http://b:3000/key/a?max_bytes=1");
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::env::{Env, EnvFile};
+use crate::error::Error;
+use crate::format;
+
+/// The name of the manifest, inside the segments path.
+pub(crate) const MANIFEST_NAME: &str = "MANIFEST";
+
+/// The name of the manifest replaced by the last rollover, kept until the next one.
+pub(crate) const OLD_MANIFEST_NAME: &str = "MANIFEST.old";
+
+/// How many edits the manifest holds before it is rolled over, unless it still would be at least
+/// half as long once rolled over.
+const ROLLOVER_EDITS: usize = 1024;
+
+/// A change to the set of sstables of the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Edit {
+    /// A sstable, named after its file, was added to the given level.
+    AddTable { name: String, level: usize },
+    /// Some sstables were replaced by others at once, e.g. by a compaction. The added ones belong
+    /// to the given level.
+    ReplaceTables {
+        removed: Vec<String>,
+        added: Vec<String>,
+        level: usize,
+    },
+    /// Everything stored so far was removed: the sstables, and the memtables with an id below
+    /// `first_memtable`, whose WALs are obsolete.
+    Clear { first_memtable: usize },
+}
+
+/// Replays the edits of a manifest, returning the names of the sstables of each level in the
+/// order they were added.
+pub(crate) fn replay(edits: Vec<Edit>) -> Vec<Vec<String>> {
+    let mut levels: Vec<Vec<String>> = Vec::new();
+
+    for edit in edits {
+        apply(&mut levels, edit);
+    }
+
+    levels
+}
+
+fn apply(levels: &mut Vec<Vec<String>>, edit: Edit) {
+    match edit {
+        Edit::AddTable { name, level } => add_table(levels, name, level),
+        Edit::ReplaceTables { removed, added, level } => {
+            for tables in levels.iter_mut() {
+                tables.retain(|name| !removed.contains(name));
+            }
+
+            for name in added {
+                add_table(levels, name, level);
+            }
+        }
+        Edit::Clear { .. } => levels.clear(),
+    }
+}
+
+/// The id of the oldest memtable that survived the last clear, or 0 if the storage was never
+/// cleared. The WALs of older memtables were left behind by a crash, and must not be recovered.
+pub(crate) fn first_memtable(edits: &[Edit]) -> usize {
+    edits
+        .iter()
+        .filter_map(|edit| match edit {
+            Edit::Clear { first_memtable } => Some(*first_memtable),
+            _ => None,
+        })
+        .next_back()
+        .unwrap_or(0)
+}
+
+fn add_table(levels: &mut Vec<Vec<String>>, name: String, level: usize) {
+    if levels.len() <= level {
+        levels.resize(level + 1, Vec::new());
+    }
+
+    if !levels[level].contains(&name) {
+        levels[level].push(name);
+    }
+}
+
+/// The log of the changes to the set of sstables of the storage.
+///
+/// A sstable only belongs to the storage once the manifest records it: files in the segments path
+/// that it doesn't mention were left behind by a crash, and may be incomplete. Every edit is
+/// synced before `record` returns, so whatever the edit makes redundant, e.g. the WAL of a
+/// persisted memtable, can be removed afterwards.
+///
+/// Once it holds [`ROLLOVER_EDITS`] edits, the manifest is rolled over: replaced at once by a new
+/// one recording the sstables it leaves, and kept as [`OLD_MANIFEST_NAME`] until the next rollover.
+pub(crate) struct Manifest {
+    env: Arc<dyn Env>,
+    path: PathBuf,
+    file: Box<dyn EnvFile>,
+    /// The sstables of each level, as the edits recorded so far leave them.
+    levels: Vec<Vec<String>>,
+    first_memtable: usize,
+    /// How many edits the manifest holds.
+    edits: usize,
+}
+
+impl Manifest {
+    /// Opens the manifest of the given segments path, returning it along with the edits recorded
+    /// so far. A torn edit at the end of the manifest, left by a crash while it was appended, is
+    /// discarded: one cut short by the end of the file, or one that doesn't match its checksum but
+    /// nothing follows. Any other edit that can't be read fails with [`Error::Corruption`], as the
+    /// edits after it can't be trusted to be all there is, and the manifest is left as is, along
+    /// with the [`OLD_MANIFEST_NAME`] kept by the last rollover, for whoever repairs it.
+    ///
+    /// Storages created before the manifest existed don't have one. Their sstables are all
+    /// recorded into L0, as they were always loaded, in a manifest that only replaces the missing
+    /// one once it is complete.
+    pub fn open(env: &Arc<dyn Env>, segments_path: &Path, sstable_names: &[String]) -> Result<(Self, Vec<Edit>)> {
+        let path = segments_path.join(MANIFEST_NAME);
+
+        if !env.read_dir(segments_path)?.contains(&path) {
+            let edits: Vec<Edit> = sstable_names
+                .iter()
+                .map(|name| Edit::AddTable { name: name.clone(), level: 0 })
+                .collect();
+
+            Manifest::create(env.as_ref(), &path, &edits)?;
+            env.sync_dir(segments_path)?;
+        }
+
+        let mut file = env.open_writable(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut edits = Vec::new();
+        let mut valid_len = 0;
+        let mut remaining = contents.as_slice();
+        loop {
+            match format::read_record(&mut remaining) {
+                Ok(Some(edit)) => {
+                    edits.push(edit);
+                    valid_len = (contents.len() - remaining.len()) as u64;
+                }
+                Ok(None) => break,
+                Err(_) if remaining.is_empty() => break,
+                Err(error) => {
+                    return Err(Error::Corruption {
+                        path,
+                        offset: valid_len,
+                        reason: error.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::Start(valid_len))?;
+
+        let manifest = Manifest {
+            env: env.clone(),
+            path,
+            file,
+            levels: replay(edits.clone()),
+            first_memtable: first_memtable(&edits),
+            edits: edits.len(),
+        };
+
+        Ok((manifest, edits))
+    }
+
+    /// Writes a manifest holding the given edits next to `path`, and renames it into place.
+    pub fn create(env: &dyn Env, path: &Path, edits: &[Edit]) -> Result<()> {
+        let temporary = Manifest::write_temporary(env, path, edits)?;
+        env.rename(&temporary, path)?;
+
+        Ok(())
+    }
+
+    /// Writes a manifest holding the given edits next to `path`, and syncs it. Returns its path.
+    fn write_temporary(env: &dyn Env, path: &Path, edits: &[Edit]) -> Result<PathBuf> {
+        let mut temporary = PathBuf::from(path);
+        temporary.set_extension("tmp");
+
+        let mut file = env.create(&temporary)?;
+        for edit in edits {
+            format::write_record(&mut file, edit)?;
+        }
+        file.flush()?;
+        file.sync()?;
+
+        Ok(temporary)
+    }
+
+    /// Appends an edit to the manifest, and syncs it. The manifest is rolled over first if it is
+    /// due, so that the edit is never recorded when rolling over fails.
+    pub fn record(&mut self, edit: &Edit) -> Result<()> {
+        let live = self.levels.iter().map(Vec::len).sum::<usize>() + 1;
+        if self.edits >= ROLLOVER_EDITS.max(live * 2) {
+            self.roll_over()?;
+        }
+
+        format::write_record(&mut self.file, edit)?;
+        self.file.flush()?;
+        self.file.sync()?;
+
+        apply(&mut self.levels, edit.clone());
+        if let Edit::Clear { first_memtable } = edit {
+            self.first_memtable = *first_memtable;
+        }
+        self.edits += 1;
+
+        Ok(())
+    }
+
+    /// Replaces the manifest with one that only records the sstables it leaves, keeping it as
+    /// [`OLD_MANIFEST_NAME`]. A crash at any point leaves either manifest in place, whole.
+    fn roll_over(&mut self) -> Result<()> {
+        let mut snapshot = Vec::new();
+        if self.first_memtable > 0 {
+            snapshot.push(Edit::Clear { first_memtable: self.first_memtable });
+        }
+        for (level, names) in self.levels.iter().enumerate() {
+            snapshot.extend(names.iter().map(|name| Edit::AddTable { name: name.clone(), level }));
+        }
+
+        let env = self.env.as_ref();
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let old = dir.join(OLD_MANIFEST_NAME);
+
+        let temporary = Manifest::write_temporary(env, &self.path, &snapshot)?;
+        if env.read_dir(dir)?.contains(&old) {
+            env.remove_file(&old)?;
+        }
+        env.link(&self.path, &old)?;
+        env.rename(&temporary, &self.path)?;
+        env.sync_dir(dir)?;
+
+        self.file = env.open_writable(&self.path)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.edits = snapshot.len();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use anyhow::Result;
+
+    use super::{first_memtable, replay, Edit, Manifest, MANIFEST_NAME, OLD_MANIFEST_NAME, ROLLOVER_EDITS};
+    use crate::error::Error;
+    use crate::test_utils::Test;
+
+    #[test]
+    fn manifest_replays_its_edits_and_discards_a_torn_one() -> Result<()> {
+        let test = Test::new()?;
+        let env = test.env();
+        let legacy = ["sstable-0".to_owned()];
+
+        let (mut manifest, edits) = Manifest::open(&env, &test.test_path(), &legacy)?;
+        assert_eq!(edits, vec![Edit::AddTable { name: "sstable-0".to_owned(), level: 0 }]);
+
+        manifest.record(&Edit::AddTable { name: "sstable-1".to_owned(), level: 0 })?;
+        drop(manifest);
+
+        OpenOptions::new()
+            .append(true)
+            .open(test.path(MANIFEST_NAME))?
+            .write_all(&[0x20, 0, 0])?;
+
+        let (_, edits) = Manifest::open(&env, &test.test_path(), &[])?;
+        assert_eq!(
+            edits,
+            vec![
+                Edit::AddTable { name: "sstable-0".to_owned(), level: 0 },
+                Edit::AddTable { name: "sstable-1".to_owned(), level: 0 },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_a_corrupt_last_edit_is_discarded() -> Result<()> {
+        let test = Test::new()?;
+        let env = test.env();
+        let add = |name: &str| Edit::AddTable { name: name.to_owned(), level: 0 };
+
+        let (mut manifest, _) = Manifest::open(&env, &test.test_path(), &[])?;
+        for name in ["sstable-1", "sstable-2", "sstable-3"] {
+            manifest.record(&add(name))?;
+        }
+        drop(manifest);
+
+        let path = test.path(MANIFEST_NAME);
+        let flip = |name: &[u8]| -> Result<()> {
+            let mut contents = std::fs::read(&path)?;
+            let position = contents.windows(name.len()).position(|window| window == name).unwrap();
+            contents[position] ^= 1;
+            std::fs::write(&path, contents)?;
+            Ok(())
+        };
+
+        // An edit in the middle is corrupt, so the storage is refused rather than losing the
+        // tables recorded after it.
+        flip(b"sstable-2")?;
+        let len = std::fs::metadata(&path)?.len();
+        let error = Manifest::open(&env, &test.test_path(), &[]).err().unwrap();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Corruption { path: corrupt_path, offset, .. }) => {
+                assert_eq!(*corrupt_path, path);
+                assert!(*offset > 0 && *offset < len);
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), len);
+
+        // Once it is the last one, it is the torn edit of a crash.
+        flip(b"rstable-2")?;
+        flip(b"sstable-3")?;
+        let (_, edits) = Manifest::open(&env, &test.test_path(), &[])?;
+        assert_eq!(edits, vec![add("sstable-1"), add("sstable-2")]);
+        assert!(std::fs::metadata(&path)?.len() < len);
+
+        Ok(())
+    }
+
+    #[test]
+    fn long_manifests_are_rolled_over_keeping_the_previous_one() -> Result<()> {
+        let test = Test::new()?;
+        let env = test.env();
+        let (mut manifest, _) = Manifest::open(&env, &test.test_path(), &[])?;
+
+        manifest.record(&Edit::Clear { first_memtable: 3 })?;
+        // Every sstable flushed is compacted into a new L1 one, replacing the previous one.
+        for i in 1..ROLLOVER_EDITS {
+            let name = format!("sstable-{}", i);
+            manifest.record(&Edit::AddTable { name: name.clone(), level: 0 })?;
+
+            let removed = vec![name, format!("sstable-l1-{}", i - 1)];
+            let added = vec![format!("sstable-l1-{}", i)];
+            manifest.record(&Edit::ReplaceTables { removed, added, level: 1 })?;
+        }
+        manifest.record(&Edit::AddTable { name: "sstable-last".to_owned(), level: 0 })?;
+        drop(manifest);
+
+        let (_, edits) = Manifest::open(&env, &test.test_path(), &[])?;
+        assert!(edits.len() < ROLLOVER_EDITS);
+        assert_eq!(first_memtable(&edits), 3);
+
+        let levels = replay(edits);
+        assert_eq!(levels[0], ["sstable-last"]);
+        assert_eq!(levels[1], [format!("sstable-l1-{}", ROLLOVER_EDITS - 1)]);
+
+        // The previous manifest is whole, and only lacks the edits recorded since.
+        let old = test.test_path().join("old");
+        std::fs::create_dir(&old)?;
+        std::fs::copy(test.path(OLD_MANIFEST_NAME), old.join(MANIFEST_NAME))?;
+
+        let (_, old_edits) = Manifest::open(&env, &old, &[])?;
+        assert_eq!(first_memtable(&old_edits), 3);
+        let old_levels = replay(old_edits);
+        assert!(!old_levels[0].contains(&"sstable-last".to_owned()));
+        assert_eq!(old_levels[1].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replaying_moves_replaced_tables_into_their_level() {
+        let add = |name: &str| Edit::AddTable { name: name.to_owned(), level: 0 };
+        let edits = vec![
+            add("sstable-0"),
+            add("sstable-1"),
+            Edit::ReplaceTables {
+                removed: vec!["sstable-0".to_owned(), "sstable-1".to_owned()],
+                added: vec!["sstable-a".to_owned()],
+                level: 1,
+            },
+            add("sstable-2"),
+        ];
+
+        assert_eq!(replay(edits), vec![vec!["sstable-2".to_owned()], vec!["sstable-a".to_owned()]]);
+    }
+}
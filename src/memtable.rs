@@ -1,11 +1,71 @@
-use crate::format;
+use crate::bloom::{self, BloomFilter};
+use crate::index::IndexBuilder;
+use crate::env::{Env, EnvFile};
+use crate::error::Error;
+use crate::format::{self, WalEntry};
+use crate::wal::{RecoveryMode, WalReplay};
 use crate::Stored;
-use crate::sstable::SSTable;
+use crate::sstable::{SSTable, TableOptions};
 use anyhow::Result;
-use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The entries of a memtable, by key.
+pub(crate) type Entries = BTreeMap<Bytes, Record>;
+
+/// The entries of a memtable by hash of their key, sharing their keys and values with the ordered
+/// ones.
+type Index = HashMap<Bytes, Record>;
+
+/// What a memtable holds for a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    /// The value, unless the key was removed.
+    pub value: Option<Bytes>,
+    /// When the value expires, in milliseconds since the UNIX epoch, if it was inserted with a
+    /// TTL.
+    pub expires_at: Option<u64>,
+    pub seqno: u64,
+    /// The CRC32 of the value, computed when it was written, or 0 for a tombstone. See
+    /// [`MemTable::lookup_verified`].
+    pub checksum: u32,
+}
+
+/// The size of the chunks the keys and values of a memtable are copied into.
+const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where the keys and values of a memtable live.
+///
+/// They are copied into large chunks and handed out as slices sharing them, instead of taking two
+/// small allocations per entry. A chunk is freed once nothing refers to it anymore, so the arena
+/// is reset along with the memtable once it is flushed, and every snapshot of it is dropped.
+struct Arena {
+    chunk: BytesMut,
+    /// How many bytes were copied into the arena so far.
+    allocated: usize,
+}
+
+impl Arena {
+    fn new() -> Self {
+        Arena {
+            chunk: BytesMut::new(),
+            allocated: 0,
+        }
+    }
+
+    fn alloc(&mut self, data: &[u8]) -> Bytes {
+        if self.chunk.capacity() - self.chunk.len() < data.len() {
+            self.chunk = BytesMut::with_capacity(ARENA_CHUNK_SIZE.max(data.len()));
+        }
+
+        self.allocated += data.len();
+        self.chunk.extend_from_slice(data);
+        self.chunk.split().freeze()
+    }
+}
 
 /// An in-memory data-structure that keeps entries ordered by key.
 ///
@@ -17,127 +77,517 @@ use std::path::{Path, PathBuf};
 /// inserted into a Write-Ahead Log. As such, insertions and removes can fail if they are unable
 /// to persist to disk.
 ///
+/// To save a write per entry, entries can be buffered before they reach the log, up to a given
+/// size. Buffered entries are lost on a crash: they are written once the buffer fills up, and
+/// whenever [`MemTable::flush_wal`] is called, e.g. when the MemTable is frozen or dropped.
+///
 /// In case of remove operations, the original key-pair may already be persisted in a persisted
 /// SSTable and thus cannot be simply removed. This is why we insert a Tombstone in remove
 /// operations.
+///
+/// Every entry carries the sequence number of the write that stored it. Sequence numbers keep
+/// increasing from one memtable to the next, so that they tell which of two entries for the same
+/// key is the most recent one wherever they end up.
+///
+/// The entries are shared with the iterators created before a write, which keep seeing them as
+/// they were: a write copies them first if any iterator still holds them.
+///
+/// Once frozen, a MemTable gets a bloom filter of its keys, so that reads of the keys it doesn't
+/// hold mostly skip it without searching its entries.
+///
+/// A MemTable may also keep a hash index of its entries, which point lookups use instead of
+/// searching the ordered entries. Scans and flushes still go through the ordered ones.
 pub struct MemTable {
     pub id: usize,
-    pub(crate) tree: BTreeMap<String, Stored>,
+    pub(crate) tree: Arc<Entries>,
+    arena: Arena,
+    /// The entries by hash of their key, if the MemTable keeps a hash index.
+    index: Option<Index>,
+    /// The filter of the keys, built once the MemTable is frozen.
+    filter: Option<BloomFilter>,
+    next_seqno: u64,
+    /// The sequence number of the first write, unless there was none.
+    first_seqno: Option<u64>,
+    env: Arc<dyn Env>,
     wal_path: PathBuf,
-    wal: File,
+    wal: Box<dyn EnvFile>,
+    /// How many bytes the WAL holds, which a failed write to it is rolled back to.
+    wal_len: u64,
+    /// The entries not written to the WAL yet.
+    wal_buffer: Vec<u8>,
+    /// How many bytes of entries are buffered before they are written to the WAL.
+    wal_buffer_size: usize,
 }
 
 impl MemTable {
-    /// Creates an empty MemTable.
-    pub fn new(id: usize, wal_path: &Path) -> Result<Self> {
-        let wal = MemTable::create_wal(id, wal_path)?;
+    /// Creates an empty MemTable, whose first write gets the given sequence number. Up to
+    /// `wal_buffer_size` bytes of entries are buffered before they are written to the WAL.
+    pub(crate) fn new(
+        env: &Arc<dyn Env>,
+        id: usize,
+        wal_path: &Path,
+        next_seqno: u64,
+        wal_buffer_size: usize,
+        hash_index: bool,
+    ) -> Result<Self> {
+        let mut wal = MemTable::create_wal(env.as_ref(), id, wal_path)?;
+        let wal_len = wal.stream_position()?;
+        // Otherwise the WAL could vanish on a crash, along with the writes synced into it.
+        if let Some(dir) = wal_path.parent() {
+            env.sync_dir(dir)?;
+        }
 
         Ok(MemTable {
             id,
-            tree: BTreeMap::new(),
+            tree: Arc::new(BTreeMap::new()),
+            arena: Arena::new(),
+            index: hash_index.then(HashMap::new),
+            filter: None,
+            next_seqno,
+            first_seqno: None,
+            env: env.clone(),
             wal_path: wal_path.to_path_buf(),
             wal,
+            wal_len,
+            wal_buffer: Vec::new(),
+            wal_buffer_size,
         })
     }
 
     /// Creates a MemTable from a write-ahead-log
-    pub fn recover(wal_path: &Path) -> Result<Self> {
-        let wal = MemTable::open_wal(wal_path)?;
-        let id = format::read_memtable_header(&wal)?.unwrap();
+    ///
+    /// Corrupt entries are dropped or fail the recovery as the recovery mode tells, and the log is
+    /// truncated right after the last entry kept so that new entries are appended after it. I/O
+    /// errors and entries that can't be framed fail the recovery, leaving the log as is.
+    ///
+    /// Returns the memtable along with what was replayed from the log, or `None` if the log
+    /// doesn't even hold its header, which happens when a crash interrupts its creation. Such a
+    /// log cannot hold any entries.
+    pub(crate) fn recover(
+        env: &Arc<dyn Env>,
+        wal_path: &Path,
+        wal_buffer_size: usize,
+        hash_index: bool,
+        mode: RecoveryMode,
+    ) -> Result<Option<(Self, WalReplay)>> {
+        let mut wal = env.open_writable(wal_path)?;
+        let id = match format::read_memtable_header(&mut wal)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
 
         let mut tree = BTreeMap::new();
-        let mut bytes_read = format::memtable_metadata_size(id)?;
+        let mut arena = Arena::new();
+        let mut index = hash_index.then(HashMap::new);
+        let mut next_seqno = 0;
+        let mut first_seqno: Option<u64> = None;
+        let mut bytes_read = wal.stream_position()?;
+        let mut replay = WalReplay {
+            path: wal_path.to_path_buf(),
+            ..WalReplay::default()
+        };
+
+        let corruption = |offset, reason| Error::Corruption {
+            path: wal_path.to_path_buf(),
+            offset,
+            reason,
+        };
+
+        loop {
+            let writes = match format::read_wal_entry(&mut wal) {
+                Ok(Some(WalEntry::Writes(writes))) => writes,
+                Ok(Some(WalEntry::Corrupt(_))) if mode == RecoveryMode::SkipCorruptRecords => {
+                    bytes_read = wal.stream_position()?;
+                    replay.skipped += 1;
+                    continue;
+                }
+                Ok(Some(WalEntry::Corrupt(reason))) => {
+                    let end = wal.stream_position()?;
+                    if mode == RecoveryMode::AbsoluteConsistency || end != wal.seek(SeekFrom::End(0))? {
+                        return Err(corruption(bytes_read, reason).into());
+                    }
+                    break;
+                }
+                Ok(None) => break,
+                Err(error) if format::is_io_error(&error) => return Err(error),
+                Err(error) => return Err(corruption(bytes_read, error.to_string()).into()),
+            };
+
+            bytes_read = wal.stream_position()?;
+            replay.entries += writes.len();
+            for (key, value, seqno) in writes {
+                next_seqno = next_seqno.max(seqno + 1);
+                first_seqno = Some(first_seqno.map_or(seqno, |first| first.min(seqno)));
+                MemTable::store(&mut tree, index.as_mut(), &mut arena, &key, &value, seqno);
+            }
+        }
 
-        while let Ok(Some(deserialized_value)) = format::read_entry(&wal) {
-            bytes_read += format::entry_size(&deserialized_value)?;
-            tree.insert(deserialized_value.0, deserialized_value.1);
+        replay.truncated_bytes = wal.seek(SeekFrom::End(0))? - bytes_read;
+        if mode == RecoveryMode::AbsoluteConsistency && replay.truncated_bytes > 0 {
+            return Err(corruption(bytes_read, "torn entry".to_owned()).into());
         }
 
         wal.set_len(bytes_read)?;
+        wal.seek(SeekFrom::Start(bytes_read))?;
 
-        Ok(MemTable {
+        let memtable = MemTable {
             id,
-            tree,
+            tree: Arc::new(tree),
+            arena,
+            index,
+            filter: None,
+            next_seqno,
+            first_seqno,
+            env: env.clone(),
             wal_path: wal_path.to_path_buf(),
             wal,
-        })
+            wal_len: bytes_read,
+            wal_buffer: Vec::new(),
+            wal_buffer_size,
+        };
+
+        Ok(Some((memtable, replay)))
     }
 
     /// Inserts a new entry into the MemTable.
     /// The new entry is persisted into the WAL for recovery purposes.
-    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<()> {
-        let value = Stored::Value(value);
-
-        format::write_entry(&mut self.wal, &key, &value)?;
-        self.wal.flush()?;
-        self.tree.insert(key, value);
+    pub(crate) fn insert(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.write(key, Stored::Value(value))
+    }
 
-        Ok(())
+    /// Inserts a new entry that expires at the given time, in milliseconds since the UNIX epoch,
+    /// persisting it into the WAL like [`MemTable::insert`].
+    pub(crate) fn insert_expiring(&mut self, key: String, value: Vec<u8>, expires_at: u64) -> Result<()> {
+        self.write(key, Stored::Expiring(value, expires_at))
     }
 
     /// Removes an entry from the MemTable putting a tombstone in its place.
     /// The tombstone is persisted into the WAL for recovery purposes.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        format::write_entry(&mut self.wal, &key, &Stored::Tombstone)?;
-        self.wal.flush()?;
-        self.tree.insert(key, Stored::Tombstone);
+    pub(crate) fn remove(&mut self, key: String) -> Result<()> {
+        self.write(key, Stored::Tombstone)
+    }
+
+    /// Removes all the given keys, putting tombstones in their place, as a batch. See
+    /// [`MemTable::insert_batch`].
+    pub(crate) fn remove_all(&mut self, keys: &[String]) -> Result<()> {
+        let writes: Vec<(String, Stored)> = keys.iter().map(|key| (key.clone(), Stored::Tombstone)).collect();
+
+        self.insert_batch(&writes)
+    }
+
+    /// Stores all the given values and tombstones, in order. They are appended to the WAL as a
+    /// single entry, so that recovery replays either all of them or none.
+    pub(crate) fn insert_batch(&mut self, writes: &[(String, Stored)]) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let seqno = self.next_seqno;
+        self.log(|buffer| format::write_batch(buffer, writes, seqno))?;
+
+        self.first_seqno.get_or_insert(self.next_seqno);
+
+        let tree = Arc::make_mut(&mut self.tree);
+        for (key, value) in writes {
+            MemTable::store(tree, self.index.as_mut(), &mut self.arena, key, value, self.next_seqno);
+            self.next_seqno += 1;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, key: String, value: Stored) -> Result<()> {
+        let seqno = self.next_seqno;
+
+        self.log(|buffer| format::write_entry(buffer, &key, &value, seqno))?;
+
+        let tree = Arc::make_mut(&mut self.tree);
+        MemTable::store(tree, self.index.as_mut(), &mut self.arena, &key, &value, seqno);
+        self.first_seqno.get_or_insert(seqno);
+        self.next_seqno += 1;
+
+        Ok(())
+    }
+
+    /// Buffers the entries encoded by `encode` for the WAL, writing the buffer to it once full. If
+    /// that fails, the entries are dropped from the buffer, so that a failed write doesn't reach
+    /// the WAL along with the next ones.
+    fn log(&mut self, encode: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> Result<()> {
+        let buffered = self.wal_buffer.len();
+        let mut logged = encode(&mut self.wal_buffer);
+        if logged.is_ok() && self.wal_buffer.len() >= self.wal_buffer_size {
+            logged = self.flush_wal();
+        }
+        if logged.is_err() {
+            self.wal_buffer.truncate(buffered);
+        }
+
+        logged
+    }
+
+    /// Copies an entry into the arena, and adds it to the entries and to the index, if any.
+    fn store(
+        tree: &mut Entries,
+        index: Option<&mut Index>,
+        arena: &mut Arena,
+        key: &str,
+        value: &Stored,
+        seqno: u64,
+    ) {
+        let record = match value {
+            Stored::Value(value) => Record {
+                value: Some(arena.alloc(value)),
+                expires_at: None,
+                seqno,
+                checksum: crc32fast::hash(value),
+            },
+            Stored::Expiring(value, expires_at) => Record {
+                value: Some(arena.alloc(value)),
+                expires_at: Some(*expires_at),
+                seqno,
+                checksum: crc32fast::hash(value),
+            },
+            Stored::Tombstone => Record {
+                value: None,
+                expires_at: None,
+                seqno,
+                checksum: 0,
+            },
+        };
+        let key = arena.alloc(key.as_bytes());
+
+        if let Some(index) = index {
+            index.insert(key.clone(), record.clone());
+        }
+        tree.insert(key, record);
+    }
+
+    /// What is stored for the given key, through the index if there is one.
+    fn entry(&self, key: &str) -> Option<&Record> {
+        match &self.index {
+            Some(index) => index.get(key.as_bytes()),
+            None => self.tree.get(key.as_bytes()),
+        }
+    }
+
+    /// Writes the buffered entries to the WAL.
+    pub(crate) fn flush_wal(&mut self) -> Result<()> {
+        if self.wal_buffer.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(error) = self.wal.write_all(&self.wal_buffer).and_then(|()| self.wal.flush()) {
+            // Drops the part of the buffer that made it to the WAL, which would be written twice
+            // otherwise.
+            self.wal.set_len(self.wal_len)?;
+            self.wal.seek(SeekFrom::Start(self.wal_len))?;
+            return Err(error.into());
+        }
+        self.wal_len += self.wal_buffer.len() as u64;
+        self.wal_buffer.clear();
+
+        Ok(())
+    }
+
+    /// Writes the buffered entries to the WAL, and ensures the WAL reached the storage device.
+    pub(crate) fn sync_wal(&mut self) -> Result<()> {
+        self.flush_wal()?;
+        self.wal.sync()?;
 
         Ok(())
     }
 
+    /// The sequence number the next write will get.
+    pub fn next_seqno(&self) -> u64 {
+        self.next_seqno
+    }
+
+    /// The sequence number of the first write to the MemTable, unless there was none yet.
+    pub fn first_seqno(&self) -> Option<u64> {
+        self.first_seqno
+    }
+
+    /// Makes the next writes get sequence numbers from the given one on, unless they already do.
+    pub(crate) fn resume_seqno(&mut self, next_seqno: u64) {
+        self.next_seqno = self.next_seqno.max(next_seqno);
+    }
+
+    /// The entries of the MemTable as they are now, unaffected by later writes.
+    pub(crate) fn snapshot(&self) -> Arc<Entries> {
+        self.tree.clone()
+    }
+
+    /// The bytes of the keys and values copied into the MemTable, including those of entries
+    /// overwritten since, which stay in the arena until the MemTable is dropped.
+    pub fn allocated_bytes(&self) -> usize {
+        self.arena.allocated
+    }
+
     /// The number of entries in the MemTable.
     pub fn len(&self) -> usize {
         self.tree.len()
     }
 
+    /// Whether the MemTable has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The keys of the MemTable whose value was inserted with a TTL, along with when it expires.
+    pub(crate) fn expiring(&self) -> impl Iterator<Item = (u64, String)> + '_ {
+        self.tree.iter().filter_map(|(key, record)| {
+            record.expires_at.map(|expires_at| (expires_at, String::from_utf8_lossy(key).into_owned()))
+        })
+    }
+
+    /// Returns the entries of the MemTable in key order, along with their sequence number. The
+    /// value of a key that the MemTable removes is `None`, and expired values are returned as
+    /// others.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Option<Vec<u8>>, u64)> + '_ {
+        // Keys are only ever copied from strings, so they are valid UTF-8.
+        self.tree.iter().map(|(key, record)| {
+            let key = String::from_utf8_lossy(key).into_owned();
+            (key, record.value.as_ref().map(|value| value.to_vec()), record.seqno)
+        })
+    }
+
     /// Returns the value corresponding to the given key, if present.
-    pub fn get(&self, key: &str) -> Option<&[u8]> {
-        match self.tree.get(key) {
-            Some(Stored::Value(v)) => Some(v),
-            _ => None,
+    #[cfg(test)]
+    pub(crate) fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entry(key).and_then(|record| record.value.as_deref())
+    }
+
+    /// Returns what is stored for the given key, including tombstones.
+    pub(crate) fn lookup(&self, key: &str) -> Option<Stored> {
+        if self.filter.as_ref().is_some_and(|filter| !filter.may_contain(key.as_bytes())) {
+            return None;
         }
+
+        self.entry(key).map(stored)
     }
 
-    /// Persists the MemTable to disk storing its entries in-order.
-    ///
-    /// Returns the corresponding SSTable.
-    pub fn persist(&self, path: &Path) -> Result<SSTable> {
-        let mut fd = File::create(path)?;
+    /// Returns what is stored for the given key like [`MemTable::lookup`], failing with
+    /// [`Error::Corruption`] if the value no longer matches the checksum computed when it was
+    /// written, e.g. after a bit flip in memory.
+    pub(crate) fn lookup_verified(&self, key: &str) -> Result<Option<Stored>> {
+        if self.filter.as_ref().is_some_and(|filter| !filter.may_contain(key.as_bytes())) {
+            return Ok(None);
+        }
+        let Some(record) = self.entry(key) else {
+            return Ok(None);
+        };
+
+        if let Some(value) = &record.value {
+            let actual = crc32fast::hash(value);
+            if actual != record.checksum {
+                let reason = format!(
+                    "checksum mismatch for {}: expected {:08x}, found {:08x}",
+                    key, record.checksum, actual
+                );
+                return Err(Error::Corruption {
+                    path: self.wal_path.clone(),
+                    offset: 0,
+                    reason,
+                }
+                .into());
+            }
+        }
+
+        Ok(Some(stored(record)))
+    }
 
-        let kvs: Vec<(String, Stored)> = self.tree.clone().into_iter().collect();
-        for (key, value) in kvs {
-            format::write_entry(&mut fd, &key, &value)?;
+    /// Builds the filter of the keys of the MemTable, which must not be written to anymore.
+    pub(crate) fn freeze(&mut self) {
+        let mut filter = BloomFilter::new(self.tree.len(), bloom::BITS_PER_KEY);
+        for key in self.tree.keys() {
+            filter.insert(key);
         }
+
+        self.filter = Some(filter);
+    }
+
+    /// Persists the MemTable to disk storing its entries in-order, and syncs the file.
+    ///
+    /// Returns the corresponding SSTable. The WAL is kept, as the SSTable is not part of the
+    /// storage until the manifest records it: see [`MemTable::remove_wal`].
+    pub(crate) fn persist(&self, path: &Path, options: &TableOptions) -> Result<SSTable> {
+        let mut fd = options.create(self.env.as_ref(), path)?;
+        let mut index = IndexBuilder::new(options);
+        let mut entry = Vec::new();
+        let mut offset = 0;
+
+        for (key, record) in self.tree.iter() {
+            let key = std::str::from_utf8(key)?;
+            let value = stored(record);
+            entry.clear();
+            format::write_table_entry(&mut entry, key, &value, record.seqno, options.compression_of(&value))?;
+            fd.write_all(&entry)?;
+            index.add(key, &value, record.seqno, offset);
+            offset += entry.len() as u64;
+        }
+        index.finish(&mut fd, offset)?;
         fd.flush()?;
+        fd.sync()?;
+
+        Ok(SSTable::new(&self.env, path))
+    }
+
+    pub(crate) fn wal_path(&self) -> &Path {
+        &self.wal_path
+    }
 
-        std::fs::remove_file(self.wal_path.to_owned())?;
+    /// How many bytes of the WAL were written, leaving out the entries still buffered.
+    pub(crate) fn wal_len(&self) -> u64 {
+        self.wal_len
+    }
 
-        Ok(SSTable::new(path))
+    /// Removes the WAL of a persisted MemTable, overwriting it first if `shred` is set: see
+    /// [`Env::shred_file`].
+    pub(crate) fn remove_wal(&self, shred: bool) -> Result<()> {
+        match shred {
+            true => self.env.shred_file(&self.wal_path)?,
+            false => self.env.remove_file(&self.wal_path)?,
+        }
+
+        Ok(())
     }
 
-    fn create_wal(id: usize, path: &Path) -> Result<File> {
-        let mut f = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
+    fn create_wal(env: &dyn Env, id: usize, path: &Path) -> Result<Box<dyn EnvFile>> {
+        let mut f = env.create(path)?;
 
         format::write_memtable_header(&mut f, id)?;
         Ok(f)
     }
+}
+
+/// What an entry of a memtable stands for.
+pub(crate) fn stored(record: &Record) -> Stored {
+    match (&record.value, record.expires_at) {
+        (Some(value), None) => Stored::Value(value.to_vec()),
+        (Some(value), Some(expires_at)) => Stored::Expiring(value.to_vec(), expires_at),
+        (None, _) => Stored::Tombstone,
+    }
+}
 
-    fn open_wal(path: &Path) -> std::io::Result<File> {
-        OpenOptions::new().read(true).write(true).open(path)
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        // There is no one to report the failure to. The entries are lost, as on a crash.
+        let _ = self.flush_wal();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
+    use std::sync::Arc;
 
+    use crate::env::Env;
+    use crate::fault_injection::FaultInjectionEnv;
     use crate::format;
-    use crate::memtable::MemTable;
-    use crate::{test_utils::*, Stored};
+    use crate::memtable::{Arena, MemTable, ARENA_CHUNK_SIZE};
+    use crate::sstable::TableOptions;
+    use crate::wal::RecoveryMode;
+    use crate::{test_utils::*, Error, Stored};
 
     use anyhow::Result;
 
@@ -167,6 +617,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn frozen_memtables_still_see_their_entries() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+
+        for i in 0..100 {
+            memtable.insert(format!("key{}", i), format!("value{}", i).into_bytes())?;
+        }
+        memtable.remove("key5".to_string())?;
+        memtable.freeze();
+
+        assert!((0..100).filter(|i| *i != 5).all(|i| memtable.get(&format!("key{}", i)).is_some()));
+        assert_eq!(memtable.lookup("key5"), Some(Stored::Tombstone));
+        assert_eq!(memtable.lookup("other"), None);
+
+        let entries: Vec<_> = memtable.iter().collect();
+        assert_eq!(entries.len(), 100);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(entries[0], ("key0".to_owned(), Some(b"value0".to_vec()), 1));
+        assert!(entries.contains(&("key5".to_owned(), None, 101)));
+        Ok(())
+    }
+
+    #[test]
+    fn hash_indexed_memtables_see_the_latest_entries() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = MemTable::new(&test.env(), 0, &test.wal_path(), 1, 0, true)?;
+
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
+        memtable.insert("key1".to_string(), "updated".as_bytes().to_owned())?;
+        memtable.remove("key2".to_string())?;
+
+        assert_eq!(memtable.get("key1"), Some("updated".as_bytes()));
+        assert_eq!(memtable.lookup("key2"), Some(Stored::Tombstone));
+        assert_eq!(memtable.lookup("key3"), None);
+
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, true, RecoveryMode::default())?.unwrap().0;
+        assert_eq!(recovered.get("key1"), Some("updated".as_bytes()));
+        assert_eq!(recovered.lookup("key2"), Some(Stored::Tombstone));
+        assert_eq!(memtable.tree, recovered.tree);
+        Ok(())
+    }
+
+    #[test]
+    fn arena_packs_small_entries_into_shared_chunks() {
+        let mut arena = Arena::new();
+
+        let key = arena.alloc(b"key1");
+        let value = arena.alloc(b"value1");
+        assert_eq!(&key[..], b"key1");
+        assert_eq!(&value[..], b"value1");
+        assert_eq!(value.as_ptr(), key.as_ptr().wrapping_add(key.len()));
+
+        let large = vec![7; ARENA_CHUNK_SIZE * 2];
+        assert_eq!(arena.alloc(&large), large);
+        assert_eq!(&arena.alloc(b"key2")[..], b"key2");
+    }
+
     #[test]
     fn recover_should_yield_the_same_memtable() -> Result<()> {
         let test = Test::new()?;
@@ -175,7 +684,7 @@ mod tests {
         memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
         memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
 
-        let recovered = MemTable::recover(&test.wal_path())?;
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
 
         assert_eq!(memtable.tree, recovered.tree);
         Ok(())
@@ -193,7 +702,7 @@ mod tests {
 
         test.corrupt_wal()?;
 
-        let recovered = MemTable::recover(&test.wal_path())?;
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
         assert_eq!(memtable.tree, recovered.tree);
 
         Ok(())
@@ -208,20 +717,243 @@ mod tests {
         memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
         memtable.insert("key3".to_string(), "value3".as_bytes().to_owned())?;
 
-        let wal = MemTable::open_wal(&test.wal_path())?;
-        let wal_metadata = wal.metadata()?;
-        let wal_length = wal_metadata.len();
+        let wal_length = std::fs::metadata(test.wal_path())?.len();
 
         test.corrupt_wal()?;
 
-        MemTable::recover(&test.wal_path())?;
-        let wal_metadata = wal.metadata()?;
-        let recovered_wal_length = wal_metadata.len();
+        MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?;
+        let recovered_wal_length = std::fs::metadata(test.wal_path())?.len();
 
         assert_eq!(wal_length, recovered_wal_length);
         Ok(())
     }
 
+    #[test]
+    fn recovered_memtable_appends_after_the_last_valid_entry() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        test.corrupt_wal()?;
+
+        let mut recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        recovered.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
+
+        let recovered_again = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        assert_eq!(recovered.tree, recovered_again.tree);
+        assert_eq!(recovered_again.get("key2"), Some("value2".as_bytes()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_modes_decide_what_happens_to_corrupt_entries() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
+        memtable.insert("key3".to_string(), "value3".as_bytes().to_owned())?;
+        drop(memtable);
+
+        // The entry of key2 is whole, but no longer matches its checksum.
+        let mut contents = std::fs::read(test.wal_path())?;
+        let position = contents.windows(6).position(|window| window == b"value2").unwrap();
+        contents[position + 5] = b'9';
+        std::fs::write(test.wal_path(), &contents)?;
+
+        let recover = |mode| MemTable::recover(&test.env(), &test.wal_path(), 0, false, mode);
+        let keys = |memtable: &MemTable| memtable.iter().map(|(key, _, _)| key).collect::<Vec<_>>();
+
+        let error = recover(RecoveryMode::AbsoluteConsistency).err().unwrap();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+
+        let (recovered, replay) = recover(RecoveryMode::SkipCorruptRecords)?.unwrap();
+        assert_eq!(keys(&recovered), ["key1", "key3"]);
+        assert_eq!((replay.entries, replay.skipped, replay.truncated_bytes), (2, 1, 0));
+        drop(recovered);
+        assert_eq!(std::fs::metadata(test.wal_path())?.len(), contents.len() as u64);
+
+        // It is followed by whole entries, so it can't have been torn by a crash.
+        let error = recover(RecoveryMode::TolerateCorruptTail).err().unwrap();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+        assert_eq!(std::fs::read(test.wal_path())?, contents);
+
+        // Once it is the last one, it is dropped.
+        contents[position + 5] = b'2';
+        let position = contents.windows(6).position(|window| window == b"value3").unwrap();
+        contents[position + 5] = b'9';
+        std::fs::write(test.wal_path(), &contents)?;
+        let (recovered, replay) = recover(RecoveryMode::TolerateCorruptTail)?.unwrap();
+        assert_eq!(keys(&recovered), ["key1", "key2"]);
+        assert_eq!((replay.entries, replay.skipped), (2, 0));
+        assert_eq!(replay.truncated_bytes, contents.len() as u64 - std::fs::metadata(test.wal_path())?.len());
+        drop(recovered);
+
+        // An entry that can't be framed hides where the next ones start, whatever the mode.
+        contents[position + 5] = b'3';
+        let position = contents.windows(4).position(|window| window == b"key1").unwrap();
+        let tag = std::mem::replace(&mut contents[position + 4], 0xff);
+        std::fs::write(test.wal_path(), &contents)?;
+        for mode in [RecoveryMode::TolerateCorruptTail, RecoveryMode::SkipCorruptRecords] {
+            let error = recover(mode).err().unwrap();
+            assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+        }
+        assert_eq!(std::fs::read(test.wal_path())?, contents);
+        contents[position + 4] = tag;
+        std::fs::write(test.wal_path(), &contents)?;
+
+        // Even a torn entry at the end fails the recovery when it must be consistent.
+        test.corrupt_wal()?;
+        let error = recover(RecoveryMode::AbsoluteConsistency).err().unwrap();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+        assert_eq!(keys(&recover(RecoveryMode::SkipCorruptRecords)?.unwrap().0), ["key1", "key2", "key3"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_lengths_fail_the_recovery_instead_of_exhausting_the_memory() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
+        drop(memtable);
+
+        // The length prefix of key2 claims far more bytes than any entry may take.
+        let mut contents = std::fs::read(test.wal_path())?;
+        let position = contents.windows(4).position(|window| window == b"key2").unwrap();
+        contents[position - 8..position].copy_from_slice(&(u64::MAX >> 4).to_le_bytes());
+        std::fs::write(test.wal_path(), &contents)?;
+
+        for mode in [
+            RecoveryMode::AbsoluteConsistency,
+            RecoveryMode::TolerateCorruptTail,
+            RecoveryMode::SkipCorruptRecords,
+        ] {
+            let error = MemTable::recover(&test.env(), &test.wal_path(), 0, false, mode)
+                .err()
+                .unwrap();
+            assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+        }
+        assert_eq!(std::fs::read(test.wal_path())?, contents);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_reads_fail_the_recovery_without_truncating_the_wal() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        memtable.insert("key2".to_string(), "value2".as_bytes().to_owned())?;
+        drop(memtable);
+        let len = std::fs::metadata(test.wal_path())?.len();
+
+        for mode in [RecoveryMode::TolerateCorruptTail, RecoveryMode::SkipCorruptRecords] {
+            let env = FaultInjectionEnv::default();
+            env.fail_reads_after(len as usize / 2);
+            let env: Arc<dyn Env> = Arc::new(env);
+            let error = MemTable::recover(&env, &test.wal_path(), 0, false, mode).err().unwrap();
+
+            assert!(error.downcast_ref::<Error>().is_none());
+            assert_eq!(std::fs::metadata(test.wal_path())?.len(), len);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn batches_are_recovered_whole_or_not_at_all() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = test.create_memtable()?;
+
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        let batch = [
+            ("key1".to_string(), Stored::Tombstone),
+            ("key2".to_string(), Stored::Value("value2".as_bytes().to_owned())),
+            ("key3".to_string(), Stored::Value("value3".as_bytes().to_owned())),
+        ];
+        memtable.insert_batch(&batch)?;
+        assert_eq!(memtable.next_seqno(), 5);
+        drop(memtable);
+
+        let recover = || MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default());
+        let (recovered, replay) = recover()?.unwrap();
+        let entries: Vec<_> = recovered.iter().collect();
+        assert_eq!(
+            entries,
+            [
+                ("key1".to_string(), None, 2),
+                ("key2".to_string(), Some("value2".as_bytes().to_owned()), 3),
+                ("key3".to_string(), Some("value3".as_bytes().to_owned()), 4),
+            ]
+        );
+        assert_eq!(replay.entries, 4);
+        drop(recovered);
+
+        // A crash tore the end of the batch: none of its writes are recovered.
+        let wal = std::fs::OpenOptions::new().write(true).open(test.wal_path())?;
+        wal.set_len(wal.metadata()?.len() - 3)?;
+        drop(wal);
+
+        let (recovered, replay) = recover()?.unwrap();
+        assert_eq!(recovered.iter().count(), 1);
+        assert_eq!(recovered.get("key1"), Some("value1".as_bytes()));
+        assert_eq!(replay.entries, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_entries_reach_the_wal_once_flushed() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = MemTable::new(&test.env(), 0, &test.wal_path(), 1, 1024, false)?;
+
+        memtable.insert("key1".to_string(), "value1".as_bytes().to_owned())?;
+        memtable.remove("key2".to_string())?;
+
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        assert_eq!(recovered.len(), 0);
+
+        memtable.flush_wal()?;
+
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        assert_eq!(memtable.tree, recovered.tree);
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_wal_buffers_are_written_without_waiting_for_a_flush() -> Result<()> {
+        let test = Test::new()?;
+        let mut memtable = MemTable::new(&test.env(), 0, &test.wal_path(), 1, 100, false)?;
+
+        for i in 0..10 {
+            memtable.insert(format!("key{i}"), "value".as_bytes().to_owned())?;
+        }
+
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        assert!(!recovered.is_empty());
+        assert!(recovered.len() < 10);
+
+        drop(memtable);
+
+        let recovered = MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.unwrap().0;
+        assert_eq!(recovered.len(), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_should_skip_wal_without_header() -> Result<()> {
+        let test = Test::new()?;
+        File::create(test.wal_path())?;
+
+        assert!(MemTable::recover(&test.env(), &test.wal_path(), 0, false, RecoveryMode::default())?.is_none());
+        Ok(())
+    }
+
     #[test]
     fn persist_should_store_all_elements_in_order() -> Result<()> {
         let test = Test::new()?;
@@ -233,7 +965,7 @@ mod tests {
         memtable.insert("b".to_string(), "value2".as_bytes().to_owned())?;
 
         let sstable_path = test.path("sstable-1");
-        memtable.persist(&sstable_path)?;
+        memtable.persist(&sstable_path, &TableOptions::default())?;
 
         let fd = File::open(sstable_path)?;
         assert_eq!(
@@ -259,14 +991,17 @@ mod tests {
     }
 
     #[test]
-    fn persisting_memtable_should_delete_wal() -> Result<()> {
+    fn persisting_memtable_should_keep_wal_until_removed() -> Result<()> {
         let test = Test::new()?;
 
         let mut memtable = test.create_memtable()?;
         memtable.insert("c".to_string(), "value1".as_bytes().to_owned())?;
 
         let sstable_path = test.path("sstable-1");
-        memtable.persist(&sstable_path)?;
+        memtable.persist(&sstable_path, &TableOptions::default())?;
+        assert!(test.wal_path().exists());
+
+        memtable.remove_wal(false)?;
 
         let wal_path = test.wal_path();
         let wal = File::open(wal_path);
@@ -0,0 +1,192 @@
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use anyhow::Result;
+
+use crate::env::EnvFile;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads which read the blocks of files ahead of the readers that are going to consume them.
+///
+/// The threads stop once the prefetcher and every reader holding it are dropped.
+pub(crate) struct Prefetcher {
+    reads: Mutex<Sender<Job>>,
+}
+
+impl Prefetcher {
+    /// Starts the given number of threads, which must not be 0.
+    pub fn new(threads: usize) -> Result<Arc<Self>> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new().name(format!("lsm-prefetch-{}", index)).spawn(move || loop {
+                let job = receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            })?;
+        }
+
+        Ok(Arc::new(Prefetcher {
+            reads: Mutex::new(sender),
+        }))
+    }
+
+    /// Reads the block of the file at the offset in one of the threads, returning where the
+    /// block is going to be sent once read.
+    fn read(&self, file: Arc<dyn EnvFile>, offset: u64, size: usize) -> Receiver<io::Result<Vec<u8>>> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let job = Box::new(move || {
+            let _ = sender.send(read_block(file.as_ref(), offset, size));
+        });
+
+        // Threads only stop once the sender is dropped, so the read is always picked up.
+        let _ = self.reads.lock().unwrap_or_else(PoisonError::into_inner).send(job);
+
+        receiver
+    }
+}
+
+/// Reads a file from an offset on, one block after the other. While a block is consumed, the next
+/// one is read by the prefetcher, if any, so that sequential reads don't wait for the device at
+/// every block boundary. At most two blocks are held at once.
+pub(crate) struct BlockReader {
+    file: Arc<dyn EnvFile>,
+    block_size: usize,
+    prefetcher: Option<Arc<Prefetcher>>,
+    /// The block being consumed, and how much of it was.
+    block: Vec<u8>,
+    consumed: usize,
+    /// Whether the block being consumed is the last one, as it is shorter than the others.
+    last: bool,
+    /// The offset of the block after the current one.
+    next_offset: u64,
+    /// The next block, if its read was handed to the prefetcher.
+    next: Option<Receiver<io::Result<Vec<u8>>>>,
+}
+
+impl BlockReader {
+    /// Reads the file from the offset on, in blocks of the given size, which must not be 0.
+    pub fn new(file: Box<dyn EnvFile>, offset: u64, block_size: usize, prefetcher: Option<Arc<Prefetcher>>) -> Self {
+        BlockReader {
+            file: Arc::from(file),
+            block_size,
+            prefetcher,
+            block: Vec::new(),
+            consumed: 0,
+            last: false,
+            next_offset: offset,
+            next: None,
+        }
+    }
+
+    /// Hands the read of the next block to the prefetcher, unless it was already or the reader
+    /// has none. Readers prefetch on their own while they are consumed: this is for those whose
+    /// first block is about to be needed.
+    pub fn prefetch(&mut self) {
+        if let (None, Some(prefetcher)) = (&self.next, &self.prefetcher) {
+            self.next = Some(prefetcher.read(self.file.clone(), self.next_offset, self.block_size));
+        }
+    }
+
+    /// Whether the block being consumed is the last one of the file.
+    pub fn on_last_block(&self) -> bool {
+        self.last
+    }
+
+    fn next_block(&mut self) -> io::Result<Vec<u8>> {
+        match self.next.take() {
+            Some(receiver) => receiver
+                .recv()
+                .unwrap_or_else(|_| Err(io::Error::other("the prefetcher stopped"))),
+            None => read_block(self.file.as_ref(), self.next_offset, self.block_size),
+        }
+    }
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.block.len() {
+            if self.last {
+                return Ok(0);
+            }
+
+            self.block = self.next_block()?;
+            self.consumed = 0;
+            self.last = self.block.len() < self.block_size;
+            self.next_offset += self.block.len() as u64;
+
+            if !self.last {
+                self.prefetch();
+            }
+        }
+
+        let read = buf.len().min(self.block.len() - self.consumed);
+        buf[..read].copy_from_slice(&self.block[self.consumed..self.consumed + read]);
+        self.consumed += read;
+
+        Ok(read)
+    }
+}
+
+/// Reads the block of the file at the offset, which is only shorter than the given size if the
+/// file ends before it does.
+fn read_block(file: &dyn EnvFile, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut block = vec![0; size];
+    let mut len = 0;
+
+    while len < size {
+        match file.read_at(&mut block[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    block.truncate(len);
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use anyhow::Result;
+
+    use super::{BlockReader, Prefetcher};
+    use crate::env::{Env, OsEnv};
+    use crate::test_utils::Test;
+
+    #[test]
+    fn files_are_read_the_same_with_or_without_prefetching() -> Result<()> {
+        let test = Test::new()?;
+        let path = test.path("file");
+        let contents: Vec<u8> = (0..10_000u32).map(|byte| byte as u8).collect();
+        std::fs::write(&path, &contents)?;
+
+        let prefetcher = Prefetcher::new(2)?;
+        for offset in [0, 1, 999, 1000, 9_999, 10_000] {
+            for block_size in [1, 7, 1000, 4096, 10_000, 20_000] {
+                for prefetcher in [None, Some(prefetcher.clone())] {
+                    let mut reader = BlockReader::new(OsEnv.open(&path)?, offset, block_size, prefetcher);
+                    if offset % 2 == 0 {
+                        reader.prefetch();
+                    }
+
+                    let mut read = Vec::new();
+                    reader.read_to_end(&mut read)?;
+                    assert_eq!(read, contents[offset as usize..], "offset {}, blocks of {}", offset, block_size);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1533 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+use crate::env::{Env, EnvFile};
+use crate::error::Error;
+use crate::format;
+use crate::scheduler;
+use crate::storage::Storage;
+
+/// The name of the log of a member, inside its directory.
+const LOG_NAME: &str = "raft-log";
+
+/// The name of the [`State`] of a member, inside its directory.
+const STATE_NAME: &str = "raft-state";
+
+/// The name of the last snapshot a member installed, inside its directory, kept until its storage
+/// holds it.
+const SNAPSHOT_NAME: &str = "snapshot";
+
+/// How many entries an append request carries at most.
+const MAX_APPEND_ENTRIES: usize = 256;
+
+/// Identifies a member of a cluster.
+pub type NodeId = u64;
+
+/// A write the members of a cluster apply to their storage once a majority of them logged it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Inserts a value, which expires at the given time, in milliseconds since the UNIX epoch, if
+    /// any. The time is set by the leader, so that every member expires the value at once.
+    Insert {
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Remove { key: String },
+    /// Applies the commands in order through a single writer, so that no other write lands between
+    /// them.
+    Batch(Vec<Command>),
+}
+
+/// An entry of the log of a member.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// The term of the leader that appended the entry.
+    pub term: u64,
+    /// The write, or none for the entry a leader appends once elected, which commits those of the
+    /// terms before its own.
+    pub command: Option<Command>,
+}
+
+/// Asks a member for its vote. See [`Raft::handle_vote`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: NodeId,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+/// Replicates the entries of the leader after the one at `prev_log_index`, or none, as a
+/// heartbeat. See [`Raft::handle_append`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: NodeId,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    /// The index of the last entry the member now shares with the leader if it appended the
+    /// entries, or else of the last one it may share, from which the leader tries again.
+    pub last_index: u64,
+}
+
+/// Replaces the storage of a member with a snapshot of that of the leader, which holds the
+/// entries up to `last_index`. See [`Raft::install_snapshot`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub term: u64,
+    pub leader: NodeId,
+    pub last_index: u64,
+    pub last_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub term: u64,
+}
+
+/// Carries the requests of a member to the others, which pass them to the matching handler of
+/// their [`Raft`], e.g. over HTTP. Requests may fail or take long: members retry them.
+pub trait Transport: Send + Sync {
+    fn request_vote(&self, to: NodeId, request: &VoteRequest) -> Result<VoteResponse>;
+
+    fn append_entries(&self, to: NodeId, request: &AppendRequest) -> Result<AppendResponse>;
+
+    /// Sends the snapshot written into the given directory, a checkpoint of the storage of the
+    /// leader, for the member to receive into a directory of [`Raft::snapshot_dir`] and install
+    /// with [`Raft::install_snapshot`].
+    fn install_snapshot(&self, to: NodeId, request: &SnapshotRequest, dir: &Path) -> Result<SnapshotResponse>;
+}
+
+/// What a member keeps across restarts, besides its log.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct State {
+    term: u64,
+    voted_for: Option<NodeId>,
+    /// The index and the term of the last entry the log dropped, which the storage holds.
+    snapshot: (u64, u64),
+    /// The index of the last entry the storage durably applied. If below that of the snapshot,
+    /// the snapshot was being installed, and is installed again on restart.
+    applied: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// How far the leader replicated its log to another member.
+struct Progress {
+    /// The index of the next entry to send.
+    next: u64,
+    /// The index of the last entry the member is known to share with the leader.
+    matched: u64,
+    /// When the last request was sent, and the read round it was sent in.
+    sent: Option<(Instant, u64)>,
+    /// The last read round the member acknowledged the leadership of the leader in.
+    acked: u64,
+    /// When to send again, once a request failed.
+    retry_at: Option<Instant>,
+}
+
+/// The outcome of an entry proposed by this member, once applied.
+struct Proposal {
+    term: u64,
+    outcome: Option<Result<()>>,
+}
+
+struct Node {
+    role: Role,
+    state: State,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    leader: Option<NodeId>,
+    /// When to start an election, unless the leader is heard from before.
+    deadline: Instant,
+    /// The progress of every other member, while this one leads.
+    progress: HashMap<NodeId, Progress>,
+    proposals: HashMap<u64, Proposal>,
+    /// Counts the reads that confirmed the leadership of this member, see [`Raft::read_barrier`].
+    round: u64,
+    /// Why the member stopped, if persisting its log or state, or applying an entry, failed.
+    failure: Option<String>,
+}
+
+/// Builder to create a member of a cluster.
+pub struct RaftBuilder {
+    id: NodeId,
+    members: Vec<NodeId>,
+    dir: PathBuf,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    commit_timeout: Duration,
+    max_log_entries: usize,
+}
+
+impl RaftBuilder {
+    /// Creates a builder for the member with the given id, of the cluster made of the given
+    /// members, itself included. Every member must be given the same ones.
+    pub fn new(id: NodeId, members: Vec<NodeId>) -> Self {
+        RaftBuilder {
+            id,
+            members,
+            dir: PathBuf::from("raft"),
+            election_timeout: Duration::from_millis(1000),
+            heartbeat_interval: Duration::from_millis(100),
+            commit_timeout: Duration::from_secs(5),
+            max_log_entries: 10_000,
+        }
+    }
+
+    /// Sets the directory the member keeps its log and state in, apart from the storage. Defaults
+    /// to `raft`, inside the current directory.
+    pub fn dir(mut self, dir: PathBuf) -> Self {
+        self.dir = dir;
+        self
+    }
+
+    /// Sets how long a member waits to hear from a leader before it stands for election, at
+    /// least: each wait lasts up to twice as long, at random, so that members rarely stand at
+    /// once. Defaults to a second.
+    pub fn election_timeout(mut self, timeout: Duration) -> Self {
+        self.election_timeout = timeout;
+        self
+    }
+
+    /// Sets how often the leader sends the others a request, even if it has no entries to send
+    /// them, so that they don't stand for election. Must be well below the election timeout.
+    /// Defaults to 100ms.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Sets how long a write or a read barrier waits for a majority of the members before failing.
+    /// A write that timed out may still commit. Defaults to 5s.
+    pub fn commit_timeout(mut self, timeout: Duration) -> Self {
+        self.commit_timeout = timeout;
+        self
+    }
+
+    /// Sets how many entries the log holds before those applied are dropped, once the storage
+    /// durably holds them. Members that fall further behind install a snapshot instead. Defaults
+    /// to 10 000.
+    pub fn max_log_entries(mut self, entries: usize) -> Self {
+        self.max_log_entries = entries;
+        self
+    }
+
+    /// Starts the member, with the given storage as its state machine. The storage must only be
+    /// written through the member, e.g. [`Raft::insert`], which applies the writes once they
+    /// commit. If a snapshot was being installed, the storage is replaced with it again, and the
+    /// entries the storage may have lost in a crash are applied again as they commit.
+    pub fn build(self, storage: Storage, transport: Arc<dyn Transport>) -> Result<Raft> {
+        if !self.members.contains(&self.id) {
+            return Err(anyhow!("member {} isn't one of the members {:?}", self.id, self.members));
+        }
+
+        let env = storage.config.env.clone();
+        env.create_dir_all(&self.dir)?;
+
+        let state = read_state(env.as_ref(), &self.dir)?.unwrap_or_default();
+        let log = Log::open(&env, &self.dir, state.snapshot)?;
+
+        let node = Node {
+            role: Role::Follower,
+            state,
+            log,
+            commit: state.applied,
+            applied: state.applied,
+            leader: None,
+            deadline: Instant::now() + random_timeout(self.election_timeout),
+            progress: HashMap::new(),
+            proposals: HashMap::new(),
+            round: 0,
+            failure: None,
+        };
+
+        let shared = Arc::new(Shared {
+            id: self.id,
+            peers: self.members.iter().copied().filter(|&member| member != self.id).collect(),
+            election_timeout: self.election_timeout,
+            heartbeat_interval: self.heartbeat_interval,
+            commit_timeout: self.commit_timeout,
+            max_log_entries: self.max_log_entries,
+            storage,
+            transport,
+            env,
+            dir: self.dir,
+            node: Mutex::new(node),
+            changed: Condvar::new(),
+            stopping: AtomicBool::new(false),
+        });
+
+        {
+            let mut node = shared.lock();
+            if node.state.applied < node.state.snapshot.0 {
+                shared.restore(&mut node)?;
+            }
+        }
+
+        let mut threads = Vec::new();
+        let ticker = shared.clone();
+        threads.push(thread::spawn(move || ticker.tick()));
+        for &peer in &shared.peers {
+            let replicator = shared.clone();
+            threads.push(thread::spawn(move || replicator.replicate(peer)));
+        }
+
+        Ok(Raft { shared, threads })
+    }
+}
+
+/// A member of a cluster that replicates the writes to a storage through the Raft consensus
+/// algorithm, so that they are only applied once a majority of the members logged them, in the
+/// same order everywhere, and survive the loss of any minority of the members.
+///
+/// Only the leader takes writes, and reads that must see every write that committed before them,
+/// see [`Raft::read_barrier`]. The others fail them with [`Error::NotLeader`]. Members whose
+/// leader dropped the entries they lack from its log are sent a checkpoint of its storage
+/// instead, see [`Storage::checkpoint`], which replaces theirs.
+///
+/// Members are fixed, as given to the [`RaftBuilder`]: they can't be added or removed while the
+/// cluster runs.
+pub struct Raft {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    id: NodeId,
+    /// The other members.
+    peers: Vec<NodeId>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    commit_timeout: Duration,
+    max_log_entries: usize,
+    storage: Storage,
+    transport: Arc<dyn Transport>,
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    node: Mutex<Node>,
+    /// Notified whenever the node changes, e.g. the log grows or entries are applied.
+    changed: Condvar,
+    stopping: AtomicBool,
+}
+
+impl Raft {
+    pub fn builder(id: NodeId, members: Vec<NodeId>) -> RaftBuilder {
+        RaftBuilder::new(id, members)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    /// The storage the member applies the committed writes to, which reads may go to.
+    pub fn storage(&self) -> &Storage {
+        &self.shared.storage
+    }
+
+    /// The leader this member knows of, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.shared.lock().leader
+    }
+
+    /// Inserts a value once a majority of the members logged it. See [`Raft::propose`].
+    pub fn insert(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.propose(Command::Insert { key, value, expires_at: None })
+    }
+
+    /// Inserts a value like [`Raft::insert`], which expires once the given TTL elapses, as told by
+    /// the clock of the storage of the leader. See [`Storage::insert_with_ttl`].
+    pub fn insert_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = scheduler::millis(self.shared.storage.config.clock.now().saturating_add(ttl));
+
+        self.propose(Command::Insert { key, value, expires_at: Some(expires_at) })
+    }
+
+    /// Removes a key once a majority of the members logged it. See [`Raft::propose`].
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.propose(Command::Remove { key })
+    }
+
+    /// Inserts many values at once, which no other write lands between. See [`Raft::propose`].
+    pub fn insert_batch(&self, entries: impl IntoIterator<Item = (String, Vec<u8>)>) -> Result<()> {
+        let commands = entries
+            .into_iter()
+            .map(|(key, value)| Command::Insert { key, value, expires_at: None })
+            .collect();
+
+        self.propose(Command::Batch(commands))
+    }
+
+    /// Appends the write to the log of the leader, and waits for it to be applied to its storage,
+    /// once a majority of the members logged it. Fails with [`Error::NotLeader`] unless this member
+    /// is the leader, or stops being it before the write commits, and with [`Error::QuotaExceeded`],
+    /// without proposing it, if a key it inserts is over its quota on the leader. The members apply
+    /// the writes that committed regardless of their own quotas.
+    ///
+    /// Fails once the commit timeout elapses, see [`RaftBuilder::commit_timeout`], in which case
+    /// the write may still commit.
+    pub fn propose(&self, command: Command) -> Result<()> {
+        let shared = &self.shared;
+        let mut node = shared.lock();
+        shared.check(&node)?;
+        if node.role != Role::Leader {
+            return Err(Error::NotLeader { leader: node.leader }.into());
+        }
+        shared.check_quotas(&command)?;
+
+        let term = node.state.term;
+        let index = node.log.last_index() + 1;
+        let appended = node.log.append(vec![LogEntry { term, command: Some(command) }]);
+        shared.fail_on(&mut node, appended)?;
+        node.proposals.insert(index, Proposal { term, outcome: None });
+        let committed = shared.advance_commit(&mut node);
+        shared.fail_on(&mut node, committed)?;
+        shared.changed.notify_all();
+
+        let deadline = Instant::now() + shared.commit_timeout;
+        loop {
+            if let Some(outcome) = node.proposals.get_mut(&index).and_then(|proposal| proposal.outcome.take()) {
+                node.proposals.remove(&index);
+                return outcome;
+            }
+            if let Err(error) = shared.check(&node) {
+                node.proposals.remove(&index);
+                return Err(error);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                node.proposals.remove(&index);
+                return Err(anyhow!("timed out waiting for entry {} to commit, which it still may", index));
+            }
+            node = shared.wait(node, deadline - now);
+        }
+    }
+
+    /// Waits until the storage holds every write that committed before this was called, so that
+    /// the reads that follow see them: makes sure that this member still leads, as a majority of
+    /// the members acknowledge it, and that it applied the entries that committed by then. Fails
+    /// with [`Error::NotLeader`] unless this member is the leader, and once the commit timeout
+    /// elapses.
+    pub fn read_barrier(&self) -> Result<()> {
+        let shared = &self.shared;
+        let mut node = shared.lock();
+        let deadline = Instant::now() + shared.commit_timeout;
+
+        let mut confirmed: Option<(u64, u64)> = None;
+        loop {
+            shared.check(&node)?;
+            if node.role != Role::Leader {
+                return Err(Error::NotLeader { leader: node.leader }.into());
+            }
+
+            // A new leader only knows which entries committed once one of its own term did.
+            let term = node.state.term;
+            if confirmed.is_none() && node.log.term(node.commit) == Some(term) {
+                node.round += 1;
+                confirmed = Some((node.round, node.commit));
+                shared.changed.notify_all();
+            }
+
+            if let Some((round, commit)) = confirmed {
+                let acked = node.progress.values().filter(|progress| progress.acked >= round).count();
+                if acked + 1 >= shared.majority() && node.applied >= commit {
+                    return Ok(());
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(anyhow!("timed out waiting for a majority of the members"));
+            }
+            node = shared.wait(node, deadline - now);
+        }
+    }
+
+    /// Answers a [`VoteRequest`] of another member.
+    pub fn handle_vote(&self, request: &VoteRequest) -> Result<VoteResponse> {
+        let mut node = self.shared.lock();
+        self.shared.check(&node)?;
+
+        let response = self.shared.vote(&mut node, request);
+        self.shared.fail_on(&mut node, response)
+    }
+
+    /// Answers an [`AppendRequest`] of the leader: appends its entries, in place of those that
+    /// conflict with them, and applies those that committed.
+    pub fn handle_append(&self, request: &AppendRequest) -> Result<AppendResponse> {
+        let mut node = self.shared.lock();
+        self.shared.check(&node)?;
+
+        let response = self.shared.append(&mut node, request);
+        self.shared.fail_on(&mut node, response)
+    }
+
+    /// A new directory to receive a snapshot into, for [`Raft::install_snapshot`]. It is removed
+    /// once dropped, unless installed.
+    pub fn snapshot_dir(&self) -> Result<TempDir> {
+        Ok(tempfile::Builder::new().prefix(".snapshot-").tempdir_in(&self.shared.dir)?)
+    }
+
+    /// Answers a [`SnapshotRequest`] of the leader, whose snapshot was received into the given
+    /// directory of [`Raft::snapshot_dir`]: replaces the storage with the snapshot, along with the
+    /// entries of the log it holds, unless the storage already holds them.
+    pub fn install_snapshot(&self, request: &SnapshotRequest, dir: TempDir) -> Result<SnapshotResponse> {
+        let mut node = self.shared.lock();
+        self.shared.check(&node)?;
+
+        let response = self.shared.install(&mut node, request, dir);
+        self.shared.fail_on(&mut node, response)
+    }
+}
+
+impl Drop for Raft {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Node> {
+        self.node.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, node: MutexGuard<'a, Node>, timeout: Duration) -> MutexGuard<'a, Node> {
+        self.changed
+            .wait_timeout(node, timeout)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+
+    /// How many members make a majority.
+    fn majority(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    /// Fails with [`Error::BackgroundError`] once the member stopped.
+    fn check(&self, node: &Node) -> Result<()> {
+        match &node.failure {
+            Some(reason) => Err(Error::BackgroundError { reason: reason.clone() }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops the member if the result is an error, as what it persisted and what it holds in
+    /// memory may not match anymore.
+    fn fail_on<T>(&self, node: &mut Node, result: Result<T>) -> Result<T> {
+        if let Err(error) = &result {
+            node.failure = Some(format!("{:#}", error));
+            self.changed.notify_all();
+        }
+
+        result
+    }
+
+    fn vote(&self, node: &mut Node, request: &VoteRequest) -> Result<VoteResponse> {
+        if request.term > node.state.term {
+            self.become_follower(node, request.term)?;
+        }
+
+        let last = (node.log.last_term(), node.log.last_index());
+        let up_to_date = (request.last_log_term, request.last_log_index) >= last;
+        let free = node.state.voted_for.is_none_or(|voted_for| voted_for == request.candidate);
+        let granted = request.term == node.state.term && free && up_to_date;
+        if granted {
+            node.state.voted_for = Some(request.candidate);
+            self.persist(node)?;
+            node.deadline = Instant::now() + random_timeout(self.election_timeout);
+        }
+
+        Ok(VoteResponse { term: node.state.term, granted })
+    }
+
+    fn append(&self, node: &mut Node, request: &AppendRequest) -> Result<AppendResponse> {
+        let term = node.state.term;
+        if request.term < term {
+            return Ok(AppendResponse { term, success: false, last_index: node.log.last_index() });
+        }
+        self.heard_from(node, request.term, request.leader)?;
+
+        let last_index = node.log.last_index();
+        if request.prev_log_index > last_index {
+            return Ok(AppendResponse { term: request.term, success: false, last_index });
+        }
+
+        // The entries the snapshot holds committed, so they match those of the leader.
+        let base = node.log.base.0;
+        if request.prev_log_index >= base && node.log.term(request.prev_log_index) != Some(request.prev_log_term) {
+            let last_index = request.prev_log_index - 1;
+            return Ok(AppendResponse { term: request.term, success: false, last_index });
+        }
+
+        let mut new_entries = Vec::new();
+        for (index, entry) in (request.prev_log_index + 1..).zip(&request.entries) {
+            if index <= base {
+                continue;
+            }
+            if new_entries.is_empty() {
+                match node.log.term(index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => node.log.truncate_from(index)?,
+                    None => {}
+                }
+            }
+            new_entries.push(entry.clone());
+        }
+        node.log.append(new_entries)?;
+
+        let last_new = request.prev_log_index + request.entries.len() as u64;
+        let commit = request.leader_commit.min(last_new);
+        if commit > node.commit {
+            node.commit = commit;
+            self.apply_committed(node)?;
+        }
+        self.changed.notify_all();
+
+        Ok(AppendResponse { term: request.term, success: true, last_index: last_new })
+    }
+
+    fn install(&self, node: &mut Node, request: &SnapshotRequest, dir: TempDir) -> Result<SnapshotResponse> {
+        let term = node.state.term;
+        if request.term < term {
+            return Ok(SnapshotResponse { term });
+        }
+        self.heard_from(node, request.term, request.leader)?;
+
+        if request.last_index <= node.applied {
+            return Ok(SnapshotResponse { term: request.term });
+        }
+
+        let path = self.dir.join(SNAPSHOT_NAME);
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        self.env.rename(&dir.keep(), &path)?;
+        self.env.sync_dir(&self.dir)?;
+
+        // Once the state records the snapshot, it is installed again on restart until it is whole.
+        let snapshot = (request.last_index, request.last_term);
+        node.state.snapshot = snapshot;
+        self.persist(node)?;
+        if node.log.term(request.last_index) == Some(request.last_term) {
+            node.log.compact(snapshot)?;
+        } else {
+            node.log.reset(snapshot)?;
+        }
+
+        self.restore(node)?;
+        self.changed.notify_all();
+
+        Ok(SnapshotResponse { term: request.term })
+    }
+
+    /// Writes the state next to its file, and renames it into place.
+    fn persist(&self, node: &Node) -> Result<()> {
+        let path = self.dir.join(STATE_NAME);
+        let mut temporary = path.clone();
+        temporary.set_extension("tmp");
+
+        let mut file = self.env.create(&temporary)?;
+        format::write_record(&mut file, &node.state)?;
+        file.flush()?;
+        file.sync()?;
+        self.env.rename(&temporary, &path)?;
+        self.env.sync_dir(&self.dir)?;
+
+        Ok(())
+    }
+
+    /// Follows the leader of the given term, whose request was just heard from.
+    fn heard_from(&self, node: &mut Node, term: u64, leader: NodeId) -> Result<()> {
+        if term > node.state.term || node.role != Role::Follower {
+            self.become_follower(node, term)?;
+        }
+        node.leader = Some(leader);
+        node.deadline = Instant::now() + random_timeout(self.election_timeout);
+
+        Ok(())
+    }
+
+    fn become_follower(&self, node: &mut Node, term: u64) -> Result<()> {
+        if term > node.state.term {
+            node.state.term = term;
+            node.state.voted_for = None;
+            node.leader = None;
+            self.persist(node)?;
+        }
+        node.role = Role::Follower;
+        node.progress.clear();
+        node.deadline = Instant::now() + random_timeout(self.election_timeout);
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    fn become_leader(&self, node: &mut Node) -> Result<()> {
+        node.role = Role::Leader;
+        node.leader = Some(self.id);
+
+        let next = node.log.last_index() + 1;
+        node.progress = self
+            .peers
+            .iter()
+            .map(|&peer| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    sent: None,
+                    acked: 0,
+                    retry_at: None,
+                };
+                (peer, progress)
+            })
+            .collect();
+
+        let term = node.state.term;
+        node.log.append(vec![LogEntry { term, command: None }])?;
+        self.advance_commit(node)?;
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Commits the entries of the leader that a majority of the members logged, as long as the
+    /// last of them belongs to its own term, and applies them.
+    fn advance_commit(&self, node: &mut Node) -> Result<()> {
+        let mut matched: Vec<u64> = node.progress.values().map(|progress| progress.matched).collect();
+        matched.push(node.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let commit = matched[self.majority() - 1];
+        if commit > node.commit && node.log.term(commit) == Some(node.state.term) {
+            node.commit = commit;
+            self.apply_committed(node)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the entries up to the commit index, tells their proposers how it went, and drops
+    /// them from the log once it holds too many.
+    fn apply_committed(&self, node: &mut Node) -> Result<()> {
+        while node.applied < node.commit {
+            let index = node.applied + 1;
+            let entry = node.log.entry(index).cloned().context("a committed entry is missing from the log")?;
+
+            // A failure may be this member's own, so it can't skip the entry.
+            if let Some(command) = entry.command {
+                self.apply(command).with_context(|| format!("applying entry {}", index))?;
+            }
+
+            if let Some(proposal) = node.proposals.get_mut(&index) {
+                proposal.outcome = Some(match proposal.term == entry.term {
+                    true => Ok(()),
+                    false => Err(Error::NotLeader { leader: node.leader }.into()),
+                });
+            }
+            node.applied = index;
+        }
+
+        if node.log.entries.len() > self.max_log_entries {
+            self.compact(node)?;
+        }
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Applies a committed write to the storage, regardless of the quotas: the bytes the sstables
+    /// of each member take depend on when it flushes and compacts, so only the leader checks them,
+    /// before proposing the write. See [`Shared::check_quotas`].
+    fn apply(&self, command: Command) -> Result<()> {
+        let mut writer = self.storage.wait_for_writer();
+        let mut commands = vec![command];
+
+        while let Some(command) = commands.pop() {
+            match command {
+                Command::Insert { key, value, expires_at } => writer.put_replicated(key, value, expires_at)?,
+                Command::Remove { key } => writer.remove(key)?,
+                Command::Batch(batch) => commands.extend(batch.into_iter().rev()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with [`Error::QuotaExceeded`] if a key the command inserts is over its quota on this
+    /// member.
+    fn check_quotas(&self, command: &Command) -> Result<()> {
+        match command {
+            Command::Insert { key, .. } => self.storage.check_quotas(key),
+            Command::Remove { .. } => Ok(()),
+            Command::Batch(batch) => batch.iter().try_for_each(|command| self.check_quotas(command)),
+        }
+    }
+
+    /// Drops the applied entries from the log, once the storage durably holds them.
+    fn compact(&self, node: &mut Node) -> Result<()> {
+        self.storage.sync()?;
+
+        let applied = node.applied;
+        let term = node.log.term(applied).context("the last entry applied is missing from the log")?;
+        node.state.snapshot = (applied, term);
+        node.state.applied = applied;
+        self.persist(node)?;
+
+        node.log.compact((applied, term))
+    }
+
+    /// Replaces the storage with the snapshot the state records, and records that it durably holds
+    /// it.
+    fn restore(&self, node: &mut Node) -> Result<()> {
+        let path = self.dir.join(SNAPSHOT_NAME);
+        self.storage.restore_checkpoint(&path)?;
+        self.storage.sync()?;
+
+        let (index, _) = node.state.snapshot;
+        node.state.applied = index;
+        self.persist(node)?;
+        node.applied = index;
+        node.commit = node.commit.max(index);
+        std::fs::remove_dir_all(&path)?;
+
+        Ok(())
+    }
+
+    /// Stands for election whenever the leader wasn't heard from for an election timeout.
+    fn tick(&self) {
+        let mut node = self.lock();
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if node.role == Role::Leader || node.failure.is_some() {
+                node = self.wait(node, self.election_timeout);
+                continue;
+            }
+            if now < node.deadline {
+                let timeout = node.deadline - now;
+                node = self.wait(node, timeout);
+                continue;
+            }
+
+            node = match self.stand_for_election(node) {
+                Ok(node) => node,
+                Err(error) => {
+                    let mut node = self.lock();
+                    let _ = self.fail_on::<()>(&mut node, Err(error.context("standing for election")));
+                    node
+                }
+            };
+        }
+    }
+
+    fn stand_for_election<'a>(&'a self, mut node: MutexGuard<'a, Node>) -> Result<MutexGuard<'a, Node>> {
+        node.state.term += 1;
+        node.state.voted_for = Some(self.id);
+        node.role = Role::Candidate;
+        node.leader = None;
+        node.deadline = Instant::now() + random_timeout(self.election_timeout);
+        self.persist(&node)?;
+
+        let term = node.state.term;
+        let deadline = node.deadline;
+        let request = VoteRequest {
+            term,
+            candidate: self.id,
+            last_log_index: node.log.last_index(),
+            last_log_term: node.log.last_term(),
+        };
+        drop(node);
+
+        // The votes are counted as they come, so that members that don't answer can't hold up the
+        // election: their requests are left to finish on their own.
+        let (sender, receiver) = mpsc::channel();
+        for &peer in &self.peers {
+            let (transport, request, sender) = (self.transport.clone(), request.clone(), sender.clone());
+            thread::spawn(move || sender.send(transport.request_vote(peer, &request)));
+        }
+        drop(sender);
+
+        let (mut votes, mut newer) = (1, None);
+        while votes < self.majority() && newer.is_none() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Ok(response)) if response.term > term => newer = Some(response.term),
+                Ok(Ok(response)) if response.granted => votes += 1,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+
+        let mut node = self.lock();
+        if let Some(newer) = newer {
+            self.become_follower(&mut node, newer)?;
+        }
+        if node.role == Role::Candidate && node.state.term == term && votes >= self.majority() {
+            self.become_leader(&mut node)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Sends the other member the entries it lacks, or a snapshot if the log dropped them, and
+    /// heartbeats in between, while this member leads.
+    fn replicate(&self, peer: NodeId) {
+        let mut node = self.lock();
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            let wait = self.replication_due(&node, peer);
+            if let Some(wait) = wait {
+                node = self.wait(node, wait);
+                continue;
+            }
+
+            node = match self.send_to(node, peer) {
+                Ok(node) => node,
+                Err(error) => {
+                    let mut node = self.lock();
+                    let _ = self.fail_on::<()>(&mut node, Err(error.context(format!("replicating to {}", peer))));
+                    node
+                }
+            };
+        }
+    }
+
+    /// How long to wait before sending the other member a request, or none if one is due now.
+    fn replication_due(&self, node: &Node, peer: NodeId) -> Option<Duration> {
+        let progress = match (node.role, node.failure.is_none(), node.progress.get(&peer)) {
+            (Role::Leader, true, Some(progress)) => progress,
+            _ => return Some(self.heartbeat_interval),
+        };
+
+        let now = Instant::now();
+        if let Some(retry_at) = progress.retry_at.filter(|&retry_at| retry_at > now) {
+            return Some(retry_at - now);
+        }
+
+        // Entries to send, or a read waiting for the leadership to be confirmed, can't wait.
+        let heartbeat = match progress.sent {
+            Some((sent_at, round)) if round >= node.round && progress.next > node.log.last_index() => {
+                sent_at + self.heartbeat_interval
+            }
+            _ => return None,
+        };
+
+        (heartbeat > now).then(|| heartbeat - now)
+    }
+
+    fn send_to<'a>(&'a self, mut node: MutexGuard<'a, Node>, peer: NodeId) -> Result<MutexGuard<'a, Node>> {
+        let term = node.state.term;
+        let round = node.round;
+        let progress = node.progress.get_mut(&peer).context("no progress for the member")?;
+        progress.sent = Some((Instant::now(), round));
+        progress.retry_at = None;
+        let next = progress.next;
+
+        let response = if next <= node.log.base.0 {
+            // The checkpoint is written without holding the lock, so that the member keeps
+            // voting, appending and applying meanwhile. The storage holds the entries applied by
+            // now, as they are applied under the lock, along with any applied meanwhile, which the
+            // other member then applies again: every write replaces what it writes alike.
+            let last_index = node.applied;
+            let last_term = node.log.term(last_index).context("the last entry applied is missing from the log")?;
+            drop(node);
+
+            let dir = tempfile::Builder::new().prefix(".snapshot-").tempdir_in(&self.dir)?;
+            self.storage.checkpoint(dir.path())?;
+            node = self.lock();
+            if node.role != Role::Leader || node.state.term != term {
+                return Ok(node);
+            }
+
+            let request = SnapshotRequest { term, leader: self.id, last_index, last_term };
+            drop(node);
+
+            let response = self.transport.install_snapshot(peer, &request, dir.path());
+            node = self.lock();
+            response.map(|response| (response.term, request.last_index, true))
+        } else {
+            let request = AppendRequest {
+                term,
+                leader: self.id,
+                prev_log_index: next - 1,
+                prev_log_term: node.log.term(next - 1).context("the entry before the next one is missing")?,
+                entries: node.log.entries_from(next, MAX_APPEND_ENTRIES),
+                leader_commit: node.commit,
+            };
+            drop(node);
+
+            let response = self.transport.append_entries(peer, &request);
+            node = self.lock();
+            response.map(|response| (response.term, response.last_index, response.success))
+        };
+
+        let stale = node.role != Role::Leader || node.state.term != term;
+        let (response_term, last_index, success) = match response {
+            Ok(response) => response,
+            Err(_) => {
+                if let Some(progress) = node.progress.get_mut(&peer).filter(|_| !stale) {
+                    progress.retry_at = Some(Instant::now() + self.heartbeat_interval);
+                }
+                return Ok(node);
+            }
+        };
+
+        if response_term > node.state.term {
+            self.become_follower(&mut node, response_term)?;
+            return Ok(node);
+        }
+        let progress = match node.progress.get_mut(&peer) {
+            Some(progress) if !stale => progress,
+            _ => return Ok(node),
+        };
+
+        if success {
+            progress.matched = progress.matched.max(last_index);
+            progress.next = progress.matched + 1;
+            progress.acked = progress.acked.max(round);
+            self.advance_commit(&mut node)?;
+        } else {
+            progress.next = (last_index + 1).min(progress.next - 1).max(1);
+            progress.sent = None;
+        }
+        self.changed.notify_all();
+
+        Ok(node)
+    }
+}
+
+/// A timeout between the given one and twice as long, at random.
+fn random_timeout(timeout: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let nanos = u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX).max(1);
+
+    timeout + Duration::from_nanos(random % nanos)
+}
+
+fn read_state(env: &dyn Env, dir: &Path) -> Result<Option<State>> {
+    let path = dir.join(STATE_NAME);
+    if !env.read_dir(dir)?.contains(&path) {
+        return Ok(None);
+    }
+
+    let mut contents = Vec::new();
+    env.open(&path)?.read_to_end(&mut contents)?;
+
+    format::read_record(contents.as_slice())
+}
+
+/// The entries of a member that follow its snapshot, each written along with its index as a
+/// record of its own, see [`format::write_record`]. Entries are only ever appended, unless some
+/// are dropped, which rewrites the log whole.
+struct Log {
+    env: Arc<dyn Env>,
+    path: PathBuf,
+    file: Box<dyn EnvFile>,
+    /// The index and the term of the last entry the snapshot holds.
+    base: (u64, u64),
+    entries: Vec<LogEntry>,
+}
+
+impl Log {
+    /// Opens the log in the given directory, keeping the entries after the given snapshot. A torn
+    /// entry at the end of the log, left by a crash while it was appended, is dropped.
+    fn open(env: &Arc<dyn Env>, dir: &Path, base: (u64, u64)) -> Result<Self> {
+        let path = dir.join(LOG_NAME);
+
+        let mut contents = Vec::new();
+        if env.read_dir(dir)?.contains(&path) {
+            env.open(&path)?.read_to_end(&mut contents)?;
+        }
+
+        let mut entries = Vec::new();
+        let mut remaining = contents.as_slice();
+        loop {
+            let offset = (contents.len() - remaining.len()) as u64;
+            match format::read_record::<_, (u64, LogEntry)>(&mut remaining) {
+                Ok(Some((index, entry))) => {
+                    // Entries that don't follow the snapshot were dropped before a crash.
+                    if index == base.0 + entries.len() as u64 + 1 {
+                        entries.push(entry);
+                    }
+                }
+                Ok(None) => break,
+                Err(_) if remaining.is_empty() => break,
+                Err(error) => {
+                    return Err(Error::Corruption {
+                        path,
+                        offset,
+                        reason: error.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        let file = Log::write(env.as_ref(), &path, base, &entries)?;
+
+        Ok(Log {
+            env: env.clone(),
+            path,
+            file,
+            base,
+            entries,
+        })
+    }
+
+    fn last_index(&self) -> u64 {
+        self.base.0 + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.base.1, |entry| entry.term)
+    }
+
+    /// The term of the entry at the given index, unless the log doesn't hold it.
+    fn term(&self, index: u64) -> Option<u64> {
+        match index == self.base.0 {
+            true => Some(self.base.1),
+            false => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        let position = index.checked_sub(self.base.0 + 1)?;
+
+        self.entries.get(usize::try_from(position).ok()?)
+    }
+
+    /// Up to `max` entries from the given index on, which must follow the snapshot.
+    fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = ((index - self.base.0 - 1) as usize).min(self.entries.len());
+
+        self.entries[start..].iter().take(max).cloned().collect()
+    }
+
+    /// Appends the entries after the last one, and syncs them.
+    fn append(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut records = Vec::new();
+        for (index, entry) in (self.last_index() + 1..).zip(&entries) {
+            format::write_record(&mut records, &(index, entry))?;
+        }
+        self.file.write_all(&records)?;
+        self.file.flush()?;
+        self.file.sync()?;
+
+        self.entries.extend(entries);
+
+        Ok(())
+    }
+
+    /// Drops the entries from the given index on.
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.base.0 - 1) as usize);
+
+        self.rewrite()
+    }
+
+    /// Drops the entries the given snapshot holds, which must be in the log.
+    fn compact(&mut self, base: (u64, u64)) -> Result<()> {
+        let dropped = ((base.0 - self.base.0) as usize).min(self.entries.len());
+        self.entries.drain(..dropped);
+        self.base = base;
+
+        self.rewrite()
+    }
+
+    /// Drops every entry, as the given snapshot replaces them.
+    fn reset(&mut self, base: (u64, u64)) -> Result<()> {
+        self.entries.clear();
+        self.base = base;
+
+        self.rewrite()
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        self.file = Log::write(self.env.as_ref(), &self.path, self.base, &self.entries)?;
+
+        Ok(())
+    }
+
+    /// Writes the entries after the given snapshot next to the log at the given path, renames
+    /// them into place, and opens the log to append to it.
+    fn write(env: &dyn Env, path: &Path, base: (u64, u64), entries: &[LogEntry]) -> Result<Box<dyn EnvFile>> {
+        let mut temporary = path.to_path_buf();
+        temporary.set_extension("tmp");
+
+        let mut file = env.create(&temporary)?;
+        for (index, entry) in (base.0 + 1..).zip(entries) {
+            format::write_record(&mut file, &(index, entry))?;
+        }
+        file.flush()?;
+        file.sync()?;
+        env.rename(&temporary, path)?;
+        env.sync_dir(path.parent().unwrap_or(Path::new(".")))?;
+
+        let mut file = env.open_writable(path)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex, Weak};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use anyhow::{anyhow, Result};
+
+    use crate::raft::{
+        AppendRequest, AppendResponse, NodeId, Raft, SnapshotRequest, SnapshotResponse, Transport, VoteRequest,
+        VoteResponse,
+    };
+    use crate::storage::Storage;
+    use crate::test_utils::Test;
+    use crate::Error;
+
+    /// Passes the requests of the members straight to one another, unless either is cut off.
+    #[derive(Default)]
+    struct Network {
+        members: Mutex<HashMap<NodeId, Weak<Raft>>>,
+        down: Mutex<HashSet<NodeId>>,
+    }
+
+    impl Network {
+        fn reach(&self, from: NodeId, to: NodeId) -> Result<Arc<Raft>> {
+            let down = self.down.lock().unwrap();
+            if down.contains(&from) || down.contains(&to) {
+                return Err(anyhow!("{} can't reach {}", from, to));
+            }
+
+            let members = self.members.lock().unwrap();
+            members.get(&to).and_then(Weak::upgrade).ok_or_else(|| anyhow!("{} is stopped", to))
+        }
+
+        fn set_down(&self, member: NodeId, down: bool) {
+            let mut members = self.down.lock().unwrap();
+            match down {
+                true => members.insert(member),
+                false => members.remove(&member),
+            };
+        }
+    }
+
+    struct Link {
+        from: NodeId,
+        network: Arc<Network>,
+    }
+
+    impl Transport for Link {
+        fn request_vote(&self, to: NodeId, request: &VoteRequest) -> Result<VoteResponse> {
+            self.network.reach(self.from, to)?.handle_vote(request)
+        }
+
+        fn append_entries(&self, to: NodeId, request: &AppendRequest) -> Result<AppendResponse> {
+            self.network.reach(self.from, to)?.handle_append(request)
+        }
+
+        fn install_snapshot(&self, to: NodeId, request: &SnapshotRequest, dir: &Path) -> Result<SnapshotResponse> {
+            let member = self.network.reach(self.from, to)?;
+
+            let received = member.snapshot_dir()?;
+            for file in std::fs::read_dir(dir)? {
+                let path = file?.path();
+                std::fs::copy(&path, received.path().join(path.file_name().unwrap()))?;
+            }
+
+            member.install_snapshot(request, received)
+        }
+    }
+
+    struct Cluster {
+        members: Vec<Arc<Raft>>,
+        network: Arc<Network>,
+        /// Keeps the directories of the members until they stop.
+        _tests: Vec<Test>,
+    }
+
+    impl Cluster {
+        fn new(size: u64, max_log_entries: usize) -> Result<Self> {
+            Cluster::with_storages(size, max_log_entries, Test::create_storage)
+        }
+
+        /// Starts a cluster whose members apply the writes to the storages built by the given
+        /// function.
+        fn with_storages(size: u64, max_log_entries: usize, storage: impl Fn(&Test) -> Result<Storage>) -> Result<Self> {
+            let network = Arc::new(Network::default());
+            let ids: Vec<NodeId> = (1..=size).collect();
+
+            let mut tests = Vec::new();
+            let mut members = Vec::new();
+            for &id in &ids {
+                let test = Test::new()?;
+                let link = Arc::new(Link { from: id, network: network.clone() });
+                let member = Arc::new(start(&test, id, ids.clone(), max_log_entries, storage(&test)?, link)?);
+                network.members.lock().unwrap().insert(id, Arc::downgrade(&member));
+
+                tests.push(test);
+                members.push(member);
+            }
+
+            Ok(Cluster { members, network, _tests: tests })
+        }
+
+        fn member(&self, id: NodeId) -> &Raft {
+            &self.members[id as usize - 1]
+        }
+
+        /// Waits for one of the members that aren't cut off to lead the others.
+        fn leader(&self) -> NodeId {
+            let down = || self.network.down.lock().unwrap().clone();
+            let mut leader = None;
+
+            wait_until(|| {
+                let down = down();
+                let leaders: HashSet<_> = self
+                    .members
+                    .iter()
+                    .filter(|member| !down.contains(&member.id()))
+                    .map(|member| member.leader())
+                    .collect();
+
+                leader = leaders.into_iter().next().flatten().filter(|&leader| !down.contains(&leader));
+                leader.is_some_and(|leader| self.member(leader).read_barrier().is_ok())
+                    && self
+                        .members
+                        .iter()
+                        .filter(|member| !down.contains(&member.id()))
+                        .all(|member| member.leader() == leader)
+            });
+
+            leader.unwrap()
+        }
+    }
+
+    fn start(
+        test: &Test,
+        id: NodeId,
+        members: Vec<NodeId>,
+        max_log_entries: usize,
+        storage: Storage,
+        link: Arc<Link>,
+    ) -> Result<Raft> {
+        Raft::builder(id, members)
+            .dir(test.path("raft"))
+            .election_timeout(Duration::from_millis(50))
+            .heartbeat_interval(Duration::from_millis(10))
+            .commit_timeout(Duration::from_secs(2))
+            .max_log_entries(max_log_entries)
+            .build(storage, link)
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for the condition");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn reads(member: &Raft, key: &str) -> Option<Vec<u8>> {
+        member.storage().read(key).unwrap()
+    }
+
+    #[test]
+    fn the_leader_replicates_the_writes_to_the_others() -> Result<()> {
+        let cluster = Cluster::new(3, 10_000)?;
+        let leader = cluster.leader();
+
+        cluster.member(leader).insert("a".to_owned(), b"1".to_vec())?;
+        cluster.member(leader).insert_batch([("b".to_owned(), b"2".to_vec()), ("c".to_owned(), b"3".to_vec())])?;
+        cluster.member(leader).remove("a".to_owned())?;
+        assert_eq!(reads(cluster.member(leader), "b"), Some(b"2".to_vec()));
+
+        for member in &cluster.members {
+            // The key was inserted before the others, so it is only missing once removed.
+            wait_until(|| reads(member, "c") == Some(b"3".to_vec()) && reads(member, "a").is_none());
+            assert_eq!(reads(member, "b"), Some(b"2".to_vec()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn followers_refuse_writes_and_name_the_leader() -> Result<()> {
+        let cluster = Cluster::new(3, 10_000)?;
+        let leader = cluster.leader();
+        let follower = cluster.members.iter().find(|member| member.id() != leader).unwrap();
+
+        let error = follower.insert("a".to_owned(), b"1".to_vec()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::NotLeader { leader: Some(id) }) if *id == leader));
+        let error = follower.read_barrier().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::NotLeader { .. })));
+        assert_eq!(reads(follower, "a"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_others_elect_a_new_leader_once_the_leader_is_cut_off() -> Result<()> {
+        let cluster = Cluster::new(3, 10_000)?;
+        let old_leader = cluster.leader();
+        cluster.member(old_leader).insert("a".to_owned(), b"1".to_vec())?;
+
+        cluster.network.set_down(old_leader, true);
+        let error = cluster.member(old_leader).insert("b".to_owned(), b"2".to_vec()).unwrap_err();
+        assert!(error.downcast_ref::<Error>().is_none(), "{:#}", error);
+
+        let leader = cluster.leader();
+        assert_ne!(leader, old_leader);
+        cluster.member(leader).insert("c".to_owned(), b"3".to_vec())?;
+
+        // The write the old leader couldn't commit is dropped once it hears from the new one.
+        cluster.network.set_down(old_leader, false);
+        wait_until(|| reads(cluster.member(old_leader), "c") == Some(b"3".to_vec()));
+        assert_eq!(cluster.leader(), leader);
+        for member in &cluster.members {
+            wait_until(|| reads(member, "c").is_some());
+            assert_eq!(reads(member, "a"), Some(b"1".to_vec()));
+            assert_eq!(reads(member, "b"), None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn members_that_fall_behind_the_log_install_a_snapshot() -> Result<()> {
+        let cluster = Cluster::new(3, 4)?;
+        let leader = cluster.leader();
+        let behind = cluster.members.iter().map(|member| member.id()).find(|&id| id != leader).unwrap();
+        cluster.member(leader).insert("old".to_owned(), b"value".to_vec())?;
+        wait_until(|| reads(cluster.member(behind), "old").is_some());
+
+        cluster.network.set_down(behind, true);
+        cluster.member(leader).remove("old".to_owned())?;
+        for i in 0..20 {
+            cluster.member(leader).insert(format!("key-{:02}", i), b"value".to_vec())?;
+        }
+        let ttl = Duration::from_secs(3600);
+        cluster.member(leader).insert_with_ttl("expiring".to_owned(), b"value".to_vec(), ttl)?;
+        assert!(cluster.member(leader).shared.lock().log.base.0 > 1);
+
+        cluster.network.set_down(behind, false);
+        let member = cluster.member(behind);
+        wait_until(|| reads(member, "expiring").is_some());
+        for i in 0..20 {
+            assert_eq!(reads(member, &format!("key-{:02}", i)), Some(b"value".to_vec()));
+        }
+        assert_eq!(reads(member, "old"), None);
+
+        // It keeps up through the log once it installed the snapshot.
+        cluster.member(leader).insert("new".to_owned(), b"value".to_vec())?;
+        wait_until(|| reads(member, "new").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_leader_checks_the_quotas_so_that_every_member_applies_the_same_writes() -> Result<()> {
+        let cluster = Cluster::with_storages(3, 10_000, |test| test.storage_builder().quota("q/", 64).build())?;
+        let leader = cluster.leader();
+        let follower = cluster.members.iter().find(|member| member.id() != leader).unwrap();
+
+        // Only the sstables of the follower hold the key yet, which puts it over the quota.
+        cluster.member(leader).insert("q/a".to_owned(), vec![0; 128])?;
+        wait_until(|| reads(follower, "q/a").is_some());
+        follower.storage().flush()?;
+        follower.storage().tick()?;
+
+        cluster.member(leader).insert("q/b".to_owned(), b"value".to_vec())?;
+        for member in &cluster.members {
+            wait_until(|| reads(member, "q/b").is_some());
+        }
+
+        // Once those of the leader hold it too, the writes under the prefix aren't proposed.
+        cluster.member(leader).storage().flush()?;
+        cluster.member(leader).storage().tick()?;
+        let error = cluster.member(leader).insert("q/c".to_owned(), b"value".to_vec()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::QuotaExceeded { .. })));
+        cluster.member(leader).insert("other".to_owned(), b"value".to_vec())?;
+        for member in &cluster.members {
+            wait_until(|| reads(member, "other").is_some());
+            assert_eq!(reads(member, "q/c"), None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn a_member_keeps_its_writes_and_term_across_restarts() -> Result<()> {
+        let test = Test::new()?;
+        let network = Arc::new(Network::default());
+        let link = || Arc::new(Link { from: 1, network: network.clone() });
+
+        let member = start(&test, 1, vec![1], 2, test.create_storage()?, link())?;
+        wait_until(|| member.leader() == Some(1));
+        for i in 0..5 {
+            member.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+        let term = member.shared.lock().state.term;
+        drop(member);
+
+        let member = start(&test, 1, vec![1], 2, test.create_storage()?, link())?;
+        assert!(member.shared.lock().state.term >= term);
+        wait_until(|| member.leader() == Some(1));
+        assert!(member.shared.lock().state.term > term);
+
+        for i in 0..5 {
+            assert_eq!(reads(&member, &format!("key-{}", i)), Some(b"value".to_vec()));
+        }
+        member.insert("key-5".to_owned(), b"value".to_vec())?;
+        member.read_barrier()?;
+        assert_eq!(reads(&member, "key-5"), Some(b"value".to_vec()));
+
+        Ok(())
+    }
+}
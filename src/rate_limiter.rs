@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::scheduler::Clock;
+
+/// Throttles writes to a number of bytes and of operations per second, as token buckets refilled
+/// by the clock of the storage. Each bucket holds up to a second worth of tokens, so writes may
+/// burst that much after being idle. See
+/// [`StorageBuilder::write_bytes_per_sec`](crate::storage::StorageBuilder::write_bytes_per_sec).
+pub(crate) struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+struct State {
+    bytes: Option<Bucket>,
+    ops: Option<Bucket>,
+    refilled_at: Duration,
+    /// How long the writes were told to wait so far.
+    delayed: Duration,
+}
+
+struct Bucket {
+    /// How many tokens are added per second, which is also how many the bucket holds.
+    rate: f64,
+    /// Negative while the writes let through owe tokens, which the next ones wait for.
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Bucket { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + self.rate * elapsed.as_secs_f64()).min(self.rate);
+    }
+
+    /// Takes the given number of tokens, returning how long it takes for the bucket to no longer
+    /// owe any.
+    fn take(&mut self, tokens: u64) -> Duration {
+        self.tokens -= tokens as f64;
+
+        Duration::from_secs_f64((-self.tokens / self.rate).max(0.0))
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter for the given rates, unless neither is limited.
+    pub fn new(clock: Arc<dyn Clock>, bytes_per_sec: Option<u64>, ops_per_sec: Option<u64>) -> Option<Self> {
+        if bytes_per_sec.is_none() && ops_per_sec.is_none() {
+            return None;
+        }
+
+        let state = State {
+            bytes: bytes_per_sec.map(Bucket::new),
+            ops: ops_per_sec.map(Bucket::new),
+            refilled_at: clock.now(),
+            delayed: Duration::ZERO,
+        };
+
+        Some(RateLimiter {
+            clock,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Lets a write of the given number of bytes and operations through, returning how long it
+    /// has to wait first. A write larger than a bucket still gets through, once it has waited for
+    /// what it takes beyond it.
+    pub fn reserve(&self, bytes: u64, ops: u64) -> Duration {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(state.refilled_at);
+        state.refilled_at = now;
+
+        let mut delay = Duration::ZERO;
+        if let Some(bucket) = state.bytes.as_mut() {
+            bucket.refill(elapsed);
+            delay = delay.max(bucket.take(bytes));
+        }
+        if let Some(bucket) = state.ops.as_mut() {
+            bucket.refill(elapsed);
+            delay = delay.max(bucket.take(ops));
+        }
+        state.delayed += delay;
+
+        delay
+    }
+
+    /// Waits until a write of the given number of bytes and operations may go through.
+    pub fn acquire(&self, bytes: u64, ops: u64) {
+        let delay = self.reserve(bytes, ops);
+
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// How long the writes were told to wait since the limiter was created.
+    pub fn delayed(&self) -> Duration {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).delayed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::ManualClock;
+
+    #[test]
+    fn writes_wait_once_a_second_worth_of_tokens_is_spent() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::new(clock.clone(), Some(1000), Some(10)).unwrap();
+
+        // The buckets start full.
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(50, 1), Duration::ZERO);
+        }
+        assert_eq!(limiter.reserve(50, 1), Duration::from_millis(100));
+        assert_eq!(limiter.reserve(50, 1), Duration::from_millis(200));
+
+        // Once the debt is paid, the buckets fill up again, but no further than a second worth.
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.reserve(1000, 1), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, 1), Duration::from_millis(500));
+        assert_eq!(limiter.delayed(), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn limiters_are_only_created_for_some_limit() {
+        let clock = Arc::new(ManualClock::new());
+        assert!(RateLimiter::new(clock.clone(), None, None).is_none());
+
+        let limiter = RateLimiter::new(clock, Some(100), None).unwrap();
+        assert_eq!(limiter.reserve(100, 1000), Duration::ZERO);
+        assert_eq!(limiter.reserve(100, 1000), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of time for the storage.
+///
+/// Every time-based decision goes through a `Clock`, so that tests can control time instead of
+/// depending on how fast the machine running them is.
+pub trait Clock: Send + Sync {
+    /// The time elapsed since the UNIX epoch.
+    fn now(&self) -> Duration;
+}
+
+/// The given time since the UNIX epoch, in milliseconds, as stored in the files of the storage.
+pub(crate) fn millis(time: Duration) -> u64 {
+    u64::try_from(time.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// The default `Clock`, backed by the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A `Clock` that only moves when told to. Starts at the UNIX epoch.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward by the given amount.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Defines how the background work of the storage, flushes and compactions, is run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduling {
+    /// Background work runs in a dedicated thread as soon as it is scheduled.
+    #[default]
+    Background,
+    /// Background work is queued until [`Storage::tick`](crate::storage::Storage::tick) runs it
+    /// in the calling thread. This makes the state of the storage deterministic, e.g. in tests.
+    Manual,
+}
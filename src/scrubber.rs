@@ -0,0 +1,217 @@
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::engine::Engine;
+use crate::events::CorruptionInfo;
+use crate::manifest::Edit;
+use crate::sstable::SSTable;
+use crate::storage::Config;
+
+/// The directory, inside the segments path, where corrupt sstables are moved to.
+pub(crate) const QUARANTINE_NAME: &str = "quarantine";
+
+/// Periodically re-reads every sstable to find the corrupt ones before a read stumbles on them.
+///
+/// Scrubbing is meant to run with low priority: the engine is only locked to list the sstables and
+/// to quarantine the corrupt ones, never while reading them.
+pub(crate) struct Scrubber {
+    engine: Weak<Mutex<Engine>>,
+    config: Config,
+    last_scrub: Duration,
+}
+
+impl Scrubber {
+    pub fn new(engine: &Arc<Mutex<Engine>>, config: Config) -> Self {
+        let last_scrub = config.clock.now();
+
+        Scrubber {
+            engine: Arc::downgrade(engine),
+            config,
+            last_scrub,
+        }
+    }
+
+    /// Scrubs once per scrub interval, until the storage is dropped.
+    pub fn run(&mut self) -> Result<()> {
+        let interval = match self.config.scrub_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+
+        loop {
+            thread::sleep(interval);
+
+            if self.engine.strong_count() == 0 {
+                return Ok(());
+            }
+
+            self.scrub()?;
+        }
+    }
+
+    /// Scrubs if the scrub interval elapsed since the last time. Returns whether it did.
+    pub fn scrub_if_due(&mut self) -> Result<bool> {
+        let interval = match self.config.scrub_interval {
+            Some(interval) => interval,
+            None => return Ok(false),
+        };
+
+        let now = self.config.clock.now();
+        if now.saturating_sub(self.last_scrub) < interval {
+            return Ok(false);
+        }
+
+        self.scrub()?;
+        self.last_scrub = now;
+
+        Ok(true)
+    }
+
+    /// Verifies every sstable, reporting the corrupt ones to the event listeners and quarantining
+    /// them if configured to. Returns how many were found.
+    pub fn scrub(&mut self) -> Result<usize> {
+        let engine = match self.engine.upgrade() {
+            Some(engine) => engine,
+            None => return Ok(0),
+        };
+
+        let sstables: Vec<SSTable> = {
+            let engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+            engine.version.sstables0.iter().chain(engine.version.sstables1.iter()).cloned().collect()
+        };
+
+        let mut corrupt = 0;
+
+        for sstable in sstables {
+            let error = match sstable.verify() {
+                Ok(_) => continue,
+                Err(error) => error,
+            };
+
+            corrupt += 1;
+
+            let quarantined = self.config.quarantine_corrupt_files && self.quarantine(&engine, &sstable)?;
+            let info = CorruptionInfo {
+                path: sstable.path().to_path_buf(),
+                error: format!("{:#}", error),
+                quarantined,
+            };
+
+            for listener in &self.config.event_listeners {
+                listener.on_corruption(&info);
+            }
+        }
+
+        Ok(corrupt)
+    }
+
+    /// Removes the sstable from the manifest and the engine, and moves its file into the
+    /// quarantine directory. Returns false if the sstable was compacted away in the meantime.
+    fn quarantine(&self, engine: &Mutex<Engine>, sstable: &SSTable) -> Result<bool> {
+        let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let levels = [&engine.version.sstables0, &engine.version.sstables1];
+        let (level, index) = match levels
+            .iter()
+            .enumerate()
+            .find_map(|(level, sstables)| Some((level, sstables.iter().position(|table| table == sstable)?)))
+        {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        let mut destination = self.config.segments_path.clone();
+        destination.push(QUARANTINE_NAME);
+        self.config.env.create_dir_all(&destination)?;
+
+        let name = sstable.path().file_name().unwrap_or_default();
+        engine.manifest.record(&Edit::ReplaceTables {
+            removed: vec![name.to_string_lossy().into_owned()],
+            added: Vec::new(),
+            level,
+        })?;
+
+        let version = engine.version_mut();
+        let (sstables, readers) = match level {
+            0 => (&mut version.sstables0, &mut version.sstable_readers0),
+            _ => (&mut version.sstables1, &mut version.sstable_readers1),
+        };
+        sstables.remove(index);
+        readers.remove(index);
+
+        destination.push(name);
+        self.config.env.rename(sstable.path(), &destination)?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::QUARANTINE_NAME;
+    use crate::events::{CorruptionInfo, EventListener};
+    use crate::manifest::{replay, Manifest};
+    use crate::scheduler::ManualClock;
+    use crate::test_utils::Test;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<CorruptionInfo>>);
+
+    impl EventListener for Recorder {
+        fn on_corruption(&self, info: &CorruptionInfo) {
+            self.0.lock().unwrap().push(info.clone());
+        }
+    }
+
+    #[test]
+    fn corrupt_sstables_are_reported_and_quarantined() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let recorder = Arc::new(Recorder::default());
+        let mut storage = test
+            .storage_builder()
+            .clock(clock.clone())
+            .scrub_interval(Duration::from_secs(60))
+            .quarantine_corrupt_files(true)
+            .event_listener(recorder.clone())
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+
+        let corrupt_path = storage.engine.lock().unwrap().version.sstables0[0].path().to_path_buf();
+        OpenOptions::new().append(true).open(&corrupt_path)?.write_all(&[0xff; 3])?;
+
+        clock.advance(Duration::from_secs(60));
+        storage.tick()?;
+
+        let reports = recorder.0.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].path, corrupt_path);
+        assert!(reports[0].quarantined);
+
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 1);
+        assert!(!corrupt_path.exists());
+        assert!(test.path(QUARANTINE_NAME).join(corrupt_path.file_name().unwrap()).exists());
+        drop(reports);
+        drop(storage);
+
+        // The manifest no longer lists it either.
+        let (_, edits) = Manifest::open(&test.env(), &test.test_path(), &[])?;
+        let name = corrupt_path.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(!replay(edits).concat().contains(&name));
+
+        Ok(())
+    }
+}
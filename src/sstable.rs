@@ -1,128 +1,1121 @@
-use crate::format;
+use crate::compression::Compression;
+use crate::env::{Advice, Env, EnvFile, OsEnv};
+use crate::error::Error;
+use crate::cache::BlockCache;
+use crate::filter::{self, Filter, FilterInfo, FilterKind};
+use crate::format::{self, TableItem};
+use crate::index::{self, Block, IndexBuilder, IndexPartition, TableIndex};
+use crate::prefetch::{BlockReader, Prefetcher};
+use crate::scheduler;
+use crate::versioned::{Pruner, Retention};
 use crate::Stored;
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 /// A data structure that allows read-only access into an ordered set of <key, value> pairs persisted on-disk.
 ///
-/// Upon initialization, all entries are read to build an index with the offset for each key. This
-/// allows for quick reads into the log by seeking directly into the correct offset.
-#[derive(PartialEq, Eq, Clone)]
+/// Upon initialization, all entries are read to build an index with the offset for each key, unless
+/// the SSTable persists its index, partitioned. This allows for quick reads into the log by seeking
+/// directly into the correct offset.
+#[derive(Clone)]
 pub struct SSTable {
+    env: Arc<dyn Env>,
     path: PathBuf,
 }
 
+/// How SSTables are written by flushes and compactions, and read by compactions.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TableOptions {
+    /// The codec compressing the values.
+    pub compression: Compression,
+    /// The size below which values are stored uncompressed.
+    pub min_compressed_value_size: usize,
+    /// The filter written along with the keys, if any.
+    pub filter: Option<FilterKind>,
+    /// The bits per key of the filter.
+    pub filter_bits_per_key: usize,
+    /// How many bytes are read at once when reading a whole SSTable.
+    pub readahead: usize,
+    /// Whether the I/O bypasses the page cache.
+    pub direct_io: bool,
+    /// Whether merges leave tombstones out of their output, which is only correct when no older
+    /// SSTable may hold the keys they remove.
+    pub drop_tombstones: bool,
+    /// The size past which merges start a new output SSTable, if any.
+    pub target_file_size: Option<u64>,
+    /// The size of the entries past which the index of the SSTables is partitioned, if any. See
+    /// [`IndexBuilder`].
+    pub partition_index_above: Option<u64>,
+    /// Which versions of the keys written through a
+    /// [`VersionedStorage`](crate::versioned::VersionedStorage) merges keep, if not all of them.
+    pub retention: Option<Retention>,
+    /// The level the SSTables are written into.
+    pub level: usize,
+    /// What writes the SSTables.
+    pub source: TableSource,
+    /// When the SSTables are written, since the UNIX epoch.
+    pub created_at: Duration,
+}
+
+/// What wrote a SSTable.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TableSource {
+    #[default]
+    Flush,
+    Compaction,
+    /// A merge of an ingested SSTable with the tables it overlaps.
+    Ingestion,
+}
+
+/// Describes a SSTable. It is stored in the footer of the file, after the last entry, so that
+/// tools inspecting the file need nothing else. SSTables written before footers were introduced
+/// have none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableMetadata {
+    /// When the SSTable was written, in milliseconds since the UNIX epoch, as told by the clock of
+    /// the storage.
+    pub created_at: u64,
+    pub source: TableSource,
+    /// The level the SSTable was written into.
+    pub level: usize,
+    /// The lowest sequence number of the entries, or 0 if there are none.
+    pub min_seqno: u64,
+    /// The highest sequence number of the entries, or 0 if there are none.
+    pub max_seqno: u64,
+}
+
+impl TableOptions {
+    /// The metadata of a SSTable written with these options, whose entries have the given lowest
+    /// and highest sequence numbers, unless it has none.
+    pub fn metadata(&self, seqnos: Option<(u64, u64)>) -> TableMetadata {
+        let (min_seqno, max_seqno) = seqnos.unwrap_or_default();
+
+        TableMetadata {
+            created_at: scheduler::millis(self.created_at),
+            source: self.source,
+            level: self.level,
+            min_seqno,
+            max_seqno,
+        }
+    }
+
+    /// The codec compressing the given value: the one of the options, unless the value is too
+    /// small to be worth it. Each entry records whether its value is compressed, and with which
+    /// codec, so that entries written either way are read alike.
+    pub fn compression_of(&self, value: &Stored) -> Compression {
+        match value {
+            Stored::Value(value) | Stored::Expiring(value, _) if value.len() < self.min_compressed_value_size => {
+                Compression::None
+            }
+            _ => self.compression,
+        }
+    }
+
+    /// Creates the file of a new SSTable.
+    pub fn create(&self, env: &dyn Env, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        if self.direct_io {
+            env.create_direct(path)
+        } else {
+            env.create(path)
+        }
+    }
+}
+
+/// Reads the entries of an SSTable through an index of its keys: the one persisted in the file if
+/// the index is partitioned, or else one built when it is opened.
+///
+/// Readers are also meant for tools inspecting the files of a storage, e.g. while it is closed:
+/// see [`SSTableReader::open`].
 pub struct SSTableReader {
-    fd: File,
-    indexes: HashMap<String, u64>,
+    path: PathBuf,
+    fd: Box<dyn EnvFile>,
+    /// Tells the blocks of the reader apart from those of the others in the block cache.
+    id: u64,
+    index: Index,
+    /// Where the partitions of the index and their filters are cached once read, if anywhere.
+    cache: Option<Arc<BlockCache<Block>>>,
+    /// The offset right after the last entry.
+    end: u64,
+    tombstones: usize,
+    max_seqno: u64,
+    metadata: Option<TableMetadata>,
+    /// Set once the SSTable was replaced, to delete its file when the reader is dropped, along
+    /// with whether to shred it. See [`SSTableReader::mark_obsolete`].
+    obsolete: OnceLock<(Arc<dyn Env>, bool)>,
+}
+
+/// The index of the keys of an open SSTable.
+enum Index {
+    /// Every key along with the offset of its entry, sorted by key, built by reading the whole
+    /// SSTable when it is opened.
+    Resident {
+        offsets: Vec<(String, u64)>,
+        /// Tells the keys that are definitely not in the SSTable, unless it was written without one.
+        filter: Option<Filter>,
+        /// When each value inserted with a TTL expires, along with the index of its key.
+        expiring: Vec<(u64, usize)>,
+    },
+    /// The index persisted in the SSTable, whose partitions and their filters are read on demand.
+    Partitioned(TableIndex),
+}
+
+/// Tells the readers apart in the block cache.
+static NEXT_READER_ID: AtomicU64 = AtomicU64::new(0);
+
+impl PartialEq for SSTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
 }
 
+impl Eq for SSTable {}
+
 impl SSTable {
     /// Initializes a SSTable for the provided path and scans the log to build the in-memory index.
-    pub fn new(path: &Path) -> Self {
-        SSTable { path: path.to_path_buf() }
+    pub fn new(env: &Arc<dyn Env>, path: &Path) -> Self {
+        SSTable {
+            env: env.clone(),
+            path: path.to_path_buf(),
+        }
     }
 
-    pub fn reader(&self) -> Result<SSTableReader> {
-        let fd = File::open(&self.path)?;
-        let indexes = SSTable::build_index_table(&fd)?;
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        Ok(SSTableReader { fd, indexes })
+    /// The size of the SSTable on-disk, in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.env.file_size(&self.path)?)
     }
 
-    fn build_index_table(fd: &File) -> Result<HashMap<String, u64>> {
-        let mut indexes = HashMap::new();
+    /// Opens the SSTable to be read from start to end, through a buffer of `readahead` bytes.
+    pub(crate) fn sequential_reader(&self, options: &TableOptions) -> Result<BufReader<Box<dyn EnvFile>>> {
+        let mut fd = if options.direct_io {
+            self.env.open_direct(&self.path)?
+        } else {
+            self.env.open(&self.path)?
+        };
+        let _ = fd.advise(Advice::Sequential);
 
-        let mut bytes_read = 0;
+        Ok(BufReader::with_capacity(options.readahead, fd))
+    }
 
-        while let Ok(Some(entry)) = format::read_entry(fd) {
-            let pair_size = format::entry_size(&entry)?;
-            indexes.insert(entry.0, bytes_read);
-            bytes_read += pair_size;
-        }
+    /// Opens the SSTable to be read from the offset to the end, in blocks of `readahead` bytes,
+    /// each read by the prefetcher, if any, while the one before it is consumed.
+    pub(crate) fn block_reader(
+        &self,
+        options: &TableOptions,
+        offset: u64,
+        prefetcher: Option<Arc<Prefetcher>>,
+    ) -> Result<BlockReader> {
+        let mut fd = if options.direct_io {
+            self.env.open_direct(&self.path)?
+        } else {
+            self.env.open(&self.path)?
+        };
+        let _ = fd.advise(Advice::Sequential);
 
-        Ok(indexes)
+        Ok(BlockReader::new(fd, offset, options.readahead.max(1), prefetcher))
     }
 
-    pub(crate) fn merge(
-        path: PathBuf,
-        old_sstable: &mut SSTableReader,
-        new_sstable: &mut SSTableReader,
-    ) -> Result<SSTable> {
-        old_sstable.fd.rewind()?;
-        new_sstable.fd.rewind()?;
-
-        let mut old_entry = format::read_entry(&old_sstable.fd)?;
-        let mut new_entry = format::read_entry(&new_sstable.fd)?;
-        
-        let mut fd = File::create(&path)?;
-
-        while let Some(((old_key, old_value), (new_key, new_value))) =
-            old_entry.as_ref().zip(new_entry.as_ref())
+    /// Reads the whole SSTable, checking that every entry can be decoded and matches its checksum,
+    /// that the keys are in strictly ascending order, that the filter and the footer, if any, match
+    /// the entries, and that nothing follows the last entry or the footer. SSTables whose index is
+    /// partitioned are also checked to hold every key in their partitions, each admitted by the
+    /// filter of its partition, and to end with a trailer pointing to the index. Returns how many
+    /// entries it holds.
+    pub fn verify(&self) -> Result<usize> {
+        let path = self.path.display();
+        let mut input = BufReader::new(self.env.open(&self.path)?);
+        let mut last_key: Option<String> = None;
+        let mut offsets = Vec::new();
+        let mut hashes = Vec::new();
+        let mut seqnos: Option<(u64, u64)> = None;
+        let mut filters = HashMap::new();
+        let mut partitions = HashMap::new();
+        let mut index = None;
+        let mut footer = None;
+        let mut offset = 0;
+        let mut end = None;
+
+        while let Some(item) = format::read_table_item(&mut input, true)
+            .with_context(|| format!("{}: unreadable entry at offset {}", path, offset))?
         {
-            match old_key.cmp(new_key) {
-                std::cmp::Ordering::Equal => {
-                    format::write_entry(&mut fd, new_key, new_value)?;
-                    old_entry = format::read_entry(&old_sstable.fd)?;
-                    new_entry = format::read_entry(&new_sstable.fd)?;
+            let item_offset = offset;
+            offset = input.stream_position()?;
+
+            if !matches!(item, TableItem::Entry(..)) {
+                end.get_or_insert(item_offset);
+            }
+
+            let (key, seqno) = match item {
+                TableItem::Entry(..) if end.is_some() => {
+                    bail!("{}: entry at offset {} after the filter or the index", path, item_offset);
+                }
+                TableItem::Entry(key, _value, seqno) => (key, seqno),
+                TableItem::Filter(_) | TableItem::IndexPartition(_) if index.is_some() => {
+                    bail!("{}: partition at offset {} after the index", path, item_offset);
                 }
-                std::cmp::Ordering::Less => {
-                    format::write_entry(&mut fd, old_key, old_value)?;
-                    old_entry = format::read_entry(&old_sstable.fd)?;
+                TableItem::Filter(table_filter) => {
+                    filters.insert(item_offset, table_filter);
+                    continue;
                 }
-                std::cmp::Ordering::Greater => {
-                    format::write_entry(&mut fd, new_key, new_value)?;
-                    new_entry = format::read_entry(&new_sstable.fd)?;
+                TableItem::IndexPartition(partition) => {
+                    partitions.insert(item_offset, partition);
+                    continue;
+                }
+                TableItem::Index(_) if index.is_some() => {
+                    bail!("{}: second index at offset {}", path, item_offset);
+                }
+                TableItem::Index(table_index) => {
+                    index = Some((item_offset, table_index));
+                    continue;
+                }
+                TableItem::Footer(metadata) => {
+                    footer = Some(metadata);
+                    break;
+                }
+            };
+
+            hashes.push(filter::hash(key.as_bytes()));
+            if last_key.as_ref().is_some_and(|last_key| *last_key >= key) {
+                bail!("{}: key {} at offset {} is out of order", path, key, item_offset);
+            }
+
+            last_key = Some(key.clone());
+            offsets.push((key, item_offset));
+            extend_seqnos(&mut seqnos, seqno);
+        }
+
+        let size = self.size()?;
+        match &index {
+            Some((index_offset, table_index)) => {
+                if footer.is_none() || offset + index::TRAILER_LEN != size {
+                    bail!("{}: no trailer after the footer of the index at offset {}", path, offset);
+                }
+
+                let mut trailer = [0; index::TRAILER_LEN as usize];
+                input.read_exact(&mut trailer)?;
+                if trailer[8..] != index::MAGIC[..] || trailer[..8] != index_offset.to_le_bytes() {
+                    bail!("{}: the trailer doesn't point to the index at offset {}", path, index_offset);
+                }
+
+                self.verify_partitions(table_index, &offsets, &hashes, &partitions, &filters, end)?;
+            }
+            None if offset != size => bail!("{}: {} unreadable bytes at offset {}", path, size - offset, offset),
+            None if filters.len() > 1 || !partitions.is_empty() => {
+                bail!("{}: {} filters and {} partitions without an index", path, filters.len(), partitions.len());
+            }
+            None => {}
+        }
+
+        if let (None, Some(filter)) = (&index, filters.values().next()) {
+            if let Some(index) = hashes.iter().position(|&hash| !filter.may_contain_hash(hash)) {
+                bail!("{}: the filter rules out the key of entry {}", path, index);
+            }
+        }
+
+        let (min_seqno, max_seqno) = seqnos.unwrap_or_default();
+        let mismatched = |footer: &TableMetadata| (footer.min_seqno, footer.max_seqno) != (min_seqno, max_seqno);
+        if let Some(footer) = footer.filter(mismatched) {
+            bail!(
+                "{}: the footer records sequence numbers {} to {}, but the entries have {} to {}",
+                path,
+                footer.min_seqno,
+                footer.max_seqno,
+                min_seqno,
+                max_seqno
+            );
+        }
+
+        Ok(offsets.len())
+    }
+
+    /// Checks that the partitions of the index hold the keys of the entries, along with their
+    /// offset, in order, and that the filter of each partition admits its keys.
+    fn verify_partitions(
+        &self,
+        index: &TableIndex,
+        offsets: &[(String, u64)],
+        hashes: &[u64],
+        partitions: &HashMap<u64, IndexPartition>,
+        filters: &HashMap<u64, Filter>,
+        end: Option<u64>,
+    ) -> Result<()> {
+        let path = self.path.display();
+        if index.entries != offsets.len() as u64 || Some(index.end) != end {
+            bail!("{}: the index records {} entries up to offset {}", path, index.entries, index.end);
+        }
+
+        let filtered = index.partitions.iter().filter(|handle| handle.filter.is_some()).count();
+        if index.partitions.len() != partitions.len() || filtered != filters.len() {
+            bail!("{}: the index doesn't record every partition", path);
+        }
+
+        let mut first_index = 0;
+        for handle in &index.partitions {
+            let partition = partitions.get(&handle.index.0);
+            let keys = offsets.get(first_index..first_index + partition.map_or(0, |p| p.offsets.len()));
+            let matches = partition.zip(keys).is_some_and(|(partition, keys)| {
+                partition.offsets == keys
+                    && handle.first_index == first_index as u64
+                    && keys.first().is_some_and(|(key, _)| *key == handle.first_key)
+                    && keys.last().is_some_and(|(key, _)| *key == handle.last_key)
+            });
+            if !matches {
+                bail!("{}: the partition at offset {} doesn't match the entries", path, handle.index.0);
+            }
+
+            let len = partition.map_or(0, |partition| partition.offsets.len());
+            if let Some((filter_offset, _)) = handle.filter {
+                let filter = filters.get(&filter_offset);
+                let hashes = &hashes[first_index..first_index + len];
+                if !filter.is_some_and(|filter| hashes.iter().all(|&hash| filter.may_contain_hash(hash))) {
+                    bail!("{}: the filter at offset {} rules out keys of its partition", path, filter_offset);
                 }
             }
+
+            first_index += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn reader(&self) -> Result<SSTableReader> {
+        let fd = self.env.open(&self.path)?;
+
+        SSTableReader::new(self.path.clone(), fd, None)
+    }
+
+    /// Opens the SSTable to be read as [`SSTable::reader`] does, caching the partitions of its
+    /// index, if it is partitioned, in the given cache.
+    pub(crate) fn cached_reader(&self, cache: &Arc<BlockCache<Block>>) -> Result<SSTableReader> {
+        let fd = self.env.open(&self.path)?;
+
+        SSTableReader::new(self.path.clone(), fd, Some(cache.clone()))
+    }
+
+    /// Merges SSTables into new ones in a single pass, given from the oldest to the newest. When
+    /// several hold the same key, the entry with the highest sequence number is kept, and the one
+    /// of the newest SSTable on a tie, which happens for entries written before sequence numbers
+    /// existed. Values that expired by `options.created_at` are written as tombstones. Tombstones
+    /// are left out if `options.drop_tombstones` is set, and so are the versions
+    /// `options.retention` doesn't keep, if set.
+    ///
+    /// The output is a single SSTable, unless `options.target_file_size` is set: then a new one,
+    /// at the path returned by `next_path`, is started once the current one reaches that size.
+    /// Keys are written in order, so the outputs hold non-overlapping ranges, in ascending order.
+    ///
+    /// The inputs are read sequentially through a readahead buffer, and the outputs are dropped
+    /// from the page cache once synced, so that compactions don't evict the data hot for reads.
+    pub(crate) fn merge(
+        env: &Arc<dyn Env>,
+        next_path: impl FnMut() -> PathBuf,
+        sstables: &[&SSTable],
+        options: &TableOptions,
+    ) -> Result<Merged> {
+        let inputs = sstables
+            .iter()
+            .map(|sstable| Ok((sstable.sequential_reader(options)?, false)))
+            .collect::<Result<_>>()?;
+
+        merge_inputs(env, next_path, inputs, options)
+    }
+
+    /// Merges an SSTable written elsewhere with the given ones, as [`SSTable::merge`] does, as if
+    /// its entries were written before any other: with sequence number 0, so that the merge
+    /// prefers every other entry for their key.
+    pub(crate) fn ingest(
+        env: &Arc<dyn Env>,
+        next_path: impl FnMut() -> PathBuf,
+        external: &SSTable,
+        sstables: &[&SSTable],
+        options: &TableOptions,
+    ) -> Result<Merged> {
+        let mut inputs = vec![(external.sequential_reader(options)?, true)];
+        for sstable in sstables {
+            inputs.push((sstable.sequential_reader(options)?, false));
+        }
+
+        merge_inputs(env, next_path, inputs, options)
+    }
+}
+
+/// An input of a merge, along with whether its sequence numbers are read as 0.
+type MergeInput = (BufReader<Box<dyn EnvFile>>, bool);
+
+/// Merges the inputs, from the oldest to the newest. See [`SSTable::merge`].
+fn merge_inputs(
+    env: &Arc<dyn Env>,
+    next_path: impl FnMut() -> PathBuf,
+    mut inputs: Vec<MergeInput>,
+    options: &TableOptions,
+) -> Result<Merged> {
+    let mut heads: Vec<_> = inputs.iter_mut().map(read_input).collect::<Result<_>>()?;
+    let mut output = MergeOutput::new(env, options, next_path)?;
+
+    // Merges only have a few inputs, so the smallest key is looked for among all of their heads.
+    while let Some(key) = heads.iter().flatten().map(|(key, _, _)| key).min().cloned() {
+        let holding: Vec<usize> = (0..heads.len())
+            .filter(|&index| heads[index].as_ref().is_some_and(|(head, _, _)| *head == key))
+            .collect();
+        let seqno = |index: usize| heads[index].as_ref().map_or(0, |(_, _, seqno)| *seqno);
+        let latest = holding.iter().copied().max_by_key(|&index| (seqno(index), index));
+
+        if let Some(entry) = latest.and_then(|index| heads[index].as_ref()) {
+            output.write(entry)?;
+        }
+        for index in holding {
+            heads[index] = read_input(&mut inputs[index])?;
+        }
+    }
+
+    output.finish()
+}
+
+fn read_input((input, zero_seqnos): &mut MergeInput) -> Result<Option<(String, Stored, u64)>> {
+    let entry = format::read_sequenced_entry(input, false)?;
+
+    Ok(entry.map(|(key, value, seqno)| (key, value, if *zero_seqnos { 0 } else { seqno })))
+}
+
+/// Widens the lowest and highest sequence numbers seen so far, if any, to the given one.
+pub(crate) fn extend_seqnos(seqnos: &mut Option<(u64, u64)>, seqno: u64) {
+    *seqnos = Some(seqnos.map_or((seqno, seqno), |(min, max)| (min.min(seqno), max.max(seqno))));
+}
+
+/// What a merge wrote. See [`SSTable::merge`].
+pub(crate) struct Merged {
+    /// There is always at least one, even if it ends up empty.
+    pub tables: Vec<SSTable>,
+    /// How many entries were written.
+    pub entries: usize,
+    /// How many versions the retention dropped.
+    pub dropped_versions: u64,
+}
+
+/// The SSTables written by a merge. There is always at least one, even if it ends up empty.
+struct MergeOutput<'a, P> {
+    env: &'a Arc<dyn Env>,
+    options: &'a TableOptions,
+    next_path: P,
+    /// The SSTable being written, if any.
+    current: Option<OutputTable>,
+    finished: Vec<SSTable>,
+    entry: Vec<u8>,
+    pruner: Pruner,
+    entries: usize,
+    dropped_versions: u64,
+}
+
+struct OutputTable {
+    path: PathBuf,
+    writer: BufWriter<Box<dyn EnvFile>>,
+    size: u64,
+    index: IndexBuilder,
+}
+
+impl<'a, P: FnMut() -> PathBuf> MergeOutput<'a, P> {
+    fn new(env: &'a Arc<dyn Env>, options: &'a TableOptions, next_path: P) -> Result<Self> {
+        let mut output = MergeOutput {
+            env,
+            options,
+            next_path,
+            current: None,
+            finished: Vec::new(),
+            entry: Vec::new(),
+            pruner: Pruner::default(),
+            entries: 0,
+            dropped_versions: 0,
+        };
+        output.start()?;
+
+        Ok(output)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let path = (self.next_path)();
+        let fd = self.options.create(self.env.as_ref(), &path)?;
+        self.current = Some(OutputTable {
+            path,
+            writer: BufWriter::new(fd),
+            size: 0,
+            index: IndexBuilder::new(self.options),
+        });
+
+        Ok(())
+    }
+
+    fn write(&mut self, (key, value, seqno): &(String, Stored, u64)) -> Result<()> {
+        // An expired value still hides the older ones, as a tombstone does, but its value can go.
+        let value = match value.expired(scheduler::millis(self.options.created_at)) {
+            true => &Stored::Tombstone,
+            false => value,
+        };
+
+        if self.options.drop_tombstones && *value == Stored::Tombstone {
+            return Ok(());
+        }
+
+        if let Some(retention) = self.options.retention {
+            if !self.pruner.keeps(retention, key) {
+                self.dropped_versions += 1;
+                return Ok(());
+            }
         }
 
-        while let Some((old_key, old_value)) = old_entry {
-            format::write_entry(&mut fd, &old_key, &old_value)?;
-            old_entry = format::read_entry(&old_sstable.fd)?;
+        // The next SSTable is only started once there is something to write into it.
+        if self.current.is_none() {
+            self.start()?;
         }
 
-        while let Some((new_key, new_value)) = new_entry {
-            format::write_entry(&mut fd, &new_key, &new_value)?;
-            new_entry = format::read_entry(&new_sstable.fd)?;
+        self.entry.clear();
+        format::write_table_entry(&mut self.entry, key, value, *seqno, self.options.compression_of(value))?;
+
+        let current = self.current.as_mut().unwrap();
+        current.writer.write_all(&self.entry)?;
+        current.index.add(key, value, *seqno, current.size);
+        self.entries += 1;
+        current.size += self.entry.len() as u64;
+
+        // The filter and the index count towards the size, as they are written along with the
+        // entries.
+        let size = current.size + current.index.size(current.size);
+        if self.options.target_file_size.is_some_and(|target| size >= target) {
+            self.finish_current()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_current(&mut self) -> Result<()> {
+        if let Some(OutputTable { path, mut writer, size, index }) = self.current.take() {
+            index.finish(&mut writer, size)?;
+            let mut fd = writer.into_inner().map_err(|error| error.into_error())?;
+
+            // Only pages that reached the disk can be dropped from the cache. The advice is a
+            // hint, so failing to give it is not an error.
+            fd.sync()?;
+            let _ = fd.advise(Advice::DontNeed);
+
+            self.finished.push(SSTable::new(self.env, &path));
         }
 
-        Ok(SSTable { path })
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Merged> {
+        self.finish_current()?;
+
+        Ok(Merged {
+            tables: self.finished,
+            entries: self.entries,
+            dropped_versions: self.dropped_versions,
+        })
     }
 }
 
 impl SSTableReader {
-    /// Returns the value for the provided key if it is stored in the SSTable.
-    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
-        // TODO: this shouldn't need to be mutable
-        let value_position = &self.indexes.get(key);
+    /// Opens the SSTable at the given path, outside of any storage. The file is only read, so it
+    /// may belong to a storage that is open.
+    pub fn open(path: &Path) -> Result<Self> {
+        SSTableReader::new(path.to_owned(), OsEnv.open(path)?, None)
+    }
 
-        if value_position.is_none() {
-            return Ok(None);
+    /// Reads the index of the SSTable, if it is partitioned, or else builds it, counting its
+    /// tombstones and finding its highest sequence number along the way: the footer doesn't keep
+    /// the former, and older SSTables have no footer at all. The filter and the footer, if any, are
+    /// read last. Fails with [`Error::Corruption`] if an entry can't be decoded, rather than
+    /// leaving out the entries after it.
+    ///
+    /// Entries are stored in order, so the index is sorted as it is read.
+    fn new(path: PathBuf, mut fd: Box<dyn EnvFile>, cache: Option<Arc<BlockCache<Block>>>) -> Result<Self> {
+        let id = NEXT_READER_ID.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if let Some((index, metadata)) = Self::read_index(fd.as_mut())? {
+            return Ok(SSTableReader {
+                path,
+                fd,
+                id,
+                end: index.end,
+                tombstones: index.tombstones as usize,
+                max_seqno: index.max_seqno,
+                index: Index::Partitioned(index),
+                cache,
+                metadata,
+                obsolete: OnceLock::new(),
+            });
         }
 
-        self.fd.seek(SeekFrom::Start(*value_position.unwrap()))?;
-        let (_key, value) = format::read_entry(&self.fd)?.unwrap();
+        let mut offsets = Vec::new();
+        let mut tombstones = 0;
+        let mut expiring = Vec::new();
+        let mut max_seqno = 0;
+        let mut filter = None;
+        let mut metadata = None;
 
-        match value {
-            Stored::Value(v) => Ok(Some(v)),
-            Stored::Tombstone => Ok(None),
+        // Compressed entries take less space on disk than once read, so the offsets come from the
+        // file itself.
+        let mut offset = fd.stream_position()?;
+
+        loop {
+            let item = match format::read_table_item(&mut fd, false) {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(error) if format::is_io_error(&error) => return Err(error),
+                Err(error) => {
+                    let reason = format!("{:#}", error);
+                    return Err(Error::Corruption { path, offset, reason }.into());
+                }
+            };
+
+            let (key, value, seqno) = match item {
+                TableItem::Entry(key, value, seqno) => (key, value, seqno),
+                TableItem::Filter(table_filter) => {
+                    filter = Some(table_filter);
+                    continue;
+                }
+                // The index couldn't be read from the trailer, but the entries can still be
+                // scanned. The filter read was the one of the first partition.
+                TableItem::IndexPartition(_) | TableItem::Index(_) => {
+                    filter = None;
+                    break;
+                }
+                TableItem::Footer(footer) => {
+                    metadata = Some(footer);
+                    break;
+                }
+            };
+
+            match value {
+                Stored::Tombstone => tombstones += 1,
+                Stored::Expiring(_, expires_at) => expiring.push((expires_at, offsets.len())),
+                Stored::Value(_) => {}
+            }
+            max_seqno = max_seqno.max(seqno);
+
+            offsets.push((key, offset));
+            offset = fd.stream_position()?;
+        }
+
+        Ok(SSTableReader {
+            path,
+            fd,
+            id,
+            index: Index::Resident {
+                offsets,
+                filter,
+                expiring,
+            },
+            cache,
+            end: offset,
+            tombstones,
+            max_seqno,
+            metadata,
+            obsolete: OnceLock::new(),
+        })
+    }
+
+    /// Reads the index and the footer of the SSTable through its trailer, unless it has none, as
+    /// its index isn't partitioned, or the index it points to can't be read.
+    fn read_index(fd: &mut dyn EnvFile) -> Result<Option<(TableIndex, Option<TableMetadata>)>> {
+        let index_offset = match read_trailer(fd)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let mut input = BufReader::new(PositionedReader { fd: &*fd, offset: index_offset });
+        let index = match format::read_table_item(&mut input, true) {
+            Ok(Some(TableItem::Index(index))) => index,
+            _ => return Ok(None),
+        };
+        let metadata = match format::read_table_item(&mut input, true) {
+            Ok(Some(TableItem::Footer(metadata))) => Some(metadata),
+            _ => None,
+        };
+
+        Ok(Some((index, metadata)))
+    }
+
+    /// Marks the SSTable as replaced, e.g. by a compaction, so that its file is deleted through
+    /// the given environment once the reader is dropped, shredded if `shred` is set. Readers are
+    /// shared by the versions that hold the SSTable, so the file stays until no version does, and
+    /// the iterators created from them keep reading it.
+    pub(crate) fn mark_obsolete(&self, env: &Arc<dyn Env>, shred: bool) {
+        let _ = self.obsolete.set((env.clone(), shred));
+    }
+
+    /// The keys stored in the SSTable, in order. The partitions of the index, if any, are read.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        match &self.index {
+            Index::Resident { offsets, .. } => Ok(offsets.iter().map(|(key, _)| key.clone()).collect()),
+            Index::Partitioned(index) => {
+                let mut keys = Vec::with_capacity(index.entries as usize);
+                for handle in &index.partitions {
+                    let partition = self.block(handle.index, false)?;
+                    keys.extend(partition.offsets().iter().map(|(key, _)| key.clone()));
+                }
+
+                Ok(keys)
+            }
+        }
+    }
+
+    /// Describes the filter of the SSTable, unless it was written without one.
+    pub fn filter(&self) -> Option<FilterInfo> {
+        match &self.index {
+            Index::Resident { filter, .. } => filter.as_ref().map(Filter::info),
+            Index::Partitioned(index) => index.filter,
+        }
+    }
+
+    /// Whether the index of the SSTable is partitioned, and read on demand.
+    pub fn partitioned_index(&self) -> bool {
+        matches!(self.index, Index::Partitioned(_))
+    }
+
+    /// The offset of the entry of the given key, if the SSTable holds it. Keys the filter rules
+    /// out aren't searched for in the index.
+    pub(crate) fn offset(&self, key: &str) -> Result<Option<u64>> {
+        let offsets = match &self.index {
+            Index::Resident { offsets, filter, .. } => {
+                if filter.as_ref().is_some_and(|filter| !filter.may_contain(key.as_bytes())) {
+                    return Ok(None);
+                }
+
+                let index = offsets.binary_search_by(|(other, _)| other.as_str().cmp(key));
+                return Ok(index.ok().map(|index| offsets[index].1));
+            }
+            Index::Partitioned(index) => {
+                let partition = index.partitions.partition_point(|handle| handle.last_key.as_str() < key);
+                let handle = match index.partitions.get(partition) {
+                    Some(handle) if handle.first_key.as_str() <= key => handle,
+                    _ => return Ok(None),
+                };
+
+                if let Some(filter) = handle.filter {
+                    if !self.block(filter, true)?.may_contain(key.as_bytes()) {
+                        return Ok(None);
+                    }
+                }
+
+                self.block(handle.index, false)?
+            }
+        };
+
+        let offsets = offsets.offsets();
+        let index = offsets.binary_search_by(|(other, _)| other.as_str().cmp(key));
+        Ok(index.ok().map(|index| offsets[index].1))
+    }
+
+    /// How many keys of the SSTable match the given predicate, which must hold for every key
+    /// smaller than one it holds for.
+    fn rank(&self, predicate: impl Fn(&str) -> bool) -> Result<usize> {
+        match &self.index {
+            Index::Resident { offsets, .. } => Ok(offsets.partition_point(|(key, _)| predicate(key))),
+            Index::Partitioned(index) => {
+                let partition = index.partitions.partition_point(|handle| predicate(&handle.last_key));
+                match index.partitions.get(partition) {
+                    Some(handle) => {
+                        let offsets = self.block(handle.index, false)?;
+                        let rank = offsets.offsets().partition_point(|(key, _)| predicate(key));
+                        Ok(handle.first_index as usize + rank)
+                    }
+                    None => Ok(index.entries as usize),
+                }
+            }
+        }
+    }
+
+    /// The offset of the entry with the given rank among the keys of the SSTable, if there is one.
+    fn offset_at(&self, rank: usize) -> Result<Option<u64>> {
+        match &self.index {
+            Index::Resident { offsets, .. } => Ok(offsets.get(rank).map(|(_, offset)| *offset)),
+            Index::Partitioned(index) => {
+                let partition = index.partitions.partition_point(|handle| handle.first_index as usize <= rank);
+                match partition.checked_sub(1).map(|partition| &index.partitions[partition]) {
+                    Some(handle) => {
+                        let offsets = self.block(handle.index, false)?;
+                        let offset = offsets.offsets().get(rank - handle.first_index as usize);
+                        Ok(offset.map(|(_, offset)| *offset))
+                    }
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Reads the partition of the index, or the filter, at the given offset and of the given
+    /// length, through the block cache if the reader has one.
+    fn block(&self, (offset, len): (u64, u64), filter: bool) -> Result<Arc<Block>> {
+        let load = || {
+            let mut bytes = vec![0; len as usize];
+            PositionedReader { fd: self.fd.as_ref(), offset }.read_exact(&mut bytes)?;
+
+            let block = match format::read_table_item(bytes.as_slice(), true) {
+                Ok(Some(TableItem::Filter(table_filter))) if filter => Block::Filter(table_filter),
+                Ok(Some(TableItem::IndexPartition(partition))) if !filter => Block::Index(partition),
+                Ok(_) => return Err(self.corruption(offset, "unexpected index block".to_owned()).into()),
+                Err(error) => return Err(self.corruption(offset, format!("{:#}", error)).into()),
+            };
+
+            Ok((block, bytes.len()))
+        };
+
+        match &self.cache {
+            Some(cache) => cache.get_or_load(self.id, offset, load),
+            None => Ok(Arc::new(load()?.0)),
+        }
+    }
+
+    /// The offset of the first entry whose key is not smaller than the given one, if any. Reading
+    /// the SSTable from there on yields the keys from the given one on, in order.
+    pub(crate) fn seek(&self, key: &str) -> Result<Option<u64>> {
+        self.offset_at(self.rank(|other| other < key)?)
+    }
+
+    /// The bytes taken on disk by the entries whose key starts with the given prefix.
+    pub(crate) fn prefix_bytes(&self, prefix: &str) -> Result<u64> {
+        let start = self.rank(|key| key < prefix)?;
+        let end = self.rank(|key| key < prefix || key.starts_with(prefix))?;
+
+        let offset = |rank: usize| Ok::<_, anyhow::Error>(self.offset_at(rank)?.unwrap_or(self.end));
+        Ok(offset(end)? - offset(start)?)
+    }
+
+    /// The number of entries in the SSTable whose key is in the given range, tombstones included.
+    pub(crate) fn count_in<'a>(&self, range: &impl RangeBounds<&'a str>) -> Result<usize> {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.rank(|key| key < *start)?,
+            Bound::Excluded(start) => self.rank(|key| key <= *start)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.rank(|key| key <= *end)?,
+            Bound::Excluded(end) => self.rank(|key| key < *end)?,
+            Bound::Unbounded => self.len(),
+        };
+
+        Ok(end.saturating_sub(start))
+    }
+
+    /// The number of entries in the SSTable.
+    pub fn len(&self) -> usize {
+        match &self.index {
+            Index::Resident { offsets, .. } => offsets.len(),
+            Index::Partitioned(index) => index.entries as usize,
+        }
+    }
+
+    /// Whether the SSTable has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of entries in the SSTable that are tombstones.
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    /// The keys of the SSTable whose value was inserted with a TTL, along with when it expires.
+    pub(crate) fn expiring(&self) -> Box<dyn Iterator<Item = (u64, &str)> + '_> {
+        match &self.index {
+            Index::Resident { offsets, expiring, .. } => {
+                Box::new(expiring.iter().map(|&(expires_at, index)| (expires_at, offsets[index].0.as_str())))
+            }
+            Index::Partitioned(index) => {
+                Box::new(index.expiring.iter().map(|(expires_at, key)| (*expires_at, key.as_str())))
+            }
+        }
+    }
+
+    /// The fraction of the entries in the SSTable that are tombstones.
+    pub fn tombstone_ratio(&self) -> f64 {
+        if self.is_empty() {
+            0.0
+        } else {
+            self.tombstones as f64 / self.len() as f64
+        }
+    }
+
+    /// The highest sequence number of the entries in the SSTable, or 0 if it has none.
+    pub fn max_seqno(&self) -> u64 {
+        self.max_seqno
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What the footer of the SSTable says about it, unless it has none.
+    pub fn metadata(&self) -> Option<&TableMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// The smallest key of the SSTable, unless it is empty.
+    pub fn first_key(&self) -> Option<&str> {
+        match &self.index {
+            Index::Resident { offsets, .. } => offsets.first().map(|(key, _)| key.as_str()),
+            Index::Partitioned(index) => index.partitions.first().map(|handle| handle.first_key.as_str()),
+        }
+    }
+
+    /// The largest key of the SSTable, unless it is empty.
+    pub fn last_key(&self) -> Option<&str> {
+        match &self.index {
+            Index::Resident { offsets, .. } => offsets.last().map(|(key, _)| key.as_str()),
+            Index::Partitioned(index) => index.partitions.last().map(|handle| handle.last_key.as_str()),
+        }
+    }
+
+    /// Returns the value for the provided key if it is stored in the SSTable, and not removed by
+    /// it, whether or not it expired. Fails with [`Error::Corruption`] if the entry doesn't match
+    /// its checksum.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.lookup(key, true)? {
+            Some(Stored::Value(v) | Stored::Expiring(v, _)) => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the entries of the SSTable in key order, along with their sequence number. The
+    /// value of a key that the SSTable removes is `None`, and expired values are returned as
+    /// others. Entries are checked against their checksum as they are read.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, Option<Vec<u8>>, u64)>> + '_ {
+        let mut input = BufReader::new(PositionedReader { fd: self.fd.as_ref(), offset: 0 }.take(self.end));
+        let mut failed = false;
+
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+
+            let entry = format::read_sequenced_entry(&mut input, true).transpose()?;
+            failed = entry.is_err();
+
+            Some(entry.map(|(key, value, seqno)| match value {
+                Stored::Value(value) | Stored::Expiring(value, _) => (key, Some(value), seqno),
+                Stored::Tombstone => (key, None, seqno),
+            }))
+        })
+    }
+
+    /// Returns what is stored for the given key, including tombstones. If `verify_checksums` is set,
+    /// fails with [`Error::Corruption`] instead of returning an entry that doesn't match its
+    /// checksum.
+    ///
+    /// The entry is read at its offset without moving the position of the file, so the SSTable
+    /// can be read by many threads at once.
+    pub(crate) fn lookup(&self, key: &str, verify_checksums: bool) -> Result<Option<Stored>> {
+        let offset = match self.offset(key)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let input = BufReader::new(PositionedReader {
+            fd: self.fd.as_ref(),
+            offset,
+        });
+
+        match format::read_checked_entry(input, verify_checksums) {
+            Ok(Some((_key, value))) => Ok(Some(value)),
+            Ok(None) => Err(self.corruption(offset, "truncated entry".to_owned()).into()),
+            Err(error) => Err(self.corruption(offset, format!("{:#}", error)).into()),
+        }
+    }
+
+    /// The descriptor of the file, to read its entries through io_uring, unless its environment
+    /// doesn't expose one.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.fd.raw_fd()
+    }
+
+    /// Returns what is stored for the given key, as [`SSTableReader::lookup`] does, out of the
+    /// bytes read from the offset of its entry. If they don't hold the entry whole, or it can't be
+    /// decoded, it is looked up again, to be read whole or reported as corrupt.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn decode_lookup(&self, key: &str, bytes: &[u8], verify_checksums: bool) -> Result<Option<Stored>> {
+        match format::read_checked_entry(bytes, verify_checksums) {
+            Ok(Some((_key, value))) => Ok(Some(value)),
+            _ => self.lookup(key, verify_checksums),
+        }
+    }
+
+    fn corruption(&self, offset: u64, reason: String) -> Error {
+        Error::Corruption {
+            path: self.path.clone(),
+            offset,
+            reason,
         }
     }
 }
 
+/// Reads the trailer at the end of the file, returning the offset of the index it points to, unless
+/// the file has none.
+fn read_trailer(fd: &mut dyn EnvFile) -> Result<Option<u64>> {
+    let size = fd.seek(SeekFrom::End(0))?;
+    fd.seek(SeekFrom::Start(0))?;
+    if size < index::TRAILER_LEN {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; index::TRAILER_LEN as usize];
+    PositionedReader { fd: &*fd, offset: size - index::TRAILER_LEN }.read_exact(&mut trailer)?;
+    if trailer[8..] != index::MAGIC[..] {
+        return Ok(None);
+    }
+
+    Ok(Some(u64::from_le_bytes(trailer[..8].try_into()?)))
+}
+
+/// Reads a file from an offset on, through positioned reads.
+struct PositionedReader<'a> {
+    fd: &'a dyn EnvFile,
+    offset: u64,
+}
+
+impl io::Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fd.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Drop for SSTableReader {
+    fn drop(&mut self) {
+        // There is no one to report the failure to. The file is left behind, as on a crash before
+        // it could be deleted.
+        let _ = match self.obsolete.get() {
+            Some((env, true)) => env.shred_file(&self.path),
+            Some((env, false)) => env.remove_file(&self.path),
+            None => Ok(()),
+        };
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::SSTable;
-    use crate::{format, test_utils::*, Stored};
+    use super::{SSTable, SSTableReader, TableMetadata, TableOptions, TableSource};
+    use crate::cache::BlockCache;
+    use crate::compression::Compression;
+    use crate::filter::FilterKind;
+    use crate::{format, test_utils::*, Error, Stored};
     use anyhow::Result;
     use std::{
         fs::File,
         io::{Seek, SeekFrom},
+        sync::Arc,
     };
 
     #[test]
@@ -136,29 +1129,29 @@ mod tests {
         ];
 
         test.generate_sstable("table", &contents)?;
-        let sstable = SSTable::new(&sstable_path);
+        let sstable = SSTable::new(&test.env(), &sstable_path);
         let mut sstable_reader = sstable.reader()?;
-        let index1 = sstable_reader.indexes.get("key-1").unwrap();
-        let index2 = sstable_reader.indexes.get("key-2").unwrap();
-        let index3 = sstable_reader.indexes.get("key-3").unwrap();
+        let index1 = sstable_reader.offset("key-1")?.unwrap();
+        let index2 = sstable_reader.offset("key-2")?.unwrap();
+        let index3 = sstable_reader.offset("key-3")?.unwrap();
 
         assert_eq!(contents.len(), 3);
 
-        sstable_reader.fd.seek(SeekFrom::Start(*index1))?;
+        sstable_reader.fd.seek(SeekFrom::Start(index1))?;
         assert_eq!(
-            format::read_entry(&sstable_reader.fd)?.unwrap(),
+            format::read_entry(&mut sstable_reader.fd)?.unwrap(),
             ("key-1".to_owned(), Stored::Value(b"value-1".to_vec()))
         );
 
-        sstable_reader.fd.seek(SeekFrom::Start(*index2))?;
+        sstable_reader.fd.seek(SeekFrom::Start(index2))?;
         assert_eq!(
-            format::read_entry(&sstable_reader.fd)?.unwrap(),
+            format::read_entry(&mut sstable_reader.fd)?.unwrap(),
             ("key-2".to_owned(), Stored::Value(b"value-2".to_vec()))
         );
 
-        sstable_reader.fd.seek(SeekFrom::Start(*index3))?;
+        sstable_reader.fd.seek(SeekFrom::Start(index3))?;
         assert_eq!(
-            format::read_entry(&sstable_reader.fd)?.unwrap(),
+            format::read_entry(&mut sstable_reader.fd)?.unwrap(),
             ("key-3".to_owned(), Stored::Value(b"value-3".to_vec()))
         );
 
@@ -171,13 +1164,13 @@ mod tests {
 
         let sstable = test.generate_sstable(
             "table",
-            &vec![
+            &[
                 ("key-1".to_owned(), Stored::Value(b"value-1".to_vec())),
                 ("key-2".to_owned(), Stored::Value(b"value-2".to_vec())),
                 ("key-3".to_owned(), Stored::Value(b"value-3".to_vec())),
             ],
         )?;
-        let mut sstable_reader = sstable.reader()?;
+        let sstable_reader = sstable.reader()?;
 
         let value = sstable_reader.get("key-1")?;
         assert!(value.is_some());
@@ -188,13 +1181,116 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn tables_open_from_a_path_yield_their_entries_in_order() -> Result<()> {
+        let test = Test::new()?;
+        let sstable = test.generate_sstable(
+            "table",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1".to_vec())),
+                ("key-2".to_owned(), Stored::Tombstone),
+                ("key-3".to_owned(), Stored::Value(b"value-3".to_vec())),
+            ],
+        )?;
+
+        let reader = SSTableReader::open(sstable.path())?;
+        assert_eq!(reader.get("key-3")?, Some(b"value-3".to_vec()));
+        assert_eq!(reader.get("key-2")?, None);
+
+        let entries: Vec<_> = reader
+            .iter()
+            .map(|entry| entry.map(|(key, value, _)| (key, value)))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            entries,
+            vec![
+                ("key-1".to_owned(), Some(b"value-1".to_vec())),
+                ("key-2".to_owned(), None),
+                ("key-3".to_owned(), Some(b"value-3".to_vec())),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tables_fail_to_open_on_an_entry_that_cant_be_decoded() -> Result<()> {
+        let test = Test::new()?;
+        let contents: Vec<_> = (1..=3)
+            .map(|i| (format!("key-{}", i), Stored::Value(format!("value-{}", i).into_bytes())))
+            .collect();
+        let sstable = test.generate_sstable("table", &contents)?;
+        let offset = sstable.reader()?.offset("key-2")?.unwrap();
+
+        // The length of the key claims more than any entry may take.
+        let mut bytes = std::fs::read(sstable.path())?;
+        bytes[offset as usize..][..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(sstable.path(), bytes)?;
+
+        let error = SSTableReader::open(sstable.path()).err().unwrap();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Corruption { offset: at, .. }) => assert_eq!(*at, offset),
+            _ => panic!("expected a corruption, got {:#}", error),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn many_threads_can_read_one_table_at_once() -> Result<()> {
+        let test = Test::new()?;
+        let contents: Vec<_> = (0..100)
+            .map(|i| (format!("key-{:03}", i), Stored::Value(format!("value-{}", i).into_bytes())))
+            .collect();
+
+        let sstable_reader = test.generate_sstable("table", &contents)?.reader()?;
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let sstable_reader = &sstable_reader;
+                let contents = &contents;
+
+                scope.spawn(move || {
+                    for (key, value) in contents.iter().skip(thread).step_by(4).rev() {
+                        assert_eq!(sstable_reader.lookup(key, true).unwrap().as_ref(), Some(value));
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn seeking_finds_the_first_entry_not_smaller_than_the_key() -> Result<()> {
+        let test = Test::new()?;
+        let contents = vec![
+            ("key-1".to_owned(), Stored::Value(b"value-1".to_vec())),
+            ("key-3".to_owned(), Stored::Tombstone),
+            ("key-5".to_owned(), Stored::Value(b"value-5".to_vec())),
+        ];
+
+        let mut sstable_reader = test.generate_sstable("table", &contents)?.reader()?;
+
+        assert_eq!(sstable_reader.seek("key-0")?, sstable_reader.offset("key-1")?);
+        assert_eq!(sstable_reader.seek("key-3")?, sstable_reader.offset("key-3")?);
+        assert_eq!(sstable_reader.seek("key-6")?, None);
+
+        let offset = sstable_reader.seek("key-2")?.unwrap();
+        sstable_reader.fd.seek(SeekFrom::Start(offset))?;
+        assert_eq!(format::read_entry(&mut sstable_reader.fd)?.unwrap(), contents[1]);
+        assert_eq!(format::read_entry(&mut sstable_reader.fd)?.unwrap(), contents[2]);
+
+        Ok(())
+    }
+
     #[test]
     fn merging_should_write_in_order_and_merge_all_elements() -> Result<()> {
         let test = Test::new()?;
 
         let old_sstable = test.generate_sstable(
             "table1",
-            &vec![
+            &[
                 ("key-1".to_owned(), Stored::Value(b"value-1".to_vec())),
                 ("key-2".to_owned(), Stored::Value(b"value-2".to_vec())),
                 ("key-3".to_owned(), Stored::Value(b"value-3".to_vec())),
@@ -204,7 +1300,7 @@ mod tests {
 
         let new_sstable = test.generate_sstable(
             "table2",
-            &vec![
+            &[
                 ("key-1".to_owned(), Stored::Value(b"value-5".to_vec())),
                 ("key-3".to_owned(), Stored::Tombstone),
                 ("key-4".to_owned(), Stored::Value(b"value-4".to_vec())),
@@ -212,7 +1308,12 @@ mod tests {
         )?;
 
         let sstable_path = test.sstable_path("merged-table");
-        SSTable::merge(sstable_path.clone(), &mut old_sstable.reader()?, &mut new_sstable.reader()?)?;
+        SSTable::merge(
+            &test.env(),
+            || sstable_path.clone(),
+            &[&old_sstable, &new_sstable],
+            &TableOptions::default(),
+        )?;
 
         let fd = File::open(sstable_path)?;
 
@@ -243,4 +1344,279 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn merging_resolves_duplicates_by_sequence_number() -> Result<()> {
+        let test = Test::new()?;
+
+        // Compacted out of order: the "old" input holds the most recent writes of some keys.
+        let old_sstable = test.generate_sequenced_sstable(
+            "table1",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1-new".to_vec()), 5),
+                ("key-2".to_owned(), Stored::Tombstone, 6),
+                ("key-3".to_owned(), Stored::Value(b"value-3-old".to_vec()), 1),
+            ],
+        )?;
+
+        let new_sstable = test.generate_sequenced_sstable(
+            "table2",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1-old".to_vec()), 2),
+                ("key-2".to_owned(), Stored::Value(b"value-2-old".to_vec()), 3),
+                ("key-3".to_owned(), Stored::Value(b"value-3-new".to_vec()), 4),
+            ],
+        )?;
+
+        let sstable_path = test.sstable_path("merged-table");
+        let merged = SSTable::merge(
+            &test.env(),
+            || sstable_path.clone(),
+            &[&old_sstable, &new_sstable],
+            &TableOptions::default(),
+        )?;
+
+        let fd = File::open(sstable_path)?;
+
+        assert_eq!(
+            format::read_sequenced_entry(&fd, true)?.unwrap(),
+            ("key-1".to_string(), Stored::Value(b"value-1-new".to_vec()), 5)
+        );
+
+        assert_eq!(
+            format::read_sequenced_entry(&fd, true)?.unwrap(),
+            ("key-2".to_string(), Stored::Tombstone, 6)
+        );
+
+        assert_eq!(
+            format::read_sequenced_entry(&fd, true)?.unwrap(),
+            ("key-3".to_string(), Stored::Value(b"value-3-new".to_vec()), 4)
+        );
+
+        assert_eq!(merged.tables.len(), 1);
+        assert_eq!(merged.tables[0].reader()?.max_seqno(), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merging_many_tables_at_once_keeps_the_latest_entry_of_each_key() -> Result<()> {
+        let test = Test::new()?;
+        let oldest = test.generate_sequenced_sstable(
+            "table1",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1-old".to_vec()), 1),
+                ("key-2".to_owned(), Stored::Value(b"value-2-new".to_vec()), 9),
+                ("key-4".to_owned(), Stored::Value(b"value-4-old".to_vec()), 0),
+            ],
+        )?;
+        let middle = test.generate_sequenced_sstable(
+            "table2",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1-new".to_vec()), 7),
+                ("key-3".to_owned(), Stored::Value(b"value-3".to_vec()), 3),
+            ],
+        )?;
+        let newest = test.generate_sequenced_sstable(
+            "table3",
+            &[
+                ("key-2".to_owned(), Stored::Tombstone, 4),
+                ("key-4".to_owned(), Stored::Value(b"value-4-new".to_vec()), 0),
+            ],
+        )?;
+
+        let sstable_path = test.sstable_path("merged-table");
+        let merged = SSTable::merge(
+            &test.env(),
+            || sstable_path.clone(),
+            &[&oldest, &middle, &newest],
+            &TableOptions::default(),
+        )?;
+
+        // Ties, between entries written before sequence numbers existed, go to the newest table.
+        let entries: Vec<_> = merged.tables[0].reader()?.iter().collect::<Result<_>>()?;
+        let expected = vec![
+            ("key-1".to_owned(), Some(b"value-1-new".to_vec()), 7),
+            ("key-2".to_owned(), Some(b"value-2-new".to_vec()), 9),
+            ("key-3".to_owned(), Some(b"value-3".to_vec()), 3),
+            ("key-4".to_owned(), Some(b"value-4-new".to_vec()), 0),
+        ];
+        assert_eq!(entries, expected);
+        assert_eq!(merged.entries, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merging_into_the_last_level_drops_tombstones() -> Result<()> {
+        let test = Test::new()?;
+
+        let old_sstable = test.generate_sstable(
+            "table1",
+            &[
+                ("key-1".to_owned(), Stored::Value(b"value-1".to_vec())),
+                ("key-2".to_owned(), Stored::Value(b"value-2".to_vec())),
+            ],
+        )?;
+
+        let new_sstable = test.generate_sstable(
+            "table2",
+            &[
+                ("key-1".to_owned(), Stored::Tombstone),
+                ("key-3".to_owned(), Stored::Tombstone),
+            ],
+        )?;
+
+        assert_eq!(new_sstable.reader()?.tombstones(), 2);
+        assert_eq!(new_sstable.reader()?.tombstone_ratio(), 1.0);
+
+        let options = TableOptions {
+            drop_tombstones: true,
+            ..TableOptions::default()
+        };
+        let merged = SSTable::merge(
+            &test.env(),
+            || test.sstable_path("merged-table"),
+            &[&old_sstable, &new_sstable],
+            &options,
+        )?;
+
+        let reader = merged.tables[0].reader()?;
+        assert_eq!(reader.keys()?, vec!["key-2"]);
+        assert_eq!(reader.tombstones(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn footers_describe_the_table_and_are_checked_against_its_entries() -> Result<()> {
+        let test = Test::new()?;
+        let write = |name: &str, max_seqno: u64| -> Result<SSTable> {
+            let path = test.sstable_path(name);
+            let mut fd = File::create(&path)?;
+            for (key, seqno) in [("key-1", 3), ("key-2", 7)] {
+                format::write_table_entry(&mut fd, key, &Stored::Value(b"value".to_vec()), seqno, Compression::None)?;
+            }
+
+            let options = TableOptions {
+                level: 1,
+                source: TableSource::Compaction,
+                ..TableOptions::default()
+            };
+            format::write_table_footer(&mut fd, &TableMetadata { max_seqno, ..options.metadata(Some((3, 7))) })?;
+
+            Ok(SSTable::new(&test.env(), &path))
+        };
+
+        let sstable = write("table", 7)?;
+        assert_eq!(sstable.verify()?, 2);
+
+        let reader = sstable.reader()?;
+        assert_eq!(reader.keys()?, vec!["key-1", "key-2"]);
+        assert_eq!(reader.iter().count(), 2);
+        let metadata = reader.metadata().unwrap();
+        assert_eq!((metadata.source, metadata.level), (TableSource::Compaction, 1));
+        assert_eq!((metadata.min_seqno, metadata.max_seqno), (3, 7));
+
+        assert!(write("mismatched", 8)?.verify().is_err());
+
+        // Tables written before footers were introduced have none.
+        let legacy = test.generate_sstable("legacy", &[("key-1".to_owned(), Stored::Tombstone)])?;
+        assert_eq!(legacy.verify()?, 1);
+        assert!(legacy.reader()?.metadata().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn partitioned_indexes_read_the_same_as_resident_ones() -> Result<()> {
+        let test = Test::new()?;
+        let contents: Vec<_> = (0..2000)
+            .map(|index| {
+                let value = match index % 7 {
+                    0 => Stored::Tombstone,
+                    1 => Stored::Expiring(format!("value-{}", index).into_bytes(), u64::MAX - index),
+                    _ => Stored::Value(format!("value-{}", index).into_bytes()),
+                };
+                (format!("key-{:05}", index * 2), value)
+            })
+            .collect();
+        let external = test.generate_sstable("external", &contents)?;
+
+        let write = |name: &str, options: TableOptions| -> Result<SSTable> {
+            let path = test.sstable_path(name);
+            let merged = SSTable::ingest(&test.env(), || path.clone(), &external, &[], &options)?;
+            Ok(merged.tables[0].clone())
+        };
+        let filtered = TableOptions {
+            filter: Some(FilterKind::Bloom),
+            filter_bits_per_key: 10,
+            ..Default::default()
+        };
+        let partitioned = TableOptions {
+            partition_index_above: Some(0),
+            ..filtered
+        };
+        let resident = write("resident", filtered)?;
+
+        // The cache is smaller than the partitions of the index, so they are also evicted.
+        let cache = Arc::new(BlockCache::new(16 * 1024));
+        let tables = [
+            (write("partitioned", partitioned)?, Some(cache), Some(FilterKind::Bloom)),
+            (write("unfiltered", TableOptions { filter: None, ..partitioned })?, None, None),
+            (write("xor", TableOptions { filter: Some(FilterKind::Xor), ..partitioned })?, None, Some(FilterKind::Xor)),
+        ];
+
+        assert_eq!(resident.verify()?, contents.len());
+        let expected = resident.reader()?;
+        assert!(!expected.partitioned_index());
+
+        for (table, cache, kind) in tables {
+            assert_eq!(table.verify()?, contents.len());
+            let reader = match &cache {
+                Some(cache) => table.cached_reader(cache)?,
+                None => table.reader()?,
+            };
+            assert!(reader.partitioned_index());
+
+            assert_eq!(reader.len(), expected.len());
+            assert_eq!(reader.tombstones(), expected.tombstones());
+            assert_eq!((reader.first_key(), reader.last_key()), (expected.first_key(), expected.last_key()));
+            assert_eq!(reader.keys()?, expected.keys()?);
+            assert_eq!(reader.expiring().collect::<Vec<_>>(), expected.expiring().collect::<Vec<_>>());
+            assert_eq!(reader.metadata(), expected.metadata());
+            assert_eq!(reader.filter().map(|info| info.kind), kind);
+
+            for index in (0..4002).step_by(3) {
+                let key = format!("key-{:05}", index);
+                assert_eq!(reader.lookup(&key, true)?, expected.lookup(&key, true)?, "{}", key);
+                // The entries of both tables are written alike, so they are at the same offsets.
+                assert_eq!(reader.seek(&key)?, expected.seek(&key)?, "{}", key);
+                assert_eq!(reader.count_in(&(key.as_str()..))?, expected.count_in(&(key.as_str()..))?);
+            }
+
+            for prefix in ["key-0", "key-01", "key-0199", "key-03999", "key-1", "other"] {
+                assert_eq!(reader.prefix_bytes(prefix)?, expected.prefix_bytes(prefix)?, "{}", prefix);
+            }
+            let range = "key-00100"..="key-02001";
+            assert_eq!(reader.count_in(&range)?, expected.count_in(&range)?);
+        }
+
+        // Without its trailer, a partitioned table is still read by scanning its entries, but it
+        // no longer verifies.
+        let path = test.sstable_path("partitioned");
+        let size = std::fs::metadata(&path)?.len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(size - 1)?;
+
+        let table = SSTable::new(&test.env(), &path);
+        assert!(table.verify().is_err());
+        let reader = table.reader()?;
+        assert!(!reader.partitioned_index());
+        assert_eq!(reader.keys()?, expected.keys()?);
+        assert_eq!(reader.filter(), None);
+        assert_eq!(reader.get("key-00004")?, expected.get("key-00004")?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+use crate::filter::FilterInfo;
+use crate::sstable::TableMetadata;
+
+/// How many bytes have been written since the storage was opened, by who wrote them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BytesWritten {
+    /// The keys and values given to inserts and removals.
+    pub user: u64,
+    /// The sstables written when persisting memtables.
+    pub flush: u64,
+    /// The sstables written by compactions.
+    pub compaction: u64,
+}
+
+/// The latencies of the operations of the storage since it was opened, in nanoseconds.
+pub(crate) struct Latencies {
+    pub get: Histogram<u64>,
+    pub put: Histogram<u64>,
+    pub flush: Histogram<u64>,
+    pub compaction: Histogram<u64>,
+}
+
+impl Latencies {
+    pub fn new() -> Self {
+        // Three significant digits keep the error of every percentile below 0.1%.
+        let histogram = || Histogram::new(3).expect("3 significant digits are supported");
+
+        Latencies {
+            get: histogram(),
+            put: histogram(),
+            flush: histogram(),
+            compaction: histogram(),
+        }
+    }
+}
+
+/// Records the latency of an operation into its histogram.
+pub(crate) fn record(histogram: &mut Histogram<u64>, latency: Duration) {
+    let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+    histogram.saturating_record(nanos);
+}
+
+/// The distribution of the latencies of an operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// How many times the operation ran.
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl From<&Histogram<u64>> for LatencySummary {
+    fn from(histogram: &Histogram<u64>) -> Self {
+        let quantile = |quantile| Duration::from_nanos(histogram.value_at_quantile(quantile));
+
+        LatencySummary {
+            count: histogram.len(),
+            p50: quantile(0.5),
+            p95: quantile(0.95),
+            p99: quantile(0.99),
+            p999: quantile(0.999),
+            max: Duration::from_nanos(histogram.max()),
+        }
+    }
+}
+
+/// The reads and writes of the keys of a tenant since the storage was opened. See
+/// [`StorageBuilder::tenant_of`](crate::storage::StorageBuilder::tenant_of).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TenantStats {
+    /// How many keys were read, whether they were found or not.
+    pub reads: u64,
+    /// The bytes of the values found by reads.
+    pub read_bytes: u64,
+    /// How many inserts and removals there were.
+    pub writes: u64,
+    /// The bytes of the keys and values given to inserts and removals.
+    pub write_bytes: u64,
+}
+
+/// A key read often, as found by [`Storage::top_keys`](crate::storage::Storage::top_keys).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HotKey {
+    pub key: String,
+    /// An estimate of how many times the key was read since the storage was opened. It may be too
+    /// high, but never too low.
+    pub reads: u64,
+}
+
+/// Aggregates over the keys in a range, as found by
+/// [`Storage::aggregate`](crate::storage::Storage::aggregate).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Aggregate {
+    /// How many keys the range holds.
+    pub count: u64,
+    /// The smallest key in the range, unless it holds none.
+    pub min_key: Option<String>,
+    /// The largest key in the range, unless it holds none.
+    pub max_key: Option<String>,
+    /// The bytes of the values of the keys in the range.
+    pub value_bytes: u64,
+}
+
+/// A snapshot of the statistics of the storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// The bytes of the keys and values given to inserts and removals since the storage was opened.
+    pub user_bytes_written: u64,
+    /// The bytes of the sstables written when persisting memtables since the storage was opened.
+    pub flush_bytes_written: u64,
+    /// The bytes of the sstables written by compactions since the storage was opened.
+    pub compaction_bytes_written: u64,
+    /// How many frozen memtables are waiting to be persisted.
+    pub frozen_memtables: usize,
+    /// How long writes waited for the write rate limits since the storage was opened. See
+    /// [`StorageBuilder::write_bytes_per_sec`](crate::storage::StorageBuilder::write_bytes_per_sec).
+    pub write_throttle: Duration,
+    /// The size of all the sstables of the storage, in bytes.
+    pub sstable_bytes: u64,
+    /// The size of the sstables of the last level, in bytes.
+    pub last_level_bytes: u64,
+    /// The latencies of reads, including the time spent waiting for the engine lock.
+    pub get: LatencySummary,
+    /// The latencies of inserts and removals, including freezing the memtable when it is full,
+    /// waiting for frozen ones to be persisted and for the write rate limits.
+    pub put: LatencySummary,
+    /// The latencies of persisting frozen memtables.
+    pub flush: LatencySummary,
+    /// The latencies of compactions.
+    pub compaction: LatencySummary,
+    /// The versions of the keys of a [`VersionedStorage`](crate::versioned::VersionedStorage)
+    /// dropped by compactions since the storage was opened.
+    pub dropped_versions: u64,
+    /// The reads and writes of each tenant. Scans are left out.
+    pub tenants: BTreeMap<String, TenantStats>,
+    /// The error of the flush or compaction that failed, if any did. The storage stops running
+    /// them from then on.
+    pub background_error: Option<String>,
+    /// The status of each background thread, in the order they were started. There are none when
+    /// background work is scheduled manually.
+    pub tasks: Vec<TaskStatus>,
+}
+
+/// Whether a background thread of the storage is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TaskState {
+    Running,
+    /// The thread returned, e.g. once the storage was dropped.
+    Stopped,
+    /// The thread failed, and won't be restarted.
+    Failed,
+}
+
+/// How a background thread of the storage is doing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskStatus {
+    /// The name of the thread, e.g. `lsm-worker-0` or `lsm-scrubber`.
+    pub name: String,
+    pub state: TaskState,
+    /// How many times the thread was restarted after failing or panicking.
+    pub restarts: u32,
+    /// Why the thread last failed, if it ever did.
+    pub last_error: Option<String>,
+}
+
+impl Stats {
+    /// The bytes written to sstables per byte written by the user, or 0 if nothing was written.
+    ///
+    /// The write-ahead log is left out.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes_written == 0 {
+            return 0.0;
+        }
+
+        (self.flush_bytes_written + self.compaction_bytes_written) as f64 / self.user_bytes_written as f64
+    }
+
+    /// The size of all the sstables per byte of the last level, or 0 if the last level is empty.
+    ///
+    /// The last level holds a single version of each key, so its size approximates the size of the
+    /// live data.
+    pub fn space_amplification(&self) -> f64 {
+        if self.last_level_bytes == 0 {
+            return 0.0;
+        }
+
+        self.sstable_bytes as f64 / self.last_level_bytes as f64
+    }
+}
+
+/// What purging the removed values of a range of keys did. See
+/// [`Storage::purge_deleted`](crate::storage::Storage::purge_deleted).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PurgeReport {
+    /// How many sstables were rewritten, or none if no sstable held tombstones in the range.
+    pub sstables_rewritten: usize,
+    /// How many bytes the rewritten sstables shrank by.
+    pub bytes_reclaimed: u64,
+    /// How many archived WALs were removed for holding writes to the range.
+    pub wals_removed: usize,
+}
+
+/// Describes a sstable of the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TableInfo {
+    pub path: PathBuf,
+    /// The size of the sstable, in bytes.
+    pub size: u64,
+    /// How many entries the sstable holds, including tombstones.
+    pub entries: usize,
+    pub tombstones: usize,
+    /// The smallest key of the sstable, or `None` if it is empty.
+    pub first_key: Option<String>,
+    /// The largest key of the sstable, or `None` if it is empty.
+    pub last_key: Option<String>,
+    /// What the footer of the sstable says about it, or `None` if it was written before footers
+    /// were introduced.
+    pub metadata: Option<TableMetadata>,
+    /// The filter of the sstable, or `None` if it was written without one.
+    pub filter: Option<FilterInfo>,
+    /// Whether the index of the sstable is partitioned and read on demand, rather than held in
+    /// memory.
+    pub partitioned_index: bool,
+}
@@ -1,38 +1,365 @@
-use std::borrow::BorrowMut;
-use std::path::{PathBuf, Path};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError};
 use std::thread;
-use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crate::{SEGMENTS_NAME, WAL_NAME, memtable};
-use crate::compactor::start_compaction;
-use crate::engine::Engine;
+use crate::{Error, SEGMENTS_NAME, WAL_ARCHIVE_NAME, WAL_NAME, Stored};
+use crate::bloom;
+use crate::cache::{BlockCache, NegativeCache, RowCache};
+use crate::compression::Compression;
+use crate::compactor::{self, plan_l0_compaction, trigger_l0_compaction, Compactor, Job};
+use crate::engine::{Engine, Version};
+use crate::env::{Env, EnvFile, OsEnv};
+use crate::events::EventListener;
+use crate::expiry::{self, Expirer};
+use crate::export;
+use crate::filter::FilterKind;
+use crate::hot_keys::HotKeys;
+use crate::iterator::{Continuation, Source};
+use crate::identity::{Identity, IDENTITY_NAME};
+use crate::index::Block;
+use crate::manifest::{self, Edit, Manifest, MANIFEST_NAME, OLD_MANIFEST_NAME};
 use crate::memtable::MemTable;
-use crate::sstable::SSTable;
+use crate::prefetch::Prefetcher;
+use crate::rate_limiter::RateLimiter;
+use crate::scheduler::{self, Clock, Scheduling, SystemClock};
+use crate::scrubber::Scrubber;
+use crate::sstable::{SSTable, SSTableReader, TableOptions, TableSource};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::{Ring, ENTRY_READ_SIZE};
+use crate::supervisor::Supervisor;
+use crate::tuning::{AutoTune, Tuner, Tuning};
+use crate::stats::{self, Aggregate, BytesWritten, HotKey, Latencies, PurgeReport, Stats, TableInfo};
+use crate::verify::{self, VerifyLevel, VerifyReport};
+use crate::versioned::{self, Retention, RetentionPolicy};
+use crate::wal::{self, RecoveryMode, RecoveryReport, Wal, WalReplay, WalTail};
+use crate::watch::Watchers;
 
-use anyhow::Result;
-use tokio::sync::mpsc::UnboundedSender;
+use anyhow::{bail, Result};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+pub use crate::compactor::CompactionPlan;
+pub use crate::iterator::StorageIterator;
+pub use crate::watch::Change;
+
+/// The name of the file locked while the storage is open, inside the segments path.
+const LOCK_NAME: &str = "LOCK";
+
+/// The name of the file locked while the storage is open, inside the WAL path, so that no other
+/// storage writes its WALs there. It differs from [`LOCK_NAME`], as both paths may be the same.
+const WAL_LOCK_NAME: &str = "LOCK-WAL";
+
+/// The properties reported by [`Storage::property`]:
+/// - `num-files-at-level<N>`: how many sstables level N holds
+/// - `num-immutable-memtables`: how many frozen memtables are waiting to be persisted
+/// - `num-entries-active-memtable`: how many entries the active memtable holds
+/// - `estimated-memtable-bytes`: the bytes of keys and values held by all the memtables
+/// - `is-compaction-pending`: 1 if L0 holds sstables that a compaction would move into L1
+/// - `total-sstable-bytes`: the size of all the sstables, in bytes
+pub const PROPERTIES: &[&str] = &[
+    "num-files-at-level0",
+    "num-files-at-level1",
+    "num-immutable-memtables",
+    "num-entries-active-memtable",
+    "estimated-memtable-bytes",
+    "is-compaction-pending",
+    "total-sstable-bytes",
+];
+
+/// The sstables of L0, by the id of the memtable they were persisted from, and those of L1.
+type Levels = (BTreeMap<usize, SSTable>, Vec<SSTable>);
+
+/// Tells the tenant of a key, if it belongs to one. See [`StorageBuilder::tenant_of`].
+pub(crate) type TenantOf = Arc<dyn Fn(&str) -> Option<&str> + Send + Sync>;
 
 /// Defines the configuration for the storage necessary to handle sstables.
 #[derive(Clone)]
 pub(crate) struct Config {
     /// The path where the segments are stored.
-    segments_path: PathBuf,
+    pub segments_path: PathBuf,
     /// The path where the WALs are stored.
     wal_path: PathBuf,
     /// The size at which a memtable is converted into a sstable.
     pub threshold: usize,
+    /// How many bytes of entries are buffered before they are written to the WAL.
+    pub wal_buffer_size: usize,
+    /// How many bytes the WALs of persisted memtables may take once archived, if they are.
+    pub wal_archive_size: Option<u64>,
+    /// Whether memtables keep a hash index of their entries for point lookups.
+    pub memtable_hash_index: bool,
+    /// How the memtables are recovered from WALs holding corrupt entries.
+    pub recovery_mode: RecoveryMode,
+    /// The environment through which all the I/O is performed.
+    pub env: Arc<dyn Env>,
+    /// The source of time for time-based decisions.
+    pub clock: Arc<dyn Clock>,
+    /// How the background work is run.
+    pub scheduling: Scheduling,
+    /// How often L0 is compacted, if at all.
+    pub compaction_interval: Option<Duration>,
+    /// The fraction of tombstones in an sstable past which L0 is compacted without waiting for the
+    /// interval, if any.
+    pub compaction_tombstone_ratio: Option<f64>,
+    /// How many sstables L0 may hold before it is compacted without waiting for the interval, if
+    /// there is a limit.
+    pub l0_compaction_trigger: Option<usize>,
+    /// The bounds within which the threshold and the L0 compaction trigger are tuned, if they are.
+    pub auto_tune: Option<AutoTune>,
+    /// How many frozen memtables may wait to be persisted before writes wait for them, if there is
+    /// a limit.
+    pub max_frozen_memtables: Option<usize>,
+    /// How many bytes of keys and values may be written per second, if there is a limit.
+    pub write_bytes_per_sec: Option<u64>,
+    /// How many keys may be written per second, if there is a limit.
+    pub write_ops_per_sec: Option<u64>,
+    /// How many threads run the background jobs.
+    pub max_background_jobs: usize,
+    /// Whether one more thread only runs flushes.
+    pub dedicated_flush_thread: bool,
+    /// How many keys confirmed to be absent are remembered.
+    pub negative_cache_capacity: usize,
+    /// How many bytes of keys and values are kept in the row cache.
+    pub row_cache_capacity: usize,
+    /// The codec used by the sstables of each level. Levels without one aren't compressed.
+    pub compression: Vec<Compression>,
+    /// The size below which values are stored uncompressed, whatever the codec of their level.
+    pub min_compressed_value_size: usize,
+    /// The kind of filter the sstables are written with.
+    pub filter: FilterKind,
+    /// The bits per key of the filters of the sstables of each level, where 0 means none. Levels
+    /// without one have filters of the default bits per key.
+    pub filter_bits_per_key: Vec<usize>,
+    /// How many bytes of the input sstables a compaction reads at once.
+    pub compaction_readahead: usize,
+    /// How many threads read the next blocks of the sstables iterators are reading.
+    pub prefetch_threads: usize,
+    /// The size of the entries of a sstable past which its index is partitioned, if any.
+    pub partition_index_above: Option<u64>,
+    /// Where the partitions of the indexes of the sstables, and their filters, are cached once read.
+    pub block_cache: Arc<BlockCache<Block>>,
+    /// Whether flushes and compactions bypass the page cache.
+    pub direct_io: bool,
+    /// The size past which compactions start a new sstable.
+    pub target_file_size: u64,
+    /// How often every sstable is verified, if at all.
+    pub scrub_interval: Option<Duration>,
+    /// Whether corrupt sstables are moved away from the storage once found.
+    pub quarantine_corrupt_files: bool,
+    /// How often the keys whose TTL is past are removed, if at all.
+    pub expiry_purge_interval: Option<Duration>,
+    /// Whether obsolete sstables and WALs are overwritten before they are removed.
+    pub secure_delete: bool,
+    /// Who is notified about what happens inside the storage.
+    pub event_listeners: Vec<Arc<dyn EventListener>>,
+    /// Whether reads validate the checksums of the entries they read by default.
+    pub verify_checksums: bool,
+    /// How many threads look up the sstables for a multi-get.
+    pub read_parallelism: usize,
+    /// Whether a multi-get reads the entries it finds in the sstables through io_uring.
+    #[cfg(feature = "io-uring")]
+    pub io_uring: bool,
+    /// Whether reads are still served once a flush or compaction failed, while writes are refused.
+    pub read_only_on_background_error: bool,
+    /// The most bytes the keys starting with each prefix may take in the sstables.
+    pub quotas: Vec<(String, u64)>,
+    /// How the tenant of a key is told, if at all.
+    pub tenant_of: Option<TenantOf>,
+    /// Whether compactions re-read their outputs before installing them.
+    pub verify_compaction_outputs: bool,
+    /// Which versions of the keys of a [`VersionedStorage`](crate::versioned::VersionedStorage)
+    /// compactions keep, if not all of them.
+    pub retention: Option<RetentionPolicy>,
+    /// How thoroughly the storage is checked when opened, if at all.
+    pub verify_on_open: Option<VerifyLevel>,
+    /// Whether opening repairs what the check finds instead of failing.
+    pub repair: bool,
+    /// How many of the keys read the most are tracked.
+    pub hot_keys: usize,
+}
+
+impl Config {
+    /// The codec used by the sstables of the given level.
+    pub fn compression(&self, level: usize) -> Compression {
+        self.compression.get(level).copied().unwrap_or_default()
+    }
+
+    /// The bits per key of the filters of the sstables of the given level, or 0 if they have none.
+    pub fn filter_bits_per_key(&self, level: usize) -> usize {
+        self.filter_bits_per_key.get(level).copied().unwrap_or(bloom::BITS_PER_KEY)
+    }
+
+    /// The time now, in milliseconds since the UNIX epoch, as told by the clock.
+    pub fn now(&self) -> u64 {
+        scheduler::millis(self.clock.now())
+    }
+
+    /// The tenant of the given key, if it belongs to one.
+    pub fn tenant<'k>(&self, key: &'k str) -> Option<&'k str> {
+        self.tenant_of.as_ref().and_then(|tenant_of| tenant_of(key))
+    }
+
+    /// How flushes and compactions handle the sstables of the given level. Only flushes write into
+    /// L0, and compactions into the other levels.
+    pub fn table_options(&self, level: usize) -> TableOptions {
+        TableOptions {
+            compression: self.compression(level),
+            min_compressed_value_size: self.min_compressed_value_size,
+            filter: (self.filter_bits_per_key(level) > 0).then_some(self.filter),
+            filter_bits_per_key: self.filter_bits_per_key(level),
+            readahead: self.compaction_readahead,
+            direct_io: self.direct_io,
+            drop_tombstones: false,
+            target_file_size: None,
+            partition_index_above: self.partition_index_above,
+            retention: None,
+            level,
+            source: if level == 0 { TableSource::Flush } else { TableSource::Compaction },
+            created_at: self.clock.now(),
+        }
+    }
+
+    /// Which versions a compaction running now keeps, if not all of them.
+    pub fn retention(&self) -> Option<Retention> {
+        self.retention.map(|policy| policy.at(self.clock.now()))
+    }
+
+    /// Removes a file holding entries the storage no longer needs, shredding it first if
+    /// [`StorageBuilder::secure_delete`] is set.
+    pub fn remove_obsolete(&self, path: &Path) -> io::Result<()> {
+        match self.secure_delete {
+            true => self.env.shred_file(path),
+            false => self.env.remove_file(path),
+        }
+    }
+
+    /// Gets rid of the WAL of a persisted memtable: archives it if
+    /// [`StorageBuilder::wal_archive_size`] is set, removing the oldest archived WALs until they
+    /// fit, or else removes it.
+    pub fn retire_wal(&self, memtable: &MemTable) -> Result<()> {
+        let capacity = match self.wal_archive_size {
+            Some(capacity) => capacity,
+            None => return memtable.remove_wal(self.secure_delete),
+        };
+
+        let archived_path = self.wal_path.join(format!("{}-{}", WAL_ARCHIVE_NAME, memtable.id));
+        self.env.rename(memtable.wal_path(), &archived_path)?;
+
+        let archived = wal::archived(self.env.as_ref(), &self.wal_path)?;
+        let sizes = archived.iter().map(|(_, path)| self.env.file_size(path)).collect::<io::Result<Vec<_>>>()?;
+        let mut size: u64 = sizes.iter().sum();
+        for ((_, path), file_size) in archived.iter().zip(sizes) {
+            if size <= capacity {
+                break;
+            }
+
+            self.remove_obsolete(path)?;
+            size -= file_size;
+        }
+        self.env.sync_dir(&self.wal_path)?;
+
+        Ok(())
+    }
+}
+
+/// Options for a single read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Whether the checksums of the entries read from sstables are validated, failing with
+    /// [`Error::Corruption`](crate::Error::Corruption) instead of returning corrupt data.
+    pub verify_checksums: bool,
+}
+
+/// A value read by [`Storage::read_verified`], along with its CRC32, which callers can check the
+/// value against once it reached them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedValue {
+    pub value: Vec<u8>,
+    pub checksum: u32,
+    /// The time left before the value expires, if it was inserted with a TTL.
+    pub ttl: Option<Duration>,
+}
+
+/// How a replication follower catches up with its leader. See [`Storage::catch_up`].
+pub enum CatchUp {
+    /// The follower applies the writes the tail yields, from the one right after its last on.
+    Resume(Box<WalTail>),
+    /// The follower replaces its storage with the checkpoint written, and applies the writes after
+    /// the given sequence number, that of the last write the checkpoint holds. The storage the
+    /// checkpoint opens as may tell an older one, if compactions dropped the last writes.
+    Checkpoint { seqno: u64 },
+}
+
+/// A value found by a read, along with when it expires, in milliseconds since the UNIX epoch, if
+/// it was inserted with a TTL.
+type Found = (Vec<u8>, Option<u64>);
+
+/// Limits on a single page of a scan. See [`Storage::scan_with`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// The most entries the page holds, if there is a limit.
+    pub max_keys: Option<usize>,
+    /// The bytes of keys and values past which the page ends, if there is a limit. The entry
+    /// that goes past it is part of the page.
+    pub max_bytes: Option<usize>,
+    /// When the page ends, if it must end by then. It is checked after each entry.
+    pub deadline: Option<Instant>,
+}
+
+/// A page of the entries of the storage, in key order. See [`Storage::scan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(String, Vec<u8>)>,
+    /// The token to pass to the scan of the next page, or `None` if this is the last one.
+    pub continuation: Option<String>,
+}
+
+/// Where the background work of the storage runs.
+#[derive(Clone)]
+enum Background {
+    /// In a detached thread, which stops once the storage is dropped.
+    Thread,
+    Manual(Arc<Mutex<Compactor>>),
+}
+
+/// What only the writer may change. Clones of the storage share it, so they share the writer.
+struct WriterState {
+    sequence_number: usize,
 }
 
 /// The engine and its configuration. Why isn't the configuration inside the engine itself?
 /// Maybe because it's read-only.
 #[derive(Clone)]
 pub struct Storage{
+    /// Recovered when poisoned, so that a thread panicking while holding it doesn't fail every
+    /// later call.
     pub(crate) engine: Arc<Mutex<Engine>>,
     pub(crate) config: Config,
-    persistence_sender: tokio::sync::mpsc::UnboundedSender<String>,
-    sequence_number: usize,
-    compactor: Arc<JoinHandle<()>>,
+    persistence_sender: Sender<Job>,
+    writer: Arc<Mutex<WriterState>>,
+    compactor: Background,
+    watchers: Arc<Watchers>,
+    /// What checking the storage on open found, if it was checked.
+    verify_report: Option<Arc<VerifyReport>>,
+    /// What opening the storage recovered.
+    recovery_report: Arc<RecoveryReport>,
+    identity: Arc<Identity>,
+    /// Throttles the writes, if a write rate is limited.
+    write_limiter: Option<Arc<RateLimiter>>,
+    /// Reads the sstables ahead of iterators, unless there are no threads to.
+    prefetcher: Option<Arc<Prefetcher>>,
+    /// Submits the reads of a multi-get, if they go through io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    ring: Option<Arc<Ring>>,
+    /// Owns the background threads, unless background work is scheduled manually.
+    supervisor: Supervisor,
+    /// Locked until every clone is dropped, so that the storage isn't opened twice.
+    _locks: Arc<[Box<dyn EnvFile>]>,
 }
 
 pub struct StorageBuilder {
@@ -56,268 +383,4639 @@ impl StorageBuilder {
                 segments_path,
                 wal_path,
                 threshold: 1024,
+                wal_buffer_size: 0,
+                wal_archive_size: None,
+                memtable_hash_index: false,
+                recovery_mode: RecoveryMode::default(),
+                env: Arc::new(OsEnv),
+                clock: Arc::new(SystemClock),
+                scheduling: Scheduling::Background,
+                compaction_interval: None,
+                compaction_tombstone_ratio: None,
+                l0_compaction_trigger: None,
+                auto_tune: None,
+                max_frozen_memtables: None,
+                write_bytes_per_sec: None,
+                write_ops_per_sec: None,
+                max_background_jobs: 1,
+                dedicated_flush_thread: false,
+                negative_cache_capacity: 1024,
+                row_cache_capacity: 0,
+                compression: Vec::new(),
+                min_compressed_value_size: 0,
+                filter: FilterKind::default(),
+                filter_bits_per_key: Vec::new(),
+                compaction_readahead: 2 * 1024 * 1024,
+                prefetch_threads: 1,
+                partition_index_above: None,
+                block_cache: Arc::new(BlockCache::new(8 * 1024 * 1024)),
+                direct_io: false,
+                target_file_size: 64 * 1024 * 1024,
+                scrub_interval: None,
+                quarantine_corrupt_files: false,
+                expiry_purge_interval: None,
+                secure_delete: false,
+                event_listeners: Vec::new(),
+                verify_checksums: false,
+                read_parallelism: 1,
+                #[cfg(feature = "io-uring")]
+                io_uring: false,
+                read_only_on_background_error: false,
+                quotas: Vec::new(),
+                tenant_of: None,
+                verify_compaction_outputs: false,
+                retention: None,
+                verify_on_open: None,
+                repair: false,
+                hot_keys: 0,
             },
         }
     }
 
+    /// Sets the directory of the sstables and the manifest. Defaults to `./sstable`.
     pub fn segments_path(mut self, segments_path: PathBuf) -> Self {
         self.config.segments_path = segments_path;
 
         self
     }
 
+    /// Sets the directory of the WALs, which may be on another device than the sstables, e.g. a
+    /// faster one, as only the WALs are synced on the path of writes. It may also be the
+    /// segments path. Either way, no other storage may keep its WALs there while this one is
+    /// open. Defaults to `./write-ahead-log`.
     pub fn wal_path(mut self, wal_path: PathBuf) -> Self {
         self.config.wal_path = wal_path;
 
         self
     }
 
-    /// Builds the storage.
-    /// - ensures the directory where the sstables and WALs will be stored exists
-    /// - builds a vector of sstables based on the files on that directory that match the segment
-    /// name
-    /// - creates an empty memtable
-    pub fn build(self) -> Result<Storage> {
-        std::fs::create_dir_all(&self.config.segments_path)?;
-        std::fs::create_dir_all(&self.config.wal_path)?;
+    /// Sets the number of entries at which a memtable is converted into a sstable.
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.config.threshold = threshold;
 
-        let sstables0 = self.load_sstables()?;
-        let sstable_readers0 = sstables0.iter().flat_map(|sstable| sstable.reader()).collect();
-        let (active_memtable, memtables) = self.load_memtables()?;
+        self
+    }
 
-        let engine = Arc::new(Mutex::new(Engine {
-            sstables0,
-            sstables1: Vec::new(),
-            sstable_readers0,
-            sstable_readers1: Vec::new(),
-            active_memtable,
-            memtables,
-        }));
+    /// Buffers up to the given number of bytes of entries before writing them to the WAL, instead
+    /// of writing every entry on its own. The buffered entries are lost on a crash, unless
+    /// [`Storage::flush_wal`] wrote them. Defaults to 0, which writes every entry right away.
+    pub fn wal_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.wal_buffer_size = bytes;
 
-        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self
+    }
 
-        let compactor_engine = engine.clone();
-        let compactor_thread = thread::spawn(move || {
-            start_compaction(compactor_engine, receiver);
-        });
+    /// Archives the WALs of persisted memtables next to the others instead of removing them, up
+    /// to the given number of bytes, past which the oldest ones are removed. A replication
+    /// follower that falls behind catches up from them, rather than from a whole checkpoint: see
+    /// [`Storage::catch_up`]. Defaults to none, which removes every WAL once its memtable is
+    /// persisted.
+    pub fn wal_archive_size(mut self, bytes: u64) -> Self {
+        self.config.wal_archive_size = Some(bytes);
 
-        Ok(Storage {
-            config: self.config,
-            engine,
-            persistence_sender: sender,
-            compactor: Arc::new(compactor_thread),
-            sequence_number: 0,
-        })
+        self
     }
 
-    fn load_memtables(&self) -> Result<(MemTable, Vec<Arc<MemTable>>)> {
-        let mut memtables = Vec::new();
+    /// Makes memtables keep a hash index of their entries alongside the ordered ones, so that
+    /// point lookups find a key in constant time rather than by searching the ordered entries.
+    /// Suits workloads made mostly of gets, as the index takes memory and slows down every write.
+    /// Defaults to false.
+    pub fn memtable_hash_index(mut self, enabled: bool) -> Self {
+        self.config.memtable_hash_index = enabled;
 
-        for entry in std::fs::read_dir(&self.config.wal_path)? {
-            let path = entry?.path();
-            let filename = path.file_name().unwrap().to_str().unwrap();
+        self
+    }
 
-            if filename.starts_with(WAL_NAME) {
-                let memtable = MemTable::recover(&path)?;
-                memtables.push(memtable);
-            }
-        }
-    
-        memtables.sort_by_key(|t| t.id);
-        let memtable = memtables.pop();
-    
-        match memtable {
-            None => {
-                let mut wal_path = self.config.wal_path.clone();
-                wal_path.push(format!("{}-{}", WAL_NAME, 0));
+    /// Sets how the memtables are recovered from WALs holding corrupt entries when the storage is
+    /// opened. See [`RecoveryMode`]. Defaults to [`RecoveryMode::TolerateCorruptTail`].
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.config.recovery_mode = mode;
 
-                let memtable = MemTable::new(0, &wal_path)?;
-                Ok((memtable, vec![]))
-            }
-            Some(memtable) => {
-                let memtables = memtables.into_iter().map(|t| Arc::new(t)).collect();
-                Ok((memtable, memtables))
-            }
-        }
+        self
     }
 
-    // TODO: a sstable may be corrupted due to a crash while being written. Fix this later.
-    fn load_sstables(&self) -> Result<Vec<SSTable>> {
-        let mut sstables = Vec::new();
+    /// Sets the environment through which all the I/O is performed. Defaults to [`OsEnv`].
+    pub fn env(mut self, env: Arc<dyn Env>) -> Self {
+        self.config.env = env;
 
-        for entry in std::fs::read_dir(&self.config.segments_path)? {
-            let path = entry?.path();
-            let filename = path.file_name().unwrap().to_str().unwrap();
+        self
+    }
 
-            if filename.starts_with(SEGMENTS_NAME) {
-                let id = filename.rsplit('-').next().unwrap();
-                let id: usize = id.parse()?;
+    /// Sets the source of time for time-based decisions. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
 
-                sstables.push((id, SSTable::new(&path)));
-            }
-        }
+        self
+    }
 
-        sstables.sort_by_key(|t| t.0);
+    /// Sets how the background work is run. Defaults to [`Scheduling::Background`].
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.config.scheduling = scheduling;
 
-        Ok(sstables.into_iter().map(|t| t.1).collect())
+        self
     }
-}
 
-impl Default for StorageBuilder {
-    fn default() -> Self {
-        Self::new()
+    /// Compacts L0 into L1 whenever the given interval elapses. The check happens after the
+    /// background work triggered by writes, so a compaction runs at most once per interval.
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.config.compaction_interval = Some(interval);
+
+        self
     }
-}
 
-impl Storage {
-    pub fn builder() -> StorageBuilder {
-        StorageBuilder::new()
+    /// Compacts L0 into L1 as soon as any sstable has at least the given fraction of tombstones,
+    /// without waiting for the compaction interval. Compactions into L1 drop the tombstones along
+    /// with the values they shadow, reclaiming the space of removed keys sooner.
+    pub fn compaction_tombstone_ratio(mut self, ratio: f64) -> Self {
+        self.config.compaction_tombstone_ratio = Some(ratio);
+
+        self
     }
 
-    pub fn new() -> Result<Self> {
-        StorageBuilder::new().build()
+    /// Compacts L0 into L1 as soon as it holds at least the given number of sstables, without
+    /// waiting for the compaction interval. Every L0 sstable may hold any key, so each one adds a
+    /// lookup to the reads of keys found in none of the newer ones. Defaults to none, which leaves
+    /// L0 to grow until the interval elapses.
+    pub fn l0_compaction_trigger(mut self, files: usize) -> Self {
+        self.config.l0_compaction_trigger = Some(files);
+
+        self
     }
 
-    fn segment_path(&self, seg_id: usize) -> PathBuf {
-        let mut path = PathBuf::new();
-        path.push(&self.config.segments_path);
-        path.push(format!("{}-{}", SEGMENTS_NAME, seg_id));
+    /// Tunes the threshold of the memtables and the L0 compaction trigger to the workload, within
+    /// the given bounds, starting from the configured ones. After every flush, if it took half of
+    /// the time since the previous one or more, the threshold doubles and the trigger goes up by
+    /// one, so that flushes and compactions keep up with the writes. If it took a tenth or less,
+    /// they shrink back as much, so that memtables take less memory and reads look into fewer
+    /// sstables. The event listeners are told of each change. Defaults to none, which keeps the
+    /// configured ones.
+    pub fn auto_tune(mut self, threshold: RangeInclusive<usize>, l0_compaction_trigger: RangeInclusive<usize>) -> Self {
+        self.config.auto_tune = Some(AutoTune {
+            threshold,
+            l0_compaction_trigger,
+        });
 
-        path
+        self
     }
 
-    /// Performs a read by trying to find the value in the memtable and falling back to the
-    /// sstables if not successful.
-    pub fn read(&self, key: &str) -> Option<Vec<u8>> {
-        let engine = &mut self.engine.lock().unwrap();
+    /// Stalls writes while the given number of frozen memtables wait to be persisted, at least
+    /// one, so that they can't pile up in memory when persisting them falls behind. With
+    /// [`Scheduling::Manual`], writes fail with [`Error::WriteStalled`] instead, until a tick
+    /// persists some. Defaults to none, which lets them pile up.
+    pub fn max_frozen_memtables(mut self, memtables: usize) -> Self {
+        self.config.max_frozen_memtables = Some(memtables);
 
-        engine.memtables
-            .iter()
-            .rev()
-            .find_map(|memtable| memtable.get(key))
-            .map(|v| v.to_vec())
-            .or_else(|| {
-                for table in engine.sstable_readers0.iter_mut().rev().borrow_mut() {
-                    let v = table.get(key).unwrap();
-
-                    if v.is_some() {
-                        return v;
-                    }
-                }
+        self
+    }
 
-                None
-            })
+    /// Throttles writes to the given number of bytes of keys and values per second, at least one,
+    /// so that a bulk import can't write faster than flushes and compactions keep up with. Writes
+    /// may burst up to a second worth after being idle, and then wait, holding the writer, for
+    /// the bytes they take beyond it. Defaults to none, which doesn't throttle them.
+    pub fn write_bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.config.write_bytes_per_sec = Some(bytes);
+
+        self
     }
 
-    /// Inserts a value into the memtable. If the memtable size reaches its threshold, converts it
-    /// into a sstable.
-    ///
-    /// TODO:
-    /// - the memtable is swapped with an empty one before it is persisted. concurrent readers will
-    /// see the storage in a past state state.
-    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<()> {
-        let mut engine = self.engine.lock().unwrap();
+    /// Throttles writes to the given number of keys per second, at least one, as
+    /// [`write_bytes_per_sec`](Self::write_bytes_per_sec) does to bytes. A batch counts each of
+    /// its keys. Defaults to none, which doesn't throttle them.
+    pub fn write_ops_per_sec(mut self, ops: u64) -> Self {
+        self.config.write_ops_per_sec = Some(ops);
+
+        self
+    }
+
+    /// Sets how many threads run the background work. Flushes take priority over compactions,
+    /// which take priority over deleting obsolete files, and only one flush and one compaction
+    /// run at a time, so more threads mostly let a flush run while a compaction does. Only
+    /// applies to [`Scheduling::Background`]. Defaults to 1.
+    pub fn max_background_jobs(mut self, threads: usize) -> Self {
+        self.config.max_background_jobs = threads;
+
+        self
+    }
+
+    /// Runs flushes in one more thread, reserved for them, so that a long compaction never delays
+    /// persisting the frozen memtables: compactions only hold the engine to install their outputs,
+    /// not while merging. Only applies to [`Scheduling::Background`]. Defaults to false.
+    pub fn dedicated_flush_thread(mut self, enabled: bool) -> Self {
+        self.config.dedicated_flush_thread = enabled;
+
+        self
+    }
+
+    /// Sets how many keys confirmed to be absent are remembered, to answer repeated misses without
+    /// looking into every table. Defaults to 1024, and 0 disables it.
+    pub fn negative_cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.negative_cache_capacity = capacity;
+
+        self
+    }
 
-        engine.active_memtable.insert(key, value).unwrap();
+    /// Sets how many bytes of recently read keys and values are cached, to answer reads of hot keys
+    /// without looking into the tables. Defaults to 0, which disables the cache.
+    pub fn row_cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.row_cache_capacity = capacity;
+
+        self
+    }
 
-        if engine.active_memtable.len() == self.config.threshold {
-            Storage::replace_memtable(&self.persistence_sender, &mut self.sequence_number, &mut engine, &self.config.wal_path)?;
-            self.persistence_sender.send("message".to_string())?;
+    /// Sets the codec that compresses the values of the sstables in the given level, e.g. none on
+    /// L0, where tables are short-lived, and a slower but tighter one on the bottom level. Defaults
+    /// to [`Compression::None`].
+    pub fn compression(mut self, level: usize, compression: Compression) -> Self {
+        if self.config.compression.len() <= level {
+            self.config.compression.resize(level + 1, Compression::None);
         }
 
-        Ok(())
+        self.config.compression[level] = compression;
+
+        self
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let mut engine = self.engine.lock().unwrap();
+    /// Stores the values smaller than the given number of bytes uncompressed, whatever the codec
+    /// of their level, as compressing tiny values costs CPU for little or no space saved. Each
+    /// entry records whether its value is compressed, so the size may change between openings.
+    /// Defaults to 0, which compresses every value.
+    pub fn min_compressed_value_size(mut self, bytes: usize) -> Self {
+        self.config.min_compressed_value_size = bytes;
+
+        self
+    }
 
-        engine.active_memtable.remove(key).unwrap();
+    /// Sets the kind of filter the sstables are written with, which rules out most of the keys
+    /// they don't hold without searching for them. Xor filters take less memory for fewer false
+    /// positives than bloom filters, but take longer to build. Each sstable records the kind of
+    /// its filter, so the kind may change between openings. Defaults to [`FilterKind::Bloom`].
+    pub fn filter(mut self, kind: FilterKind) -> Self {
+        self.config.filter = kind;
 
-        if engine.active_memtable.len() == self.config.threshold {
-            Storage::replace_memtable(&self.persistence_sender, &mut self.sequence_number, &mut engine, &self.config.wal_path)?;
+        self
+    }
+
+    /// Sets how many bits per key the filters of the sstables in the given level take, e.g. none
+    /// on L0, whose tables are small and short-lived, and more on the bottom level, where most
+    /// lookups of absent keys end up. More bits make fewer false positives: see [`FilterKind`].
+    /// 0 writes the sstables of the level without a filter. Each sstable records the bits per key
+    /// of its filter, so they may change between openings. Defaults to 10.
+    pub fn filter_bits_per_key(mut self, level: usize, bits: usize) -> Self {
+        if self.config.filter_bits_per_key.len() <= level {
+            self.config.filter_bits_per_key.resize(level + 1, bloom::BITS_PER_KEY);
         }
 
-        Ok(())
+        self.config.filter_bits_per_key[level] = bits;
+
+        self
     }
 
-    fn replace_memtable(sender: &UnboundedSender<String>, sequence_number: &mut usize, engine: &mut MutexGuard<Engine>, path: &Path) -> Result<()> {
-        *sequence_number += 1;
-        let new_memtable = MemTable::new(*sequence_number, &path)?;
-        let old_memtable = std::mem::replace(&mut engine.active_memtable, new_memtable);
-        engine.memtables.push(Arc::new(old_memtable));
+    /// Sets how many bytes of the input sstables a compaction reads at once. Defaults to 2 MiB.
+    pub fn compaction_readahead(mut self, readahead: usize) -> Self {
+        self.config.compaction_readahead = readahead;
+
+        self
+    }
 
-        sender.send("message".to_string())?;
+    /// Sets how many threads read the sstables ahead of iterators: while an iterator consumes a
+    /// block of a sstable, the next one is read in one of them, so that scans don't wait for the
+    /// device at every block boundary. Blocks are as large as the compaction readahead, and each
+    /// sstable an iterator reads holds up to two at once. 0 reads every block when it is needed.
+    /// Defaults to 1.
+    pub fn prefetch_threads(mut self, threads: usize) -> Self {
+        self.config.prefetch_threads = threads;
 
-        Ok(())
+        self
     }
 
-}
+    /// Partitions the index of the sstables whose entries take more than the given number of
+    /// bytes, e.g. those of the bottom level: the keys of their index, along with their filter,
+    /// are split in partitions of about 4 KiB persisted after the entries, and only read when a
+    /// lookup needs them, through the block cache. Opening such a sstable only reads its index,
+    /// rather than the whole file, and keeps the first and last keys of each partition in memory
+    /// rather than every key. Each sstable records whether its index is partitioned, so the size
+    /// may change between openings. Defaults to never.
+    pub fn partition_index_above(mut self, bytes: u64) -> Self {
+        self.config.partition_index_above = Some(bytes);
 
-#[cfg(test)]
-mod tests {
-    use std::ops::Range;
+        self
+    }
 
-    use anyhow::Result;
+    /// Sets how many bytes of the partitions of the indexes of the sstables, and of their filters,
+    /// are cached once read, evicting the least recently used ones first. See
+    /// [`StorageBuilder::partition_index_above`]. Defaults to 8 MiB.
+    pub fn block_cache_capacity(mut self, bytes: usize) -> Self {
+        self.config.block_cache = Arc::new(BlockCache::new(bytes));
 
-    use crate::{storage::Storage, test_utils::*};
+        self
+    }
 
-    #[test]
-    fn memtables_are_converted_to_sstables_when_threshold_is_reached() -> Result<()> {
-        let test = Test::new()?;
-        let mut storage = test.create_storage()?;
+    /// Makes flushes and compactions bypass the page cache, so that background I/O doesn't evict
+    /// the data hot for reads. Only worth it on hosts dedicated to the storage, as the OS no
+    /// longer caches the data written. Ignored where direct I/O is unsupported. Defaults to false.
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.config.direct_io = enabled;
 
-        let number_of_rows = storage.config.threshold * 2;
-        inject_rows(&mut storage, 0..number_of_rows);
+        self
+    }
 
-        let engine = storage.engine.lock().unwrap();
+    /// Sets the size past which a compaction starts a new sstable, so that L1 is made of many
+    /// sstables with non-overlapping key ranges instead of a single one holding everything. An
+    /// sstable ends with the entry that reaches the size, so it may be slightly larger. Defaults
+    /// to 64 MiB.
+    pub fn target_file_size(mut self, bytes: u64) -> Self {
+        self.config.target_file_size = bytes;
 
-        assert_eq!(engine.sstables0.len(), 2);
-        assert_eq!(engine.memtable.len(), 0);
+        self
+    }
 
-        Ok(())
+    /// Re-reads every sstable whenever the given interval elapses, reporting the corrupt ones to
+    /// the event listeners, instead of only finding them when a read stumbles on them.
+    pub fn scrub_interval(mut self, interval: Duration) -> Self {
+        self.config.scrub_interval = Some(interval);
+
+        self
     }
 
-    #[test]
-    fn engine_loads_sstables_and_wal_when_it_starts() -> Result<()> {
-        let test = Test::new()?;
-        let mut storage = test.create_storage()?;
+    /// Removes the keys whose TTL is past whenever the given interval elapses, as
+    /// [`Storage::purge_expired`] does, instead of leaving them to compactions. Expired keys read
+    /// as absent either way. Defaults to never.
+    pub fn expiry_purge_interval(mut self, interval: Duration) -> Self {
+        self.config.expiry_purge_interval = Some(interval);
 
-        let number_of_rows = storage.config.threshold * 2;
-        inject_rows(&mut storage, 0..number_of_rows);
+        self
+    }
 
-        let storage = test.create_storage()?;
-        let engine = storage.engine.lock().unwrap();
+    /// Moves the corrupt sstables found by scrubbing into a `quarantine` directory, inside the
+    /// segments path, so that they are no longer read. Defaults to false.
+    pub fn quarantine_corrupt_files(mut self, enabled: bool) -> Self {
+        self.config.quarantine_corrupt_files = enabled;
 
-        assert_eq!(engine.sstables0.len(), 2);
-        assert_eq!(engine.memtable.len(), 0); // TODO: We have no guarantee that the WAL was flushed to disk so there might be data missing.
+        self
+    }
 
-        Ok(())
+    /// Overwrites the sstables replaced by compactions and the WALs of persisted memtables with
+    /// zeros before removing them, as [`destroy`](Storage::destroy) does to every file, so that
+    /// removed values can't be recovered from the disk, e.g. for data-erasure requirements. The
+    /// storage doesn't encrypt its files, so there is no key whose destruction would do instead.
+    /// Sstables still linked into a checkpoint are only removed. See [`Env::shred_file`] for what
+    /// overwriting can't reach. Defaults to false.
+    pub fn secure_delete(mut self, enabled: bool) -> Self {
+        self.config.secure_delete = enabled;
+
+        self
     }
 
-    #[test]
-    fn reads_from_memtable_and_sstable() -> Result<()> {
-        let test = Test::new()?;
-        let mut storage = test.create_storage()?;
-        let threshold = storage.config.threshold;
+    /// Adds a listener to be notified about what happens inside the storage.
+    pub fn event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.config.event_listeners.push(listener);
 
-        let v1 = storage.read("key-500");
-        let v2 = storage.read("key-1500");
-        assert_eq!(None, v1);
-        assert_eq!(None, v2);
+        self
+    }
 
-        inject_rows(&mut storage, 0..threshold);
+    /// Makes reads validate the checksums of the entries they read from sstables, see
+    /// [`ReadOptions::verify_checksums`]. Defaults to false.
+    pub fn verify_checksums(mut self, enabled: bool) -> Self {
+        self.config.verify_checksums = enabled;
 
-        let v1 = String::from_utf8(storage.read("key-500").unwrap()).unwrap();
-        let v2 = storage.read("key-1500");
-        assert_eq!("value-500", v1);
-        assert_eq!(None, v2);
+        self
+    }
 
-        inject_rows(&mut storage, threshold..threshold*2);
+    /// Sets how many threads a [`Storage::multi_get`] splits the sstables among, each looking up
+    /// every key in its share of them. Defaults to 1, which looks them up in the calling thread.
+    pub fn read_parallelism(mut self, threads: usize) -> Self {
+        self.config.read_parallelism = threads.max(1);
 
-        let v1 = String::from_utf8(storage.read("key-500").unwrap()).unwrap();
-        let v2 = String::from_utf8(storage.read("key-1500").unwrap()).unwrap();
-        assert_eq!("value-500", v1);
-        assert_eq!("value-1500", v2);
+        self
+    }
+
+    /// Makes a [`Storage::multi_get`] submit the reads of the entries it finds in the sstables
+    /// together through io_uring, so that they overlap on the device instead of taking a syscall
+    /// each, one after the other. Only the index and filter of each sstable are searched for every
+    /// key, and only the entry of the newest sstable holding it is read. Opening the storage fails
+    /// if the kernel doesn't allow io_uring. Does nothing outside of Linux. Defaults to false.
+    #[cfg(feature = "io-uring")]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.config.io_uring = enabled;
+
+        self
+    }
+
+    /// Keeps serving reads once a flush or compaction fails, so that only writes fail with
+    /// [`Error::BackgroundError`]. Defaults to false, which fails reads as well.
+    pub fn read_only_on_background_error(mut self, enabled: bool) -> Self {
+        self.config.read_only_on_background_error = enabled;
+
+        self
+    }
+
+    /// Tracks how often keys are read, remembering the given number of keys read the most for
+    /// [`Storage::top_keys`]. Reads are counted in a count-min sketch, which takes a fixed 64 KiB
+    /// however many keys there are, at the cost of overestimating the keys that share counters
+    /// with hotter ones. Defaults to 0, which tracks nothing.
+    pub fn hot_keys(mut self, capacity: usize) -> Self {
+        self.config.hot_keys = capacity;
+
+        self
+    }
+
+    /// Limits the keys starting with the given prefix, e.g. those of a tenant, to the given bytes
+    /// in the sstables: once they take as many, inserting any of them fails with
+    /// [`Error::QuotaExceeded`] until removals free enough space. May be called for many prefixes.
+    ///
+    /// The bytes are measured when memtables are flushed and sstables compacted, so a prefix may
+    /// go over its quota by up to what the memtables hold. Defaults to no quota.
+    pub fn quota(mut self, prefix: impl Into<String>, bytes: u64) -> Self {
+        self.config.quotas.push((prefix.into(), bytes));
+
+        self
+    }
+
+    /// Sets how the tenant of a key is told, e.g. as the part before its first `/`, so that
+    /// [`Storage::stats`] reports the reads and writes of each tenant apart. Keys for which it
+    /// returns `None` belong to no tenant. Defaults to none, which reports no tenant.
+    pub fn tenant_of<F>(mut self, tenant_of: F) -> Self
+    where
+        F: Fn(&str) -> Option<&str> + Send + Sync + 'static,
+    {
+        self.config.tenant_of = Some(Arc::new(tenant_of));
+
+        self
+    }
+
+    /// Makes compactions re-read the sstables they write before installing them, checking that
+    /// every entry decodes and matches its checksum, that the keys are in order and that no entry
+    /// went missing. A compaction whose outputs fail the check deletes them and fails, keeping its
+    /// inputs. Defaults to false.
+    pub fn verify_compaction_outputs(mut self, enabled: bool) -> Self {
+        self.config.verify_compaction_outputs = enabled;
+
+        self
+    }
+
+    /// Sets which versions of the keys written through a
+    /// [`VersionedStorage`](crate::versioned::VersionedStorage) compactions keep, dropping the
+    /// others. Ages are measured by the clock against the timestamps of the versions. Defaults to
+    /// none, which keeps every version.
+    ///
+    /// Versions are told apart by the `\0` in their keys, so the keys written other than through
+    /// a [`VersionedStorage`](crate::versioned::VersionedStorage) may not hold it then.
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.config.retention = Some(policy);
+
+        self
+    }
+
+    /// Checks the storage when opening it, at the given level: whether the manifest and the
+    /// segments path agree on the sstables, and, at [`VerifyLevel::Full`], whether every entry of
+    /// every recorded sstable matches its checksum. Opening fails with
+    /// [`Error::VerificationFailed`] if recorded sstables are missing or corrupt, unless repairing.
+    /// The report is then available from [`Storage::verify_report`]. Defaults to none, which
+    /// skips the missing sstables and only finds the corrupt ones when reading them.
+    pub fn verify_on_open(mut self, level: VerifyLevel) -> Self {
+        self.config.verify_on_open = Some(level);
+
+        self
+    }
+
+    /// Makes opening with [`verify_on_open`](Self::verify_on_open) repair what it finds instead
+    /// of failing: the missing and corrupt sstables are removed from the manifest, losing what they
+    /// held, and the corrupt and unrecorded ones are moved into the `quarantine` directory, inside
+    /// the segments path. Defaults to false.
+    pub fn repair(mut self, enabled: bool) -> Self {
+        self.config.repair = enabled;
+
+        self
+    }
+
+    /// Builds the storage.
+    /// - ensures the directory where the sstables and WALs will be stored exists
+    /// - checks that both hold the identity of the same storage, which this build can read, or
+    ///   gives them a new one
+    /// - checks the storage, if configured to
+    /// - builds a vector of sstables for each level based on the ones recorded in the manifest
+    /// - recovers the memtables that weren't persisted from their WALs, or creates an empty one
+    /// - schedules the flush of the recovered memtables that were frozen
+    pub fn build(self) -> Result<Storage> {
+        self.config.env.create_dir_all(&self.config.segments_path)?;
+        self.config.env.create_dir_all(&self.config.wal_path)?;
+        let locks = self.lock()?;
+        let identity = Identity::load(
+            self.config.env.as_ref(),
+            &self.config.segments_path,
+            &self.config.wal_path,
+            &self.config.compression,
+        )?;
+
+        let verify_report = match self.config.verify_on_open {
+            Some(level) => Some(Arc::new(verify::verify(&self.config, level, self.config.repair)?)),
+            None => None,
+        };
+
+        let (manifest, first_memtable, (sstables0, sstables1)) = self.load_sstables()?;
+        let (mut active_memtable, memtables, mut recovery_report) = self.load_memtables(&sstables0, first_memtable)?;
+        let sequence_number = active_memtable.id;
+        let frozen_memtables = memtables.len();
+
+        recovery_report.sstables = vec![sstables0.len(), sstables1.len()];
+        if let Some(report) = verify_report.as_ref().filter(|report| report.repaired) {
+            let corrupt = report.corrupt.iter().map(|(name, _)| name);
+            recovery_report.quarantined = corrupt.chain(&report.unrecorded).cloned().collect();
+        }
+
+        let sstables0: Vec<SSTable> = sstables0.into_values().collect();
+        let reader = |sstable: &SSTable| Ok(Arc::new(sstable.cached_reader(&self.config.block_cache)?));
+        let sstable_readers0: Vec<_> = sstables0.iter().map(reader).collect::<Result<_>>()?;
+        let sstable_readers1: Vec<_> = sstables1.iter().map(reader).collect::<Result<_>>()?;
+
+        // New writes must be more recent than anything already stored, wherever it is.
+        let next_seqno = sstable_readers0
+            .iter()
+            .chain(&sstable_readers1)
+            .map(|reader| reader.max_seqno() + 1)
+            .chain(memtables.iter().map(|memtable| memtable.next_seqno()))
+            .max()
+            .unwrap_or(1);
+        active_memtable.resume_seqno(next_seqno);
+
+        let mut version = Version {
+            memtables,
+            sstables0,
+            sstables1,
+            sstable_readers0,
+            sstable_readers1,
+        };
+        // The manifest keeps the L1 sstables in the order they were added.
+        version.sort_l1();
+
+        let readers = version.sstable_readers0.iter().chain(&version.sstable_readers1);
+        let mut expiries: BTreeSet<(u64, String)> =
+            readers.flat_map(|reader| reader.expiring().map(|(expires_at, key)| (expires_at, key.to_owned()))).collect();
+        for memtable in version.memtables.iter().map(|memtable| memtable.as_ref()).chain([&active_memtable]) {
+            expiries.extend(memtable.expiring());
+        }
+
+        let tuning = Tuning {
+            threshold: self.config.threshold,
+            l0_compaction_trigger: self.config.l0_compaction_trigger,
+        };
+        let (tuner, tuning) = match self.config.auto_tune.clone() {
+            Some(bounds) => {
+                let (tuner, tuning) = Tuner::new(bounds, tuning.threshold, tuning.l0_compaction_trigger);
+                (Some(tuner), tuning)
+            }
+            None => (None, tuning),
+        };
+
+        let mut engine = Engine {
+            active_memtable,
+            version: Arc::new(version),
+            absent_keys: NegativeCache::new(self.config.negative_cache_capacity),
+            rows: RowCache::new(self.config.row_cache_capacity),
+            bytes_written: BytesWritten::default(),
+            latencies: Latencies::new(),
+            manifest,
+            background_error: None,
+            prefix_bytes: HashMap::new(),
+            tenants: HashMap::new(),
+            dropped_versions: 0,
+            hot_keys: HotKeys::new(self.config.hot_keys),
+            flushed: Arc::new(Condvar::new()),
+            tuning,
+            tuner,
+            expiries,
+        };
+
+        engine.measure_prefixes(&self.config.quotas)?;
+        let engine = Arc::new(Mutex::new(engine));
+
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..frozen_memtables {
+            sender.send(Job::Flush)?;
+        }
+
+        let compactor = Compactor::new(engine.clone(), self.config.clone(), receiver);
+        let supervisor = Supervisor::default();
+        let compactor = match self.config.scheduling {
+            Scheduling::Background => {
+                if self.config.scrub_interval.is_some() {
+                    // A scrubber restarted after failing waits for the interval before scrubbing
+                    // again.
+                    let mut scrubber = Scrubber::new(&engine, self.config.clone());
+                    supervisor.spawn("lsm-scrubber", Some(Duration::ZERO), move || scrubber.run())?;
+                }
+                if self.config.expiry_purge_interval.is_some() {
+                    let mut expirer = Expirer::new(&engine, self.config.clone());
+                    supervisor.spawn("lsm-expirer", Some(Duration::ZERO), move || expirer.run())?;
+                }
+
+                compactor.spawn(&supervisor)?;
+
+                Background::Thread
+            }
+            Scheduling::Manual => Background::Manual(Arc::new(Mutex::new(compactor))),
+        };
+
+        let write_limiter = RateLimiter::new(
+            self.config.clock.clone(),
+            self.config.write_bytes_per_sec,
+            self.config.write_ops_per_sec,
+        )
+        .map(Arc::new);
+
+        let prefetcher = match self.config.prefetch_threads {
+            0 => None,
+            threads => Some(Prefetcher::new(threads)?),
+        };
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let ring = match self.config.io_uring {
+            true => Some(Arc::new(Ring::new()?)),
+            false => None,
+        };
+
+        Ok(Storage {
+            config: self.config,
+            engine,
+            persistence_sender: sender,
+            compactor,
+            writer: Arc::new(Mutex::new(WriterState { sequence_number })),
+            watchers: Arc::new(Watchers::default()),
+            verify_report,
+            recovery_report: Arc::new(recovery_report),
+            identity: Arc::new(identity),
+            write_limiter,
+            prefetcher,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring,
+            supervisor,
+            _locks: Arc::from(locks),
+        })
+    }
+
+    /// Deletes the sstables, WALs and manifest of the storage, which must not be open. Fails with
+    /// [`Error::Locked`] instead of deleting anything if it is. Quarantined sstables are kept.
+    ///
+    /// The manifest is deleted last, so that a crash halfway leaves a storage that opens with
+    /// whatever wasn't deleted yet.
+    pub fn destroy(self) -> Result<()> {
+        let locks = self.lock()?;
+        let env = &self.config.env;
+
+        for path in env.read_dir(&self.config.wal_path)? {
+            let filename = path.file_name().unwrap().to_string_lossy();
+
+            if filename.starts_with(WAL_NAME) || filename.starts_with(WAL_ARCHIVE_NAME) {
+                self.config.remove_obsolete(&path)?;
+            } else if filename == WAL_LOCK_NAME || filename == IDENTITY_NAME {
+                env.remove_file(&path)?;
+            }
+        }
+        env.sync_dir(&self.config.wal_path)?;
+
+        for path in env.read_dir(&self.config.segments_path)? {
+            if path.file_name().unwrap().to_string_lossy().starts_with(SEGMENTS_NAME) {
+                self.config.remove_obsolete(&path)?;
+            }
+        }
+        env.sync_dir(&self.config.segments_path)?;
+
+        for name in [OLD_MANIFEST_NAME, MANIFEST_NAME, IDENTITY_NAME, LOCK_NAME] {
+            let path = self.config.segments_path.join(name);
+
+            if env.read_dir(&self.config.segments_path)?.contains(&path) {
+                env.remove_file(&path)?;
+            }
+        }
+        env.sync_dir(&self.config.segments_path)?;
+        drop(locks);
+
+        Ok(())
+    }
+
+    /// Locks the segments path and the WAL path of the storage, failing with [`Error::Locked`] if
+    /// either is in use already.
+    fn lock(&self) -> Result<Vec<Box<dyn EnvFile>>> {
+        let paths = [
+            self.config.segments_path.join(LOCK_NAME),
+            self.config.wal_path.join(WAL_LOCK_NAME),
+        ];
+
+        paths
+            .iter()
+            .map(|path| match self.config.env.lock(path) {
+                Ok(lock) => Ok(lock),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => Err(Error::Locked.into()),
+                Err(error) => Err(error.into()),
+            })
+            .collect()
+    }
+
+    /// Recovers the memtables from their WALs. The WALs of memtables that were already persisted
+    /// into one of the given sstables, which happens if a crash interrupts a flush right after the
+    /// manifest records it, are removed instead, as are those of memtables older than
+    /// `first_memtable`, which were cleared. Also returns a report of what the WALs held.
+    fn load_memtables(
+        &self,
+        sstables: &BTreeMap<usize, SSTable>,
+        first_memtable: usize,
+    ) -> Result<(MemTable, Vec<Arc<MemTable>>, RecoveryReport)> {
+        let mut memtables = Vec::new();
+        let mut replays: Vec<(usize, WalReplay)> = Vec::new();
+        let mut report = RecoveryReport::default();
+
+        for path in self.config.env.read_dir(&self.config.wal_path)? {
+            let filename = path.file_name().unwrap().to_str().unwrap();
+
+            if filename.starts_with(WAL_NAME) {
+                let config = &self.config;
+                let memtable = MemTable::recover(
+                    &config.env,
+                    &path,
+                    config.wal_buffer_size,
+                    config.memtable_hash_index,
+                    config.recovery_mode,
+                )?;
+                match memtable {
+                    Some((memtable, _)) if sstables.contains_key(&memtable.id) || memtable.id < first_memtable => {
+                        config.retire_wal(&memtable)?;
+                        report.removed_wals.push(path);
+                    }
+                    Some((memtable, replay)) => {
+                        replays.push((memtable.id, replay));
+                        memtables.push(memtable);
+                    }
+                    None => {
+                        config.remove_obsolete(&path)?;
+                        report.removed_wals.push(path);
+                    }
+                }
+            }
+        }
+    
+        replays.sort_by_key(|(id, _)| *id);
+        report.wals = replays.into_iter().map(|(_, replay)| replay).collect();
+        report.removed_wals.sort();
+        memtables.sort_by_key(|t| t.id);
+        let memtable = memtables.pop();
+    
+        match memtable {
+            None => {
+                let id = sstables.keys().next_back().map_or(0, |id| id + 1).max(first_memtable);
+
+                let mut wal_path = self.config.wal_path.clone();
+                wal_path.push(format!("{}-{}", WAL_NAME, id));
+
+                let config = &self.config;
+                let memtable =
+                    MemTable::new(&config.env, id, &wal_path, 1, config.wal_buffer_size, config.memtable_hash_index)?;
+                Ok((memtable, vec![], report))
+            }
+            Some(memtable) => {
+                let memtables = memtables
+                    .into_iter()
+                    .map(|mut memtable| {
+                        memtable.freeze();
+                        Arc::new(memtable)
+                    })
+                    .collect();
+                Ok((memtable, memtables, report))
+            }
+        }
+    }
+
+    /// Opens the manifest and loads the sstables it records into their level: L0 ordered by id, and
+    /// L1 in the order they were added. Sstables that it doesn't record may be incomplete, as a
+    /// crash interrupted their flush or compaction, and are ignored: memtables are recovered from
+    /// the WAL instead. Recorded sstables whose file is gone, e.g. because it was quarantined, are
+    /// skipped. Also returns the id of the oldest memtable that survived the last clear.
+    fn load_sstables(&self) -> Result<(Manifest, usize, Levels)> {
+        let mut names = Vec::new();
+
+        for path in self.config.env.read_dir(&self.config.segments_path)? {
+            let filename = path.file_name().unwrap().to_str().unwrap();
+
+            if filename.starts_with(SEGMENTS_NAME) {
+                names.push(filename.to_owned());
+            }
+        }
+
+        let (manifest, edits) = Manifest::open(&self.config.env, &self.config.segments_path, &names)?;
+        let first_memtable = manifest::first_memtable(&edits);
+        let mut levels = manifest::replay(edits).into_iter().map(|level| {
+            level
+                .into_iter()
+                .filter(|name| names.contains(name))
+                .map(|name| (SSTable::new(&self.config.env, &self.config.segments_path.join(&name)), name))
+        });
+
+        let mut sstables0 = BTreeMap::new();
+        for (sstable, name) in levels.next().into_iter().flatten() {
+            let id: usize = name.rsplit('-').next().unwrap().parse()?;
+            sstables0.insert(id, sstable);
+        }
+
+        let sstables1 = levels.flatten().map(|(sstable, _)| sstable).collect();
+
+        Ok((manifest, first_memtable, (sstables0, sstables1)))
+    }
+}
+
+impl Default for StorageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage {
+    pub fn builder() -> StorageBuilder {
+        StorageBuilder::new()
+    }
+
+    pub fn new() -> Result<Self> {
+        StorageBuilder::new().build()
+    }
+
+    /// Performs a read by trying to find the key in the memtables, from the active one to the
+    /// oldest, and falling back to the sstables, from the newest to the oldest, if not successful.
+    /// The first match wins, so a removed key is not looked up in older tables. Hot keys and keys
+    /// recently found to be absent are answered from the row and negative caches instead.
+    ///
+    /// Fails if a sstable can't be read, e.g. with [`Error::Corruption`], or with
+    /// [`Error::BackgroundError`] if a flush or compaction failed, unless reads are still served
+    /// then: see [`StorageBuilder::read_only_on_background_error`].
+    pub fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let options = ReadOptions {
+            verify_checksums: self.config.verify_checksums,
+        };
+
+        self.read_with(key, &options)
+    }
+
+    /// Performs a read like [`Storage::read`], with the given options.
+    pub fn read_with(&self, key: &str, options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_expiring(key, options)?.map(|(value, _)| value))
+    }
+
+    /// Performs a read like [`Storage::read_with`], and returns the value along with when it
+    /// expires, if it was inserted with a TTL.
+    fn read_expiring(&self, key: &str, options: &ReadOptions) -> Result<Option<Found>> {
+        self.read_found(key, options, false, self.config.now())
+    }
+
+    /// Performs a read like [`Storage::read`], and returns the value along with its checksum, the
+    /// CRC32 of the value as it was written, and the time left before it expires, if it was
+    /// inserted with a TTL. Values found in the memtables are checked against the
+    /// checksum computed when they were written, and entries read from sstables against theirs,
+    /// whatever the storage is configured to do, failing with [`Error::Corruption`] on a mismatch.
+    /// The row cache is neither read nor filled, as the copies it holds aren't checksummed.
+    pub fn read_verified(&self, key: &str) -> Result<Option<VerifiedValue>> {
+        let options = ReadOptions { verify_checksums: true };
+        let now = self.config.now();
+        let found = self.read_found(key, &options, true, now)?;
+
+        Ok(found.map(|(value, expires_at)| VerifiedValue {
+            checksum: crc32fast::hash(&value),
+            value,
+            ttl: expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))),
+        }))
+    }
+
+    /// Reads the key from the caches, memtables and sstables, in that order, as of the given time,
+    /// in milliseconds since the UNIX epoch. If `verified` is set, the values found in the
+    /// memtables are checked against their checksums, and the row cache is left alone.
+    ///
+    /// The caches and memtables are read with the engine locked, but the sstables of the version
+    /// current by then without it, so that other reads and writes go on while they are probed.
+    /// What they hold is only cached if nothing was written nor installed in the meantime, as
+    /// the caches would otherwise keep what a newer write replaced.
+    fn read_found(&self, key: &str, options: &ReadOptions, verified: bool, now: u64) -> Result<Option<Found>> {
+        let start = Instant::now();
+        let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        self.check_readable(&engine)?;
+
+        let found = match Storage::read_from_memory(&mut engine, key, verified, now) {
+            Ok(Some(found)) => Ok(found),
+            Ok(None) => {
+                let version = engine.version.clone();
+                let next_seqno = engine.active_memtable.next_seqno();
+                drop(engine);
+
+                let stored = Storage::lookup_sstables(&version, key, options);
+                engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+                let unchanged = Storage::unchanged_since(&engine, &version, next_seqno);
+                stored.map(|stored| match unchanged {
+                    true => Storage::remember(&mut engine, key, stored, !verified, now),
+                    false => Storage::live(stored, now),
+                })
+            }
+            Err(error) => Err(error),
+        };
+        if let Ok(found) = &found {
+            self.record_read(&mut engine, key, found.as_ref().map(|(value, _)| value.as_slice()));
+        }
+        stats::record(&mut engine.latencies.get, start.elapsed());
+
+        found
+    }
+
+    /// Looks the key up in the sstables of the version, from the newest to the oldest. Returns
+    /// what the newest sstable holding it stores for it.
+    fn lookup_sstables(version: &Version, key: &str, options: &ReadOptions) -> Result<Option<Stored>> {
+        for table in version.sstable_readers0.iter().rev() {
+            if let Some(stored) = table.lookup(key, options.verify_checksums)? {
+                return Ok(Some(stored));
+            }
+        }
+
+        match version.l1_reader_for(key) {
+            Some(table) => table.lookup(key, options.verify_checksums),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether nothing was written to the storage since the active memtable was to give the given
+    /// sequence number to its next write, and the given version is still the current one.
+    fn unchanged_since(engine: &Engine, version: &Arc<Version>, next_seqno: u64) -> bool {
+        Arc::ptr_eq(&engine.version, version) && engine.active_memtable.next_seqno() == next_seqno
+    }
+
+    /// Answers a read from the caches or the memtables, if the key is known to any of them. If
+    /// `verified` is set, the values of the memtables are checked against their checksums, and
+    /// the row cache is left alone.
+    fn read_from_memory(
+        engine: &mut Engine,
+        key: &str,
+        verified: bool,
+        now: u64,
+    ) -> Result<Option<Option<Found>>> {
+        if engine.absent_keys.contains(key) {
+            return Ok(Some(None));
+        }
+
+        if !verified {
+            if let Some(value) = engine.rows.get(key) {
+                return Ok(Some(Some((value, None))));
+            }
+        }
+
+        let mut stored = None;
+        let memtables = std::iter::once(&engine.active_memtable)
+            .chain(engine.version.memtables.iter().rev().map(|memtable| memtable.as_ref()));
+        for memtable in memtables {
+            stored = match verified {
+                true => memtable.lookup_verified(key)?,
+                false => memtable.lookup(key),
+            };
+
+            if stored.is_some() {
+                break;
+            }
+        }
+
+        Ok(stored.map(|stored| Storage::remember(engine, key, Some(stored), !verified, now)))
+    }
+
+    /// The value of what a read found, unless it expired by the given time, as
+    /// [`Storage::remember`] returns it, but without caching it.
+    fn live(stored: Option<Stored>, now: u64) -> Option<Found> {
+        match stored {
+            Some(Stored::Value(value)) => Some((value, None)),
+            Some(Stored::Expiring(value, expires_at)) if expires_at > now => Some((value, Some(expires_at))),
+            _ => None,
+        }
+    }
+
+    /// Caches what a read found for the key, in the row cache only if `cache_row` is set, and
+    /// returns its value, unless it expired by the given time. Values inserted with a TTL are
+    /// never cached, as the cache would outlive them.
+    fn remember(engine: &mut Engine, key: &str, stored: Option<Stored>, cache_row: bool, now: u64) -> Option<Found> {
+        match stored {
+            Some(Stored::Value(value)) => {
+                if cache_row {
+                    engine.rows.insert(key, &value);
+                }
+                Some((value, None))
+            }
+            Some(Stored::Expiring(value, expires_at)) if expires_at > now => Some((value, Some(expires_at))),
+            _ => {
+                engine.absent_keys.insert(key);
+                None
+            }
+        }
+    }
+
+    /// Reads many keys at once, returning their values in the same order. Keys missing from the
+    /// caches and memtables are looked up in the sstables by up to
+    /// [`StorageBuilder::read_parallelism`] threads, each probing its share of the sstables for
+    /// all of them, instead of probing one sstable after the other, or read together through
+    /// io_uring: see [`StorageBuilder::io_uring`]. As for a single read, the sstables are probed
+    /// without holding the engine, see [`Storage::read_found`].
+    pub fn multi_get(&self, keys: &[&str], options: &ReadOptions) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        self.check_readable(&engine)?;
+        let now = self.config.now();
+        let mut values = Vec::with_capacity(keys.len());
+        // The indexes of the keys to look up in the sstables.
+        let mut missing = Vec::new();
+
+        for (index, key) in keys.iter().enumerate() {
+            let value = Storage::read_from_memory(&mut engine, key, false, now)?;
+
+            if value.is_none() {
+                missing.push(index);
+            }
+            values.push(value.flatten().map(|(value, _)| value));
+        }
+
+        if !missing.is_empty() {
+            let version = engine.version.clone();
+            let next_seqno = engine.active_memtable.next_seqno();
+            drop(engine);
+
+            let missing_keys: Vec<&str> = missing.iter().map(|&index| keys[index]).collect();
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            let found = match &self.ring {
+                Some(ring) => Storage::lookup_through_ring(&version, &missing_keys, options, ring),
+                None => Storage::lookup_in_parallel(&version, &missing_keys, options, self.config.read_parallelism),
+            };
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            let found = Storage::lookup_in_parallel(&version, &missing_keys, options, self.config.read_parallelism);
+
+            engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+            let unchanged = Storage::unchanged_since(&engine, &version, next_seqno);
+            for (index, stored) in missing.into_iter().zip(found?) {
+                let found = match unchanged {
+                    true => Storage::remember(&mut engine, keys[index], stored, true, now),
+                    false => Storage::live(stored, now),
+                };
+                values[index] = found.map(|(value, _)| value);
+            }
+        }
+
+        for (key, value) in keys.iter().zip(&values) {
+            self.record_read(&mut engine, key, value.as_deref());
+        }
+
+        Ok(values)
+    }
+
+    /// Looks up the keys in every sstable, splitting the sstables among up to `parallelism`
+    /// threads. Returns what the newest sstable holding each key stores for it.
+    fn lookup_in_parallel(
+        version: &Version,
+        keys: &[&str],
+        options: &ReadOptions,
+        parallelism: usize,
+    ) -> Result<Vec<Option<Stored>>> {
+        let tables = version.readers_newest_first();
+
+        let probe = |tables: &[&SSTableReader]| -> Result<Vec<Vec<Option<Stored>>>> {
+            tables
+                .iter()
+                .map(|table| keys.iter().map(|key| table.lookup(key, options.verify_checksums)).collect())
+                .collect()
+        };
+
+        let share = tables.len().div_ceil(parallelism).max(1);
+        let found = if share >= tables.len() {
+            probe(&tables)?
+        } else {
+            thread::scope(|scope| {
+                let probes: Vec<_> = tables
+                    .chunks(share)
+                    .map(|tables| scope.spawn(move || probe(tables)))
+                    .collect();
+
+                probes
+                    .into_iter()
+                    .map(|probe| probe.join().unwrap())
+                    .collect::<Result<Vec<_>>>()
+            })?
+            .into_iter()
+            .flatten()
+            .collect()
+        };
+
+        let mut stored: Vec<Option<Stored>> = vec![None; keys.len()];
+        for table in found {
+            for (stored, found) in stored.iter_mut().zip(table) {
+                if stored.is_none() {
+                    *stored = found;
+                }
+            }
+        }
+
+        Ok(stored)
+    }
+
+    /// Looks up the keys in the indexes and filters of the sstables, and then reads the entries of
+    /// the newest sstable holding each key through the ring, all at once. Returns what the newest
+    /// sstable holding each key stores for it, as [`Storage::lookup_in_parallel`] does.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn lookup_through_ring(
+        version: &Version,
+        keys: &[&str],
+        options: &ReadOptions,
+        ring: &Ring,
+    ) -> Result<Vec<Option<Stored>>> {
+        let tables = version.readers_newest_first();
+        let mut stored: Vec<Option<Stored>> = vec![None; keys.len()];
+        // The index of each key read through the ring, along with the sstable holding it.
+        let mut found = Vec::new();
+        let mut reads = Vec::new();
+
+        for (index, key) in keys.iter().enumerate() {
+            for table in &tables {
+                let offset = match table.offset(key)? {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+
+                // Files whose environment doesn't expose their descriptor are read as usual.
+                match table.raw_fd() {
+                    Some(fd) => {
+                        found.push((index, *table));
+                        reads.push((fd, offset, ENTRY_READ_SIZE));
+                    }
+                    None => stored[index] = table.lookup(key, options.verify_checksums)?,
+                }
+                break;
+            }
+        }
+
+        for ((index, table), bytes) in found.into_iter().zip(ring.read(&reads)?) {
+            stored[index] = table.decode_lookup(keys[index], &bytes?, options.verify_checksums)?;
+        }
+
+        Ok(stored)
+    }
+
+    /// Deletes the storage whose sstables and WALs are kept in the given directory, using the
+    /// default environment. See [`StorageBuilder::destroy`].
+    pub fn destroy(path: &Path) -> Result<()> {
+        StorageBuilder::new()
+            .segments_path(path.to_path_buf())
+            .wal_path(path.to_path_buf())
+            .destroy()
+    }
+
+    /// Removes every key at once. The manifest records that everything stored so far is gone, and
+    /// the files that held it are deleted in the background. Iterators created before keep seeing
+    /// the storage as it was, and watchers aren't notified.
+    ///
+    /// Waits for the open writer, if any, to be dropped first.
+    pub fn clear(&self) -> Result<()> {
+        self.wait_for_writer().clear()
+    }
+
+    /// Replaces everything the storage holds with what the checkpoint in the given directory
+    /// holds, e.g. one written by another storage with [`Storage::checkpoint`]: clears the storage
+    /// as [`Storage::clear`] does, and then inserts the live entries of the checkpoint, along with
+    /// when they expire, through the writer it waited for. They are copied, so the directory can be
+    /// removed afterwards.
+    ///
+    /// A crash before this returns may leave the storage holding only part of the checkpoint.
+    pub fn restore_checkpoint(&self, dir: &Path) -> Result<()> {
+        let checkpoint = StorageBuilder::new()
+            .segments_path(dir.to_path_buf())
+            .wal_path(dir.to_path_buf())
+            .env(self.config.env.clone())
+            .clock(self.config.clock.clone())
+            .scheduling(Scheduling::Manual)
+            .build()?;
+
+        let mut writer = self.wait_for_writer();
+        writer.clear()?;
+
+        let mut entries = checkpoint.iter()?;
+        while let Some((key, value)) = entries.next_live()? {
+            match value {
+                Stored::Value(value) => writer.put(key, value, None)?,
+                Stored::Expiring(value, expires_at) => writer.put(key, value, Some(expires_at))?,
+                Stored::Tombstone => {}
+            }
+        }
+        drop(entries);
+
+        checkpoint.close()
+    }
+
+    /// Writes the entries buffered so far to the WAL. See [`StorageBuilder::wal_buffer_size`].
+    pub fn flush_wal(&self) -> Result<()> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).active_memtable.flush_wal()
+    }
+
+    /// Makes every write acknowledged so far durable, see [`Storage::sync`]. The files of the
+    /// storage are released once every clone is dropped too.
+    pub fn close(self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Makes every write acknowledged so far durable, waiting for the open writer, if any, to be
+    /// dropped first: writes the buffered entries to the WAL, and syncs the WALs of the memtables
+    /// that aren't persisted yet.
+    pub fn sync(&self) -> Result<()> {
+        let _writer = self.wait_for_writer();
+        let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+        engine.active_memtable.sync_wal()?;
+
+        // Frozen memtables are shared with the compactor, so their WALs are synced through a new
+        // handle. Those already persisted had their WALs removed.
+        for memtable in &engine.version.memtables {
+            match self.config.env.open(memtable.wal_path()) {
+                Ok(mut wal) => wal.sync()?,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the keys and values of the storage, in key order.
+    ///
+    /// The iterator sees the storage as it was when this returns, no matter what is written,
+    /// flushed or compacted while it is alive. Its entries are read lazily, but the sstables are
+    /// all opened upfront, and the memtables are kept in memory until it is dropped.
+    pub fn iter(&self) -> Result<StorageIterator> {
+        self.iter_from("")
+    }
+
+    /// Returns an iterator over the keys and values of the storage from the given key on, in key
+    /// order. See [`Storage::iter`].
+    pub fn iter_from(&self, start: &str) -> Result<StorageIterator> {
+        self.iter_at(start, None)
+    }
+
+    /// Returns an iterator over the keys starting with the given prefix, in key order, from the
+    /// first one after `after` on, if given. See [`Storage::iter`].
+    pub fn keys(&self, prefix: &str, after: Option<&str>) -> Result<impl Iterator<Item = Result<String>>> {
+        let start = match after {
+            // The smallest key after the given one.
+            Some(after) if after >= prefix => format!("{}\0", after),
+            _ => prefix.to_owned(),
+        };
+        let prefix = prefix.to_owned();
+
+        let keys = self
+            .iter_from(&start)?
+            .map(|entry| entry.map(|(key, _)| key))
+            .take_while(move |key| key.as_ref().map_or(true, |key| key.starts_with(&prefix)));
+
+        Ok(keys)
+    }
+
+    /// Reads up to `limit` entries in key order, from the given key on, or from where the scan
+    /// that returned the continuation token left off, if one is given.
+    ///
+    /// The pages of a scan see the keys as they were when its first page was read, as long as
+    /// their older versions are still around: a key overwritten in the same memtable since, or
+    /// whose versions were merged by a compaction, is yielded as it is now, and so are the keys
+    /// inserted since. Pages never repeat a key, nor leave out one that was there all along.
+    ///
+    /// Fails with [`Error::InvalidContinuation`] if the token wasn't returned by a scan.
+    pub fn scan(&self, start: &str, limit: usize, continuation: Option<&str>) -> Result<ScanPage> {
+        let options = ScanOptions {
+            max_keys: Some(limit),
+            ..ScanOptions::default()
+        };
+
+        self.scan_with(start, continuation, &options)
+    }
+
+    /// Reads a page of entries like [`Storage::scan`], ending it once any of the limits of the
+    /// options is reached, with a continuation token if there are entries left. The byte and time
+    /// limits never leave a page empty, so that a scan always makes progress.
+    pub fn scan_with(&self, start: &str, continuation: Option<&str>, options: &ScanOptions) -> Result<ScanPage> {
+        let mut iter = match continuation {
+            Some(token) => {
+                let continuation = Continuation::decode(token)?;
+                // The smallest key after the last one.
+                let start = format!("{}\0", continuation.last_key);
+                self.iter_at(&start, Some(continuation.snapshot))?
+            }
+            None => self.iter_at(start, None)?,
+        };
+
+        let max_keys = options.max_keys.unwrap_or(usize::MAX);
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+
+        while entries.len() < max_keys {
+            let (key, value) = match iter.next() {
+                Some(entry) => entry?,
+                None => break,
+            };
+
+            bytes += key.len() + value.len();
+            entries.push((key, value));
+
+            let out_of_bytes = options.max_bytes.is_some_and(|max_bytes| bytes >= max_bytes);
+            let out_of_time = options.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if out_of_bytes || out_of_time {
+                break;
+            }
+        }
+
+        let continuation = match entries.last() {
+            Some((last_key, _)) if iter.next().is_some() => Some(Continuation {
+                last_key: last_key.clone(),
+                snapshot: iter.snapshot(),
+            }),
+            _ => None,
+        };
+
+        Ok(ScanPage {
+            entries,
+            continuation: continuation.as_ref().map(Continuation::encode),
+        })
+    }
+
+    /// Returns an iterator from the given key on, seeing the writes up to the given sequence
+    /// number, or all of them.
+    fn iter_at(&self, start: &str, snapshot: Option<u64>) -> Result<StorageIterator> {
+        let verify_checksums = self.config.verify_checksums;
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        self.check_readable(&engine)?;
+        let snapshot = snapshot.unwrap_or_else(|| engine.active_memtable.next_seqno().saturating_sub(1));
+
+        // Sstables are read from the first entry not smaller than the start, and left out if
+        // there is none.
+        let open = |sstable: &SSTable, reader: &SSTableReader, level| -> Result<Option<_>> {
+            match reader.seek(start)? {
+                Some(offset) => {
+                    let options = self.config.table_options(level);
+                    Ok(Some(sstable.block_reader(&options, offset, self.prefetcher.clone())?))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let mut sources = vec![Source::memtable(engine.active_memtable.snapshot(), start)];
+        sources.extend(engine.version.memtables.iter().rev().map(|memtable| Source::memtable(memtable.snapshot(), start)));
+
+        for (sstable, reader) in engine.version.sstables0.iter().zip(&engine.version.sstable_readers0).rev() {
+            if let Some(input) = open(sstable, reader, 0)? {
+                sources.push(Source::sstables(vec![input], verify_checksums));
+            }
+        }
+
+        let mut inputs = Vec::new();
+        for (sstable, reader) in engine.version.sstables1.iter().zip(&engine.version.sstable_readers1) {
+            inputs.extend(open(sstable, reader, 1)?);
+        }
+        sources.push(Source::sstables(inputs, verify_checksums));
+        let version = engine.version.clone();
+        drop(engine);
+
+        StorageIterator::new(sources, snapshot, self.config.now(), version)
+    }
+
+    /// Opens the storage for writing. Only one writer may be open at a time: fails with
+    /// [`Error::WriterBusy`] if another one is, rather than waiting for it to be dropped. Reads are
+    /// not blocked by the writer.
+    pub fn open_as_writer(&self) -> Result<StorageWriter<'_>> {
+        let state = match self.writer.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::WouldBlock) => return Err(Error::WriterBusy.into()),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        };
+
+        Ok(StorageWriter { storage: self, state })
+    }
+
+    /// Opens the storage for writing, waiting for the writer that is open, if any, to be dropped.
+    ///
+    /// Deadlocks if the calling thread holds the open writer.
+    pub fn wait_for_writer(&self) -> StorageWriter<'_> {
+        let state = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        StorageWriter { storage: self, state }
+    }
+
+    /// Inserts a value through a writer opened for this write alone, waiting for the open one to
+    /// be dropped first. See [`StorageWriter::insert`].
+    pub fn insert(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.wait_for_writer().insert(key, value)
+    }
+
+    /// Inserts a value that expires once the given TTL elapses through a writer opened for this
+    /// write alone, waiting for the open one to be dropped first. See
+    /// [`StorageWriter::insert_with_ttl`].
+    pub fn insert_with_ttl(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.wait_for_writer().insert_with_ttl(key, value, ttl)
+    }
+
+    /// Removes a key through a writer opened for this write alone, waiting for the open one to be
+    /// dropped first. See [`StorageWriter::remove`].
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.wait_for_writer().remove(key)
+    }
+
+    /// Updates a key through a writer opened for this update alone, waiting for the open one to be
+    /// dropped first. See [`StorageWriter::update`].
+    pub fn update(
+        &self,
+        key: String,
+        update: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.wait_for_writer().update(key, update)
+    }
+
+    /// Inserts many values through a writer opened for them alone, waiting for the open one to be
+    /// dropped first. See [`StorageWriter::insert_batch`].
+    pub fn insert_batch(&self, entries: impl IntoIterator<Item = (String, Vec<u8>)>) -> Result<()> {
+        self.wait_for_writer().insert_batch(entries)
+    }
+
+    /// Removes many keys through a writer opened for them alone, waiting for the open one to be
+    /// dropped first. See [`StorageWriter::remove_batch`].
+    pub fn remove_batch(&self, keys: impl IntoIterator<Item = String>) -> Result<()> {
+        self.wait_for_writer().remove_batch(keys)
+    }
+
+    /// Applies a write replicated from a leader through a writer opened for this write alone,
+    /// waiting for the open one to be dropped first. See [`StorageWriter::apply`].
+    pub fn apply(&self, change: Change) -> Result<()> {
+        self.wait_for_writer().apply(change)
+    }
+
+    /// Runs the background work scheduled so far, e.g. persisting frozen memtables, in the calling
+    /// thread. Returns how many jobs ran.
+    ///
+    /// Only available when the storage is built with [`Scheduling::Manual`].
+    pub fn tick(&self) -> Result<usize> {
+        match &self.compactor {
+            Background::Manual(compactor) => compactor.lock().unwrap_or_else(PoisonError::into_inner).run_pending(),
+            Background::Thread => bail!("background work only runs on tick with manual scheduling"),
+        }
+    }
+
+    /// Freezes the active memtable, unless it is empty, and schedules its persistence as if it had
+    /// reached the threshold. Waits for the open writer, if any, to be dropped first, and for
+    /// frozen memtables to be persisted as writes do. See [`StorageBuilder::max_frozen_memtables`].
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = self.wait_for_writer();
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+
+        if engine.active_memtable.is_empty() {
+            return Ok(());
+        }
+        let mut engine = self.wait_for_flushes(engine)?;
+
+        Storage::replace_memtable(&self.persistence_sender, &mut writer.state.sequence_number, &mut engine, &self.config)
+    }
+
+    /// Compacts L0 into L1 in the calling thread, without waiting for the compaction to be due.
+    pub fn compact(&self) -> Result<()> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).check_background_error()?;
+
+        trigger_l0_compaction(self.engine.clone(), &self.config)
+            .map_err(|error| self.engine.lock().unwrap_or_else(PoisonError::into_inner).record_background_error(error))?;
+
+        Ok(())
+    }
+
+    /// Erases the values removed from the keys in the given range from the files of the storage
+    /// now, rather than once compactions get to them, e.g. to honour a request to erase someone's
+    /// data. Returns how many sstables were rewritten, how many bytes that reclaimed, and how many
+    /// archived WALs were removed.
+    ///
+    /// The memtables are persisted first, waiting for the ones frozen by then as [`Storage::flush`]
+    /// and running them in the calling thread with [`Scheduling::Manual`], so that their WALs are
+    /// retired. Memtables frozen by concurrent writes meanwhile aren't waited for. Then the L0
+    /// sstables overlapping the range are compacted into the L1 sstables they overlap, along with
+    /// the older L0 sstables sharing keys with them, which drops their tombstones along with the
+    /// values they remove, as L1 is the last level. Compactions leave no tombstones in L1, so no
+    /// other sstable holds removed values. The replaced sstables are deleted once no iterator
+    /// reads them anymore.
+    ///
+    /// If [`StorageBuilder::wal_archive_size`] is set, the archived WALs holding writes to the
+    /// range are removed as well, so tails reading them may miss their changes, see
+    /// [`WalTail`]. The checkpoints written before still hold the removed
+    /// values, as they link to the replaced sstables and copy the WALs, see
+    /// [`Storage::checkpoint`].
+    ///
+    /// The versions of the keys of a [`VersionedStorage`](crate::versioned::VersionedStorage)
+    /// that its retention keeps are kept.
+    pub fn purge_deleted<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<PurgeReport> {
+        self.flush()?;
+
+        let engine = match &self.compactor {
+            Background::Manual(_) => {
+                self.tick()?;
+                self.engine.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+            Background::Thread => {
+                let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+                // Memtables are persisted in the order they were frozen, so the newest one is the
+                // last to go.
+                let newest = engine.version.memtables.last().cloned();
+                let frozen = |engine: &Engine| {
+                    newest.as_ref().is_some_and(|newest| {
+                        engine.version.memtables.iter().any(|memtable| Arc::ptr_eq(memtable, newest))
+                    })
+                };
+                while frozen(&engine) {
+                    engine.check_background_error()?;
+                    let flushed = engine.flushed.clone();
+                    engine = flushed.wait(engine).unwrap_or_else(PoisonError::into_inner);
+                }
+
+                engine
+            }
+        };
+        engine.check_background_error()?;
+
+        let overlaps = |reader: &SSTableReader| match reader.first_key().zip(reader.last_key()) {
+            Some((first, last)) => {
+                let after_start = match range.start_bound() {
+                    Bound::Included(start) => last >= *start,
+                    Bound::Excluded(start) => last > *start,
+                    Bound::Unbounded => true,
+                };
+                let before_end = match range.end_bound() {
+                    Bound::Included(end) => first <= *end,
+                    Bound::Excluded(end) => first < *end,
+                    Bound::Unbounded => true,
+                };
+
+                after_start && before_end
+            }
+            None => false,
+        };
+        let overlapping = engine.version.sstable_readers0.iter().any(|reader| overlaps(reader));
+        drop(engine);
+
+        let mut report = PurgeReport::default();
+        if overlapping {
+            let compacted = compactor::compact_l0(self.engine.clone(), &self.config, overlaps)
+                .map_err(|error| self.engine.lock().unwrap_or_else(PoisonError::into_inner).record_background_error(error))?;
+
+            report.sstables_rewritten = compacted.inputs;
+            report.bytes_reclaimed = compacted.input_bytes.saturating_sub(compacted.output_bytes);
+        }
+
+        if self.config.wal_archive_size.is_some() {
+            let env = self.config.env.as_ref();
+            for (_, path) in wal::archived(env, &self.config.wal_path)? {
+                if !wal::holds_write(env, &path, |key| range.contains(&key))? {
+                    continue;
+                }
+
+                match self.config.remove_obsolete(&path) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                    _ => report.wals_removed += 1,
+                }
+            }
+            env.sync_dir(&self.config.wal_path)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Removes the keys whose TTL is past now, found through an index of the keys inserted with a
+    /// TTL, rather than leaving their values to compactions. Returns how many were removed.
+    ///
+    /// The index is rebuilt from the sstables and memtables when the storage is opened. The keys
+    /// are removed by a batch of tombstones, which compactions then drop along with the values.
+    /// Expired keys read as absent whether they were purged or not, so their watchers aren't
+    /// notified. See [`StorageBuilder::expiry_purge_interval`] to purge periodically.
+    pub fn purge_expired(&self) -> Result<usize> {
+        expiry::purge_expired(&self.engine, &self.config)
+    }
+
+    /// Ingests a sstable written by another storage, e.g. one where historical data was
+    /// backfilled, behind everything this one holds: its entries are only read for the keys that
+    /// have no other. It goes straight into L1, merged only with the L1 sstables whose key range
+    /// overlaps its own, so neither L0 nor the rest of L1 are compacted. The file is left as
+    /// is, as its entries are copied, and fails to ingest if it doesn't verify.
+    ///
+    /// The key watchers aren't notified of the ingested keys.
+    pub fn ingest_behind(&self, path: &Path) -> Result<()> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).check_background_error()?;
+
+        compactor::ingest_behind(&self.engine, &self.config, path)
+    }
+
+    /// Writes a copy of the storage as it is now into the given directory, which must be empty or
+    /// missing. The copy opens as a storage whose sstables and WALs are both kept there.
+    ///
+    /// The sstables are hard-linked, as they never change once written, so the directory must be
+    /// on the same file system. The WALs of the memtables are copied, along with the entries
+    /// buffered for them.
+    pub fn checkpoint(&self, dir: &Path) -> Result<()> {
+        self.write_checkpoint(dir).map(|_| ())
+    }
+
+    /// Writes a checkpoint like [`Storage::checkpoint`], returning the sequence number of the last
+    /// write it holds.
+    fn write_checkpoint(&self, dir: &Path) -> Result<u64> {
+        let env = &self.config.env;
+
+        env.create_dir_all(dir)?;
+        if !env.read_dir(dir)?.is_empty() {
+            bail!("{} is not empty", dir.display());
+        }
+
+        // Only what the checkpoint holds is collected while the engine is held, so that writes go
+        // on while the files are copied. The WALs are linked right away, as a flush could remove
+        // them meanwhile, while the sstables stay as long as the copy of the version holds them.
+        let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.active_memtable.flush_wal()?;
+        let seqno = engine.active_memtable.next_seqno() - 1;
+        let version = engine.version.clone();
+
+        let mut wals = Vec::new();
+        let memtables = std::iter::once(&engine.active_memtable)
+            .chain(engine.version.memtables.iter().map(|memtable| memtable.as_ref()));
+        for memtable in memtables {
+            let name = memtable.wal_path().file_name().unwrap();
+            let link = dir.join(format!("{}.link", name.to_string_lossy()));
+            env.link(memtable.wal_path(), &link)?;
+
+            wals.push((link, dir.join(name), memtable.wal_len()));
+        }
+        drop(engine);
+
+        let mut edits = Vec::new();
+        for (level, sstables) in [&version.sstables0, &version.sstables1].into_iter().enumerate() {
+            for sstable in sstables {
+                let name = sstable.path().file_name().unwrap();
+                env.link(sstable.path(), &dir.join(name))?;
+
+                let name = name.to_string_lossy().into_owned();
+                edits.push(Edit::AddTable { name, level });
+            }
+        }
+        drop(version);
+
+        // The active WAL keeps growing through its link, so only the entries written by the time
+        // of the checkpoint are copied.
+        for (link, path, len) in wals {
+            let mut output = env.create(&path)?;
+
+            io::copy(&mut io::Read::take(env.open(&link)?, len), &mut output)?;
+            output.sync()?;
+            env.remove_file(&link)?;
+        }
+
+        self.identity.write(env.as_ref(), dir)?;
+        Manifest::create(env.as_ref(), &dir.join(MANIFEST_NAME), &edits)?;
+        env.sync_dir(dir)?;
+
+        Ok(seqno)
+    }
+
+    /// Backs the storage up to the location the URL names: `s3://bucket/path`, `gs://bucket/path`
+    /// or `file:///path`. The credentials are taken from the environment, e.g.
+    /// `AWS_ACCESS_KEY_ID` or `GOOGLE_SERVICE_ACCOUNT`.
+    ///
+    /// A backup uploads a [checkpoint](Storage::checkpoint) of the storage, except for the
+    /// sstables that an earlier backup at the same location uploaded already, and ends with a
+    /// description of itself. Backups missing it are ignored when restoring. The backup is
+    /// encrypted with the current key of the options, if they provide keys.
+    #[cfg(feature = "remote-backup")]
+    pub async fn backup_to_remote(
+        &self,
+        url: &str,
+        options: &crate::backup::BackupOptions,
+    ) -> Result<crate::backup::BackupManifest> {
+        crate::backup::backup(self, url, options).await
+    }
+
+    /// Downloads the latest backup at the location the URL names into the given directory, which
+    /// must be empty or missing. The directory opens as a storage whose sstables and WALs are
+    /// both kept there, as a checkpoint does. Fails if the backup is encrypted with a key the
+    /// options can't provide. See [`Storage::backup_to_remote`].
+    #[cfg(feature = "remote-backup")]
+    pub async fn restore_from_remote(
+        url: &str,
+        dir: &Path,
+        options: &crate::backup::BackupOptions,
+    ) -> Result<crate::backup::BackupManifest> {
+        crate::backup::restore(url, dir, options).await
+    }
+
+    /// Checks the latest backup at the location the URL names without restoring it: that every
+    /// object it is made of is there and matches its checksum. Fails if there is no backup, or if
+    /// it is encrypted with a key the options can't provide. See [`Storage::backup_to_remote`].
+    #[cfg(feature = "remote-backup")]
+    pub async fn verify_backup(
+        url: &str,
+        options: &crate::backup::BackupOptions,
+    ) -> Result<crate::backup::BackupReport> {
+        crate::backup::verify(url, options).await
+    }
+
+    /// Exports the keys in the given range, as they are now, into standalone sstables inside the
+    /// given directory, which must be empty or missing. Returns their paths, in key order.
+    ///
+    /// The exported sstables don't belong to any storage: they describe themselves in a footer,
+    /// and are read with an [`ExportReader`](crate::export::ExportReader), e.g. by offline
+    /// analytics. They hold the newest value of each key, with the removed keys left out, split at
+    /// the target file size and compressed with the codec of L1.
+    pub fn export_sst<'a>(&self, dir: &Path, range: impl RangeBounds<&'a str>) -> Result<Vec<PathBuf>> {
+        let env = &self.config.env;
+
+        env.create_dir_all(dir)?;
+        if !env.read_dir(dir)?.is_empty() {
+            bail!("{} is not empty", dir.display());
+        }
+
+        let iter = self.iter_bounded(range.start_bound())?;
+        let snapshot = iter.snapshot();
+        let entries = iter.take_while(|entry| before_end(entry, range.end_bound()));
+
+        export::write(
+            env.as_ref(),
+            dir,
+            entries,
+            snapshot,
+            self.config.compression(1),
+            self.config.target_file_size,
+        )
+    }
+
+    /// Counts the keys in the given range, as they are now.
+    ///
+    /// When the storage holds nothing but L1, whose sstables have no overlapping keys, and these
+    /// have neither tombstones nor expired values, the keys are counted from the indexes of the
+    /// sstables without reading any entry. Otherwise, the keys are read as by
+    /// [`Storage::aggregate`].
+    pub fn count<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<u64> {
+        {
+            let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+            self.check_readable(&engine)?;
+
+            let now = self.config.now();
+            let only_l1 = engine.active_memtable.is_empty()
+                && engine.version.memtables.is_empty()
+                && engine.version.sstables0.is_empty()
+                && engine.version.sstable_readers1.iter().all(|reader| {
+                    reader.tombstones() == 0 && reader.expiring().all(|(expires_at, _)| expires_at > now)
+                });
+
+            if only_l1 {
+                let counts = engine.version.sstable_readers1.iter().map(|reader| reader.count_in(&range));
+                return counts.map(|count| Ok(count? as u64)).sum();
+            }
+        }
+
+        Ok(self.aggregate(range)?.count)
+    }
+
+    /// Counts the keys in the given range, and finds the smallest and largest of them and the bytes
+    /// of their values, all over a snapshot of the storage as it is now. Every key in the range
+    /// is read, but nothing is kept in memory beyond the current one.
+    pub fn aggregate<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Aggregate> {
+        let mut aggregate = Aggregate::default();
+
+        let iter = self.iter_bounded(range.start_bound())?;
+        for entry in iter.take_while(|entry| before_end(entry, range.end_bound())) {
+            let (key, value) = entry?;
+
+            aggregate.count += 1;
+            aggregate.value_bytes += value.len() as u64;
+            if aggregate.min_key.is_none() {
+                aggregate.min_key = Some(key.clone());
+            }
+            aggregate.max_key = Some(key);
+        }
+
+        Ok(aggregate)
+    }
+
+    /// Returns an iterator from the given start bound on. See [`Storage::iter`].
+    fn iter_bounded(&self, start: Bound<&&str>) -> Result<StorageIterator> {
+        match start {
+            Bound::Included(start) => self.iter_from(start),
+            // The smallest key after the start.
+            Bound::Excluded(start) => self.iter_from(&format!("{}\0", start)),
+            Bound::Unbounded => self.iter(),
+        }
+    }
+
+    /// Describes the sstables of each level: L0, from the oldest to the newest sstable, followed
+    /// by L1, ordered by key range.
+    pub fn levels(&self) -> Result<Vec<Vec<TableInfo>>> {
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let levels: Vec<Vec<_>> = [
+            (&engine.version.sstables0, &engine.version.sstable_readers0),
+            (&engine.version.sstables1, &engine.version.sstable_readers1),
+        ]
+        .into_iter()
+        .map(|(sstables, readers)| {
+            sstables
+                .iter()
+                .zip(readers)
+                .map(|(sstable, reader)| {
+                    let info = TableInfo {
+                        path: sstable.path().to_path_buf(),
+                        size: 0,
+                        entries: reader.len(),
+                        tombstones: reader.tombstones(),
+                        first_key: reader.first_key().map(str::to_owned),
+                        last_key: reader.last_key().map(str::to_owned),
+                        metadata: reader.metadata().cloned(),
+                        filter: reader.filter(),
+                        partitioned_index: reader.partitioned_index(),
+                    };
+
+                    (sstable.clone(), info)
+                })
+                .collect()
+        })
+        .collect();
+        drop(engine);
+
+        levels
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(|(sstable, info)| Ok(TableInfo { size: sstable.size()?, ..info }))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Reports which SSTables the next compaction would pick and what it would cost, without
+    /// executing it. Returns `None` when there is nothing to compact.
+    pub fn plan_compaction(&self) -> Result<Option<CompactionPlan>> {
+        let version = self.engine.lock().unwrap_or_else(PoisonError::into_inner).version.clone();
+
+        plan_l0_compaction(&version.sstables0, &version.sstables1[version.l1_compaction_inputs()])
+    }
+
+    /// Returns the `n` keys read the most since the storage was opened, from the hottest on, among
+    /// those tracked. See [`StorageBuilder::hot_keys`].
+    pub fn top_keys(&self, n: usize) -> Vec<HotKey> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).hot_keys.top(n)
+    }
+
+    /// What checking the storage found when it was opened, if
+    /// [`StorageBuilder::verify_on_open`] asked for it.
+    pub fn verify_report(&self) -> Option<&VerifyReport> {
+        self.verify_report.as_deref()
+    }
+
+    /// What opening the storage recovered: the writes replayed from the WALs and what was lost
+    /// to corrupt entries, the sstables loaded and those quarantined.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// The threshold of the memtables and the L0 compaction trigger in use, which differ from the
+    /// configured ones if the storage tunes itself. See [`StorageBuilder::auto_tune`].
+    pub fn tuning(&self) -> Tuning {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).tuning
+    }
+
+    /// The identity of the storage, which its checkpoints share.
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Reports how many bytes were written since the storage was opened, how much space the
+    /// sstables take and how long the operations took.
+    pub fn stats(&self) -> Result<Stats> {
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        let bytes_written = engine.bytes_written;
+        let sstables0 = engine.version.sstables0.clone();
+        let sstables1 = engine.version.sstables1.clone();
+        let get = (&engine.latencies.get).into();
+        let put = (&engine.latencies.put).into();
+        let flush = (&engine.latencies.flush).into();
+        let compaction = (&engine.latencies.compaction).into();
+        let dropped_versions = engine.dropped_versions;
+        let tenants = engine.tenants.iter().map(|(tenant, stats)| (tenant.clone(), *stats)).collect();
+        let background_error = engine.background_error.as_ref().map(ToString::to_string);
+        let frozen_memtables = engine.version.memtables.len();
+        drop(engine);
+
+        let write_throttle = self.write_limiter.as_ref().map_or(Duration::ZERO, |limiter| limiter.delayed());
+
+        let l0_bytes = sstables0.iter().map(SSTable::size).sum::<Result<u64>>()?;
+        let last_level_bytes = sstables1.iter().map(SSTable::size).sum::<Result<u64>>()?;
+
+        Ok(Stats {
+            user_bytes_written: bytes_written.user,
+            flush_bytes_written: bytes_written.flush,
+            compaction_bytes_written: bytes_written.compaction,
+            frozen_memtables,
+            write_throttle,
+            sstable_bytes: l0_bytes + last_level_bytes,
+            last_level_bytes,
+            get,
+            put,
+            flush,
+            compaction,
+            dropped_versions,
+            tenants,
+            background_error,
+            tasks: self.supervisor.status(),
+        })
+    }
+
+    /// Returns the value of a property of the storage, or `None` if there is no property with the
+    /// given name. See [`PROPERTIES`] for the names.
+    pub fn property(&self, name: &str) -> Result<Option<String>> {
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let value = match name {
+            "num-files-at-level0" => engine.version.sstables0.len().to_string(),
+            "num-files-at-level1" => engine.version.sstables1.len().to_string(),
+            "num-immutable-memtables" => engine.version.memtables.len().to_string(),
+            "num-entries-active-memtable" => engine.active_memtable.len().to_string(),
+            "estimated-memtable-bytes" => std::iter::once(&engine.active_memtable)
+                .chain(engine.version.memtables.iter().map(|memtable| memtable.as_ref()))
+                .map(MemTable::allocated_bytes)
+                .sum::<usize>()
+                .to_string(),
+            "is-compaction-pending" => u8::from(!engine.version.sstables0.is_empty()).to_string(),
+            "total-sstable-bytes" => {
+                let sstables: Vec<SSTable> = engine.version.sstables0.iter().chain(&engine.version.sstables1).cloned().collect();
+                drop(engine);
+
+                sstables.iter().map(SSTable::size).sum::<Result<u64>>()?.to_string()
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(value))
+    }
+
+    /// Returns a stream that yields the new value of the key every time it is updated, or `None`
+    /// when it is removed.
+    ///
+    /// A watcher that falls too far behind skips the updates it missed.
+    pub fn watch(&self, key: &str) -> impl Stream<Item = Option<Vec<u8>>> + Send + Unpin {
+        BroadcastStream::new(self.watchers.subscribe(key)).filter_map(|update| update.ok())
+    }
+
+    /// Returns a stream of the changes committed to the keys starting with the given prefix from
+    /// now on, in the order they were committed, each along with its sequence number.
+    ///
+    /// If `after` is given, the changes after that sequence number that the memtables still hold
+    /// are replayed first, so that a subscriber can resume where it left off. Only the last change
+    /// of each key is, as the memtables keep no other. Fails with [`Error::ChangesUnavailable`]
+    /// if some of the changes were flushed already. A subscriber that falls too far behind gets
+    /// [`Error::ChangesLost`], and goes on with the changes from then on.
+    ///
+    /// Replication followers catch up through [`Storage::catch_up`] instead, which resumes from
+    /// every write the WALs still hold, the archived ones included.
+    ///
+    /// Clearing the storage and ingesting sstables aren't changes that subscribers get.
+    pub fn subscribe(
+        &self,
+        prefix: &str,
+        after: Option<u64>,
+    ) -> Result<impl Stream<Item = Result<Change>> + Send + Unpin> {
+        // The writes after the memtables are read can't be missed, as they wait for the engine.
+        let engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        let receiver = self.watchers.subscribe_changes();
+
+        let mut replayed = Vec::new();
+        if let Some(after) = after {
+            let memtables: Vec<&MemTable> = engine
+                .version
+                .memtables
+                .iter()
+                .map(|memtable| memtable.as_ref())
+                .chain([&engine.active_memtable])
+                .collect();
+
+            let oldest = memtables
+                .iter()
+                .filter_map(|memtable| memtable.first_seqno())
+                .min()
+                .unwrap_or_else(|| engine.active_memtable.next_seqno());
+            if after.saturating_add(1) < oldest {
+                return Err(Error::ChangesUnavailable { after }.into());
+            }
+
+            // From the oldest memtable to the newest, so that the last change of each key wins.
+            let mut latest = BTreeMap::new();
+            for memtable in memtables {
+                let entries = memtable.snapshot();
+                let from = Bound::Included(prefix.as_bytes());
+
+                for (key, record) in entries.range::<[u8], _>((from, Bound::Unbounded)) {
+                    if !key.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+
+                    let change = Change {
+                        key: String::from_utf8(key.to_vec())?,
+                        value: record.value.as_ref().map(|value| value.to_vec()),
+                        seqno: record.seqno,
+                        expires_at: record.expires_at,
+                    };
+                    latest.insert(change.key.clone(), change);
+                }
+            }
+
+            replayed = latest.into_values().filter(|change| change.seqno > after).collect();
+            replayed.sort_by_key(|change| change.seqno);
+        }
+        drop(engine);
+
+        let prefix = prefix.to_owned();
+        let live = BroadcastStream::new(receiver).filter_map(move |change| match change {
+            Ok(change) if change.key.starts_with(&prefix) => Some(Ok(change)),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Error::ChangesLost { missed }.into())),
+        });
+
+        Ok(tokio_stream::iter(replayed.into_iter().map(Ok)).chain(live))
+    }
+
+    /// Follows the writes appended to the WALs after the given sequence number, or from now on
+    /// if `after` is `None`, across the WALs of every memtable. See [`WalTail`].
+    ///
+    /// Unlike a subscription, the tail yields every write, rather than the last one of each key,
+    /// but only once it reached the WAL: see [`StorageBuilder::wal_buffer_size`].
+    pub fn tail_wal(&self, after: Option<u64>) -> WalTail {
+        let after = after.unwrap_or_else(|| self.engine.lock().unwrap_or_else(PoisonError::into_inner).active_memtable.next_seqno() - 1);
+
+        Wal::new(&self.config.env, &self.config.wal_path).tail(after)
+    }
+
+    /// The sequence number of the last write, or 0 if there was none yet. A replication follower
+    /// presents it to catch up with its leader: see [`Storage::catch_up`].
+    pub fn last_seqno(&self) -> u64 {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).active_memtable.next_seqno() - 1
+    }
+
+    /// Tells a replication follower whose last write is the given one how to catch up with this
+    /// storage, its leader: by resuming from the writes right after it, which the WALs still hold,
+    /// archived or not, or else from a checkpoint written into the given directory, which must be
+    /// empty or missing. The follower applies the writes through [`StorageWriter::apply`].
+    ///
+    /// A checkpoint is also written if the follower is ahead of the leader, as it diverged. The
+    /// WALs only hold writes, so clearing the storage and ingesting sstables only reach the
+    /// followers that catch up from a later checkpoint. See [`StorageBuilder::wal_archive_size`].
+    pub fn catch_up(&self, after: u64, dir: &Path) -> Result<CatchUp> {
+        let last_seqno = {
+            let mut engine = self.engine.lock().unwrap_or_else(PoisonError::into_inner);
+            engine.active_memtable.flush_wal()?;
+            engine.active_memtable.next_seqno() - 1
+        };
+
+        if after <= last_seqno {
+            let mut tail = Wal::new(&self.config.env, &self.config.wal_path).tail(after);
+            match tail.peek() {
+                Ok(Some(_)) => return Ok(CatchUp::Resume(Box::new(tail))),
+                Ok(None) if after == last_seqno => return Ok(CatchUp::Resume(Box::new(tail))),
+                // The writes after the given one were all removed along with their WALs.
+                Ok(None) => {}
+                Err(error) if matches!(error.downcast_ref(), Some(Error::ChangesUnavailable { .. })) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(CatchUp::Checkpoint {
+            seqno: self.write_checkpoint(dir)?,
+        })
+    }
+
+    /// The memtables that are frozen, waiting to be flushed, from the oldest to the newest. Nothing
+    /// is written to them anymore.
+    pub fn frozen_memtables(&self) -> Vec<Arc<MemTable>> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).version.memtables.clone()
+    }
+
+    /// Counts a read of the key towards the hot keys, and towards its tenant, if it belongs to one.
+    fn record_read(&self, engine: &mut Engine, key: &str, value: Option<&[u8]>) {
+        engine.hot_keys.record(key);
+
+        if let Some(tenant) = self.config.tenant(key) {
+            let stats = engine.tenant_stats(tenant);
+            stats.reads += 1;
+            stats.read_bytes += value.map_or(0, |value| value.len() as u64);
+        }
+    }
+
+    /// Counts a write of the given bytes of the key towards its tenant, if it belongs to one.
+    fn record_write(&self, engine: &mut Engine, key: &str, bytes: usize) {
+        if let Some(tenant) = self.config.tenant(key) {
+            let stats = engine.tenant_stats(tenant);
+            stats.writes += 1;
+            stats.write_bytes += bytes as u64;
+        }
+    }
+
+    /// Fails with the error of the flush or compaction that failed, if any did, unless reads are
+    /// still served then. See [`StorageBuilder::read_only_on_background_error`].
+    fn check_readable(&self, engine: &Engine) -> Result<()> {
+        if self.config.read_only_on_background_error {
+            return Ok(());
+        }
+
+        engine.check_background_error()
+    }
+
+    /// Fails with [`Error::QuotaExceeded`] if the key is over the quota of a prefix, as an insert
+    /// of it would.
+    #[cfg(feature = "raft")]
+    pub(crate) fn check_quotas(&self, key: &str) -> Result<()> {
+        self.engine.lock().unwrap_or_else(PoisonError::into_inner).check_quotas(key, &self.config.quotas)
+    }
+
+    /// Waits until a write of the given number of bytes and keys is let through by the write rate
+    /// limits, if any. See [`StorageBuilder::write_bytes_per_sec`].
+    fn throttle(&self, bytes: usize, ops: usize) {
+        if let Some(limiter) = &self.write_limiter {
+            limiter.acquire(bytes as u64, ops as u64);
+        }
+    }
+
+    /// Waits while as many memtables as allowed are frozen, until one is persisted. Fails with the
+    /// error of the background work if it fails meanwhile, and with [`Error::WriteStalled`]
+    /// rather than waiting with [`Scheduling::Manual`], as nothing would persist them.
+    fn wait_for_flushes<'a>(&self, mut engine: MutexGuard<'a, Engine>) -> Result<MutexGuard<'a, Engine>> {
+        let max = match self.config.max_frozen_memtables {
+            Some(max) => max.max(1),
+            None => return Ok(engine),
+        };
+
+        while engine.version.memtables.len() >= max {
+            engine.check_background_error()?;
+            if let Background::Manual(_) = self.compactor {
+                return Err(Error::WriteStalled { frozen: engine.version.memtables.len() }.into());
+            }
+
+            let flushed = engine.flushed.clone();
+            engine = flushed.wait(engine).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        Ok(engine)
+    }
+
+    fn replace_memtable(sender: &Sender<Job>, sequence_number: &mut usize, engine: &mut MutexGuard<Engine>, config: &Config) -> Result<()> {
+        *sequence_number += 1;
+
+        let mut wal_path = config.wal_path.clone();
+        wal_path.push(format!("{}-{}", WAL_NAME, sequence_number));
+
+        let next_seqno = engine.active_memtable.next_seqno();
+        let new_memtable = MemTable::new(
+            &config.env,
+            *sequence_number,
+            &wal_path,
+            next_seqno,
+            config.wal_buffer_size,
+            config.memtable_hash_index,
+        )?;
+        let mut old_memtable = std::mem::replace(&mut engine.active_memtable, new_memtable);
+        old_memtable.flush_wal()?;
+        old_memtable.freeze();
+        engine.version_mut().memtables.push(Arc::new(old_memtable));
+
+        sender.send(Job::Flush)?;
+
+        Ok(())
+    }
+
+}
+
+/// Whether an entry read in key order comes before the given end bound. Errors do, so that they
+/// reach whoever reads the entries.
+fn before_end(entry: &Result<(String, Vec<u8>)>, end: Bound<&&str>) -> bool {
+    match (entry, end) {
+        (Ok((key, _)), Bound::Included(end)) => key.as_str() <= *end,
+        (Ok((key, _)), Bound::Excluded(end)) => key.as_str() < *end,
+        _ => true,
+    }
+}
+
+/// Exclusive write access to the storage, released when dropped. See
+/// [`Storage::open_as_writer`].
+pub struct StorageWriter<'a> {
+    storage: &'a Storage,
+    state: MutexGuard<'a, WriterState>,
+}
+
+impl StorageWriter<'_> {
+    /// Inserts a value into the memtable. If the memtable size reaches its threshold, converts it
+    /// into a sstable.
+    ///
+    /// Fails with [`Error::TooLarge`], without writing anything, if the key and the value take
+    /// more than 256 MiB, and with [`Error::InvalidKey`] if the key holds `\0` while the storage
+    /// has a retention policy, see [`StorageBuilder::retention`].
+    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.check_key(&key)?;
+        self.put(key, value, None)
+    }
+
+    /// Inserts a value like [`StorageWriter::insert`], which expires once the given TTL elapses,
+    /// as told by the clock of the storage. From then on, the key reads as absent, until it is
+    /// written again. The expired key is removed by the next purge, see
+    /// [`Storage::purge_expired`], or else by the compactions that get to it.
+    pub fn insert_with_ttl(&mut self, key: String, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.check_key(&key)?;
+        let expires_at = scheduler::millis(self.storage.config.clock.now().saturating_add(ttl));
+
+        self.put(key, value, Some(expires_at))
+    }
+
+    /// Inserts a value, which expires at the given time, in milliseconds since the UNIX epoch, if
+    /// any.
+    pub(crate) fn put(&mut self, key: String, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let storage = self.storage;
+        self.put_within(key, value, expires_at, &storage.config.quotas)
+    }
+
+    /// Inserts a value like [`StorageWriter::put`], but regardless of the quotas, as the leader
+    /// the write is replicated from already checked them, e.g. before it committed in a Raft
+    /// cluster.
+    pub(crate) fn put_replicated(&mut self, key: String, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.put_within(key, value, expires_at, &[])
+    }
+
+    /// Inserts a value, unless the key is over one of the given quotas.
+    fn put_within(&mut self, key: String, value: Vec<u8>, expires_at: Option<u64>, quotas: &[(String, u64)]) -> Result<()> {
+        let start = Instant::now();
+        self.storage.throttle(key.len() + value.len(), 1);
+        let engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+        let mut engine = self.storage.wait_for_flushes(engine)?;
+        engine.check_quotas(&key, quotas)?;
+
+        let watchers = &self.storage.watchers;
+        let watcher = watchers.sender(&key);
+        let update = watcher.as_ref().map(|_| value.clone());
+        let change = watchers.has_subscribers().then(|| Change {
+            key: key.clone(),
+            value: Some(value.clone()),
+            seqno: engine.active_memtable.next_seqno(),
+            expires_at,
+        });
+
+        engine.absent_keys.invalidate(&key);
+        engine.rows.invalidate(&key);
+        let bytes = key.len() + value.len();
+        match expires_at {
+            Some(expires_at) => {
+                engine.active_memtable.insert_expiring(key.clone(), value, expires_at)?;
+                engine.expiries.insert((expires_at, key.clone()));
+            }
+            None => engine.active_memtable.insert(key.clone(), value)?,
+        }
+        engine.bytes_written.user += bytes as u64;
+        self.storage.record_write(&mut engine, &key, bytes);
+
+        if let Some(watcher) = watcher {
+            let _ = watcher.send(update);
+        }
+        if let Some(change) = change {
+            watchers.publish(change);
+        }
+
+        if engine.active_memtable.len() >= engine.tuning.threshold {
+            Storage::replace_memtable(&self.storage.persistence_sender, &mut self.state.sequence_number, &mut engine, &self.storage.config)?;
+        }
+
+        stats::record(&mut engine.latencies.put, start.elapsed());
+
+        Ok(())
+    }
+
+    /// Removes a key, leaving a tombstone in the memtable that hides it from older tables.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.check_key(&key)?;
+        self.delete(key)
+    }
+
+    /// Removes a key like [`StorageWriter::remove`], whatever it holds.
+    fn delete(&mut self, key: String) -> Result<()> {
+        let start = Instant::now();
+        self.storage.throttle(key.len(), 1);
+        let engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+        let mut engine = self.storage.wait_for_flushes(engine)?;
+
+        let watchers = &self.storage.watchers;
+        let watcher = watchers.sender(&key);
+        let change = watchers.has_subscribers().then(|| Change {
+            key: key.clone(),
+            value: None,
+            seqno: engine.active_memtable.next_seqno(),
+            expires_at: None,
+        });
+
+        engine.rows.invalidate(&key);
+        engine.active_memtable.remove(key.clone())?;
+        engine.bytes_written.user += key.len() as u64;
+        self.storage.record_write(&mut engine, &key, key.len());
+
+        if let Some(watcher) = watcher {
+            let _ = watcher.send(None);
+        }
+        if let Some(change) = change {
+            watchers.publish(change);
+        }
+
+        if engine.active_memtable.len() >= engine.tuning.threshold {
+            Storage::replace_memtable(&self.storage.persistence_sender, &mut self.state.sequence_number, &mut engine, &self.storage.config)?;
+        }
+
+        stats::record(&mut engine.latencies.put, start.elapsed());
+
+        Ok(())
+    }
+
+    /// Replaces the value of a key with what the closure makes of the current one, if any, or
+    /// removes the key if it returns none. Returns the previous value. A value inserted with a
+    /// TTL keeps expiring when it would have, rather than living on after the update.
+    ///
+    /// No other write comes in between the read and the write, as they both happen while the
+    /// writer is held, so there is no need to retry as with a compare-and-swap.
+    pub fn update(
+        &mut self,
+        key: String,
+        update: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.check_key(&key)?;
+        let options = ReadOptions {
+            verify_checksums: self.storage.config.verify_checksums,
+        };
+        let previous = self.storage.read_expiring(&key, &options)?;
+        let expires_at = previous.as_ref().and_then(|(_, expires_at)| *expires_at);
+        let previous = previous.map(|(value, _)| value);
+
+        match update(previous.as_deref()) {
+            Some(value) => self.put(key, value, expires_at)?,
+            None if previous.is_some() => self.remove(key)?,
+            None => {}
+        }
+
+        Ok(previous)
+    }
+
+    /// Fails if the storage has a retention policy and its compactions could take the key for a
+    /// version of a [`VersionedStorage`](crate::versioned::VersionedStorage), and drop it.
+    fn check_key(&self, key: &str) -> Result<()> {
+        match self.storage.config.retention {
+            Some(_) => versioned::check_unversioned(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes every key at once. See [`Storage::clear`].
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        let mut engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+        let config = &self.storage.config;
+
+        self.state.sequence_number += 1;
+        let id = self.state.sequence_number;
+        let wal_path = config.wal_path.join(format!("{}-{}", WAL_NAME, id));
+
+        let next_seqno = engine.active_memtable.next_seqno();
+        let memtable =
+            MemTable::new(&config.env, id, &wal_path, next_seqno, config.wal_buffer_size, config.memtable_hash_index)?;
+        engine.manifest.record(&Edit::Clear { first_memtable: id })?;
+
+        let old_memtable = std::mem::replace(&mut engine.active_memtable, memtable);
+        let mut obsolete = vec![old_memtable.wal_path().to_path_buf()];
+        drop(old_memtable);
+
+        let version = std::mem::take(&mut engine.version);
+        obsolete.extend(version.memtables.iter().map(|memtable| memtable.wal_path().to_path_buf()));
+        let sstables = version.sstables0.iter().chain(&version.sstables1);
+        obsolete.extend(sstables.map(|sstable| sstable.path().to_path_buf()));
+        obsolete.extend(wal::archived(config.env.as_ref(), &config.wal_path)?.into_iter().map(|(_, path)| path));
+        engine.measure_prefixes(&config.quotas)?;
+        engine.flushed.notify_all();
+
+        engine.absent_keys = NegativeCache::new(config.negative_cache_capacity);
+        engine.rows = RowCache::new(config.row_cache_capacity);
+        engine.expiries.clear();
+        drop(engine);
+
+        self.storage.persistence_sender.send(Job::Delete(obsolete))?;
+
+        Ok(())
+    }
+
+    /// Applies a write replicated from a leader, under the sequence number the leader gave it, so
+    /// that [`Storage::last_seqno`] tells how far the storage caught up. Writes up to the last one
+    /// are skipped, as a follower may be sent them again when it resumes. See
+    /// [`Storage::catch_up`].
+    ///
+    /// The leader already accepted the write, so neither the quotas nor the checks of the keys
+    /// apply to it.
+    pub fn apply(&mut self, change: Change) -> Result<()> {
+        {
+            let mut engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+            if change.seqno < engine.active_memtable.next_seqno() {
+                return Ok(());
+            }
+            engine.active_memtable.resume_seqno(change.seqno);
+        }
+
+        match change.value {
+            Some(value) => self.put_replicated(change.key, value, change.expires_at),
+            None => self.delete(change.key),
+        }
+    }
+
+    /// Inserts many values at once, as [`StorageWriter::insert`] would one by one, but locking the
+    /// storage and appending to the WAL only once, as a batch that a crash never leaves half
+    /// recovered. Nothing is inserted if a key is over its quota, or if the batch as a whole takes
+    /// more than 256 MiB, see [`Error::TooLarge`]. The values all land in the same memtable,
+    /// which may therefore go past the threshold before it is converted into a sstable.
+    pub fn insert_batch(&mut self, entries: impl IntoIterator<Item = (String, Vec<u8>)>) -> Result<()> {
+        let entries: Vec<(String, Vec<u8>)> = entries.into_iter().collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for (key, _) in &entries {
+            self.check_key(key)?;
+        }
+
+        let start = Instant::now();
+        let bytes = entries.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.storage.throttle(bytes, entries.len());
+        let engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+        let mut engine = self.storage.wait_for_flushes(engine)?;
+        for (key, _) in &entries {
+            engine.check_quotas(key, &self.storage.config.quotas)?;
+        }
+
+        let mut updates = Vec::new();
+        for (key, value) in &entries {
+            updates.extend(self.storage.watchers.sender(key).map(|watcher| (watcher, value.clone())));
+            engine.absent_keys.invalidate(key);
+            engine.rows.invalidate(key);
+        }
+
+        let mut changes = Vec::new();
+        if self.storage.watchers.has_subscribers() {
+            let seqnos = engine.active_memtable.next_seqno()..;
+            changes.extend(entries.iter().zip(seqnos).map(|((key, value), seqno)| Change {
+                key: key.clone(),
+                value: Some(value.clone()),
+                seqno,
+                expires_at: None,
+            }));
+        }
+        let sizes: Vec<usize> = entries.iter().map(|(key, value)| key.len() + value.len()).collect();
+        let writes: Vec<(String, Stored)> = entries.into_iter().map(|(key, value)| (key, Stored::Value(value))).collect();
+        engine.active_memtable.insert_batch(&writes)?;
+        for ((key, _), bytes) in writes.iter().zip(sizes) {
+            engine.bytes_written.user += bytes as u64;
+            self.storage.record_write(&mut engine, key, bytes);
+        }
+
+        for (watcher, value) in updates {
+            let _ = watcher.send(Some(value));
+        }
+        for change in changes {
+            self.storage.watchers.publish(change);
+        }
+
+        if engine.active_memtable.len() >= engine.tuning.threshold {
+            Storage::replace_memtable(&self.storage.persistence_sender, &mut self.state.sequence_number, &mut engine, &self.storage.config)?;
+        }
+
+        stats::record(&mut engine.latencies.put, start.elapsed());
+
+        Ok(())
+    }
+
+    /// Removes many keys at once, as [`StorageWriter::remove`] would one by one, but locking the
+    /// storage and appending to the WAL only once, as a batch that a crash never leaves half
+    /// recovered. The tombstones all land in the same memtable, which may therefore go past the
+    /// threshold before it is converted into a sstable.
+    pub fn remove_batch(&mut self, keys: impl IntoIterator<Item = String>) -> Result<()> {
+        let keys: Vec<String> = keys.into_iter().collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        for key in &keys {
+            self.check_key(key)?;
+        }
+
+        let start = Instant::now();
+        self.storage.throttle(keys.iter().map(String::len).sum(), keys.len());
+        let engine = self.storage.engine.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.check_background_error()?;
+        let mut engine = self.storage.wait_for_flushes(engine)?;
+
+        let mut watchers = Vec::new();
+        for key in &keys {
+            watchers.extend(self.storage.watchers.sender(key));
+            engine.rows.invalidate(key);
+        }
+
+        let mut changes = Vec::new();
+        if self.storage.watchers.has_subscribers() {
+            let seqnos = engine.active_memtable.next_seqno()..;
+            changes.extend(keys.iter().zip(seqnos).map(|(key, seqno)| Change {
+                key: key.clone(),
+                value: None,
+                seqno,
+                expires_at: None,
+            }));
+        }
+        engine.active_memtable.remove_all(&keys)?;
+        for key in &keys {
+            engine.bytes_written.user += key.len() as u64;
+            self.storage.record_write(&mut engine, key, key.len());
+        }
+
+        for watcher in watchers {
+            let _ = watcher.send(None);
+        }
+        for change in changes {
+            self.storage.watchers.publish(change);
+        }
+
+        if engine.active_memtable.len() >= engine.tuning.threshold {
+            Storage::replace_memtable(&self.storage.persistence_sender, &mut self.state.sequence_number, &mut engine, &self.storage.config)?;
+        }
+
+        stats::record(&mut engine.latencies.put, start.elapsed());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::{Bound, Range};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use tokio_stream::StreamExt;
+
+    use crate::compression::Compression;
+    use crate::env::OsEnv;
+    use crate::fault_injection::FaultInjectionEnv;
+    use crate::filter::{FilterInfo, FilterKind};
+    use crate::events::{EventListener, TuningInfo};
+    use crate::scheduler::{ManualClock, Scheduling};
+    use crate::sstable::TableSource;
+    use crate::stats::{Aggregate, PurgeReport, TaskState, TenantStats};
+    use crate::storage::{CatchUp, Change, ReadOptions, ScanOptions};
+    use crate::tuning::Tuning;
+    use crate::verify::VerifyLevel;
+    use crate::wal;
+    use crate::{storage::Storage, test_utils::*, Error, Stored, SEGMENTS_NAME, WAL_NAME};
+
+    #[test]
+    fn memtables_are_converted_to_sstables_when_threshold_is_reached() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+
+        let number_of_rows = storage.config.threshold * 2;
+        inject_rows(&mut storage, 0..number_of_rows);
+        storage.tick()?;
+
+        let engine = storage.engine.lock().unwrap();
+
+        assert_eq!(engine.version.sstables0.len(), 2);
+        assert_eq!(engine.active_memtable.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn engine_loads_sstables_and_wal_when_it_starts() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+
+        let number_of_rows = storage.config.threshold * 2;
+        inject_rows(&mut storage, 0..number_of_rows);
+        storage.tick()?;
+        drop(storage);
+
+        let storage = test.create_storage()?;
+        let engine = storage.engine.lock().unwrap();
+
+        assert_eq!(engine.version.sstables0.len(), 2);
+        assert_eq!(engine.active_memtable.len(), 0); // TODO: We have no guarantee that the WAL was flushed to disk so there might be data missing.
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_numbers_keep_increasing_across_restarts() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+
+        let number_of_rows = storage.config.threshold * 2;
+        inject_rows(&mut storage, 0..number_of_rows);
+        storage.tick()?;
+        drop(storage);
+
+        let storage = test.create_storage()?;
+        let engine = storage.engine.lock().unwrap();
+        let max_seqno = engine.version.sstable_readers0.iter().map(|reader| reader.max_seqno()).max();
+
+        assert_eq!(max_seqno, Some(number_of_rows as u64));
+        assert_eq!(engine.active_memtable.next_seqno(), number_of_rows as u64 + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_probe_the_sstables_without_holding_the_engine() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = test.storage_builder().row_cache_capacity(1024).build()?;
+        storage.insert("flushed".to_owned(), b"old".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+
+        env.hold_reads(true);
+        std::thread::scope(|scope| {
+            let read = scope.spawn(|| storage.read("flushed"));
+            assert!(env.wait_for_held_reads(1, Duration::from_secs(10)));
+
+            // Writes and reads of the memtables go on while the sstable read waits.
+            let engine_free = storage.engine.try_lock().is_ok();
+            if engine_free {
+                storage.insert("flushed".to_owned(), b"new".to_vec())?;
+                assert_eq!(storage.read("flushed")?, Some(b"new".to_vec()));
+            }
+            env.hold_reads(false);
+
+            assert!(engine_free);
+            assert_eq!(read.join().unwrap()?, Some(b"old".to_vec()));
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // The read found the value the write replaced, which the row cache was kept from.
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(storage.read("flushed")?, Some(b"new".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_of_the_sstables_go_on_at_once_while_a_write_is_in_progress() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = test.create_storage()?;
+        storage.insert_batch([("a".to_owned(), b"a".to_vec()), ("b".to_owned(), b"b".to_vec())])?;
+        storage.flush()?;
+        storage.tick()?;
+
+        env.hold_reads(true);
+        let storage = &storage;
+        std::thread::scope(|scope| {
+            let reads = ["a", "b"].map(|key| scope.spawn(move || storage.read(key)));
+
+            // Both reads wait on the sstable at once, while the writer is held and writes.
+            let mut writer = storage.open_as_writer()?;
+            let concurrent = env.wait_for_held_reads(2, Duration::from_secs(10));
+            writer.insert("c".to_owned(), b"c".to_vec())?;
+            env.hold_reads(false);
+            drop(writer);
+
+            assert!(concurrent);
+            let values: Vec<_> = reads.into_iter().map(|read| read.join().unwrap()).collect::<Result<_>>()?;
+            assert_eq!(values, [Some(b"a".to_vec()), Some(b"b".to_vec())]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn multi_gets_probe_the_sstables_without_holding_the_engine() -> Result<()> {
+        let env = FaultInjectionEnv::default();
+        let test = Test::with_env(Arc::new(env.clone()))?;
+        let storage = test.create_storage()?;
+        storage.insert_batch([("a".to_owned(), b"a".to_vec()), ("b".to_owned(), b"b".to_vec())])?;
+        storage.flush()?;
+        storage.tick()?;
+
+        env.hold_reads(true);
+        let storage = &storage;
+        std::thread::scope(|scope| {
+            let values = scope.spawn(move || storage.multi_get(&["a", "b"], &ReadOptions::default()));
+
+            assert!(env.wait_for_held_reads(1, Duration::from_secs(10)));
+
+            // Writes go on while the probes of the sstable wait.
+            let engine_free = storage.engine.try_lock().is_ok();
+            if engine_free {
+                storage.insert("a".to_owned(), b"new".to_vec())?;
+            }
+            env.hold_reads(false);
+
+            assert!(engine_free);
+            assert_eq!(values.join().unwrap()?, [Some(b"a".to_vec()), Some(b"b".to_vec())]);
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        assert_eq!(storage.multi_get(&["a", "b"], &ReadOptions::default())?, [Some(b"new".to_vec()), Some(b"b".to_vec())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_from_memtable_and_sstable() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        let v1 = storage.read("key-500")?;
+        let v2 = storage.read("key-1500")?;
+        assert_eq!(None, v1);
+        assert_eq!(None, v2);
+
+        inject_rows(&mut storage, 0..threshold);
+
+        let v1 = String::from_utf8(storage.read("key-500")?.unwrap()).unwrap();
+        let v2 = storage.read("key-1500")?;
+        assert_eq!("value-500", v1);
+        assert_eq!(None, v2);
+
+        inject_rows(&mut storage, threshold..threshold*2);
+
+        let v1 = String::from_utf8(storage.read("key-500")?.unwrap()).unwrap();
+        let v2 = String::from_utf8(storage.read("key-1500")?.unwrap()).unwrap();
+        assert_eq!("value-500", v1);
+        assert_eq!("value-1500", v2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn removed_keys_are_not_read_from_older_tables() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+        storage.remove("key-500".to_owned())?;
+
+        assert_eq!(None, storage.read("key-500")?);
+
+        inject_rows(&mut storage, threshold..threshold * 2);
+        storage.tick()?;
+
+        assert_eq!(None, storage.read("key-500")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_see_the_current_value_and_return_it() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        let increment = |value: Option<&[u8]>| {
+            let count = value.map_or(0, |value| u64::from_le_bytes(value.try_into().unwrap()));
+            Some((count + 1).to_le_bytes().to_vec())
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        storage.update("counter".to_owned(), increment).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(storage.read("counter")?, Some(400u64.to_le_bytes().to_vec()));
+
+        let previous = storage.update("counter".to_owned(), |_| None)?;
+        assert_eq!(previous, Some(400u64.to_le_bytes().to_vec()));
+        assert_eq!(storage.read("counter")?, None);
+        assert_eq!(storage.update("absent".to_owned(), |_| None)?, None);
+        assert_eq!(storage.read("absent")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts_and_aggregates_see_the_keys_in_the_range() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().threshold(100).target_file_size(500).build()?;
+
+        for i in 0..300 {
+            storage.insert(format!("key-{:03}", i), format!("value-{}", i).into_bytes())?;
+        }
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+
+        // Only L1 is left, so the keys are counted from the indexes of its sstables.
+        assert!(storage.engine.lock().unwrap().version.sstables1.len() > 1);
+        assert_eq!(storage.count(..)?, 300);
+        assert_eq!(storage.count("key-100".."key-200")?, 100);
+        assert_eq!(storage.count("key-100"..="key-200")?, 101);
+        assert_eq!(storage.count((Bound::Excluded("key-100"), Bound::Unbounded))?, 199);
+
+        storage.remove("key-150".to_owned())?;
+        storage.insert("key-1000".to_owned(), b"new".to_vec())?;
+        assert_eq!(storage.count("key-100".."key-200")?, 100);
+
+        // key-100, key-1000 and key-101 to key-109.
+        let aggregate = storage.aggregate("key-100".."key-110")?;
+        assert_eq!(aggregate.count, 11);
+        assert_eq!(aggregate.min_key.as_deref(), Some("key-100"));
+        assert_eq!(aggregate.max_key.as_deref(), Some("key-109"));
+        assert_eq!(aggregate.value_bytes, 10 * 9 + 3);
+        assert_eq!(storage.aggregate("x"..)?, Aggregate::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_removals_hide_every_key_and_survive_a_restart() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().threshold(100).build()?;
+
+        inject_rows(&mut storage, 0..150);
+        storage.tick()?;
+        storage.remove_batch((0..150).filter(|i| i % 2 == 0).map(|i| format!("key-{}", i)))?;
+        storage.remove_batch(Vec::new())?;
+
+        assert!((0..150).all(|i| storage.read(&format!("key-{}", i)).unwrap().is_some() == (i % 2 != 0)));
+        // Two thirds of the tombstones are for keys the active memtable lacked, filling it up.
+        assert_eq!(storage.engine.lock().unwrap().version.memtables.len(), 1);
+        drop(storage);
+
+        let storage = test.storage_builder().threshold(100).build()?;
+        assert!((0..150).all(|i| storage.read(&format!("key-{}", i)).unwrap().is_some() == (i % 2 != 0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_inserts_store_every_value_and_survive_a_restart() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().threshold(100).build()?;
+
+        inject_rows(&mut storage, 0..50);
+        storage.insert_batch((0..150).map(|i| (format!("key-{}", i), format!("batch-{}", i).into_bytes())))?;
+        storage.insert_batch(Vec::new())?;
+
+        let expected = |i| Some(format!("batch-{}", i).into_bytes());
+        assert!((0..150).all(|i| storage.read(&format!("key-{}", i)).unwrap() == expected(i)));
+        // The batch went past the threshold, so it was frozen whole.
+        assert_eq!(storage.engine.lock().unwrap().version.memtables.len(), 1);
+        drop(storage);
+
+        let storage = test.storage_builder().threshold(100).build()?;
+        assert!((0..150).all(|i| storage.read(&format!("key-{}", i)).unwrap() == expected(i)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn values_inserted_with_a_ttl_read_as_absent_once_it_elapses() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let storage = test.storage_builder().clock(clock.clone()).row_cache_capacity(1024).build()?;
+
+        storage.insert("a".to_owned(), b"kept".to_vec())?;
+        storage.insert_with_ttl("b".to_owned(), b"short".to_vec(), Duration::from_secs(10))?;
+        storage.insert_with_ttl("c".to_owned(), b"long".to_vec(), Duration::from_secs(20))?;
+
+        assert_eq!(storage.read("b")?, Some(b"short".to_vec()));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(storage.read("b")?, None);
+        let values = storage.multi_get(&["a", "b", "c"], &ReadOptions::default())?;
+        assert_eq!(values, [Some(b"kept".to_vec()), None, Some(b"long".to_vec())]);
+        let keys: Vec<String> = storage.iter()?.map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, ["a", "c"]);
+
+        // The expiries are persisted along with the values, in the WAL and then in the sstables.
+        drop(storage);
+        let storage = test.storage_builder().clock(clock.clone()).build()?;
+        assert_eq!(storage.read("b")?, None);
+        assert_eq!(storage.read("c")?, Some(b"long".to_vec()));
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(storage.read("c")?, Some(b"long".to_vec()));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(storage.read("c")?, None);
+
+        // Merging into the last level drops the expired values, as it does tombstones.
+        storage.insert("d".to_owned(), b"kept".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+        let levels = storage.levels()?;
+        assert_eq!(levels[1].iter().map(|table| table.entries).sum::<usize>(), 2);
+        assert_eq!(storage.read("a")?, Some(b"kept".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_values_are_neither_counted_nor_revived_by_updates() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let storage = test.storage_builder().clock(clock.clone()).build()?;
+
+        storage.insert("a".to_owned(), b"kept".to_vec())?;
+        storage.insert_with_ttl("b".to_owned(), b"short".to_vec(), Duration::from_secs(10))?;
+        storage.insert_with_ttl("c".to_owned(), b"long".to_vec(), Duration::from_secs(20))?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+        assert_eq!(storage.count(..)?, 3);
+
+        // The update keeps the expiry of the value it replaces.
+        storage.update("b".to_owned(), |_| Some(b"updated".to_vec()))?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(storage.read("b")?, None);
+
+        // Only L1 is left, but one of its values expired, so the keys are read to be counted.
+        assert_eq!(storage.count(..)?, 2);
+        assert_eq!(storage.aggregate(..)?.count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_keys_are_purged_through_the_expiry_index() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let builder = || test.storage_builder().clock(clock.clone()).expiry_purge_interval(Duration::from_secs(60));
+        let storage = builder().build()?;
+
+        storage.insert_with_ttl("flushed".to_owned(), b"value".to_vec(), Duration::from_secs(10))?;
+        storage.insert_with_ttl("rewritten".to_owned(), b"value".to_vec(), Duration::from_secs(10))?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.insert("rewritten".to_owned(), b"new".to_vec())?;
+        storage.insert_with_ttl("later".to_owned(), b"value".to_vec(), Duration::from_secs(30))?;
+        assert_eq!(storage.purge_expired()?, 0);
+
+        // The index is rebuilt from the sstables and memtables on open.
+        drop(storage);
+        let storage = builder().build()?;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(storage.purge_expired()?, 1);
+        assert_eq!(storage.engine.lock().unwrap().active_memtable.lookup("flushed"), Some(Stored::Tombstone));
+        assert_eq!(storage.read("rewritten")?, Some(b"new".to_vec()));
+        assert_eq!(storage.purge_expired()?, 0);
+
+        // Ticking purges once the interval elapses.
+        clock.advance(Duration::from_secs(30));
+        storage.tick()?;
+        let expiring = Stored::Expiring(b"value".to_vec(), 30_000);
+        assert_eq!(storage.engine.lock().unwrap().active_memtable.lookup("later"), Some(expiring));
+        assert_eq!(storage.read("later")?, None);
+        clock.advance(Duration::from_secs(20));
+        assert!(storage.tick()? > 0);
+        assert_eq!(storage.engine.lock().unwrap().active_memtable.lookup("later"), Some(Stored::Tombstone));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_get_reads_the_most_recent_values_from_tables_looked_up_in_parallel() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().threshold(100).read_parallelism(3).build()?;
+
+        for round in 0..5 {
+            inject_rows(&mut storage, round * 100..(round + 1) * 100);
+            storage.tick()?;
+        }
+        storage.insert("key-150".to_owned(), b"updated".to_vec())?;
+        storage.remove("key-250".to_owned())?;
+        inject_rows(&mut storage, 500..599);
+        storage.tick()?;
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 6);
+
+        let keys = ["key-450", "key-150", "key-250", "key-999", "key-5", "key-450"];
+        let values = storage.multi_get(&keys, &ReadOptions::default())?;
+
+        assert_eq!(
+            values,
+            vec![
+                Some(b"value-450".to_vec()),
+                Some(b"updated".to_vec()),
+                None,
+                None,
+                Some(b"value-5".to_vec()),
+                Some(b"value-450".to_vec()),
+            ]
+        );
+        assert_eq!(storage.multi_get(&keys, &ReadOptions::default())?, values);
+
+        Ok(())
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn multi_get_reads_the_entries_it_finds_through_io_uring() -> Result<()> {
+        // Sandboxes may not allow io_uring, which leaves nothing to test.
+        if crate::uring::Ring::new().is_err() {
+            return Ok(());
+        }
+
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().threshold(100).io_uring(true).build()?;
+
+        for round in 0..3 {
+            inject_rows(&mut storage, round * 100..(round + 1) * 100);
+            storage.tick()?;
+        }
+        storage.compact()?;
+
+        // Entries larger than what is read at their offset are read again whole.
+        let large = vec![7; 3 * crate::uring::ENTRY_READ_SIZE];
+        storage.insert("key-150".to_owned(), large.clone())?;
+        storage.remove("key-250".to_owned())?;
+        inject_rows(&mut storage, 300..399);
+        storage.tick()?;
+
+        let keys = ["key-50", "key-150", "key-250", "key-999", "key-350", "key-50"];
+        let values = storage.multi_get(&keys, &ReadOptions::default())?;
+        assert_eq!(
+            values,
+            vec![
+                Some(b"value-50".to_vec()),
+                Some(large),
+                None,
+                None,
+                Some(b"value-350".to_vec()),
+                Some(b"value-50".to_vec()),
+            ]
+        );
+
+        assert_eq!(storage.multi_get(&keys, &ReadOptions { verify_checksums: true })?, values);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_storages_are_neither_opened_again_nor_destroyed() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold + 10);
+        storage.tick()?;
+
+        let error = test.create_storage().err().unwrap();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::Locked));
+        let error = test.storage_builder().destroy().unwrap_err();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::Locked));
+
+        drop(storage);
+        test.storage_builder().destroy()?;
+        assert!(std::fs::read_dir(test.test_path())?.next().is_none());
+
+        let storage = test.create_storage()?;
+        assert_eq!(storage.read("key-5")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wals_and_sstables_are_kept_in_their_own_directories() -> Result<()> {
+        let test = Test::new()?;
+        let (segments, wals) = (test.path("segments"), test.path("wals"));
+        let builder = || {
+            test.storage_builder()
+                .segments_path(segments.clone())
+                .wal_path(wals.clone())
+        };
+        let names = |dir: &Path| -> Result<Vec<String>> {
+            let mut names = std::fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+
+        let mut storage = builder().build()?;
+        let threshold = storage.config.threshold;
+        inject_rows(&mut storage, 0..threshold * 2 + 10);
+        storage.tick()?;
+
+        let in_segments = names(&segments)?;
+        let in_wals = names(&wals)?;
+        assert!(in_segments.iter().any(|name| name.starts_with(SEGMENTS_NAME)));
+        assert!(in_segments.iter().all(|name| !name.starts_with(WAL_NAME)));
+        assert_eq!(in_wals.iter().filter(|name| name.starts_with(WAL_NAME)).count(), 1);
+        assert!(in_wals.iter().all(|name| !name.starts_with(SEGMENTS_NAME)));
+
+        // Another storage can't keep its WALs in the same directory.
+        let error = test.storage_builder().wal_path(wals.clone()).build().err().unwrap();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::Locked));
+
+        drop(storage);
+        let storage = builder().build()?;
+        let last = threshold * 2 + 9;
+        assert_eq!(storage.read(&format!("key-{}", last))?, Some(format!("value-{}", last).into_bytes()));
+        assert_eq!(storage.read("key-5")?, Some(b"value-5".to_vec()));
+
+        drop(storage);
+        builder().destroy()?;
+        assert!(names(&segments)?.is_empty());
+        assert!(names(&wals)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cleared_storages_stay_empty_once_reopened() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2 + 10);
+        storage.tick()?;
+        assert_eq!(storage.read("key-5")?, Some(b"value-5".to_vec()));
+
+        storage.clear()?;
+        storage.insert("key-new".to_owned(), b"new".to_vec())?;
+        assert_eq!(storage.read("key-5")?, None);
+        assert_eq!(storage.read(&format!("key-{}", threshold * 2))?, None);
+
+        storage.tick()?;
+        let sstables = std::fs::read_dir(test.test_path())?
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("sstable"))
+            .count();
+        assert_eq!(sstables, 0);
+        drop(storage);
+
+        let storage = test.create_storage()?;
+        assert_eq!(storage.read("key-5")?, None);
+        assert_eq!(storage.read(&format!("key-{}", threshold * 2))?, None);
+        assert_eq!(storage.read("key-new")?, Some(b"new".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn properties_describe_the_memtables_and_sstables() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        let observer = storage.clone();
+        let property = |name| observer.property(name).unwrap();
+
+        assert_eq!(property("num-files-at-level0"), Some("0".to_owned()));
+        assert_eq!(property("is-compaction-pending"), Some("0".to_owned()));
+        assert_eq!(property("estimated-memtable-bytes"), Some("0".to_owned()));
+        assert_eq!(property("no-such-property"), None);
+
+        inject_rows(&mut storage, 0..threshold + 1);
+        assert_eq!(property("num-immutable-memtables"), Some("1".to_owned()));
+        assert_eq!(property("num-entries-active-memtable"), Some("1".to_owned()));
+        let bytes: usize = (0..threshold + 1).map(|i| format!("key-{}value-{}", i, i).len()).sum();
+        assert_eq!(property("estimated-memtable-bytes"), Some(bytes.to_string()));
+
+        storage.tick()?;
+        assert_eq!(property("num-files-at-level0"), Some("1".to_owned()));
+        assert_eq!(property("num-files-at-level1"), Some("0".to_owned()));
+        assert_eq!(property("num-immutable-memtables"), Some("0".to_owned()));
+        assert_eq!(property("is-compaction-pending"), Some("1".to_owned()));
+
+        let sstable_bytes = std::fs::metadata(test.path("sstable-0"))?.len();
+        assert_eq!(property("total-sstable-bytes"), Some(sstable_bytes.to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_and_compactions_can_be_forced() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+
+        inject_rows(&mut storage, 0..10);
+        storage.flush()?;
+        storage.flush()?;
+        storage.tick()?;
+
+        let levels = storage.levels()?;
+        assert_eq!(levels[0].len(), 1);
+        assert_eq!(levels[0][0].entries, 10);
+        assert_eq!(levels[0][0].first_key.as_deref(), Some("key-0"));
+        assert_eq!(levels[0][0].last_key.as_deref(), Some("key-9"));
+        assert!(levels[1].is_empty());
+
+        storage.compact()?;
+
+        let levels = storage.levels()?;
+        assert!(levels[0].is_empty());
+        assert_eq!(levels[1].len(), 1);
+        assert_eq!(levels[1][0].size, std::fs::metadata(&levels[1][0].path)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn levels_describe_where_each_sstable_comes_from() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let mut storage = test.storage_builder().clock(clock.clone()).build()?;
+
+        for rows in [0..10, 10..20] {
+            clock.advance(Duration::from_secs(1));
+            inject_rows(&mut storage, rows);
+            storage.flush()?;
+            storage.tick()?;
+        }
+
+        let levels = storage.levels()?;
+        let flushed: Vec<_> = levels[0].iter().map(|table| table.metadata.clone().unwrap()).collect();
+        assert_eq!(flushed.len(), 2);
+        for (table, created_at) in flushed.iter().zip([1000, 2000]) {
+            assert_eq!(table.source, TableSource::Flush);
+            assert_eq!(table.level, 0);
+            assert_eq!(table.created_at, created_at);
+            assert_eq!(table.max_seqno - table.min_seqno, 9);
+        }
+        assert!(flushed[0].max_seqno < flushed[1].min_seqno);
+
+        clock.advance(Duration::from_secs(1));
+        storage.compact()?;
+
+        let levels = storage.levels()?;
+        let compacted = levels[1][0].metadata.clone().unwrap();
+        assert_eq!(compacted.source, TableSource::Compaction);
+        assert_eq!(compacted.level, 1);
+        assert_eq!(compacted.created_at, 3000);
+        assert_eq!((compacted.min_seqno, compacted.max_seqno), (flushed[0].min_seqno, flushed[1].max_seqno));
+
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoints_open_as_a_copy_of_the_storage() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        let checkpoint = test.path("checkpoint");
+
+        inject_rows(&mut storage, 0..threshold * 2 + 10);
+        storage.tick()?;
+        storage.compact()?;
+        inject_rows(&mut storage, threshold * 2 + 10..threshold * 3 + 10);
+
+        storage.checkpoint(&checkpoint)?;
+        storage.remove("key-5".to_owned())?;
+        assert!(storage.checkpoint(&checkpoint).is_err());
+
+        let copy = Storage::builder()
+            .segments_path(checkpoint.clone())
+            .wal_path(checkpoint)
+            .scheduling(Scheduling::Manual)
+            .build()?;
+
+        assert_eq!(copy.read("key-5")?, Some(b"value-5".to_vec()));
+        for i in [0, threshold * 2 + 5, threshold * 3 + 9] {
+            assert_eq!(copy.read(&format!("key-{}", i))?, Some(format!("value-{}", i).into_bytes()));
+        }
+        assert_eq!(copy.identity(), storage.identity());
+        assert_ne!(Test::new()?.create_storage()?.identity().id, storage.identity().id);
+
+        Ok(())
+    }
+
+    /// Catches the follower up with the leader, returning the sequence number of the checkpoint
+    /// written into the given directory if it can't resume.
+    fn catch_up(leader: &Storage, follower: &Storage, dir: &Path) -> Result<Option<u64>> {
+        match leader.catch_up(follower.last_seqno(), dir)? {
+            CatchUp::Resume(mut tail) => {
+                while let Some(change) = tail.poll()? {
+                    follower.apply(change)?;
+                }
+                Ok(None)
+            }
+            CatchUp::Checkpoint { seqno } => Ok(Some(seqno)),
+        }
+    }
+
+    #[test]
+    fn followers_catch_up_from_archived_wals_or_else_from_a_checkpoint() -> Result<()> {
+        let test = Test::new()?;
+        let leader = test.storage_builder().wal_archive_size(1 << 20).build()?;
+        let builder = |path: &Path| {
+            Storage::builder()
+                .segments_path(path.to_path_buf())
+                .wal_path(path.to_path_buf())
+                .scheduling(Scheduling::Manual)
+        };
+        let follower = builder(&test.path("follower")).build()?;
+
+        leader.insert("a".to_owned(), b"1".to_vec())?;
+        leader.insert_with_ttl("b".to_owned(), b"2".to_vec(), Duration::from_secs(60))?;
+        leader.flush()?;
+        leader.tick()?;
+        leader.remove("a".to_owned())?;
+
+        // The writes of the persisted memtable are read from its archived WAL.
+        assert_eq!(wal::archived(&OsEnv, &test.test_path())?.len(), 1);
+        assert_eq!(catch_up(&leader, &follower, &test.path("unused"))?, None);
+        assert_eq!(follower.last_seqno(), 3);
+        assert_eq!((follower.read("a")?, follower.read("b")?), (None, Some(b"2".to_vec())));
+        assert!(follower.read_verified("b")?.unwrap().ttl.is_some());
+
+        // Writes applied already are skipped, as they may be sent again.
+        follower.apply(Change { key: "a".to_owned(), value: Some(b"1".to_vec()), seqno: 1, expires_at: None })?;
+        assert_eq!((follower.read("a")?, follower.last_seqno()), (None, 3));
+
+        // Once the archived WALs are pruned, the follower gets a checkpoint instead.
+        let pruning_path = test.path("pruning");
+        let pruning = builder(&pruning_path).wal_archive_size(0).build()?;
+        pruning.insert("c".to_owned(), b"3".to_vec())?;
+        pruning.flush()?;
+        pruning.tick()?;
+        assert!(wal::archived(&OsEnv, &pruning_path)?.is_empty());
+
+        let checkpoint = test.path("checkpoint");
+        let fresh = builder(&test.path("fresh")).build()?;
+        assert_eq!(catch_up(&pruning, &fresh, &checkpoint)?, Some(1));
+        assert!(!test.path("unused").exists());
+
+        // So does a follower ahead of its leader, as they diverged.
+        assert_eq!(catch_up(&pruning, &follower, &test.path("diverged"))?, Some(1));
+        let follower = builder(&checkpoint).build()?;
+        assert_eq!((follower.read("c")?, follower.last_seqno()), (Some(b"3".to_vec()), 1));
+
+        // From then on, it resumes from the WALs again.
+        pruning.insert("d".to_owned(), b"4".to_vec())?;
+        assert_eq!(catch_up(&pruning, &follower, &test.path("unused"))?, None);
+        assert_eq!((follower.read("d")?, follower.last_seqno()), (Some(b"4".to_vec()), 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_keys_read_the_most_are_reported_hottest_first() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().hot_keys(2).build()?;
+        storage.insert("hot".to_owned(), b"value".to_vec())?;
+
+        for _ in 0..3 {
+            storage.read("hot")?;
+        }
+        storage.read("missing")?;
+        storage.multi_get(&["hot", "missing"], &ReadOptions::default())?;
+
+        let top: Vec<_> = storage.top_keys(10).into_iter().map(|hot_key| (hot_key.key, hot_key.reads)).collect();
+        assert_eq!(top, [("hot".to_owned(), 4), ("missing".to_owned(), 2)]);
+        assert!(Test::new()?.create_storage()?.top_keys(10).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingested_sstables_only_fill_in_the_keys_the_storage_lacks() -> Result<()> {
+        let test = Test::new()?;
+        let backfill_path = test.path("backfill");
+        let backfill = Storage::builder()
+            .segments_path(backfill_path.clone())
+            .wal_path(backfill_path)
+            .scheduling(Scheduling::Manual)
+            .build()?;
+
+        for key in ["a", "b", "c", "z"] {
+            backfill.insert(key.to_owned(), format!("old-{}", key).into_bytes())?;
+        }
+        backfill.flush()?;
+        backfill.tick()?;
+        let external = backfill.engine.lock().unwrap().version.sstables0[0].path().to_path_buf();
+
+        let storage = test.create_storage()?;
+        storage.insert("a".to_owned(), b"new-a".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+        storage.insert("b".to_owned(), b"new-b".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(storage.read("c")?, None);
+
+        storage.ingest_behind(&external)?;
+
+        {
+            let engine = storage.engine.lock().unwrap();
+            assert_eq!((engine.version.sstables0.len(), engine.version.sstables1.len()), (1, 1));
+        }
+        for (key, value) in [("a", "new-a"), ("b", "new-b"), ("c", "old-c"), ("z", "old-z")] {
+            assert_eq!(storage.read(key)?, Some(value.as_bytes().to_vec()));
+        }
+
+        storage.compact()?;
+        drop(storage);
+
+        let storage = test.create_storage()?;
+        for (key, value) in [("a", "new-a"), ("b", "new-b"), ("c", "old-c"), ("z", "old-z")] {
+            assert_eq!(storage.read(key)?, Some(value.as_bytes().to_vec()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterators_yield_the_most_recent_values_in_key_order() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().compaction_interval(Duration::ZERO).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2);
+        storage.tick()?;
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+
+        storage.insert("key-1".to_owned(), b"updated".to_vec())?;
+        storage.remove("key-2".to_owned())?;
+
+        let entries = storage.iter()?.collect::<Result<Vec<_>>>()?;
+
+        let mut expected: Vec<_> = (0..threshold * 2)
+            .filter(|i| *i != 2)
+            .map(|i| (format!("key-{i}"), format!("value-{i}").into_bytes()))
+            .collect();
+        expected.sort();
+        expected.iter_mut().find(|(key, _)| key == "key-1").unwrap().1 = b"updated".to_vec();
+
+        assert_eq!(entries, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterators_start_from_the_given_key() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().compaction_interval(Duration::ZERO).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2);
+        storage.tick()?;
+        inject_rows(&mut storage, threshold * 2..threshold * 3);
+        storage.tick()?;
+        storage.insert("key-5".to_owned(), b"updated".to_vec())?;
+
+        let keys = storage
+            .iter_from("key-5")?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut expected: Vec<_> = (0..threshold * 3)
+            .map(|i| format!("key-{i}"))
+            .filter(|key| key.as_str() >= "key-5")
+            .collect();
+        expected.sort();
+
+        assert_eq!(keys, expected);
+        assert!(storage.iter_from("key-a")?.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterators_keep_seeing_the_storage_as_it_was_when_created() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().compaction_interval(Duration::ZERO).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold / 2);
+        let mut iterator = storage.iter()?;
+        let first = iterator.next().unwrap()?;
+
+        // Overwrites every key and fills the memtable, which is swapped, flushed and compacted.
+        for i in 0..threshold {
+            storage.insert(format!("key-{i}"), b"new".to_vec())?;
+        }
+        storage.remove("key-1".to_owned())?;
+        storage.tick()?;
+        assert_eq!(storage.engine.lock().unwrap().version.sstables1.len(), 1);
+
+        let mut entries = vec![first];
+        entries.extend(iterator.collect::<Result<Vec<_>>>()?);
+
+        let mut expected: Vec<_> = (0..threshold / 2)
+            .map(|i| (format!("key-{i}"), format!("value-{i}").into_bytes()))
+            .collect();
+        expected.sort();
+
+        assert_eq!(entries, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterators_read_the_same_entries_whether_blocks_are_prefetched_or_not() -> Result<()> {
+        let test = Test::new()?;
+
+        let mut scans = Vec::new();
+        for threads in [0, 1, 4] {
+            let storage = test
+                .storage_builder()
+                .threshold(100)
+                .target_file_size(500)
+                .compaction_readahead(64)
+                .prefetch_threads(threads)
+                .build()?;
+
+            if threads == 0 {
+                for i in 0..300 {
+                    storage.insert(format!("key-{:03}", i), format!("value-{}", i).into_bytes())?;
+                }
+                storage.flush()?;
+                storage.tick()?;
+                storage.compact()?;
+                storage.insert("key-500".to_owned(), b"value-500".to_vec())?;
+                storage.flush()?;
+                storage.tick()?;
+            }
+
+            // Many sstables of L1, read in blocks much smaller than they are, and one of L0.
+            let version = storage.engine.lock().unwrap().version.clone();
+            assert!(version.sstables1.len() > 1);
+            assert_eq!(version.sstables0.len(), 1);
+
+            let entries = storage.iter_from("key-050")?.collect::<Result<Vec<_>>>()?;
+            assert_eq!(entries.len(), 251);
+            scans.push(entries);
+        }
+
+        assert_eq!(scans[0], scans[1]);
+        assert_eq!(scans[0], scans[2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_one_writer_may_be_open_at_a_time() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+        let clone = storage.clone();
+
+        let mut writer = storage.open_as_writer()?;
+        writer.insert("key-1".to_owned(), b"value-1".to_vec())?;
+
+        let error = clone.open_as_writer().err().unwrap();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::WriterBusy));
+        assert_eq!(Some(b"value-1".to_vec()), clone.read("key-1")?);
+
+        drop(writer);
+        clone.open_as_writer()?.remove("key-1".to_owned())?;
+        assert_eq!(None, storage.read("key-1")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn negative_cache_is_invalidated_by_writes() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        assert_eq!(None, storage.read("key-1")?);
+        assert!(storage.engine.lock().unwrap().absent_keys.contains("key-1"));
+
+        storage.insert("key-1".to_owned(), b"value-1".to_vec())?;
+        assert_eq!(Some(b"value-1".to_vec()), storage.read("key-1")?);
+
+        storage.remove("key-1".to_owned())?;
+        assert_eq!(None, storage.read("key-1")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn row_cache_is_invalidated_by_writes() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().row_cache_capacity(1024).build()?;
+
+        storage.insert("key-1".to_owned(), b"value-1".to_vec())?;
+        assert_eq!(Some(b"value-1".to_vec()), storage.read("key-1")?);
+        assert_eq!(Some(b"value-1".to_vec()), storage.engine.lock().unwrap().rows.get("key-1"));
+
+        storage.insert("key-1".to_owned(), b"value-2".to_vec())?;
+        assert_eq!(Some(b"value-2".to_vec()), storage.read("key-1")?);
+
+        storage.remove("key-1".to_owned())?;
+        assert_eq!(None, storage.read("key-1")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sstables_are_compressed_with_the_codec_of_their_level() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .compression(0, Compression::Lz4)
+            .compression(1, Compression::Zstd(19))
+            .compaction_interval(Duration::ZERO)
+            .threshold(64)
+            .build()?;
+        let threshold = storage.config.threshold;
+        let value = b"value ".repeat(1000);
+
+        for i in 0..threshold * 2 {
+            storage.insert(format!("key-{}", i), value.clone())?;
+        }
+        storage.tick()?;
+
+        {
+            let engine = storage.engine.lock().unwrap();
+            let uncompressed_size = (threshold * 2 * value.len()) as u64;
+
+            assert_eq!(engine.version.sstables0.len(), 0);
+            assert!(engine.version.sstables1[0].size()? < uncompressed_size / 10);
+        }
+
+        assert_eq!(Some(value.clone()), storage.read("key-0")?);
+        assert_eq!(Some(value), storage.read(&format!("key-{}", threshold * 2 - 1))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sstables_are_written_with_a_filter_of_their_kind_and_read_whatever_it_is() -> Result<()> {
+        let test = Test::new()?;
+        let filter_kinds = |storage: &Storage| {
+            let engine = storage.engine.lock().unwrap();
+            let readers = engine.version.sstable_readers0.iter().chain(&engine.version.sstable_readers1);
+            readers.map(|reader| reader.filter().map(|filter| filter.kind)).collect::<Vec<_>>()
+        };
+
+        let storage = test.storage_builder().filter(FilterKind::Xor).threshold(64).build()?;
+        for i in 0..200 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+        assert_eq!(filter_kinds(&storage), [Some(FilterKind::Xor)]);
+        drop(storage);
+
+        // Tables keep the filter they were written with until they are rewritten.
+        let storage = test.storage_builder().filter(FilterKind::Bloom).threshold(64).build()?;
+        storage.insert("key-0".to_owned(), b"updated".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(filter_kinds(&storage), [Some(FilterKind::Bloom), Some(FilterKind::Xor)]);
+
+        assert_eq!(storage.read("key-0")?, Some(b"updated".to_vec()));
+        assert!((1..200).all(|i| storage.read(&format!("key-{}", i)).unwrap() == Some(b"value".to_vec())));
+        assert!((200..1000).all(|i| storage.read(&format!("key-{}", i)).unwrap().is_none()));
+
+        let engine = storage.engine.lock().unwrap();
+        for sstable in engine.version.sstables0.iter().chain(&engine.version.sstables1) {
+            sstable.verify()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn filters_take_the_bits_per_key_of_their_level_which_the_sstables_record() -> Result<()> {
+        let test = Test::new()?;
+        let builder = || test.storage_builder().filter(FilterKind::Xor);
+        let filters = |storage: &Storage| -> Result<Vec<Vec<Option<FilterInfo>>>> {
+            let levels = storage.levels()?;
+            Ok(levels.iter().map(|tables| tables.iter().map(|table| table.filter).collect()).collect())
+        };
+
+        let storage = builder().filter_bits_per_key(0, 0).filter_bits_per_key(1, 16).build()?;
+        for i in 0..200 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+            if i == 100 {
+                storage.flush()?;
+            }
+        }
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(filters(&storage)?, [vec![None, None], vec![]]);
+
+        storage.compact()?;
+        let xor = |bits_per_key| Some(FilterInfo { kind: FilterKind::Xor, bits_per_key });
+        assert_eq!(filters(&storage)?, [vec![], vec![xor(16)]]);
+        drop(storage);
+
+        // Levels left unset get the default.
+        let storage = builder().build()?;
+        storage.insert("key-0".to_owned(), b"updated".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(filters(&storage)?, [vec![xor(10)], vec![xor(16)]]);
+
+        assert_eq!(storage.read("key-0")?, Some(b"updated".to_vec()));
+        assert_eq!(storage.read("key-1")?, Some(b"value".to_vec()));
+        assert_eq!(storage.read("key-200")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_sstables_partition_their_index_and_read_it_through_the_block_cache() -> Result<()> {
+        let test = Test::new()?;
+        let builder = || {
+            let builder = test.storage_builder().threshold(4096);
+            builder.partition_index_above(16 * 1024).block_cache_capacity(8 * 1024)
+        };
+        let partitioned = |storage: &Storage| -> Result<Vec<Vec<bool>>> {
+            let levels = storage.levels()?;
+            Ok(levels.iter().map(|tables| tables.iter().map(|table| table.partitioned_index).collect()).collect())
+        };
+
+        let storage = builder().build()?;
+        for i in 0..2000 {
+            storage.insert(format!("key-{:04}", i), b"value".to_vec())?;
+            if i == 10 {
+                storage.flush()?;
+            }
+        }
+        storage.remove("key-0500".to_owned())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(partitioned(&storage)?, [vec![false, true], vec![]]);
+
+        storage.compact()?;
+        assert_eq!(partitioned(&storage)?, [vec![], vec![true]]);
+        drop(storage);
+
+        // Opening reads the index the sstable persists, which tells whether it is partitioned,
+        // whatever the size set now.
+        let storage = test.storage_builder().build()?;
+        assert_eq!(partitioned(&storage)?, [vec![], vec![true]]);
+
+        assert_eq!(storage.read("key-0000")?, Some(b"value".to_vec()));
+        assert_eq!(storage.read("key-1999")?, Some(b"value".to_vec()));
+        assert_eq!(storage.read("key-0500")?, None);
+        assert_eq!(storage.read("key-2000")?, None);
+        assert_eq!(storage.count("key-0400".."key-0600")?, 199);
+
+        let keys: Vec<_> = storage.iter_from("key-1990")?.map(|entry| Ok(entry?.0)).collect::<Result<_>>()?;
+        assert_eq!(keys, (1990..2000).map(|i| format!("key-{:04}", i)).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn values_below_the_minimum_size_are_stored_uncompressed() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .compression(0, Compression::Lz4)
+            .compression(1, Compression::Zstd(19))
+            .min_compressed_value_size(100)
+            .threshold(64)
+            .build()?;
+        let threshold = storage.config.threshold;
+        let small = b"small ".repeat(16);
+        let large = b"large ".repeat(1000);
+
+        // Whether the sstables hold the value as is.
+        let stored_as_is = |value: &[u8]| -> Result<bool> {
+            let engine = storage.engine.lock().unwrap();
+            let sstables = engine.version.sstables0.iter().chain(&engine.version.sstables1);
+            for sstable in sstables {
+                if std::fs::read(sstable.path())?.windows(value.len()).any(|window| window == value) {
+                    return Ok(true);
+                }
+            }
+
+            Ok(false)
+        };
+
+        storage.insert("small".to_owned(), small.clone())?;
+        storage.insert("large".to_owned(), large.clone())?;
+        for i in 0..threshold * 2 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+        storage.tick()?;
+        assert!(stored_as_is(&small)?);
+        assert!(!stored_as_is(&large)?);
+
+        storage.compact()?;
+        assert!(stored_as_is(&small)?);
+        assert!(!stored_as_is(&large)?);
+
+        assert_eq!(storage.read("small")?, Some(small));
+        assert_eq!(storage.read("large")?, Some(large));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_write_and_space_amplification() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .compaction_interval(Duration::ZERO)
+            .threshold(64)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        for i in 0..threshold * 2 {
+            storage.insert(format!("key-{:03}", i), b"value".to_vec())?;
+        }
+
+        let stats = storage.stats()?;
+        assert_eq!(stats.user_bytes_written, (threshold * 2 * 12) as u64);
+        assert_eq!(stats.flush_bytes_written, 0);
+        assert_eq!(stats.space_amplification(), 0.0);
+
+        storage.tick()?;
+
+        let stats = storage.stats()?;
+        assert!(stats.flush_bytes_written > 0);
+        assert_eq!(stats.compaction_bytes_written, stats.last_level_bytes);
+        assert_eq!(stats.sstable_bytes, stats.last_level_bytes);
+        assert_eq!(stats.space_amplification(), 1.0);
+        assert!(stats.write_amplification() > 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_latency_percentiles() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .compaction_interval(Duration::ZERO)
+            .threshold(64)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        for i in 0..threshold * 2 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+        storage.tick()?;
+        storage.read("key-0")?;
+
+        let stats = storage.stats()?;
+        assert_eq!(stats.put.count, (threshold * 2) as u64);
+        assert_eq!(stats.get.count, 1);
+        assert_eq!(stats.flush.count, 2);
+        assert_eq!(stats.compaction.count, 1);
+
+        for latencies in [stats.get, stats.put, stats.flush, stats.compaction] {
+            assert!(latencies.p50 <= latencies.p95);
+            assert!(latencies.p95 <= latencies.p99);
+            assert!(latencies.p99 <= latencies.p999);
+            assert!(latencies.p999 <= latencies.max);
+            assert!(latencies.max > Duration::ZERO);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_the_reads_and_writes_of_each_tenant() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .tenant_of(|key| key.split_once('/').map(|(tenant, _)| tenant))
+            .build()?;
+
+        storage.insert("a/1".to_owned(), b"value".to_vec())?;
+        storage.insert("a/2".to_owned(), b"value".to_vec())?;
+        storage.remove("b/1".to_owned())?;
+        storage.insert("untenanted".to_owned(), b"value".to_vec())?;
+
+        storage.read("a/1")?;
+        storage.read("untenanted")?;
+        storage.multi_get(&["b/1", "a/2", "a/3"], &ReadOptions::default())?;
+
+        let tenants = storage.stats()?.tenants;
+        assert_eq!(tenants.len(), 2);
+
+        let a = TenantStats { reads: 3, read_bytes: 10, writes: 2, write_bytes: 16 };
+        let b = TenantStats { reads: 1, read_bytes: 0, writes: 1, write_bytes: 3 };
+        assert_eq!(tenants["a"], a);
+        assert_eq!(tenants["b"], b);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_the_background_tasks() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .scheduling(Scheduling::Background)
+            .max_background_jobs(2)
+            .dedicated_flush_thread(true)
+            .scrub_interval(Duration::from_secs(60))
+            .build()?;
+
+        let stats = storage.stats()?;
+        assert_eq!(stats.background_error, None);
+
+        let names: Vec<_> = stats.tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, ["lsm-scrubber", "lsm-worker-0", "lsm-worker-1", "lsm-flush", "lsm-dispatcher"]);
+        for task in &stats.tasks {
+            assert_eq!(task.state, TaskState::Running, "{}", task.name);
+            assert_eq!(task.restarts, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_stall_while_too_many_memtables_are_frozen() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().max_frozen_memtables(2).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2);
+        assert_eq!(storage.stats()?.frozen_memtables, 2);
+
+        // Nothing persists them until a tick with manual scheduling, so writes fail.
+        let error = storage.insert("key".to_owned(), b"value".to_vec()).unwrap_err();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::WriteStalled { frozen: 2 }));
+        assert!(storage.remove("key-0".to_owned()).is_err());
+        assert_eq!(storage.read("key-0")?, Some(b"value-0".to_vec()));
+
+        storage.tick()?;
+        assert_eq!(storage.stats()?.frozen_memtables, 0);
+        storage.insert("key".to_owned(), b"value".to_vec())?;
+
+        // With background work, writes wait for the flushes instead.
+        drop(storage);
+        let mut storage = test
+            .storage_builder()
+            .scheduling(Scheduling::Background)
+            .max_frozen_memtables(1)
+            .build()?;
+        for i in 0..5 {
+            inject_rows(&mut storage, threshold * i..threshold * (i + 1));
+            assert!(storage.stats()?.frozen_memtables <= 1);
+        }
+        for i in [0, threshold, threshold * 5 - 1] {
+            assert_eq!(Some(format!("value-{}", i).into_bytes()), storage.read(&format!("key-{}", i))?);
+        }
+        assert_eq!(storage.read("key")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_are_throttled_to_the_write_rate_limits() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().write_ops_per_sec(20).build()?;
+
+        // A second worth of writes goes through at once, and the next ones wait for the rest.
+        let start = Instant::now();
+        for i in 0..25 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+        storage.remove_batch(["key-0".to_owned(), "key-1".to_owned()])?;
+
+        let throttle = storage.stats()?.write_throttle;
+        assert!(throttle >= Duration::from_millis(200), "{:?}", throttle);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(storage.read("key-24")?, Some(b"value".to_vec()));
+        assert_eq!(storage.read("key-0")?, None);
+
+        assert_eq!(Test::new()?.create_storage()?.stats()?.write_throttle, Duration::ZERO);
+
+        Ok(())
+    }
+
+    #[test]
+    fn light_workloads_shrink_the_tuned_memtables_and_l0_trigger() -> Result<()> {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<TuningInfo>>);
+
+        impl EventListener for Recorder {
+            fn on_tuning(&self, info: &TuningInfo) {
+                self.0.lock().unwrap().push(info.clone());
+            }
+        }
+
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        let recorder = Arc::new(Recorder::default());
+        let mut storage = test
+            .storage_builder()
+            .clock(clock.clone())
+            .auto_tune(2..=1024, 1..=4)
+            .event_listener(recorder.clone())
+            .build()?;
+        let threshold = storage.config.threshold;
+        let configured = Tuning { threshold, l0_compaction_trigger: Some(4) };
+        assert_eq!(storage.tuning(), configured);
+
+        // The first flush only starts measuring the time between flushes.
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+        assert_eq!(storage.tuning(), configured);
+
+        // A flush taking a sliver of the time since the previous one shrinks both.
+        clock.advance(Duration::from_secs(60));
+        inject_rows(&mut storage, threshold..threshold * 2);
+        storage.tick()?;
+
+        let tuned = Tuning { threshold: threshold / 2, l0_compaction_trigger: Some(3) };
+        assert_eq!(storage.tuning(), tuned);
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].previous, events[0].tuning), (configured, tuned));
+        // The flush is timed by the same clock, which didn't move while flushing.
+        assert_eq!(events[0].flush_load, 0.0);
+
+        // Memtables are frozen at the tuned threshold from then on.
+        inject_rows(&mut storage, threshold * 2..threshold * 2 + threshold / 2);
+        assert_eq!(storage.stats()?.frozen_memtables, 1);
+
+        // Without bounds, the configured ones are kept.
+        assert_eq!(Test::new()?.create_storage()?.tuning(), Tuning { threshold, l0_compaction_trigger: None });
+
+        Ok(())
+    }
+
+    #[test]
+    fn purging_erases_removed_values_from_every_file() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        let secret = b"secret-".repeat(100);
+
+        // Whether any file of the storage still holds the secret.
+        let on_disk = |storage: &Storage| -> Result<bool> {
+            let paths = [&storage.config.segments_path, &storage.config.wal_path];
+            for path in paths.into_iter().flat_map(|dir| std::fs::read_dir(dir).unwrap()) {
+                let path = path?.path();
+                if path.is_file() && std::fs::read(&path)?.windows(secret.len()).any(|window| window == secret) {
+                    return Ok(true);
+                }
+            }
+
+            Ok(false)
+        };
+
+        storage.insert("user-42/email".to_owned(), secret.clone())?;
+        storage.insert("user-42/name".to_owned(), secret.clone())?;
+        inject_rows(&mut storage, 0..threshold * 2);
+        storage.tick()?;
+        storage.compact()?;
+
+        storage.remove_batch(["user-42/email".to_owned(), "user-42/name".to_owned()])?;
+        assert_eq!(storage.read("user-42/email")?, None);
+        assert!(on_disk(&storage)?);
+
+        // Nothing in L0 overlaps a range without writes, so nothing is rewritten.
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(storage.purge_deleted("user-43/".."user-430")?, PurgeReport::default());
+
+        let report = storage.purge_deleted("user-42/".."user-420")?;
+        assert!(report.sstables_rewritten >= 2, "{:?}", report);
+        assert!(report.bytes_reclaimed >= secret.len() as u64, "{:?}", report);
+        assert!(!on_disk(&storage)?);
+
+        assert_eq!(storage.read("user-42/name")?, None);
+        assert_eq!(storage.read("key-0")?, Some(b"value-0".to_vec()));
+
+        // The memtables are persisted along the way.
+        storage.remove("key-0".to_owned())?;
+        assert_eq!(storage.purge_deleted(..)?.sstables_rewritten, 2);
+        assert_eq!(storage.stats()?.frozen_memtables, 0);
+        assert_eq!(storage.read("key-0")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn purging_removes_the_archived_wals_holding_writes_to_the_range() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().wal_archive_size(1 << 20).build()?;
+        let archived = |storage: &Storage| wal::archived(storage.config.env.as_ref(), &storage.config.wal_path);
+
+        storage.insert("other".to_owned(), b"value".to_vec())?;
+        storage.flush()?;
+        storage.insert("user-42/email".to_owned(), b"secret".to_vec())?;
+        storage.remove("user-42/email".to_owned())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(archived(&storage)?.len(), 2);
+
+        let report = storage.purge_deleted("user-42/".."user-420")?;
+        assert_eq!(report.wals_removed, 1);
+        let kept = archived(&storage)?;
+        assert_eq!(kept.len(), 1);
+        assert!(!std::fs::read(&kept[0].1)?.windows(6).any(|window| window == b"secret"));
+
+        assert_eq!(storage.purge_deleted("user-42/".."user-420")?.wals_removed, 0);
+        assert_eq!(storage.read("other")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn purging_only_compacts_the_l0_sstables_sharing_keys_with_the_range() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+        let persist = |writes: &[(&str, Option<&str>)]| -> Result<()> {
+            for (key, value) in writes {
+                match value {
+                    Some(value) => storage.insert(key.to_string(), value.as_bytes().to_vec())?,
+                    None => storage.remove(key.to_string())?,
+                }
+            }
+            storage.flush()?;
+            storage.tick()?;
+            Ok(())
+        };
+
+        // The oldest sstable misses the range, but holds an older value of a key of the one that
+        // overlaps it, so both are compacted. The newest one shares no keys with them.
+        persist(&[("b", Some("old")), ("c", Some("old")), ("d", Some("old"))])?;
+        persist(&[("a", None), ("c", Some("new"))])?;
+        persist(&[("x", Some("new"))])?;
+
+        let report = storage.purge_deleted("a"..="a")?;
+        assert_eq!(report.sstables_rewritten, 2);
+        assert_eq!(storage.engine.lock().unwrap().version.sstables0.len(), 1);
+        assert_eq!(storage.engine.lock().unwrap().version.sstables1.len(), 1);
+
+        assert_eq!(storage.read("a")?, None);
+        assert_eq!(storage.read("c")?, Some(b"new".to_vec()));
+        assert_eq!(storage.read("x")?, Some(b"new".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_and_compactions_work_with_direct_io() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test
+            .storage_builder()
+            .direct_io(true)
+            .compaction_interval(Duration::ZERO)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2);
+        storage.tick()?;
+
+        assert_eq!(storage.engine.lock().unwrap().version.sstables1.len(), 1);
+        for i in [0, threshold, threshold * 2 - 1] {
+            assert_eq!(Some(format!("value-{}", i).into_bytes()), storage.read(&format!("key-{}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn storage_keeps_working_after_a_thread_panics_holding_the_engine() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+        inject_rows(&mut storage, 0..threshold);
+
+        let engine = storage.engine.clone();
+        let panicked = std::thread::spawn(move || {
+            let _engine = engine.lock().unwrap();
+            panic!("holding the engine");
+        });
+        assert!(panicked.join().is_err());
+        assert!(storage.engine.is_poisoned());
+
+        inject_rows(&mut storage, threshold..threshold * 2);
+        storage.flush()?;
+        storage.tick()?;
+        storage.compact()?;
+
+        for i in [0, threshold, threshold * 2 - 1] {
+            assert_eq!(Some(format!("value-{}", i).into_bytes()), storage.read(&format!("key-{}", i))?);
+        }
+        assert!(storage.stats()?.background_error.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_reports_describe_what_opening_the_storage_recovered() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+        inject_rows(&mut storage, threshold..threshold * 2 + 3);
+        drop(storage);
+
+        // A crash tore the last write, and left an sstable the manifest doesn't record.
+        let wal = test.test_path().join(format!("{}-2", WAL_NAME));
+        let mut contents = std::fs::read(&wal)?;
+        contents.extend_from_slice(b"torn");
+        std::fs::write(&wal, contents)?;
+        std::fs::write(test.test_path().join(format!("{}-stray", SEGMENTS_NAME)), b"stray")?;
+
+        let storage = test
+            .storage_builder()
+            .verify_on_open(VerifyLevel::Manifest)
+            .repair(true)
+            .build()?;
+        let report = storage.recovery_report();
+
+        let replayed: Vec<_> = report.wals.iter().map(|wal| (wal.entries, wal.skipped, wal.truncated_bytes)).collect();
+        assert_eq!(replayed, [(threshold, 0, 0), (3, 0, 4)]);
+        assert_eq!(report.wals[1].path, wal);
+        assert!(report.removed_wals.is_empty());
+        assert_eq!(report.sstables, [1, 0]);
+        assert_eq!(report.quarantined, [format!("{}-stray", SEGMENTS_NAME)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn memtables_name_their_files_after_an_id_that_resumes_on_open() -> Result<()> {
+        let test = Test::new()?;
+        let names = |prefix: &str| -> Result<Vec<String>> {
+            let mut names = Vec::new();
+            for entry in std::fs::read_dir(test.test_path())? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if name.starts_with(prefix) {
+                    names.push(name);
+                }
+            }
+            names.sort();
+            Ok(names)
+        };
+
+        let storage = test.create_storage()?;
+        storage.insert("key".to_owned(), b"first".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(names(SEGMENTS_NAME)?, [format!("{}-0", SEGMENTS_NAME)]);
+        assert_eq!(names(WAL_NAME)?, [format!("{}-1", WAL_NAME)]);
+        drop(storage);
+
+        // Reopened, the storage goes on from the id of the memtable it recovered, rather than
+        // reusing the id, and the name, of an older one.
+        let storage = test.create_storage()?;
+        storage.insert("key".to_owned(), b"second".to_vec())?;
+        storage.flush()?;
+        storage.tick()?;
+        assert_eq!(names(SEGMENTS_NAME)?, [format!("{}-0", SEGMENTS_NAME), format!("{}-1", SEGMENTS_NAME)]);
+        assert_eq!(names(WAL_NAME)?, [format!("{}-2", WAL_NAME)]);
+        drop(storage);
+
+        // The sstables are loaded back from the oldest to the newest.
+        let storage = test.create_storage()?;
+        assert_eq!(storage.read("key")?, Some(b"second".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn held_versions_are_unchanged_by_flushes_and_compactions() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold * 2);
+        storage.flush()?;
+        storage.tick()?;
+
+        let held = storage.engine.lock().unwrap().version.clone();
+        assert!(!held.sstables0.is_empty());
+        assert!(held.sstables1.is_empty());
+
+        inject_rows(&mut storage, threshold * 2..threshold * 3);
+        storage.flush()?;
+        storage.compact()?;
+
+        let current = storage.engine.lock().unwrap().version.clone();
+        assert!(current.sstables0.is_empty());
+        assert!(!current.sstables1.is_empty());
+
+        // The held version still lists the tables it had, and its readers still read them.
+        assert!(!held.sstables0.is_empty());
+        assert!(held.sstables1.is_empty());
+        assert_eq!(held.sstable_readers0.iter().map(|reader| reader.len()).sum::<usize>(), threshold * 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_verifying_checksums_report_corrupt_entries() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+
+        let path = storage.engine.lock().unwrap().version.sstables0[0].path().to_path_buf();
+        let mut contents = std::fs::read(&path)?;
+        let position = contents.windows(9).position(|window| window == b"value-500").unwrap();
+        contents[position + 6] = b'9';
+        std::fs::write(&path, contents)?;
+
+        let options = ReadOptions { verify_checksums: true };
+        let error = storage.read_with("key-500", &options).unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Corruption { path: corrupt_path, .. }) => assert_eq!(*corrupt_path, path),
+            _ => panic!("unexpected error: {}", error),
+        }
+
+        assert_eq!(Some(b"value-900".to_vec()), storage.read_with("key-500", &ReadOptions::default())?);
+        assert_eq!(Some(b"value-900".to_vec()), storage.read("key-500")?);
+        assert_eq!(Some(b"value-501".to_vec()), storage.read_with("key-501", &options)?);
+
+        // Verified reads always validate the checksums, and return the one of the value.
+        assert!(storage.read_verified("key-500").is_err());
+        let verified = storage.read_verified("key-501")?.unwrap();
+        assert_eq!(verified.value, b"value-501");
+        assert_eq!(verified.checksum, crc32fast::hash(b"value-501"));
+        assert_eq!(storage.read_verified("absent")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_of_storages_verifying_checksums_fail_on_corrupt_entries() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().verify_checksums(true).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+
+        let path = storage.engine.lock().unwrap().version.sstables0[0].path().to_path_buf();
+        let mut contents = std::fs::read(&path)?;
+        let position = contents.windows(9).position(|window| window == b"value-500").unwrap();
+        contents[position + 6] = b'9';
+        std::fs::write(&path, contents)?;
+
+        let error = storage.read("key-500").unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Corruption { path: corrupt_path, offset, .. }) => {
+                assert_eq!(*corrupt_path, path);
+                assert!(*offset <= position as u64);
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(storage.read("key-501")?, Some(b"value-501".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn verified_reads_skip_the_row_cache_and_check_memtable_values() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.storage_builder().row_cache_capacity(1024 * 1024).build()?;
+        let threshold = storage.config.threshold;
+
+        inject_rows(&mut storage, 0..threshold);
+        storage.tick()?;
+
+        let path = storage.engine.lock().unwrap().version.sstables0[0].path().to_path_buf();
+        let mut contents = std::fs::read(&path)?;
+        let position = contents.windows(9).position(|window| window == b"value-500").unwrap();
+        contents[position + 6] = b'9';
+        std::fs::write(&path, contents)?;
+
+        // The corrupt value is cached by a read that doesn't verify it, but verified reads neither
+        // take it from the cache nor evict the rows they read.
+        assert_eq!(storage.read("key-500")?, Some(b"value-900".to_vec()));
+        assert!(storage.read_verified("key-500").is_err());
+        assert_eq!(storage.read("key-501")?, Some(b"value-501".to_vec()));
+        assert_eq!(storage.read_verified("key-501")?.unwrap().value, b"value-501");
+        {
+            let mut engine = storage.engine.lock().unwrap();
+            assert_eq!(engine.rows.get("key-500"), Some(b"value-900".to_vec()));
+            assert_eq!(engine.rows.get("key-501"), Some(b"value-501".to_vec()));
+        }
+
+        // Values in the memtables are checked against the checksum computed when written.
+        storage.insert("fresh".to_owned(), b"fresh-value".to_vec())?;
+        assert_eq!(storage.read_verified("fresh")?.unwrap().checksum, crc32fast::hash(b"fresh-value"));
+        {
+            let mut engine = storage.engine.lock().unwrap();
+            let tree = Arc::make_mut(&mut engine.active_memtable.tree);
+            tree.get_mut(b"fresh".as_slice()).unwrap().checksum ^= 1;
+            engine.rows.invalidate("fresh");
+        }
+        let error = storage.read_verified("fresh").unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::Corruption { .. })));
+        assert_eq!(storage.read("fresh")?, Some(b"fresh-value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn applied_writes_are_not_held_to_the_quotas() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.storage_builder().quota("tenant-a/", 16).build()?;
+        storage.apply(Change { key: "tenant-a/1".to_owned(), value: Some(vec![0; 64]), seqno: 1, expires_at: None })?;
+        storage.flush()?;
+        storage.tick()?;
+
+        let error = storage.insert("tenant-a/2".to_owned(), b"value".to_vec()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::QuotaExceeded { .. })));
+
+        storage.apply(Change { key: "tenant-a/2".to_owned(), value: Some(b"value".to_vec()), seqno: 2, expires_at: None })?;
+        assert_eq!(storage.read("tenant-a/2")?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn inserts_over_the_quota_of_their_prefix_fail_until_space_is_freed() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .quota("tenant-a/", 1024)
+            .compaction_interval(Duration::ZERO)
+            .build()?;
+        let threshold = storage.config.threshold;
+
+        for i in 0..threshold {
+            storage.insert(format!("tenant-a/{}", i), vec![0; 64])?;
+        }
+        storage.tick()?;
+
+        let error = storage.insert("tenant-a/new".to_owned(), b"value".to_vec()).unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::QuotaExceeded { prefix, quota }) => assert_eq!((prefix.as_str(), *quota), ("tenant-a/", 1024)),
+            _ => panic!("unexpected error: {}", error),
+        }
+        storage.insert("tenant-b/new".to_owned(), b"value".to_vec())?;
+
+        // A batch is refused whole if any of its keys is over its quota.
+        let batch = [("tenant-b/batch", b"value".to_vec()), ("tenant-a/batch", b"value".to_vec())];
+        let error = storage.insert_batch(batch.map(|(key, value)| (key.to_owned(), value))).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::QuotaExceeded { .. })));
+        assert_eq!(storage.read("tenant-b/batch")?, None);
+
+        for i in 0..threshold {
+            storage.remove(format!("tenant-a/{}", i))?;
+        }
+        storage.tick()?;
+        storage.insert("tenant-a/new".to_owned(), b"value".to_vec())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_pages_see_the_storage_as_the_first_page_did() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        for i in 0..10 {
+            storage.insert(format!("key-{}", i), b"old".to_vec())?;
+        }
+        storage.flush()?;
+        storage.tick()?;
+
+        let page = storage.scan("", 4, None)?;
+        let keys: Vec<_> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["key-0", "key-1", "key-2", "key-3"]);
+
+        storage.insert("key-5".to_owned(), b"new".to_vec())?;
+        storage.remove("key-6".to_owned())?;
+        storage.insert("key-4a".to_owned(), b"new".to_vec())?;
+
+        let page = storage.scan("", 4, page.continuation.as_deref())?;
+        let expected = [("key-4", "old"), ("key-4a", "new"), ("key-5", "old"), ("key-6", "old")];
+        let expected: Vec<_> = expected.iter().map(|(key, value)| (key.to_string(), value.as_bytes().to_vec())).collect();
+        assert_eq!(page.entries, expected);
+
+        let page = storage.scan("", 4, page.continuation.as_deref())?;
+        assert_eq!(page.entries.len(), 3);
+        assert_eq!(page.continuation, None);
+
+        let error = storage.scan("", 4, Some("not-a-token")).unwrap_err();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::InvalidContinuation));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_listed_by_prefix_from_the_one_after_the_given_key() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        for key in ["a", "b/1", "b/2", "b/3", "c"] {
+            storage.insert(key.to_owned(), b"value".to_vec())?;
+        }
+        storage.flush()?;
+        storage.tick()?;
+        storage.remove("b/2".to_owned())?;
+
+        let keys = |prefix, after| storage.keys(prefix, after)?.collect::<Result<Vec<_>>>();
+        assert_eq!(keys("b/", None)?, ["b/1", "b/3"]);
+        assert_eq!(keys("b/", Some("b/1"))?, ["b/3"]);
+        assert_eq!(keys("b/", Some("a"))?, ["b/1", "b/3"]);
+        assert!(keys("b/", Some("b/3"))?.is_empty());
+        assert_eq!(keys("", Some("b/3"))?, ["c"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_pages_end_once_they_run_out_of_bytes_or_time() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        for i in 0..10 {
+            storage.insert(format!("key-{}", i), b"value".to_vec())?;
+        }
+
+        // Each entry takes 10 bytes, so the third one goes past the limit.
+        let options = ScanOptions {
+            max_bytes: Some(25),
+            ..ScanOptions::default()
+        };
+        let page = storage.scan_with("", None, &options)?;
+        assert_eq!(page.entries.len(), 3);
+
+        let page = storage.scan_with("", page.continuation.as_deref(), &options)?;
+        assert_eq!(page.entries[0].0, "key-3");
+
+        let options = ScanOptions {
+            max_keys: Some(5),
+            deadline: Some(Instant::now()),
+            ..ScanOptions::default()
+        };
+        let page = storage.scan_with("key-8", None, &options)?;
+        assert_eq!(page.entries.len(), 1);
+        let page = storage.scan_with("", page.continuation.as_deref(), &options)?;
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.continuation, None);
+
+        let page = storage.scan_with("", None, &ScanOptions::default())?;
+        assert_eq!(page.entries.len(), 10);
+        assert_eq!(page.continuation, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_yields_updates_and_removals_of_the_key() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        let mut updates = storage.watch("key-1");
+
+        storage.insert("key-1".to_owned(), b"value-1".to_vec())?;
+        storage.insert("key-2".to_owned(), b"value-2".to_vec())?;
+        storage.remove("key-1".to_owned())?;
+
+        assert_eq!(updates.next().await, Some(Some(b"value-1".to_vec())));
+        assert_eq!(updates.next().await, Some(None));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriptions_deliver_the_changes_of_the_prefix_and_resume_from_the_memtables() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        let mut changes = storage.subscribe("a/", None)?;
+        storage.insert("a/1".to_owned(), b"value-1".to_vec())?;
+        storage.insert("b/1".to_owned(), b"value-1".to_vec())?;
+        storage.remove_batch(["a/2".to_owned(), "a/1".to_owned()])?;
+
+        let change = |key: &str, value: Option<&[u8]>, seqno| Change {
+            key: key.to_owned(),
+            value: value.map(|value| value.to_vec()),
+            seqno,
+            expires_at: None,
+        };
+        assert_eq!(changes.next().await.transpose()?, Some(change("a/1", Some(b"value-1"), 1)));
+        assert_eq!(changes.next().await.transpose()?, Some(change("a/2", None, 3)));
+        assert_eq!(changes.next().await.transpose()?, Some(change("a/1", None, 4)));
+
+        // Only the last change of each key after the given one is replayed.
+        storage.flush()?;
+        storage.insert("a/3".to_owned(), b"value-3".to_vec())?;
+        let mut resumed = storage.subscribe("a/", Some(1))?;
+        storage.insert("a/4".to_owned(), b"value-4".to_vec())?;
+
+        assert_eq!(resumed.next().await.transpose()?, Some(change("a/2", None, 3)));
+        assert_eq!(resumed.next().await.transpose()?, Some(change("a/1", None, 4)));
+        assert_eq!(resumed.next().await.transpose()?, Some(change("a/3", Some(b"value-3"), 5)));
+        assert_eq!(resumed.next().await.transpose()?, Some(change("a/4", Some(b"value-4"), 6)));
+
+        storage.tick()?;
+        let error = storage.subscribe("a/", Some(1)).err().unwrap();
+        assert_eq!(error.downcast_ref::<Error>(), Some(&Error::ChangesUnavailable { after: 1 }));
+        assert!(storage.subscribe("a/", Some(4)).is_ok());
 
         Ok(())
     }
@@ -0,0 +1,152 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::stats::{TaskState, TaskStatus};
+
+/// How many times a task is restarted before it is left failed.
+const MAX_RESTARTS: u32 = 5;
+
+/// Owns the background threads of the storage, keeping track of how each is doing.
+///
+/// A task runs until it returns. One that fails or panics is restarted after its restart delay, if
+/// it has one, unless it was restarted [`MAX_RESTARTS`] times already. Either way, the failure is
+/// left for [`Supervisor::status`] to report.
+#[derive(Clone, Default)]
+pub(crate) struct Supervisor {
+    tasks: Arc<Mutex<Vec<TaskStatus>>>,
+}
+
+impl Supervisor {
+    /// Runs the task in a thread named after it.
+    pub fn spawn<F>(&self, name: &str, restart_delay: Option<Duration>, mut task: F) -> Result<()>
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let index = {
+            let mut tasks = self.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+            tasks.push(TaskStatus {
+                name: name.to_owned(),
+                state: TaskState::Running,
+                restarts: 0,
+                last_error: None,
+            });
+            tasks.len() - 1
+        };
+        let tasks = self.tasks.clone();
+
+        thread::Builder::new().name(name.to_owned()).spawn(move || {
+            let mut failures = 0;
+
+            loop {
+                let result = panic::catch_unwind(AssertUnwindSafe(&mut task))
+                    .unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&panic))));
+
+                let mut tasks = tasks.lock().unwrap_or_else(PoisonError::into_inner);
+                let status = &mut tasks[index];
+
+                let error = match result {
+                    Ok(()) => {
+                        status.state = TaskState::Stopped;
+                        return;
+                    }
+                    Err(error) => error,
+                };
+
+                failures += 1;
+                status.last_error = Some(format!("{:#}", error));
+
+                let delay = match restart_delay {
+                    Some(delay) if failures <= MAX_RESTARTS => delay,
+                    _ => {
+                        status.state = TaskState::Failed;
+                        return;
+                    }
+                };
+
+                status.restarts += 1;
+                drop(tasks);
+                thread::sleep(delay);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// The status of every task spawned, in the order they were.
+    pub fn status(&self) -> Vec<TaskStatus> {
+        self.tasks.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+/// The message a thread panicked with, if it is a string.
+pub(crate) fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use anyhow::{bail, Result};
+
+    use super::{Supervisor, MAX_RESTARTS};
+    use crate::stats::{TaskState, TaskStatus};
+
+    fn wait_for(supervisor: &Supervisor, name: &str, state: TaskState) -> TaskStatus {
+        let start = Instant::now();
+
+        loop {
+            let status = supervisor.status().into_iter().find(|status| status.name == name).unwrap();
+            if status.state == state {
+                return status;
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(5), "{} is {:?}", name, status.state);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn failed_tasks_are_restarted_until_they_fail_too_often() -> Result<()> {
+        let supervisor = Supervisor::default();
+        let delay = Some(Duration::from_millis(1));
+
+        let runs = Arc::new(AtomicU32::new(0));
+        let flaky = runs.clone();
+        supervisor.spawn("flaky", delay, move || match flaky.fetch_add(1, Ordering::SeqCst) {
+            0 => bail!("first failure"),
+            1 => panic!("second failure"),
+            _ => Ok(()),
+        })?;
+
+        supervisor.spawn("broken", delay, || bail!("always failing"))?;
+        supervisor.spawn("fragile", None, || panic!("never restarted"))?;
+
+        let flaky = wait_for(&supervisor, "flaky", TaskState::Stopped);
+        assert_eq!(flaky.restarts, 2);
+        assert_eq!(flaky.last_error.as_deref(), Some("panicked: second failure"));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        let broken = wait_for(&supervisor, "broken", TaskState::Failed);
+        assert_eq!(broken.restarts, MAX_RESTARTS);
+        assert_eq!(broken.last_error.as_deref(), Some("always failing"));
+
+        let fragile = wait_for(&supervisor, "fragile", TaskState::Failed);
+        assert_eq!(fragile.restarts, 0);
+        assert_eq!(fragile.last_error.as_deref(), Some("panicked: never restarted"));
+
+        Ok(())
+    }
+}
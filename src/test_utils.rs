@@ -1,8 +1,9 @@
-use crate::engine::Engine;
+use crate::env::{Env, OsEnv};
 use crate::format;
 use crate::memtable::MemTable;
 use crate::sstable::SSTable;
-use crate::storage::Storage;
+use crate::scheduler::Scheduling;
+use crate::storage::{Storage, StorageBuilder};
 use crate::Stored;
 
 use anyhow::Ok;
@@ -13,44 +14,80 @@ use tempfile::TempDir;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 static WAL_PATH: &str = "write-ahead-log";
 static SSTABLE_PATH: &str = "sstable";
 
 pub struct Test {
     tempdir: TempDir,
+    env: Arc<dyn Env>,
 }
 
 impl Test {
     pub fn new() -> Result<Self> {
+        Test::with_env(Arc::new(OsEnv))
+    }
+
+    /// Creates a test whose storage performs all of its I/O through the given environment.
+    pub fn with_env(env: Arc<dyn Env>) -> Result<Self> {
         Ok(Test {
             tempdir: create_tempdir()?,
+            env,
         })
     }
 
+    pub fn env(&self) -> Arc<dyn Env> {
+        self.env.clone()
+    }
+
     pub fn create_memtable(&self) -> Result<MemTable> {
         let wal_path = self.wal_path();
 
-        Ok(MemTable::new(0, &wal_path)?)
+        Ok(MemTable::new(&self.env, 0, &wal_path, 1, 0, false)?)
     }
 
     pub(crate) fn generate_sstable(
         &self,
         name: &str,
         values: &[(String, Stored)],
+    ) -> Result<SSTable> {
+        let values: Vec<_> = values
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone(), 0))
+            .collect();
+
+        self.generate_sequenced_sstable(name, &values)
+    }
+
+    /// Generates a sstable whose entries carry the given sequence numbers.
+    pub(crate) fn generate_sequenced_sstable(
+        &self,
+        name: &str,
+        values: &[(String, Stored, u64)],
     ) -> Result<SSTable> {
         let path = self.path(&format!("{}-{}", SSTABLE_PATH, name));
         let mut fd = File::create(path.clone())?;
 
-        for (key, value) in values {
-            format::write_entry(&mut fd, key, value)?;
+        for (key, value, seqno) in values {
+            format::write_entry(&mut fd, key, value, *seqno)?;
         }
 
-        Ok(SSTable::new(&path))
+        Ok(SSTable::new(&self.env, &path))
     }
 
     pub fn create_storage(&self) -> Result<Storage> {
-        Storage::builder().segments_path(self.test_path()).build()
+        self.storage_builder().build()
+    }
+
+    /// A builder for a storage kept in the test directory. Background work only runs when the
+    /// test calls `tick`.
+    pub fn storage_builder(&self) -> StorageBuilder {
+        Storage::builder()
+            .segments_path(self.test_path())
+            .wal_path(self.test_path())
+            .env(self.env.clone())
+            .scheduling(Scheduling::Manual)
     }
 
     pub fn corrupt_wal(&self) -> Result<()> {
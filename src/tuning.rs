@@ -0,0 +1,157 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use crate::events::TuningInfo;
+
+/// The share of the time between two flushes spent flushing past which writes are deemed to
+/// outrun them.
+const BUSY_LOAD: f64 = 0.5;
+
+/// The share of the time between two flushes spent flushing below which writes are deemed to be
+/// light.
+const IDLE_LOAD: f64 = 0.1;
+
+/// The bounds within which the storage tunes itself. See
+/// [`StorageBuilder::auto_tune`](crate::storage::StorageBuilder::auto_tune).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoTune {
+    /// The fewest and most entries a memtable may hold before it is frozen.
+    pub threshold: RangeInclusive<usize>,
+    /// The fewest and most sstables L0 may hold before it is compacted.
+    pub l0_compaction_trigger: RangeInclusive<usize>,
+}
+
+/// The settings the storage tunes, as they are now. See
+/// [`Storage::tuning`](crate::storage::Storage::tuning).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    /// How many entries a memtable holds before it is frozen.
+    pub threshold: usize,
+    /// How many sstables L0 holds before it is compacted without waiting for the interval, if
+    /// there is a limit.
+    pub l0_compaction_trigger: Option<usize>,
+}
+
+/// Adjusts the [`Tuning`] after every flush, from how much of the time since the previous one it
+/// took: if flushes keep the background busy, memtables grow so that there are fewer of them to
+/// flush, and L0 is let to hold more sstables so that compactions run less often. If they barely
+/// do, both shrink back, so that memtables take less memory and reads look into fewer sstables.
+/// Each flush moves them one step at most: the threshold doubles or halves, the trigger goes up
+/// or down by one.
+pub(crate) struct Tuner {
+    bounds: AutoTune,
+    /// When the last flush finished, by the clock of the storage.
+    last_flush: Option<Duration>,
+}
+
+impl Tuner {
+    /// Creates a tuner within the given bounds, along with the tuning it starts from: the
+    /// configured one, brought within them.
+    pub fn new(bounds: AutoTune, threshold: usize, l0_compaction_trigger: Option<usize>) -> (Self, Tuning) {
+        let tuning = Tuning {
+            threshold: clamp(threshold, &bounds.threshold),
+            l0_compaction_trigger: Some(clamp(
+                l0_compaction_trigger.unwrap_or(*bounds.l0_compaction_trigger.end()),
+                &bounds.l0_compaction_trigger,
+            )),
+        };
+
+        (Tuner { bounds, last_flush: None }, tuning)
+    }
+
+    /// Adjusts the tuning after a flush that finished at `now`, having taken `duration`. Returns
+    /// what changed, if anything did.
+    pub fn observe(&mut self, tuning: &mut Tuning, now: Duration, duration: Duration) -> Option<TuningInfo> {
+        let interval = now.saturating_sub(self.last_flush.replace(now)?);
+        if interval.is_zero() {
+            return None;
+        }
+
+        let flush_load = duration.as_secs_f64() / interval.as_secs_f64();
+        let trigger = tuning.l0_compaction_trigger.unwrap_or(*self.bounds.l0_compaction_trigger.end());
+        let (threshold, trigger) = if flush_load >= BUSY_LOAD {
+            (tuning.threshold.saturating_mul(2), trigger.saturating_add(1))
+        } else if flush_load <= IDLE_LOAD {
+            (tuning.threshold / 2, trigger.saturating_sub(1))
+        } else {
+            return None;
+        };
+
+        let tuned = Tuning {
+            threshold: clamp(threshold, &self.bounds.threshold),
+            l0_compaction_trigger: Some(clamp(trigger, &self.bounds.l0_compaction_trigger)),
+        };
+        if tuned == *tuning {
+            return None;
+        }
+
+        let previous = std::mem::replace(tuning, tuned);
+        Some(TuningInfo {
+            previous,
+            tuning: tuned,
+            flush_load,
+        })
+    }
+}
+
+/// Brings a value within the bounds, at least one, which also holds for bounds that are empty.
+fn clamp(value: usize, bounds: &RangeInclusive<usize>) -> usize {
+    value.min(*bounds.end()).max(*bounds.start()).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuner() -> (Tuner, Tuning) {
+        let bounds = AutoTune {
+            threshold: 100..=800,
+            l0_compaction_trigger: 2..=4,
+        };
+
+        Tuner::new(bounds, 1000, None)
+    }
+
+    #[test]
+    fn busy_flushes_grow_the_memtables_and_the_l0_trigger_within_bounds() {
+        let (mut tuner, mut tuning) = tuner();
+        assert_eq!(tuning, Tuning { threshold: 800, l0_compaction_trigger: Some(4) });
+
+        // The first flush only starts the measurement.
+        assert_eq!(tuner.observe(&mut tuning, Duration::from_secs(10), Duration::from_secs(1)), None);
+
+        // Halfway between busy and idle, nothing changes.
+        assert_eq!(tuner.observe(&mut tuning, Duration::from_secs(20), Duration::from_secs(3)), None);
+
+        let info = tuner.observe(&mut tuning, Duration::from_secs(30), Duration::from_millis(500)).unwrap();
+        assert_eq!(info.previous, Tuning { threshold: 800, l0_compaction_trigger: Some(4) });
+        assert_eq!(info.tuning, Tuning { threshold: 400, l0_compaction_trigger: Some(3) });
+        assert_eq!(info.flush_load, 0.05);
+        assert_eq!(tuning, info.tuning);
+
+        for _ in 0..5 {
+            tuner.observe(&mut tuning, tuner.last_flush.unwrap() + Duration::from_secs(10), Duration::ZERO);
+        }
+        assert_eq!(tuning, Tuning { threshold: 100, l0_compaction_trigger: Some(2) });
+
+        let info = tuner.observe(&mut tuning, Duration::from_secs(100), Duration::from_secs(10)).unwrap();
+        assert_eq!(info.tuning, Tuning { threshold: 200, l0_compaction_trigger: Some(3) });
+        for _ in 0..5 {
+            tuner.observe(&mut tuning, tuner.last_flush.unwrap() + Duration::from_secs(1), Duration::from_secs(1));
+        }
+        assert_eq!(tuning, Tuning { threshold: 800, l0_compaction_trigger: Some(4) });
+    }
+
+    #[test]
+    fn nothing_is_reported_without_changes_or_elapsed_time() {
+        let (mut tuner, mut tuning) = tuner();
+        tuner.observe(&mut tuning, Duration::from_secs(10), Duration::ZERO);
+
+        // The clock didn't move, e.g. a manual one in tests.
+        assert_eq!(tuner.observe(&mut tuning, Duration::from_secs(10), Duration::from_secs(1)), None);
+
+        // Already as large as allowed.
+        assert_eq!(tuner.observe(&mut tuning, Duration::from_secs(11), Duration::from_secs(1)), None);
+        assert_eq!(tuning, Tuning { threshold: 800, l0_compaction_trigger: Some(4) });
+    }
+}
@@ -0,0 +1,470 @@
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::ser::{self, Serialize};
+
+use crate::storage::Storage;
+
+/// A typed view over the raw byte API of [`Storage`].
+///
+/// Keys are encoded in an order-preserving way: comparing two encoded keys yields the same result
+/// as comparing the original values. Values are serialized with bincode, the same format used for
+/// the entries stored on-disk.
+pub struct TypedStorage<K, V> {
+    storage: Storage,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedStorage<K, V>
+where
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new(storage: Storage) -> Self {
+        TypedStorage {
+            storage,
+            types: PhantomData,
+        }
+    }
+
+    /// Returns the value corresponding to the given key, if present.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = encode_key(key)?;
+
+        match self.storage.read(&key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = encode_key(key)?;
+        let value = bincode::serialize(value)?;
+
+        self.storage.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        let key = encode_key(key)?;
+
+        self.storage.remove(key)
+    }
+
+    /// Returns the underlying storage.
+    pub fn into_inner(self) -> Storage {
+        self.storage
+    }
+}
+
+impl<K, V> Clone for TypedStorage<K, V> {
+    fn clone(&self) -> Self {
+        TypedStorage {
+            storage: self.storage.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+/// Encodes a key so that the order of the encoded keys matches the order of the original values.
+///
+/// The storage keys are strings, so the order-preserving bytes are hex encoded, which keeps their
+/// order intact.
+pub fn encode_key<K: Serialize + ?Sized>(key: &K) -> Result<String> {
+    let mut serializer = KeySerializer { output: Vec::new() };
+    key.serialize(&mut serializer)?;
+
+    Ok(serializer
+        .output
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// A serde serializer producing bytes whose lexicographic order matches the order of the values.
+///
+/// - integers are written in big-endian, with the sign bit flipped for signed integers
+/// - floats have their sign bit flipped, or all their bits when negative
+/// - strings and byte arrays escape `0x00` as `0x00 0xFF` and are terminated by `0x00 0x00`, so a
+///   prefix sorts before any longer value
+/// - options, sequences and maps are prefixed with a marker so that shorter values sort first
+/// - enum variants are written as their index followed by their contents
+struct KeySerializer {
+    output: Vec<u8>,
+}
+
+impl KeySerializer {
+    fn write_escaped(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.output.push(byte);
+
+            if byte == 0 {
+                self.output.push(0xff);
+            }
+        }
+
+        self.output.extend_from_slice(&[0, 0]);
+    }
+}
+
+impl ser::Serializer for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> bincode::Result<()> {
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> bincode::Result<()> {
+        self.serialize_u8(v as u8 ^ (1 << 7))
+    }
+
+    fn serialize_i16(self, v: i16) -> bincode::Result<()> {
+        self.serialize_u16(v as u16 ^ (1 << 15))
+    }
+
+    fn serialize_i32(self, v: i32) -> bincode::Result<()> {
+        self.serialize_u32(v as u32 ^ (1 << 31))
+    }
+
+    fn serialize_i64(self, v: i64) -> bincode::Result<()> {
+        self.serialize_u64(v as u64 ^ (1 << 63))
+    }
+
+    fn serialize_i128(self, v: i128) -> bincode::Result<()> {
+        self.serialize_u128(v as u128 ^ (1 << 127))
+    }
+
+    fn serialize_u8(self, v: u8) -> bincode::Result<()> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> bincode::Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> bincode::Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> bincode::Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> bincode::Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> bincode::Result<()> {
+        let bits = v.to_bits();
+
+        if bits >> 31 == 1 {
+            self.serialize_u32(!bits)
+        } else {
+            self.serialize_u32(bits ^ (1 << 31))
+        }
+    }
+
+    fn serialize_f64(self, v: f64) -> bincode::Result<()> {
+        let bits = v.to_bits();
+
+        if bits >> 63 == 1 {
+            self.serialize_u64(!bits)
+        } else {
+            self.serialize_u64(bits ^ (1 << 63))
+        }
+    }
+
+    fn serialize_char(self, v: char) -> bincode::Result<()> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> bincode::Result<()> {
+        self.write_escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> bincode::Result<()> {
+        self.write_escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> bincode::Result<()> {
+        self.output.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> bincode::Result<()> {
+        self.output.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> bincode::Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> bincode::Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> bincode::Result<()> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> bincode::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> bincode::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> bincode::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> bincode::Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> bincode::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> bincode::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> bincode::Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.output.push(1);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        self.output.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> bincode::Result<()> {
+        self.output.push(1);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        self.output.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut KeySerializer {
+    type Ok = ();
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use anyhow::Result;
+    use serde::Serialize;
+
+    use super::{encode_key, TypedStorage};
+    use crate::test_utils::Test;
+
+    fn assert_order_is_preserved<K: Serialize + Ord + Debug>(mut keys: Vec<K>) -> Result<()> {
+        keys.sort();
+
+        let encoded = keys
+            .iter()
+            .map(encode_key)
+            .collect::<Result<Vec<String>>>()?;
+
+        for (pair, keys) in encoded.windows(2).zip(keys.windows(2)) {
+            assert!(pair[0] < pair[1], "{:?} should sort before {:?}", keys[0], keys[1]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encoding_preserves_order_of_integers() -> Result<()> {
+        assert_order_is_preserved(vec![0u64, 1, 255, 256, 65_535, u64::MAX])?;
+        assert_order_is_preserved(vec![i64::MIN, -256, -1, 0, 1, 256, i64::MAX])?;
+        assert_order_is_preserved(vec![i8::MIN, -1, 0, 1, i8::MAX])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn encoding_preserves_order_of_strings() -> Result<()> {
+        assert_order_is_preserved(vec!["", "\0", "a", "a\0", "a\0b", "ab", "b", "ba"])
+    }
+
+    #[test]
+    fn encoding_preserves_order_of_composite_keys() -> Result<()> {
+        assert_order_is_preserved(vec![("a", 2u32), ("a", 10), ("ab", 1), ("b", 0)])?;
+        assert_order_is_preserved(vec![None, Some(-5i32), Some(0), Some(7)])?;
+        assert_order_is_preserved(vec![vec![], vec![1u8], vec![1, 0], vec![1, 1], vec![2]])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_stores_serialized_values_under_encoded_keys() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+        let mut typed = TypedStorage::<(String, u32), Vec<u64>>::new(storage.clone());
+
+        typed.insert(&("user".to_owned(), 7), &vec![1, 2, 3])?;
+        typed.insert(&("user".to_owned(), 8), &vec![4])?;
+        typed.remove(&("user".to_owned(), 8))?;
+
+        let engine = storage.engine.lock().unwrap();
+        let key = encode_key(&("user", 7u32))?;
+        let value = engine.active_memtable.get(&key).unwrap();
+        assert_eq!(bincode::deserialize::<Vec<u64>>(value)?, vec![1, 2, 3]);
+
+        let removed = encode_key(&("user", 8u32))?;
+        assert_eq!(engine.active_memtable.get(&removed), None);
+
+        Ok(())
+    }
+}
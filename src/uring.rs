@@ -0,0 +1,115 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::{Context, Result};
+use io_uring::{opcode, types, IoUring};
+
+/// How many reads are submitted at once.
+const ENTRIES: u32 = 64;
+
+/// How many bytes are read at the offset of each entry a multi-get finds. The entries that take
+/// more are read again whole.
+pub(crate) const ENTRY_READ_SIZE: usize = 4096;
+
+/// Submits positioned reads through io_uring, so that the reads of a batch of lookups are sent to
+/// the device together, and overlap, instead of taking a syscall each, one after the other.
+pub(crate) struct Ring {
+    ring: Mutex<IoUring>,
+}
+
+impl Ring {
+    /// Sets up the ring. Fails if the kernel doesn't support io_uring, or doesn't allow it.
+    pub fn new() -> Result<Self> {
+        Ok(Ring {
+            ring: Mutex::new(IoUring::new(ENTRIES).context("io_uring is unavailable")?),
+        })
+    }
+
+    /// Reads up to `len` bytes at the offset of the file of each read, and returns what each one
+    /// read, which is only shorter than asked if the file ends before. Reads are submitted by up to
+    /// [`ENTRIES`] at once, and waited for together.
+    pub fn read(&self, reads: &[(RawFd, u64, usize)]) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buffers: Vec<Vec<u8>> = reads.iter().map(|&(_, _, len)| vec![0; len]).collect();
+        let mut results: Vec<Option<io::Result<usize>>> = reads.iter().map(|_| None).collect();
+
+        for first in (0..reads.len()).step_by(ENTRIES as usize) {
+            let batch = first..reads.len().min(first + ENTRIES as usize);
+
+            for index in batch.clone() {
+                let (fd, offset, len) = reads[index];
+                let entry = opcode::Read::new(types::Fd(fd), buffers[index].as_mut_ptr(), len as u32)
+                    .offset(offset)
+                    .build()
+                    .user_data(index as u64);
+
+                // SAFETY: the buffer outlives the read, as it is only dropped once the read
+                // completed, or leaked if it may still be in flight.
+                if let Err(error) = unsafe { ring.submission().push(&entry) } {
+                    // Batches are no larger than the ring and each one is waited for whole, so
+                    // this doesn't happen. Otherwise the pushed reads are submitted later on.
+                    std::mem::forget(buffers);
+                    return Err(io::Error::other(error));
+                }
+            }
+
+            if let Err(error) = ring.submit_and_wait(batch.len()) {
+                // The kernel may still write into the buffers of the reads that were submitted.
+                std::mem::forget(buffers);
+                return Err(error);
+            }
+
+            for completion in ring.completion() {
+                results[completion.user_data() as usize] = Some(match completion.result() {
+                    read if read < 0 => Err(io::Error::from_raw_os_error(-read)),
+                    read => Ok(read as usize),
+                });
+            }
+        }
+
+        Ok(buffers
+            .into_iter()
+            .zip(results)
+            .map(|(mut buffer, result)| {
+                let read = result.unwrap_or_else(|| Err(io::Error::other("read never completed")))?;
+                buffer.truncate(read);
+                Ok(buffer)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use anyhow::Result;
+
+    use super::Ring;
+    use crate::test_utils::Test;
+
+    #[test]
+    fn reads_return_the_bytes_at_their_offset() -> Result<()> {
+        // Sandboxes may not allow io_uring, which leaves nothing to test.
+        let ring = match Ring::new() {
+            Ok(ring) => ring,
+            Err(_) => return Ok(()),
+        };
+
+        let test = Test::new()?;
+        let path = test.path("file");
+        let contents: Vec<u8> = (0..10_000u32).map(|byte| byte as u8).collect();
+        std::fs::write(&path, &contents)?;
+        let file = std::fs::File::open(&path)?;
+
+        // More reads than the ring holds at once, some of which go past the end of the file.
+        let reads: Vec<_> = (0..200u64).map(|index| (file.as_raw_fd(), index * 77, 100)).collect();
+        for ((_, offset, len), read) in reads.iter().zip(ring.read(&reads)?) {
+            let offset = *offset as usize;
+            assert_eq!(read?, contents[offset.min(10_000)..(offset + len).min(10_000)]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::manifest::{self, Edit, Manifest};
+use crate::scrubber::QUARANTINE_NAME;
+use crate::sstable::SSTable;
+use crate::storage::Config;
+use crate::{Error, SEGMENTS_NAME};
+
+/// How thoroughly the storage is checked when opened. See
+/// [`StorageBuilder::verify_on_open`](crate::storage::StorageBuilder::verify_on_open).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Checks that the sstables recorded in the manifest and those in the segments path agree.
+    Manifest,
+    /// Also reads every recorded sstable whole, checking the checksum of each entry.
+    Full,
+}
+
+/// What checking the storage on open found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The sstables recorded in the manifest whose file is gone.
+    pub missing: Vec<String>,
+    /// The sstables in the segments path that the manifest doesn't record, left behind by a crash.
+    pub unrecorded: Vec<String>,
+    /// The recorded sstables that failed to verify, along with why.
+    pub corrupt: Vec<(String, String)>,
+    /// Whether the missing and corrupt sstables were removed from the manifest, and the corrupt
+    /// and unrecorded ones moved into the quarantine directory.
+    pub repaired: bool,
+}
+
+impl VerifyReport {
+    /// Whether data recorded in the manifest can't be read, so that opening the storage as is
+    /// would silently lose it. Unrecorded sstables never held anything the storage relies on.
+    pub fn is_hard_failure(&self) -> bool {
+        !self.missing.is_empty() || !self.corrupt.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} missing, {} corrupt and {} unrecorded sstables",
+            self.missing.len(),
+            self.corrupt.len(),
+            self.unrecorded.len()
+        )
+    }
+}
+
+/// Checks the storage at the given level before it is loaded. Fails with
+/// [`Error::VerificationFailed`] on a hard failure, unless repairing, in which case the manifest
+/// stops recording the sstables that can't be read, and the files that don't belong to the
+/// storage are quarantined rather than deleted.
+pub(crate) fn verify(config: &Config, level: VerifyLevel, repair: bool) -> Result<VerifyReport> {
+    let env = config.env.as_ref();
+    let mut names = Vec::new();
+
+    for path in env.read_dir(&config.segments_path)? {
+        let filename = path.file_name().unwrap().to_str().unwrap();
+
+        if filename.starts_with(SEGMENTS_NAME) {
+            names.push(filename.to_owned());
+        }
+    }
+
+    let (mut manifest, edits) = Manifest::open(&config.env, &config.segments_path, &names)?;
+    let recorded: Vec<String> = manifest::replay(edits).into_iter().flatten().collect();
+
+    let mut report = VerifyReport {
+        missing: recorded.iter().filter(|name| !names.contains(name)).cloned().collect(),
+        unrecorded: names.iter().filter(|name| !recorded.contains(name)).cloned().collect(),
+        ..VerifyReport::default()
+    };
+
+    if level == VerifyLevel::Full {
+        for name in recorded.iter().filter(|name| names.contains(name)) {
+            let sstable = SSTable::new(&config.env, &config.segments_path.join(name));
+
+            if let Err(error) = sstable.verify() {
+                report.corrupt.push((name.clone(), format!("{:#}", error)));
+            }
+        }
+    }
+
+    if !repair {
+        if report.is_hard_failure() {
+            return Err(Error::VerificationFailed { report }.into());
+        }
+
+        return Ok(report);
+    }
+
+    if report.is_hard_failure() {
+        let removed = report
+            .missing
+            .iter()
+            .chain(report.corrupt.iter().map(|(name, _)| name))
+            .cloned()
+            .collect();
+
+        manifest.record(&Edit::ReplaceTables {
+            removed,
+            added: Vec::new(),
+            level: 0,
+        })?;
+    }
+
+    let quarantined: Vec<&String> = report
+        .corrupt
+        .iter()
+        .map(|(name, _)| name)
+        .chain(report.unrecorded.iter())
+        .collect();
+
+    if !quarantined.is_empty() {
+        let quarantine = config.segments_path.join(QUARANTINE_NAME);
+        env.create_dir_all(&quarantine)?;
+
+        for name in quarantined {
+            env.rename(&config.segments_path.join(name), &quarantine.join(name))?;
+        }
+        env.sync_dir(&config.segments_path)?;
+    }
+
+    report.repaired = true;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use anyhow::Result;
+
+    use super::{VerifyLevel, VerifyReport};
+    use crate::scrubber::QUARANTINE_NAME;
+    use crate::test_utils::Test;
+    use crate::Error;
+
+    #[test]
+    fn opening_refuses_a_storage_with_missing_or_corrupt_sstables_unless_repairing() -> Result<()> {
+        let test = Test::new()?;
+        let mut storage = test.create_storage()?;
+        let threshold = storage.config.threshold;
+
+        Test::inject_data(&mut storage, threshold * 2)?;
+        storage.tick()?;
+
+        let names: Vec<String> = {
+            let engine = storage.engine.lock().unwrap();
+            engine
+                .version
+                .sstables0
+                .iter()
+                .chain(engine.version.sstables1.iter())
+                .map(|sstable| sstable.path().file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert!(names.len() >= 2);
+        drop(storage);
+
+        let healthy = test.storage_builder().verify_on_open(VerifyLevel::Full).build()?;
+        assert_eq!(healthy.verify_report(), Some(&VerifyReport::default()));
+        drop(healthy);
+
+        fs::remove_file(test.path(&names[0]))?;
+        OpenOptions::new().append(true).open(test.path(&names[1]))?.write_all(&[0xff; 3])?;
+        fs::write(test.path("sstable-stray"), b"leftover")?;
+
+        // Only reading the sstables whole finds the corrupt one.
+        let error = test.storage_builder().verify_on_open(VerifyLevel::Manifest).build().err().unwrap();
+        let report = match error.downcast_ref::<Error>() {
+            Some(Error::VerificationFailed { report }) => report.clone(),
+            _ => panic!("unexpected error: {:#}", error),
+        };
+        assert_eq!(report.missing, vec![names[0].clone()]);
+        assert_eq!(report.unrecorded, vec!["sstable-stray".to_owned()]);
+        assert!(report.corrupt.is_empty());
+
+        let error = test.storage_builder().verify_on_open(VerifyLevel::Full).build().err().unwrap();
+        let report = match error.downcast_ref::<Error>() {
+            Some(Error::VerificationFailed { report }) => report.clone(),
+            _ => panic!("unexpected error: {:#}", error),
+        };
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].0, names[1]);
+
+        let repaired = test
+            .storage_builder()
+            .verify_on_open(VerifyLevel::Full)
+            .repair(true)
+            .build()?;
+        let report = repaired.verify_report().unwrap();
+        assert!(report.repaired);
+        assert_eq!(report.missing, vec![names[0].clone()]);
+        assert!(test.path(QUARANTINE_NAME).join(&names[1]).exists());
+        assert!(test.path(QUARANTINE_NAME).join("sstable-stray").exists());
+        drop(repaired);
+
+        let reopened = test.storage_builder().verify_on_open(VerifyLevel::Full).build()?;
+        assert_eq!(reopened.verify_report(), Some(&VerifyReport::default()));
+
+        Ok(())
+    }
+}
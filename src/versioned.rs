@@ -0,0 +1,328 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::storage::Storage;
+use crate::Error;
+
+/// Separates a key from the timestamp of its version in the keys of the storage.
+const SEPARATOR: char = '\0';
+
+/// How many hexadecimal digits a timestamp takes in the keys of the storage.
+const TIMESTAMP_DIGITS: usize = 16;
+
+/// Marks the values of removed versions.
+const REMOVED: u8 = 0;
+
+/// Marks the values of inserted versions, which follow it.
+const INSERTED: u8 = 1;
+
+/// Which versions of each key compactions keep. See
+/// [`StorageBuilder::retention`](crate::storage::StorageBuilder::retention).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// Keeps the given number of versions of each key, the newest one included. Keeping none is
+    /// taken as keeping one.
+    Versions(usize),
+    /// Keeps the versions written within the given time, along with the newest one written
+    /// before, which reads within the time still see.
+    Age(Duration),
+}
+
+/// A [`RetentionPolicy`] as applied by a compaction, at the time it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Retention {
+    Versions(usize),
+    /// The timestamp before which only the newest version is kept.
+    Horizon(u64),
+}
+
+impl RetentionPolicy {
+    /// How the policy applies at the given time, in milliseconds since the UNIX epoch.
+    pub(crate) fn at(&self, now: Duration) -> Retention {
+        match *self {
+            RetentionPolicy::Versions(versions) => Retention::Versions(versions.max(1)),
+            RetentionPolicy::Age(age) => {
+                let horizon = now.saturating_sub(age).as_millis();
+                Retention::Horizon(u64::try_from(horizon).unwrap_or(u64::MAX))
+            }
+        }
+    }
+}
+
+/// Tells which versions a merge keeps. The merge goes through the keys in order, so through the
+/// versions of each key from the newest to the oldest.
+#[derive(Debug, Default)]
+pub(crate) struct Pruner {
+    /// The key of the last version seen.
+    key: String,
+    /// How many versions of the key were kept.
+    kept: usize,
+    /// Whether a version of the key written before the horizon was kept.
+    kept_expired: bool,
+}
+
+impl Pruner {
+    /// Whether the merge keeps the entry of the given key. Only versions are ever dropped.
+    pub fn keeps(&mut self, retention: Retention, key: &str) -> bool {
+        let (key, timestamp) = match decode_version(key) {
+            Some(version) => version,
+            None => return true,
+        };
+
+        if key != self.key {
+            self.key = key.to_owned();
+            self.kept = 0;
+            self.kept_expired = false;
+        }
+
+        let keeps = match retention {
+            Retention::Versions(versions) => self.kept < versions,
+            Retention::Horizon(horizon) if timestamp < horizon => {
+                // Reads after the horizon only ever see the newest version before it.
+                !std::mem::replace(&mut self.kept_expired, true)
+            }
+            Retention::Horizon(_) => true,
+        };
+
+        if keeps {
+            self.kept += 1;
+        }
+        keeps
+    }
+}
+
+/// A view over a [`Storage`] keeping every version of each key, written at a timestamp given by
+/// the caller, so that the keys can be read as they were at any time.
+///
+/// Timestamps are milliseconds since the UNIX epoch, which is what [`RetentionPolicy::Age`]
+/// measures them against.
+/// Each version is stored under its own key in the storage, made of the key and the timestamp,
+/// ordered so that the newest version of a key comes first.
+pub struct VersionedStorage {
+    storage: Storage,
+}
+
+impl VersionedStorage {
+    pub fn new(storage: Storage) -> Self {
+        VersionedStorage { storage }
+    }
+
+    /// Returns the value of the key as it was at the given timestamp: that of its newest version
+    /// written at or before it, unless that version removed the key.
+    pub fn read_at(&self, key: &str, timestamp: u64) -> Result<Option<Vec<u8>>> {
+        let start = encode_version(key, timestamp)?;
+
+        let (version, value) = match self.storage.iter_from(&start)?.next().transpose()? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        match (decode_version(&version), value.split_first()) {
+            (Some((other, _)), Some((&INSERTED, value))) if other == key => Ok(Some(value.to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the value of the newest version of the key, unless it removed the key.
+    pub fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.read_at(key, u64::MAX)
+    }
+
+    /// Writes a version of the key holding the given value at the given timestamp, replacing the
+    /// version written at the same timestamp, if any. Keys may not contain `\0`.
+    pub fn insert(&self, key: &str, timestamp: u64, value: &[u8]) -> Result<()> {
+        let mut stored = Vec::with_capacity(value.len() + 1);
+        stored.push(INSERTED);
+        stored.extend_from_slice(value);
+
+        self.storage.wait_for_writer().put(encode_version(key, timestamp)?, stored, None)
+    }
+
+    /// Writes a version of the key removing it at the given timestamp. Older versions are kept,
+    /// so reads at earlier timestamps still find them.
+    pub fn remove(&self, key: &str, timestamp: u64) -> Result<()> {
+        self.storage.wait_for_writer().put(encode_version(key, timestamp)?, vec![REMOVED], None)
+    }
+
+    /// Returns the underlying storage.
+    pub fn into_inner(self) -> Storage {
+        self.storage
+    }
+}
+
+impl Clone for VersionedStorage {
+    fn clone(&self) -> Self {
+        VersionedStorage {
+            storage: self.storage.clone(),
+        }
+    }
+}
+
+/// The key of the storage holding the version of the key written at the given timestamp.
+///
+/// The timestamp is subtracted from the largest one, so that newer versions sort first: the first
+/// key from the encoded version on is then the newest version written at or before it.
+fn encode_version(key: &str, timestamp: u64) -> Result<String> {
+    if key.contains(SEPARATOR) {
+        return Err(Error::InvalidKey {
+            key: key.to_owned(),
+            reason: "versioned keys may not contain \\0".to_owned(),
+        }
+        .into());
+    }
+
+    Ok(format!("{}{}{:016x}", key, SEPARATOR, u64::MAX - timestamp))
+}
+
+/// Fails if the key, written other than through a [`VersionedStorage`], holds `\0`, as the keys of
+/// its versions do.
+pub(crate) fn check_unversioned(key: &str) -> Result<()> {
+    if key.contains(SEPARATOR) {
+        return Err(Error::InvalidKey {
+            key: key.to_owned(),
+            reason: "keys may not contain \\0 when the storage has a retention policy".to_owned(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The key and timestamp of a version, if the key of the storage holds one.
+pub(crate) fn decode_version(version: &str) -> Option<(&str, u64)> {
+    let (key, timestamp) = version.rsplit_once(SEPARATOR)?;
+
+    if timestamp.len() != TIMESTAMP_DIGITS || key.contains(SEPARATOR) {
+        return None;
+    }
+
+    let timestamp = u64::from_str_radix(timestamp, 16).ok()?;
+    Some((key, u64::MAX - timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::{decode_version, encode_version, RetentionPolicy, VersionedStorage};
+    use crate::scheduler::ManualClock;
+    use crate::test_utils::Test;
+    use crate::Error;
+
+    #[test]
+    fn versions_are_encoded_newest_first() -> Result<()> {
+        assert!(encode_version("key", 20)? < encode_version("key", 10)?);
+        assert_eq!(decode_version(&encode_version("key", 10)?), Some(("key", 10)));
+        assert_eq!(decode_version("key"), None);
+        assert!(encode_version("k\0ey", 10).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_at_returns_the_value_visible_at_the_timestamp() -> Result<()> {
+        let test = Test::new()?;
+        let storage = VersionedStorage::new(test.create_storage()?);
+
+        storage.insert("key", 10, b"first")?;
+        storage.insert("key", 20, b"second")?;
+        storage.remove("key", 30)?;
+        storage.insert("key", 40, b"third")?;
+        storage.insert("other", 15, b"other")?;
+
+        assert_eq!(storage.read_at("key", 5)?, None);
+        assert_eq!(storage.read_at("key", 10)?, Some(b"first".to_vec()));
+        assert_eq!(storage.read_at("key", 25)?, Some(b"second".to_vec()));
+        assert_eq!(storage.read_at("key", 35)?, None);
+        assert_eq!(storage.read("key")?, Some(b"third".to_vec()));
+        assert_eq!(storage.read_at("ke", 50)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_drop_the_versions_past_the_retention_horizon() -> Result<()> {
+        let test = Test::new()?;
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_millis(1000));
+
+        let storage = test
+            .storage_builder()
+            .clock(clock)
+            .retention(RetentionPolicy::Age(Duration::from_millis(100)))
+            .build()?;
+        let storage = VersionedStorage::new(storage);
+
+        // Compactions only drop versions when merging sstables, so they are split between two.
+        storage.insert("key", 100, b"first")?;
+        storage.insert("key", 200, b"second")?;
+        storage.storage.flush()?;
+        storage.insert("key", 950, b"third")?;
+        storage.insert("key", 980, b"fourth")?;
+        storage.storage.flush()?;
+        storage.storage.tick()?;
+        storage.storage.compact()?;
+
+        // The newest version before the horizon is kept, as it was still visible after it.
+        assert_eq!(storage.read_at("key", 150)?, None);
+        assert_eq!(storage.read_at("key", 250)?, Some(b"second".to_vec()));
+        assert_eq!(storage.read_at("key", 960)?, Some(b"third".to_vec()));
+        assert_eq!(storage.read("key")?, Some(b"fourth".to_vec()));
+        assert_eq!(storage.storage.stats()?.dropped_versions, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_keep_as_many_versions_as_the_retention_allows() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test
+            .storage_builder()
+            .retention(RetentionPolicy::Versions(2))
+            .build()?;
+        let storage = VersionedStorage::new(storage);
+
+        for timestamp in [10, 20, 30] {
+            storage.insert("key", timestamp, timestamp.to_string().as_bytes())?;
+            storage.insert("other", timestamp, timestamp.to_string().as_bytes())?;
+        }
+        storage.storage.flush()?;
+        storage.remove("key", 40)?;
+        storage.storage.flush()?;
+        storage.storage.tick()?;
+        storage.storage.compact()?;
+
+        assert_eq!(storage.read_at("key", 25)?, None);
+        assert_eq!(storage.read_at("key", 35)?, Some(b"30".to_vec()));
+        assert_eq!(storage.read_at("key", 45)?, None);
+        assert_eq!(storage.read_at("other", 15)?, None);
+        assert_eq!(storage.read_at("other", 25)?, Some(b"20".to_vec()));
+        assert_eq!(storage.storage.stats()?.dropped_versions, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn storages_with_a_retention_policy_refuse_other_keys_shaped_as_versions() -> Result<()> {
+        let raw = encode_version("key", 10)?;
+        Test::new()?.create_storage()?.insert(raw.clone(), b"raw".to_vec())?;
+
+        let test = Test::new()?;
+        let storage = test.storage_builder().retention(RetentionPolicy::Versions(1)).build()?;
+        let error = storage.insert(raw.clone(), b"raw".to_vec()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::InvalidKey { .. })));
+        assert!(storage.insert_batch([(raw.clone(), b"raw".to_vec())]).is_err());
+        assert!(storage.remove(raw).is_err());
+        storage.insert("key".to_owned(), b"raw".to_vec())?;
+
+        let storage = VersionedStorage::new(storage);
+        storage.insert("key", 20, b"versioned")?;
+        assert_eq!(storage.read("key")?, Some(b"versioned".to_vec()));
+
+        Ok(())
+    }
+}
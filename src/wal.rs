@@ -0,0 +1,489 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::env::{Env, EnvFile, OsEnv};
+use crate::error::Error;
+use crate::watch::Change;
+use crate::format::{self, WalEntry};
+use crate::{Stored, WAL_ARCHIVE_NAME, WAL_NAME};
+
+/// How long a blocking [`WalTail`] waits before looking for new writes again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Defines how the memtables are recovered from WALs holding corrupt entries when the storage is
+/// opened. Whatever is left after the last entry kept is truncated, so that new writes follow it.
+/// An entry that can't even be framed fails the recovery with [`Error::Corruption`] whatever the
+/// mode, as the entries after it can't be found, and an I/O error fails it as is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Drops the last entry when it is torn or doesn't match its checksum, as a crash tears at
+    /// most the last entry, and fails with [`Error::Corruption`] at any such entry followed by
+    /// others, rather than dropping entries that were whole.
+    #[default]
+    TolerateCorruptTail,
+    /// Fails with [`Error::Corruption`] at the first entry that is torn or doesn't match its
+    /// checksum, even one torn by a crash, rather than dropping any.
+    AbsoluteConsistency,
+    /// Drops the entries that don't match their checksum and keeps the ones after them, stopping
+    /// at the first torn entry as [`RecoveryMode::TolerateCorruptTail`] does.
+    SkipCorruptRecords,
+}
+
+/// What opening the storage recovered. See
+/// [`Storage::recovery_report`](crate::storage::Storage::recovery_report).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// The WALs replayed into memtables, from the oldest to the newest.
+    pub wals: Vec<WalReplay>,
+    /// The WALs removed without being replayed, or archived if they are: those of memtables
+    /// already persisted or cleared, and those a crash tore before their header was written.
+    pub removed_wals: Vec<PathBuf>,
+    /// How many sstables were loaded into each level, from L0 on.
+    pub sstables: Vec<usize>,
+    /// The sstables moved into the quarantine directory by checking the storage on open. See
+    /// [`StorageBuilder::repair`](crate::storage::StorageBuilder::repair).
+    pub quarantined: Vec<String>,
+}
+
+/// What was replayed from a WAL into its memtable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalReplay {
+    pub path: PathBuf,
+    /// How many entries were recovered.
+    pub entries: usize,
+    /// How many entries were dropped for not matching their checksum. See
+    /// [`RecoveryMode::SkipCorruptRecords`].
+    pub skipped: usize,
+    /// How many bytes were truncated after the last entry kept: a torn entry, or everything from
+    /// the first corrupt one on.
+    pub truncated_bytes: u64,
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: usize = self.wals.iter().map(|wal| wal.entries).sum();
+        let skipped: usize = self.wals.iter().map(|wal| wal.skipped).sum();
+        let truncated: u64 = self.wals.iter().map(|wal| wal.truncated_bytes).sum();
+
+        write!(
+            f,
+            "{} entries replayed from {} WALs, {} corrupt entries skipped, {} bytes truncated, {} sstables loaded and {} quarantined",
+            entries,
+            self.wals.len(),
+            skipped,
+            truncated,
+            self.sstables.iter().sum::<usize>(),
+            self.quarantined.len()
+        )
+    }
+}
+
+/// The WALs of a storage: one per memtable, holding the writes to it in the order they were
+/// committed, until the memtable is flushed, along with those archived once it was, if any.
+pub struct Wal {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+}
+
+impl Wal {
+    /// The WALs kept in the given directory, the WAL path of a storage. They are only read, so the
+    /// storage may be open.
+    pub fn open(dir: &Path) -> Self {
+        Wal::new(&(Arc::new(OsEnv) as Arc<dyn Env>), dir)
+    }
+
+    pub(crate) fn new(env: &Arc<dyn Env>, dir: &Path) -> Self {
+        Wal {
+            env: env.clone(),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Follows the writes after the given sequence number, as they are appended to the WALs,
+    /// starting from those the WALs still hold. See [`WalTail`].
+    pub fn tail(&self, after: u64) -> WalTail {
+        WalTail {
+            env: self.env.clone(),
+            dir: self.dir.clone(),
+            next_seqno: after.saturating_add(1),
+            started: false,
+            current: None,
+            pending: None,
+        }
+    }
+}
+
+/// Yields the writes appended to the WALs of a storage, in the order they were committed, moving
+/// on from the WAL of a memtable to the next one once nothing can be appended to it anymore: when
+/// the next one continues where it ends, or when it is removed after its memtable is flushed.
+///
+/// Fails with [`Error::ChangesUnavailable`] if the writes right after the sequence number the tail
+/// started from were flushed already, and with [`Error::ChangesLost`] if it falls so far behind
+/// that WALs are removed before it reads them, going on with the writes from then on.
+///
+/// A torn write at the end of a WAL is waited for, as it may still be being appended.
+pub struct WalTail {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    /// The sequence number of the next write to yield. Older writes are skipped.
+    next_seqno: u64,
+    /// Whether a write was read already, the first of which must not be past `next_seqno`.
+    started: bool,
+    current: Option<Segment>,
+    /// A write read but not yielded yet: one read after some were lost, yielded once they were
+    /// reported, or one peeked at.
+    pending: Option<Change>,
+}
+
+impl WalTail {
+    /// Returns the next write, or `None` if every write appended so far was returned already.
+    pub fn poll(&mut self) -> Result<Option<Change>> {
+        if let Some(change) = self.pending.take() {
+            return Ok(Some(self.yielded(change)));
+        }
+
+        loop {
+            let segment = match &mut self.current {
+                Some(segment) => segment,
+                None => match self.open_after(None)? {
+                    Some(segment) => self.current.insert(segment),
+                    None => return Ok(None),
+                },
+            };
+
+            if let Some(change) = segment.read()? {
+                // Nothing is yielded past the gap, however many times the tail is polled.
+                if !self.started && change.seqno > self.next_seqno {
+                    return Err(Error::ChangesUnavailable { after: self.next_seqno - 1 }.into());
+                }
+                self.started = true;
+
+                if change.seqno < self.next_seqno {
+                    continue;
+                }
+                if change.seqno > self.next_seqno && segment.follows_removed {
+                    let missed = change.seqno - self.next_seqno;
+                    self.pending = Some(change);
+                    return Err(Error::ChangesLost { missed }.into());
+                }
+
+                return Ok(Some(self.yielded(change)));
+            }
+
+            let (id, removed) = (segment.id, segment.removed);
+            if removed {
+                // Read to the end since it was removed, so nothing more can be appended to it.
+                self.current = self.open_after(Some(id))?.map(|mut next| {
+                    next.follows_removed = true;
+                    next
+                });
+                if self.current.is_none() {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let wals = self.list()?;
+            if !wals.iter().any(|(other, _)| *other == id) {
+                // The writes appended right before it was removed are read first.
+                if let Some(segment) = &mut self.current {
+                    segment.removed = true;
+                }
+                continue;
+            }
+
+            // The memtable of a WAL may get its last writes after the WAL of the next memtable is
+            // created, so the tail only moves on once the next WAL continues where this one ends.
+            let mut next = match self.open_after(Some(id))? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            match next.read()? {
+                Some(change) if change.seqno == self.next_seqno => {
+                    self.current = Some(next);
+                    return Ok(Some(self.yielded(change)));
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the next write like [`WalTail::poll`], but without moving past it, so that the
+    /// next poll returns it again.
+    pub(crate) fn peek(&mut self) -> Result<Option<&Change>> {
+        if self.pending.is_none() {
+            self.pending = self.poll()?;
+        }
+
+        Ok(self.pending.as_ref())
+    }
+
+    fn yielded(&mut self, change: Change) -> Change {
+        self.next_seqno = change.seqno + 1;
+        change
+    }
+
+    /// The WALs in the directory, archived or not, by id. A WAL keeps its id once archived.
+    fn list(&self) -> Result<Vec<(usize, PathBuf)>> {
+        Ok(list(self.env.as_ref(), &self.dir, &[WAL_NAME, WAL_ARCHIVE_NAME])?)
+    }
+
+    /// Opens the oldest WAL newer than the one with the given id, if any.
+    fn open_after(&self, id: Option<usize>) -> Result<Option<Segment>> {
+        for (other, path) in self.list()? {
+            if id.is_some_and(|id| other <= id) {
+                continue;
+            }
+
+            match self.env.open(&path) {
+                Ok(fd) => return Ok(Some(Segment::new(other, path, fd))),
+                // Removed since it was listed: its writes are flushed.
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Iterator for WalTail {
+    type Item = Result<Change>;
+
+    /// Waits for the next write, so the iterator never ends.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll() {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+/// The WALs in the given directory named after one of the given names and their id, by id.
+fn list(env: &dyn Env, dir: &Path, names: &[&str]) -> io::Result<Vec<(usize, PathBuf)>> {
+    let mut wals: Vec<(usize, PathBuf)> = env
+        .read_dir(dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let id = names
+                .iter()
+                .find_map(|prefix| name.strip_prefix(prefix)?.strip_prefix('-')?.parse().ok())?;
+            Some((id, path))
+        })
+        .collect();
+    wals.sort();
+
+    Ok(wals)
+}
+
+/// Whether the file at the given path is the WAL of a memtable, as told by its name, rather than
+/// another file of a storage. A checkpoint keeps both in the same directory, so that a storage
+/// keeping its WALs apart has to tell them apart to be replaced by it.
+pub fn is_wal(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(WAL_NAME)?.strip_prefix('-')?.parse::<usize>().ok())
+        .is_some()
+}
+
+/// The archived WALs in the given directory, the WAL path of a storage, from the oldest to the
+/// newest, by id.
+pub(crate) fn archived(env: &dyn Env, dir: &Path) -> io::Result<Vec<(usize, PathBuf)>> {
+    list(env, dir, &[WAL_ARCHIVE_NAME])
+}
+
+/// Whether the WAL at the given path holds a write to a key the predicate matches. One holding a
+/// corrupt entry is assumed to, as the entries after it can't be read, and one removed meanwhile
+/// holds none.
+pub(crate) fn holds_write(env: &dyn Env, path: &Path, matches: impl Fn(&str) -> bool) -> Result<bool> {
+    let fd = match env.open(path) {
+        Ok(fd) => fd,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error.into()),
+    };
+    let mut segment = Segment::new(0, path.to_path_buf(), fd);
+
+    loop {
+        match segment.read() {
+            Ok(Some(change)) if matches(&change.key) => return Ok(true),
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(false),
+            Err(error) if error.downcast_ref::<Error>().is_some() => return Ok(true),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// A WAL being read by a [`WalTail`].
+struct Segment {
+    id: usize,
+    path: PathBuf,
+    fd: Box<dyn EnvFile>,
+    /// Where the next write starts, or 0 until the header was read.
+    offset: u64,
+    /// Whether the WAL was removed, so that it ends once read whole.
+    removed: bool,
+    /// Whether the tail moved to this WAL because the previous one was removed, rather than
+    /// because this one continued it, so that writes may have been missed in between.
+    follows_removed: bool,
+    /// The writes of a batch read already, yet to be returned.
+    batch: VecDeque<Change>,
+}
+
+impl Segment {
+    fn new(id: usize, path: PathBuf, fd: Box<dyn EnvFile>) -> Self {
+        Segment {
+            id,
+            path,
+            fd,
+            offset: 0,
+            removed: false,
+            follows_removed: false,
+            batch: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next write, unless the WAL ends before it is whole. Fails with
+    /// [`Error::Corruption`] at an entry that doesn't match its checksum.
+    fn read(&mut self) -> Result<Option<Change>> {
+        if let Some(change) = self.batch.pop_front() {
+            return Ok(Some(change));
+        }
+
+        self.fd.seek(SeekFrom::Start(self.offset))?;
+
+        // A torn header or write ends the WAL, as it does when recovering a memtable from it.
+        if self.offset == 0 {
+            if !matches!(format::read_memtable_header(&mut self.fd), Ok(Some(_))) {
+                return Ok(None);
+            }
+            self.offset = self.fd.stream_position()?;
+        }
+
+        let writes = match format::read_wal_entry(&mut self.fd) {
+            Ok(Some(WalEntry::Writes(writes))) => writes,
+            Ok(Some(WalEntry::Corrupt(reason))) => {
+                let path = self.path.clone();
+                return Err(Error::Corruption { path, offset: self.offset, reason }.into());
+            }
+            Ok(None) | Err(_) => return Ok(None),
+        };
+        self.offset = self.fd.stream_position()?;
+
+        self.batch.extend(writes.into_iter().map(|(key, value, seqno)| {
+            let (value, expires_at) = match value {
+                Stored::Value(value) => (Some(value), None),
+                Stored::Expiring(value, expires_at) => (Some(value), Some(expires_at)),
+                Stored::Tombstone => (None, None),
+            };
+
+            Change { key, value, seqno, expires_at }
+        }));
+
+        Ok(self.batch.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Wal, WalTail};
+    use crate::storage::{Change, Storage};
+    use crate::test_utils::Test;
+    use crate::Error;
+
+    /// Polls the tail until it caught up, returning the sequence numbers of the writes.
+    fn drain(tail: &mut WalTail) -> Result<Vec<u64>> {
+        let mut seqnos = Vec::new();
+        while let Some(change) = tail.poll()? {
+            seqnos.push(change.seqno);
+        }
+
+        Ok(seqnos)
+    }
+
+    fn insert(storage: &Storage, keys: std::ops::Range<usize>) -> Result<()> {
+        for i in keys {
+            storage.insert(format!("key-{}", i), format!("value-{}", i).into_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tails_follow_the_writes_across_memtables() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+        let mut tail = storage.tail_wal(Some(0));
+
+        insert(&storage, 0..5)?;
+        let first = Change { key: "key-0".to_owned(), value: Some(b"value-0".to_vec()), seqno: 1, expires_at: None };
+        assert_eq!(tail.poll()?, Some(first));
+        assert_eq!(drain(&mut tail)?, [2, 3, 4, 5]);
+        let mut from_now = storage.tail_wal(None);
+
+        // The WAL of the frozen memtable is kept until it is flushed.
+        storage.flush()?;
+        assert_eq!(storage.frozen_memtables()[0].iter().count(), 5);
+        insert(&storage, 5..7)?;
+        assert_eq!(drain(&mut tail)?, [6, 7]);
+        assert_eq!(drain(&mut from_now)?, [6, 7]);
+
+        // Whatever was appended to a WAL is read before moving past it, even once it is removed.
+        storage.flush()?;
+        insert(&storage, 7..9)?;
+        storage.tick()?;
+        storage.remove("key-0".to_owned())?;
+        assert_eq!(drain(&mut tail)?, [8, 9, 10]);
+
+        // Tails of the files alone see the same writes.
+        let mut tail = Wal::open(&test.test_path()).tail(7);
+        assert_eq!(drain(&mut tail)?, [8, 9, 10]);
+
+        // The writes of a batch are yielded one by one.
+        storage.remove_batch(["key-1".to_owned(), "key-2".to_owned()])?;
+        let batch = tail.poll()?.unwrap();
+        assert_eq!((batch.key.as_str(), batch.value, batch.seqno), ("key-1", None, 11));
+        assert_eq!(drain(&mut tail)?, [12]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tails_report_the_writes_they_can_no_longer_read() -> Result<()> {
+        let test = Test::new()?;
+        let storage = test.create_storage()?;
+
+        insert(&storage, 0..5)?;
+        let mut tail = storage.tail_wal(Some(0));
+        assert_eq!(drain(&mut tail)?, [1, 2, 3, 4, 5]);
+
+        storage.flush()?;
+        insert(&storage, 5..8)?;
+        storage.flush()?;
+        insert(&storage, 8..9)?;
+        storage.tick()?;
+
+        // Both WALs the tail was yet to read were removed.
+        let lost = tail.poll().unwrap_err();
+        assert!(matches!(lost.downcast_ref::<Error>(), Some(Error::ChangesLost { missed: 3 })));
+        assert_eq!(drain(&mut tail)?, [9]);
+
+        let unavailable = storage.tail_wal(Some(2)).poll().unwrap_err();
+        assert!(matches!(unavailable.downcast_ref::<Error>(), Some(Error::ChangesUnavailable { after: 2 })));
+
+        Ok(())
+    }
+}
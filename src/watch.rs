@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// How many updates a watcher may fall behind before it starts missing them.
+const WATCH_CAPACITY: usize = 16;
+
+/// How many changes a subscriber may fall behind before it starts missing them.
+const CHANGES_CAPACITY: usize = 1024;
+
+/// A write committed to the storage, as delivered to its subscribers. See
+/// [`Storage::subscribe`](crate::storage::Storage::subscribe).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: String,
+    /// The new value of the key, or `None` if the key was removed.
+    pub value: Option<Vec<u8>>,
+    /// The sequence number of the write, which a subscription can be resumed after.
+    pub seqno: u64,
+    /// When the new value expires, in milliseconds since the UNIX epoch, if it was inserted with
+    /// a TTL.
+    pub expires_at: Option<u64>,
+}
+
+/// Keeps track of the keys being watched and notifies their watchers whenever they change.
+///
+/// An update carries the new value of the key, or `None` if the key was removed.
+///
+/// Every write is also sent, as a [`Change`], to those subscribed to all of them.
+pub(crate) struct Watchers {
+    senders: Mutex<HashMap<String, Sender<Option<Vec<u8>>>>>,
+    changes: Sender<Change>,
+}
+
+impl Default for Watchers {
+    fn default() -> Self {
+        Watchers {
+            senders: Mutex::default(),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        }
+    }
+}
+
+impl Watchers {
+    /// Subscribes to every change from now on.
+    pub fn subscribe_changes(&self) -> Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    /// Whether anyone is subscribed to the changes, so that they need to be published.
+    pub fn has_subscribers(&self) -> bool {
+        self.changes.receiver_count() > 0
+    }
+
+    pub fn publish(&self, change: Change) {
+        let _ = self.changes.send(change);
+    }
+
+    pub fn subscribe(&self, key: &str) -> Receiver<Option<Vec<u8>>> {
+        let mut senders = self.senders.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match senders.get(key) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(WATCH_CAPACITY);
+                senders.insert(key.to_owned(), sender);
+                receiver
+            }
+        }
+    }
+
+    /// Returns the sender for the given key if anyone is still watching it.
+    pub fn sender(&self, key: &str) -> Option<Sender<Option<Vec<u8>>>> {
+        let mut senders = self.senders.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match senders.get(key) {
+            Some(sender) if sender.receiver_count() == 0 => {
+                senders.remove(key);
+                None
+            }
+            sender => sender.cloned(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Takes a storage whose sstables and WALs are kept apart through flushes, compactions and
+//! restarts, checking that every write survives each reopen and that the files stay where the
+//! storage was told to keep them.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use lsm_storage::scheduler::Scheduling;
+use lsm_storage::storage::{Storage, StorageBuilder};
+
+const THRESHOLD: usize = 64;
+
+fn builder(segments: &Path, wals: &Path) -> StorageBuilder {
+    Storage::builder()
+        .segments_path(segments.to_path_buf())
+        .wal_path(wals.to_path_buf())
+        .threshold(THRESHOLD)
+        .scheduling(Scheduling::Manual)
+}
+
+/// Checks that the storage holds exactly the expected entries, through point reads and a scan.
+fn check(storage: &Storage, expected: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+    for (key, value) in expected {
+        assert_eq!(storage.read(key)?.as_ref(), Some(value), "{}", key);
+    }
+
+    let entries: BTreeMap<String, Vec<u8>> = storage.iter()?.collect::<Result<_>>()?;
+    assert_eq!(&entries, expected);
+
+    Ok(())
+}
+
+fn file_names(dir: &Path) -> Result<Vec<String>> {
+    fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect()
+}
+
+#[test]
+fn flushed_and_compacted_writes_survive_reopening_the_storage() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let (segments, wals) = (tempdir.path().join("segments"), tempdir.path().join("wals"));
+    let mut expected = BTreeMap::new();
+
+    let storage = builder(&segments, &wals).build()?;
+    for i in 0..THRESHOLD * 5 {
+        let (key, value) = (format!("key-{:04}", i), format!("value-{}", i).into_bytes());
+        storage.insert(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    for i in (0..THRESHOLD * 5).step_by(7) {
+        let key = format!("key-{:04}", i);
+        storage.remove(key.clone())?;
+        expected.remove(&key);
+    }
+    storage.flush()?;
+    storage.tick()?;
+    assert!(!storage.levels()?[0].is_empty());
+
+    // Only in the WAL when the storage is dropped.
+    storage.insert("unflushed".to_owned(), b"in the WAL".to_vec())?;
+    expected.insert("unflushed".to_owned(), b"in the WAL".to_vec());
+    drop(storage);
+
+    let storage = builder(&segments, &wals).build()?;
+    check(&storage, &expected)?;
+
+    storage.compact()?;
+    let levels = storage.levels()?;
+    assert!(levels[0].is_empty());
+    assert!(!levels[1].is_empty());
+    for table in levels.iter().flatten() {
+        assert_eq!(table.path.parent(), Some(segments.as_path()));
+    }
+
+    storage.insert("key-0000".to_owned(), b"again".to_vec())?;
+    expected.insert("key-0000".to_owned(), b"again".to_vec());
+    storage.flush()?;
+    storage.tick()?;
+    drop(storage);
+
+    let storage = builder(&segments, &wals).build()?;
+    check(&storage, &expected)?;
+    drop(storage);
+
+    for name in file_names(&segments)? {
+        assert!(name.starts_with("sstable-") || name == "MANIFEST" || name == "IDENTITY" || name == "LOCK", "{}", name);
+    }
+    for name in file_names(&wals)? {
+        assert!(name.starts_with("write-ahead-log-") || name == "LOCK-WAL" || name == "IDENTITY", "{}", name);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Hammers the storage with concurrent writers and readers while memtables are flushed and L0 is
+//! compacted in the background, checking that point reads are linearizable.
+//!
+//! Every write stores the next version of a key, and each key is owned by a single writer. A
+//! reader must never see a version older than the last acknowledged one, nor one that was never
+//! written, and the versions it sees for a key must never go back.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lsm_storage::storage::Storage;
+
+const WRITERS: usize = 4;
+const READERS: usize = 4;
+const KEYS: usize = 1_024;
+const WRITES_PER_WRITER: usize = 1_500;
+/// How long readers keep going after the writers finish, while the background work catches up.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+fn key(n: usize) -> String {
+    format!("key-{:04}", n)
+}
+
+fn encode(version: u64) -> Vec<u8> {
+    version.to_be_bytes().to_vec()
+}
+
+fn decode(value: &[u8]) -> u64 {
+    u64::from_be_bytes(value.try_into().expect("values are 8 bytes long"))
+}
+
+/// The versions of a key known to the test.
+#[derive(Default)]
+struct Versions {
+    /// The last version a writer started writing.
+    issued: AtomicU64,
+    /// The last version the storage acknowledged.
+    acknowledged: AtomicU64,
+}
+
+#[test]
+fn point_reads_are_linearizable_under_concurrent_writes_flushes_and_compactions() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let storage = Storage::builder()
+        .segments_path(tempdir.path().to_path_buf())
+        .wal_path(tempdir.path().to_path_buf())
+        .threshold(64)
+        .compaction_interval(Duration::from_millis(20))
+        .row_cache_capacity(16 * 1024)
+        .build()?;
+
+    let versions: Arc<Vec<Versions>> = Arc::new((0..KEYS).map(|_| Versions::default()).collect());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|id| {
+            let versions = versions.clone();
+            let writer = storage.clone();
+
+            thread::spawn(move || -> Result<()> {
+                let owned_keys: Vec<usize> = (id..KEYS).step_by(WRITERS).collect();
+
+                for n in 0..WRITES_PER_WRITER {
+                    let k = owned_keys[n % owned_keys.len()];
+                    let version = versions[k].issued.load(Ordering::SeqCst) + 1;
+
+                    versions[k].issued.store(version, Ordering::SeqCst);
+                    writer.insert(key(k), encode(version))?;
+                    versions[k].acknowledged.store(version, Ordering::SeqCst);
+                }
+
+                Ok(())
+            })
+        })
+        .collect();
+
+    let readers: Vec<_> = (0..READERS)
+        .map(|id| {
+            let versions = versions.clone();
+            let reader = storage.clone();
+            let done = done.clone();
+
+            thread::spawn(move || -> Result<usize> {
+                let mut seen: HashMap<usize, u64> = HashMap::new();
+                let mut reads = 0;
+
+                while !done.load(Ordering::SeqCst) {
+                    let k = (reads * 7 + id) % KEYS;
+                    let acknowledged = versions[k].acknowledged.load(Ordering::SeqCst);
+                    let read = reader.read(&key(k))?.map(|value| decode(&value)).unwrap_or(0);
+                    let issued = versions[k].issued.load(Ordering::SeqCst);
+
+                    if read < acknowledged {
+                        return Err(anyhow!("{} is stale: read {}, acknowledged {}", key(k), read, acknowledged));
+                    }
+
+                    if read > issued {
+                        return Err(anyhow!("{} is from the future: read {}, issued {}", key(k), read, issued));
+                    }
+
+                    let last_seen = seen.entry(k).or_default();
+                    if read < *last_seen {
+                        return Err(anyhow!("{} went back: read {} after {}", key(k), read, last_seen));
+                    }
+
+                    *last_seen = read;
+                    reads += 1;
+                }
+
+                Ok(reads)
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().map_err(|_| anyhow!("a writer panicked"))??;
+    }
+
+    thread::sleep(SETTLE_TIME);
+    done.store(true, Ordering::SeqCst);
+
+    for handle in readers {
+        let reads = handle.join().map_err(|_| anyhow!("a reader panicked"))??;
+        assert!(reads > 0);
+    }
+
+    for (k, versions) in versions.iter().enumerate() {
+        let expected = versions.acknowledged.load(Ordering::SeqCst);
+        assert_eq!(storage.read(&key(k))?.map(|value| decode(&value)), Some(expected));
+    }
+
+    Ok(())
+}